@@ -0,0 +1,109 @@
+use crate::clock;
+use crate::metrics::{MetricType, Registry};
+use crate::wireguard::{Endpoint, WireGuard};
+use std::time::Duration;
+
+/// Simple thresholds evaluated by the exporter itself, for the
+/// deployments that do not have Prometheus to evaluate alerting rules.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AlertRules {
+    /// violated if a peer's latest handshake is older than this
+    pub handshake_max_age: Option<Duration>,
+    /// violated if fewer peers than this are online
+    pub min_peers: Option<usize>,
+    /// violated if more peers than this are online
+    pub max_peers: Option<usize>,
+}
+
+impl AlertRules {
+    pub fn is_empty(&self) -> bool {
+        self.handshake_max_age.is_none() && self.min_peers.is_none() && self.max_peers.is_none()
+    }
+
+    /// Renders, for every interface and rule, a gauge that is 1 if the
+    /// rule is violated and 0 otherwise. Peers never connected do not
+    /// violate `handshake_max_age`; a peer is online as per
+    /// `RemoteEndpoint::is_online`.
+    pub fn render(
+        &self,
+        registry: &mut Registry,
+        wg: &WireGuard,
+        now: u64,
+        handshake_timeout: Duration,
+    ) {
+        let mut alert = registry.family(
+            "wireguard_exporter_alert",
+            MetricType::Gauge,
+            "Whether the alert rule is violated",
+        );
+
+        let mut interfaces: Vec<_> = wg.interfaces.iter().collect();
+        interfaces.sort_by_key(|(interface, _)| *interface);
+
+        for (interface, endpoints) in interfaces {
+            let peers: Vec<_> = endpoints
+                .iter()
+                .filter_map(|endpoint| match endpoint {
+                    Endpoint::Remote(ep) => Some(ep),
+                    Endpoint::Local(_) => None,
+                })
+                .collect();
+            let online = peers
+                .iter()
+                .filter(|ep| ep.is_online(now, handshake_timeout))
+                .count();
+
+            let mut rules = Vec::new();
+            if let Some(handshake_max_age) = self.handshake_max_age {
+                let violated = peers.iter().any(|ep| {
+                    ep.latest_handshake != 0
+                        && clock::age(now, ep.latest_handshake) > handshake_max_age.as_secs()
+                });
+                rules.push(("handshake_max_age", violated));
+            }
+            if let Some(min_peers) = self.min_peers {
+                rules.push(("min_peers", online < min_peers));
+            }
+            if let Some(max_peers) = self.max_peers {
+                rules.push(("max_peers", online > max_peers));
+            }
+
+            for (rule, violated) in rules {
+                let attributes = [("interface", interface as &str), ("rule", rule)];
+                alert.push(&attributes, f64::from(violated as u8));
+            }
+        }
+
+        registry.register(alert);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    const TEXT: &str = "wg0\t000q4qAC0ExW/BuGSmVR1nxH9JAXT6g9Wd3oEGy5lA=\t0000u8LWR682knVm350lnuqlCJzw5SNLW9Nf96P+m8=\t51820\toff
+wg0\t2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=\t(none)\t37.159.76.245:29159\t10.70.0.2/32\t1000\t10288508\t139524160\toff
+wg0\tqnoxQoQI8KKMupLnSSureORV0wMmH7JryZNsmGVISzU=\t(none)\t(none)\t10.70.0.3/32\t0\t0\t0\toff
+wg1\t928vO9Lf4+Mo84cWu4k1oRyzf0AR7FTGoPKHGoTMSHk=\t(none)\t5.90.62.106:21741\t10.70.1.80/32\t500\t283012\t6604620\toff
+";
+
+    #[test]
+    fn test_render() {
+        let wg = WireGuard::try_from(TEXT).unwrap();
+        let rules = AlertRules {
+            handshake_max_age: Some(Duration::from_secs(300)),
+            min_peers: Some(1),
+            max_peers: None,
+        };
+
+        let mut registry = Registry::default();
+        rules.render(&mut registry, &wg, 1100, Duration::from_secs(180));
+        assert_eq!(
+            registry.render_text(),
+            "# HELP wireguard_exporter_alert Whether the alert rule is violated\n# TYPE wireguard_exporter_alert gauge\nwireguard_exporter_alert{interface=\"wg0\",rule=\"handshake_max_age\"} 0\nwireguard_exporter_alert{interface=\"wg0\",rule=\"min_peers\"} 0\nwireguard_exporter_alert{interface=\"wg1\",rule=\"handshake_max_age\"} 1\nwireguard_exporter_alert{interface=\"wg1\",rule=\"min_peers\"} 1\n"
+        );
+        assert!(AlertRules::default().is_empty());
+    }
+}
@@ -0,0 +1,85 @@
+use crate::metrics::{MetricType, Registry};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Wraps the system allocator keeping track of how much memory is in use.
+/// Only compiled in with the `heap-profiling` feature since the atomic
+/// operations add a (small) cost to every allocation.
+pub(crate) struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static DEALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(allocated, Ordering::Relaxed);
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct HeapStats {
+    pub allocated_bytes: usize,
+    pub peak_allocated_bytes: usize,
+    pub allocations: usize,
+    pub deallocations: usize,
+}
+
+pub(crate) fn heap_stats() -> HeapStats {
+    HeapStats {
+        allocated_bytes: ALLOCATED.load(Ordering::Relaxed),
+        peak_allocated_bytes: PEAK.load(Ordering::Relaxed),
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        deallocations: DEALLOCATIONS.load(Ordering::Relaxed),
+    }
+}
+
+/// Renders the heap statistics as the exporter's own metrics.
+pub(crate) fn render(registry: &mut Registry) {
+    let stats = heap_stats();
+
+    let families = [
+        (
+            "wireguard_exporter_heap_allocated_bytes",
+            MetricType::Gauge,
+            "Bytes allocated on the heap by the exporter",
+            stats.allocated_bytes,
+        ),
+        (
+            "wireguard_exporter_heap_peak_allocated_bytes",
+            MetricType::Gauge,
+            "Highest number of bytes allocated on the heap by the exporter",
+            stats.peak_allocated_bytes,
+        ),
+        (
+            "wireguard_exporter_heap_allocations_total",
+            MetricType::Counter,
+            "Heap allocations performed by the exporter",
+            stats.allocations,
+        ),
+        (
+            "wireguard_exporter_heap_deallocations_total",
+            MetricType::Counter,
+            "Heap deallocations performed by the exporter",
+            stats.deallocations,
+        ),
+    ];
+    for (name, metric_type, help, value) in families.iter() {
+        let mut family = registry.family(name, *metric_type, help);
+        family.push(&[], *value as f64);
+        registry.register(family);
+    }
+}
@@ -0,0 +1,120 @@
+// The wall clock the timestamps of WireGuard are compared with. The
+// handshakes are stamped by the kernel with the real time, so the
+// exporter has to follow the system clock even when it is stepped (NTP
+// on a router booting without an RTC, for instance); the steps are
+// detected against the monotonic clock, logged and counted so the odd
+// values around them can be explained.
+use crate::metrics::{MetricType, Registry};
+use log::warn;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// the difference between the elapsed wall and monotonic times above
+// which the system clock is considered stepped
+const SKEW_TOLERANCE_SECS: i64 = 5;
+
+#[derive(Debug, Default)]
+struct State {
+    // the previous reading of both clocks
+    last: Option<(Instant, u64)>,
+    skews: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Clock {
+    state: Mutex<State>,
+}
+
+// the clock of the exporter
+static CLOCK: Clock = Clock {
+    state: Mutex::new(State {
+        last: None,
+        skews: 0,
+    }),
+};
+
+fn system_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Seconds since the epoch, the unit of `latest_handshake`.
+pub(crate) fn now() -> u64 {
+    CLOCK.now_at(Instant::now(), system_now())
+}
+
+/// Renders how many times the system clock was seen stepping.
+pub(crate) fn render(registry: &mut Registry) {
+    CLOCK.render(registry)
+}
+
+/// The age of something that happened at `then`, 0 rather than negative
+/// if the clock was stepped back since.
+pub(crate) fn age(now: u64, then: u64) -> u64 {
+    now.saturating_sub(then)
+}
+
+impl Clock {
+    /// Returns `wall`, the system time read at `instant`, checking that it
+    /// advanced as much as the monotonic clock since the previous reading.
+    pub fn now_at(&self, instant: Instant, wall: u64) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        if let Some((last_instant, last_wall)) = state.last {
+            let elapsed = instant.saturating_duration_since(last_instant).as_secs() as i64;
+            let skew = wall as i64 - last_wall as i64 - elapsed;
+            if skew.abs() > SKEW_TOLERANCE_SECS {
+                warn!("the system clock was stepped by {}s", skew);
+                state.skews += 1;
+            }
+        }
+        state.last = Some((instant, wall));
+        wall
+    }
+
+    fn render(&self, registry: &mut Registry) {
+        let mut skews = registry.family(
+            "wireguard_exporter_clock_skew_detected_total",
+            MetricType::Counter,
+            "Steps of the system clock detected against the monotonic clock",
+        );
+        skews.push(&[], self.state.lock().unwrap().skews as f64);
+        registry.register(skews);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_now_at() {
+        let clock = Clock::default();
+        let start = Instant::now();
+        assert_eq!(clock.now_at(start, 10_000), 10_000);
+        assert_eq!(
+            clock.now_at(start + Duration::from_secs(60), 10_061),
+            10_061
+        );
+        assert_eq!(clock.state.lock().unwrap().skews, 0);
+
+        // stepped back an hour, then forward again
+        assert_eq!(clock.now_at(start + Duration::from_secs(120), 6520), 6520);
+        assert_eq!(
+            clock.now_at(start + Duration::from_secs(180), 10_180),
+            10_180
+        );
+        assert_eq!(clock.state.lock().unwrap().skews, 2);
+
+        let mut registry = Registry::default();
+        clock.render(&mut registry);
+        assert!(registry
+            .render_text()
+            .contains("\nwireguard_exporter_clock_skew_detected_total 2\n"));
+
+        assert_eq!(age(1000, 1200), 0);
+        assert_eq!(age(1200, 1000), 200);
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// The metric and label names used for the per peer series. The
+/// presets other than the default mimic other WireGuard exporters, so
+/// dashboards and alerts written for them keep working.
+#[derive(Debug, PartialEq)]
+pub(crate) struct MetricNames {
+    pub sent_bytes: &'static str,
+    pub received_bytes: &'static str,
+    pub latest_handshake: &'static str,
+    pub interface: &'static str,
+    pub public_key: &'static str,
+    /// the labels of the first allowed IP address and of its prefix length
+    pub local_ip: Option<(&'static str, &'static str)>,
+    /// the label of all the allowed IPs, comma separated
+    pub allowed_ips: Option<&'static str>,
+    pub friendly_name: &'static str,
+}
+
+const DEFAULT: MetricNames = MetricNames {
+    sent_bytes: "wireguard_sent_bytes_total",
+    received_bytes: "wireguard_received_bytes_total",
+    latest_handshake: "wireguard_latest_handshake_seconds",
+    interface: "inteface",
+    public_key: "public_key",
+    local_ip: Some(("local_ip", "local_subnet")),
+    allowed_ips: None,
+    friendly_name: "friendly_name",
+};
+
+const MINDFLAVOR: MetricNames = MetricNames {
+    interface: "interface",
+    local_ip: Some(("allowed_ip_0", "allowed_subnet_0")),
+    ..DEFAULT
+};
+
+const PROMETHEUS_WIREGUARD_EXPORTER_GO: MetricNames = MetricNames {
+    sent_bytes: "wireguard_peer_transmit_bytes_total",
+    received_bytes: "wireguard_peer_receive_bytes_total",
+    latest_handshake: "wireguard_peer_last_handshake_seconds",
+    interface: "device",
+    public_key: "public_key",
+    local_ip: None,
+    allowed_ips: None,
+    friendly_name: "name",
+};
+
+const WGEXPORTER: MetricNames = MetricNames {
+    sent_bytes: "wireguard_peer_sent_bytes_total",
+    received_bytes: "wireguard_peer_received_bytes_total",
+    latest_handshake: "wireguard_peer_latest_handshake_seconds",
+    interface: "interface",
+    public_key: "public_key",
+    local_ip: None,
+    allowed_ips: Some("allowed_ips"),
+    friendly_name: "name",
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum Compat {
+    #[default]
+    None,
+    Mindflavor,
+    PrometheusWireguardExporterGo,
+    Wgexporter,
+}
+
+impl Compat {
+    pub fn metric_names(self) -> &'static MetricNames {
+        match self {
+            Compat::None => &DEFAULT,
+            Compat::Mindflavor => &MINDFLAVOR,
+            Compat::PrometheusWireguardExporterGo => &PROMETHEUS_WIREGUARD_EXPORTER_GO,
+            Compat::Wgexporter => &WGEXPORTER,
+        }
+    }
+}
+
+impl fmt::Display for Compat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compat::None => "none",
+            Compat::Mindflavor => "mindflavor",
+            Compat::PrometheusWireguardExporterGo => "prometheus_wireguard_exporter_go",
+            Compat::Wgexporter => "wgexporter",
+        })
+    }
+}
+
+impl FromStr for Compat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compat::None),
+            "mindflavor" => Ok(Compat::Mindflavor),
+            "prometheus_wireguard_exporter_go" => Ok(Compat::PrometheusWireguardExporterGo),
+            "wgexporter" => Ok(Compat::Wgexporter),
+            _ => Err(format!(
+                "invalid compat preset {}, valid values are mindflavor, prometheus_wireguard_exporter_go and wgexporter",
+                s
+            )),
+        }
+    }
+}
@@ -0,0 +1,744 @@
+// The file of --config-file: the TOML document printed by --print-config,
+// with the same keys and units. Its `version` is the version of that
+// layout; the files written for an older layout are migrated when read,
+// and those written for a newer one are refused rather than misread.
+use crate::exporter_error::ConfigFileError;
+use crate::options::{toml_array, toml_string};
+use std::fmt::Write;
+
+/// The version of the layout of the options written by this release.
+pub(crate) const CONFIG_VERSION: u32 = 1;
+
+// MIGRATIONS[n] turns a file of version n into one of version n + 1,
+// returning what it changed
+const MIGRATIONS: &[fn(&mut ConfigFile) -> Vec<String>] = &[migrate_v0];
+
+// the files without a version predate the versioning, and their layout
+// is the one of version 1
+fn migrate_v0(_: &mut ConfigFile) -> Vec<String> {
+    Vec::new()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Switch,
+    Value,
+    Multiple,
+}
+
+// key (prefixed with its table, if any) -> argument name, flag, kind
+const SCHEMA: &[(&str, &str, &str, Kind)] = &[
+    ("verbose", "verbose", "-v", Kind::Switch),
+    ("port", "port", "-p", Kind::Value),
+    ("daemonize", "daemonize", "--daemonize", Kind::Switch),
+    ("pid_file", "pid_file", "--pidfile", Kind::Value),
+    (
+        "extract_names_config_files",
+        "extract_names_config_file",
+        "-n",
+        Kind::Multiple,
+    ),
+    (
+        "name_comment_key",
+        "name_comment_key",
+        "--name-comment-key",
+        Kind::Value,
+    ),
+    (
+        "peer_id_comment_key",
+        "peer_id_comment_key",
+        "--peer-id-comment-key",
+        Kind::Value,
+    ),
+    (
+        "site_comment_key",
+        "site_comment_key",
+        "--site-comment-key",
+        Kind::Value,
+    ),
+    (
+        "client_comment_key",
+        "client_comment_key",
+        "--client-comment-key",
+        Kind::Value,
+    ),
+    (
+        "group_comment_key",
+        "group_comment_key",
+        "--group-comment-key",
+        Kind::Value,
+    ),
+    ("group_sla", "group_sla", "--group-sla", Kind::Multiple),
+    (
+        "only_configured_peers",
+        "only_configured_peers",
+        "--only-configured-peers",
+        Kind::Switch,
+    ),
+    (
+        "unnamed_peers_info",
+        "unnamed_peers_info",
+        "--unnamed-peers-info",
+        Kind::Switch,
+    ),
+    ("tc_stats", "tc_stats", "--tc-stats", Kind::Switch),
+    (
+        "firewall_counters",
+        "firewall_counters",
+        "--firewall-counters",
+        Kind::Value,
+    ),
+    (
+        "install_firewall_counters",
+        "install_firewall_counters",
+        "--install-firewall-counters",
+        Kind::Switch,
+    ),
+    (
+        "check_routes",
+        "check_routes",
+        "--check-routes",
+        Kind::Switch,
+    ),
+    (
+        "dns_check_interval",
+        "dns_check_interval",
+        "--dns-check-interval",
+        Kind::Value,
+    ),
+    (
+        "active_concurrency",
+        "active_concurrency",
+        "--active-concurrency",
+        Kind::Value,
+    ),
+    (
+        "active_jitter",
+        "active_jitter",
+        "--active-jitter",
+        Kind::Value,
+    ),
+    (
+        "min_bytes_threshold",
+        "min_bytes_threshold",
+        "--min-bytes-threshold",
+        Kind::Value,
+    ),
+    ("export_bits", "export_bits", "--export-bits", Kind::Switch),
+    ("bind_family", "bind_family", "--bind-family", Kind::Value),
+    ("seccomp", "seccomp", "--seccomp", Kind::Switch),
+    ("landlock", "landlock", "--landlock", Kind::Switch),
+    ("sandbox", "sandbox", "--sandbox", Kind::Switch),
+    ("read_only", "read_only", "--read-only", Kind::Switch),
+    ("compat", "compat", "--compat", Kind::Value),
+    ("cardinality", "cardinality", "--cardinality", Kind::Value),
+    (
+        "max_label_values",
+        "max_label_values",
+        "--max-label-values",
+        Kind::Multiple,
+    ),
+    (
+        "handshake_timeout",
+        "handshake_timeout",
+        "--handshake-timeout",
+        Kind::Value,
+    ),
+    (
+        "interface_grace_period",
+        "interface_grace_period",
+        "--interface-grace-period",
+        Kind::Value,
+    ),
+    ("collector", "collector", "--collector", Kind::Value),
+    ("wg_path", "wg_path", "--wg-path", Kind::Value),
+    ("exec_prefix", "exec_prefix", "--exec-prefix", Kind::Value),
+    ("target", "target", "--target", Kind::Multiple),
+    ("netns", "netns", "--netns", Kind::Multiple),
+    (
+        "with_fake_backend",
+        "with_fake_backend",
+        "--with-fake-backend",
+        Kind::Switch,
+    ),
+    ("dump_file", "dump_file", "--dump-file", Kind::Value),
+    ("ssh_target", "ssh_target", "--ssh-target", Kind::Value),
+    (
+        "ssh_identity",
+        "ssh_identity",
+        "--ssh-identity",
+        Kind::Value,
+    ),
+    (
+        "docker_socket",
+        "docker_socket",
+        "--docker-socket",
+        Kind::Value,
+    ),
+    (
+        "probe_module",
+        "probe_module",
+        "--probe-module",
+        Kind::Multiple,
+    ),
+    (
+        "collect_per_interface",
+        "collect_per_interface",
+        "--collect-per-interface",
+        Kind::Switch,
+    ),
+    (
+        "min_collect_interval",
+        "min_collect_interval",
+        "--min-collect-interval",
+        Kind::Value,
+    ),
+    (
+        "diagnostics_file",
+        "diagnostics_file",
+        "--diagnostics-file",
+        Kind::Value,
+    ),
+    (
+        "otlp_endpoint",
+        "otlp_endpoint",
+        "--otlp-endpoint",
+        Kind::Value,
+    ),
+    (
+        "debug_endpoints",
+        "debug_endpoints",
+        "--debug-endpoints",
+        Kind::Switch,
+    ),
+    ("log_target", "log_target", "--log-target", Kind::Value),
+    ("log_file", "log_file", "--log-file", Kind::Value),
+    ("audit_log", "audit_log", "--audit-log", Kind::Value),
+    ("rate_limit", "rate_limit", "--rate-limit", Kind::Value),
+    (
+        "worker_threads",
+        "worker_threads",
+        "--worker-threads",
+        Kind::Value,
+    ),
+    (
+        "blocking_threads",
+        "blocking_threads",
+        "--blocking-threads",
+        Kind::Value,
+    ),
+    (
+        "parse_threads",
+        "parse_threads",
+        "--parse-threads",
+        Kind::Value,
+    ),
+    (
+        "memory_budget",
+        "memory_budget",
+        "--memory-budget",
+        Kind::Value,
+    ),
+    (
+        "max_response_size",
+        "max_response_size",
+        "--max-response-size",
+        Kind::Value,
+    ),
+    (
+        "history_size",
+        "history_size",
+        "--history-size",
+        Kind::Value,
+    ),
+    ("usage_file", "usage_file", "--usage-file", Kind::Value),
+    (
+        "usage_retention_days",
+        "usage_retention",
+        "--usage-retention",
+        Kind::Value,
+    ),
+    (
+        "state_key_file",
+        "state_key_file",
+        "--state-key-file",
+        Kind::Value,
+    ),
+    (
+        "cors_allowed_origins",
+        "cors_allowed_origin",
+        "--cors-allowed-origin",
+        Kind::Multiple,
+    ),
+    (
+        "interface_labels",
+        "interface_label",
+        "--interface-label",
+        Kind::Multiple,
+    ),
+    (
+        "sample_interval",
+        "sample_interval",
+        "--sample-interval",
+        Kind::Value,
+    ),
+    ("idle_after", "idle_after", "--idle-after", Kind::Value),
+    (
+        "output_filter",
+        "output_filter",
+        "--output-filter",
+        Kind::Multiple,
+    ),
+    (
+        "textfile_output",
+        "textfile_output",
+        "--textfile-output",
+        Kind::Value,
+    ),
+    (
+        "textfile_interval",
+        "textfile_interval",
+        "--textfile-interval",
+        Kind::Value,
+    ),
+    (
+        "textfile_max_age",
+        "textfile_max_age",
+        "--textfile-max-age",
+        Kind::Value,
+    ),
+    (
+        "alert_rules.handshake_max_age",
+        "alert_handshake_max_age",
+        "--alert-handshake-max-age",
+        Kind::Value,
+    ),
+    (
+        "alert_rules.min_peers",
+        "alert_min_peers",
+        "--alert-min-peers",
+        Kind::Value,
+    ),
+    (
+        "alert_rules.max_peers",
+        "alert_max_peers",
+        "--alert-max-peers",
+        Kind::Value,
+    ),
+    (
+        "log_file_rotation.max_size",
+        "log_file_max_size",
+        "--log-file-max-size",
+        Kind::Value,
+    ),
+    (
+        "log_file_rotation.max_age",
+        "log_file_max_age",
+        "--log-file-max-age",
+        Kind::Value,
+    ),
+    (
+        "log_file_rotation.keep",
+        "log_file_keep",
+        "--log-file-keep",
+        Kind::Value,
+    ),
+    (
+        "audit_log_rotation.max_size",
+        "audit_log_max_size",
+        "--audit-log-max-size",
+        Kind::Value,
+    ),
+    (
+        "audit_log_rotation.max_age",
+        "audit_log_max_age",
+        "--audit-log-max-age",
+        Kind::Value,
+    ),
+    (
+        "audit_log_rotation.keep",
+        "audit_log_keep",
+        "--audit-log-keep",
+        Kind::Value,
+    ),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    Bool(bool),
+    Integer(i64),
+    String(String),
+    Array(Vec<String>),
+}
+
+impl Value {
+    fn to_toml(&self) -> String {
+        match self {
+            Value::Bool(b) => b.to_string(),
+            Value::Integer(i) => i.to_string(),
+            Value::String(s) => toml_string(s),
+            Value::Array(values) => toml_array(values),
+        }
+    }
+}
+
+/// The options read from a config file.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct ConfigFile {
+    /// 0 if the file has no version
+    pub version: u32,
+    /// the keys of the tables are prefixed with the table (`table.key`),
+    /// in the order of the file
+    pub entries: Vec<(String, Value)>,
+}
+
+// parses the string starting `s` (after the opening quote), returning it
+// and the rest of `s` after the closing quote
+fn parse_string(s: &str) -> Result<(String, &str), String> {
+    let mut value = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, &s[i + 1..])),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('"') => value.push('"'),
+                Some('\\') => value.push('\\'),
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some('u') => {
+                    let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                    let c = u32::from_str_radix(&hex, 16)
+                        .ok()
+                        .and_then(std::char::from_u32)
+                        .ok_or_else(|| format!("invalid escape \\u{}", hex))?;
+                    value.push(c);
+                }
+                other => return Err(format!("invalid escape \\{}", other.unwrap_or(' '))),
+            },
+            c => value.push(c),
+        }
+    }
+    Err("unterminated string".to_owned())
+}
+
+// checks that only a comment follows a value
+fn expect_end(rest: &str) -> Result<(), String> {
+    let rest = rest.trim_start();
+    if rest.is_empty() || rest.starts_with('#') {
+        Ok(())
+    } else {
+        Err(format!("unexpected {:?} after the value", rest))
+    }
+}
+
+fn parse_value(s: &str) -> Result<Value, String> {
+    if let Some(s) = s.strip_prefix('"') {
+        let (value, rest) = parse_string(s)?;
+        expect_end(rest)?;
+        return Ok(Value::String(value));
+    }
+    if let Some(mut s) = s.strip_prefix('[') {
+        let mut values = Vec::new();
+        loop {
+            s = s.trim_start();
+            if let Some(rest) = s.strip_prefix(']') {
+                expect_end(rest)?;
+                return Ok(Value::Array(values));
+            }
+            let rest = s
+                .strip_prefix('"')
+                .ok_or("the arrays can only hold strings")?;
+            let (value, rest) = parse_string(rest)?;
+            values.push(value);
+            s = rest.trim_start();
+            if let Some(rest) = s.strip_prefix(',') {
+                s = rest;
+            } else if !s.starts_with(']') {
+                return Err("unterminated array".to_owned());
+            }
+        }
+    }
+
+    let value = s.split('#').next().unwrap_or(s).trim();
+    match value {
+        "true" => Ok(Value::Bool(true)),
+        "false" => Ok(Value::Bool(false)),
+        _ => value
+            .parse()
+            .map(Value::Integer)
+            .map_err(|_| format!("invalid value {:?}", value)),
+    }
+}
+
+impl ConfigFile {
+    /// Parses the subset of TOML written by `--print-config`: tables,
+    /// and keys holding a boolean, an integer, a string or an array of
+    /// strings, one per line.
+    pub fn parse(text: &str) -> Result<ConfigFile, ConfigFileError> {
+        let mut config = ConfigFile::default();
+        let mut version = None;
+        let mut table = None;
+
+        for (n, line) in text.lines().enumerate() {
+            let syntax = |reason: String| ConfigFileError::Syntax {
+                line: n + 1,
+                reason,
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[') {
+                let name = name
+                    .split('#')
+                    .next()
+                    .unwrap_or(name)
+                    .trim_end()
+                    .strip_suffix(']')
+                    .ok_or_else(|| syntax("unterminated table header".to_owned()))?;
+                table = Some(name.trim().to_owned());
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| syntax("expected key = value".to_owned()))?;
+            let key = match &table {
+                Some(table) => format!("{}.{}", table, key.trim()),
+                None => key.trim().to_owned(),
+            };
+            let value = parse_value(value.trim()).map_err(syntax)?;
+
+            if key == "version" {
+                if version.is_some() {
+                    return Err(syntax("duplicate key version".to_owned()));
+                }
+                version = Some(match value {
+                    Value::Integer(v) if v >= 0 && v <= i64::from(u32::MAX) => v as u32,
+                    _ => {
+                        return Err(ConfigFileError::InvalidValue {
+                            key,
+                            reason: "must be a positive integer".to_owned(),
+                        })
+                    }
+                });
+            } else if config.entries.iter().any(|(k, _)| *k == key) {
+                return Err(syntax(format!("duplicate key {}", key)));
+            } else {
+                config.entries.push((key, value));
+            }
+        }
+
+        config.version = version.unwrap_or(0);
+        Ok(config)
+    }
+
+    /// Brings the file to `CONFIG_VERSION`, returning what changed. A file
+    /// written for a newer release is refused.
+    pub fn migrate(&mut self) -> Result<Vec<String>, ConfigFileError> {
+        if self.version > CONFIG_VERSION {
+            return Err(ConfigFileError::UnsupportedVersion {
+                version: self.version,
+                supported: CONFIG_VERSION,
+            });
+        }
+
+        let mut changes = Vec::new();
+        while self.version < CONFIG_VERSION {
+            changes.extend(MIGRATIONS[self.version as usize](self));
+            self.version += 1;
+        }
+        Ok(changes)
+    }
+
+    /// The command line arguments equivalent to the file, leaving out the
+    /// options for which `given` (called with the name of the argument)
+    /// is true, as the command line wins over the file. Unknown keys and
+    /// values of the wrong type are refused.
+    pub fn to_args<F>(&self, given: F) -> Result<Vec<String>, ConfigFileError>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut args = Vec::new();
+        for (key, value) in self.entries.iter() {
+            let (_, name, flag, kind) = SCHEMA
+                .iter()
+                .find(|(k, _, _, _)| k == key)
+                .ok_or_else(|| ConfigFileError::UnknownKey { key: key.clone() })?;
+            let invalid = |reason: &str| ConfigFileError::InvalidValue {
+                key: key.clone(),
+                reason: reason.to_owned(),
+            };
+
+            let values = match (kind, value) {
+                (Kind::Switch, Value::Bool(true)) => vec![None],
+                (Kind::Switch, Value::Bool(false)) => Vec::new(),
+                (Kind::Switch, _) => return Err(invalid("must be true or false")),
+                (_, Value::String(s)) => vec![Some(s.clone())],
+                (Kind::Value, Value::Integer(i)) => vec![Some(i.to_string())],
+                (Kind::Multiple, Value::Array(values)) => {
+                    values.iter().cloned().map(Some).collect()
+                }
+                (Kind::Value, _) => return Err(invalid("must be a string or an integer")),
+                (Kind::Multiple, _) => return Err(invalid("must be an array of strings")),
+            };
+
+            if given(name) {
+                continue;
+            }
+            for value in values {
+                args.push(flag.to_string());
+                args.extend(value);
+            }
+        }
+        Ok(args)
+    }
+
+    /// Renders the file back, as of its version. The comments are lost.
+    pub fn to_toml(&self) -> String {
+        let mut s = format!("version = {}\n", self.version);
+        let mut table = None;
+        for (key, value) in self.entries.iter() {
+            let key = match key.split_once('.') {
+                Some((entry_table, key)) => {
+                    if table != Some(entry_table) {
+                        let _ = write!(s, "\n[{}]\n", entry_table);
+                        table = Some(entry_table);
+                    }
+                    key
+                }
+                None => key,
+            };
+            let _ = writeln!(s, "{} = {}", key, value.to_toml());
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::{NamesFile, Options};
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse() {
+        let config = ConfigFile::parse(
+            "# written by hand\nversion = 1\nport = 9587 # not the default\nverbose = true\nname_comment_key = \"say \\\"name\\\"\"\n\n[alert_rules]\nmin_peers = 2\n\n[log_file_rotation]\nkeep = 3\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            ConfigFile {
+                version: 1,
+                entries: vec![
+                    ("port".to_owned(), Value::Integer(9587)),
+                    ("verbose".to_owned(), Value::Bool(true)),
+                    (
+                        "name_comment_key".to_owned(),
+                        Value::String("say \"name\"".to_owned())
+                    ),
+                    ("alert_rules.min_peers".to_owned(), Value::Integer(2)),
+                    ("log_file_rotation.keep".to_owned(), Value::Integer(3)),
+                ],
+            }
+        );
+        assert_eq!(
+            config.to_toml(),
+            "version = 1\nport = 9587\nverbose = true\nname_comment_key = \"say \\\"name\\\"\"\n\n[alert_rules]\nmin_peers = 2\n\n[log_file_rotation]\nkeep = 3\n"
+        );
+
+        assert_eq!(
+            ConfigFile::parse("port = 1\nport = 2\n").unwrap_err(),
+            ConfigFileError::Syntax {
+                line: 2,
+                reason: "duplicate key port".to_owned()
+            }
+        );
+        assert!(ConfigFile::parse("cors_allowed_origins = [\"a\", 1]\n").is_err());
+        assert!(ConfigFile::parse("pid_file = \"/run/wg.pid\" true\n").is_err());
+        assert!(ConfigFile::parse("verbose\n").is_err());
+    }
+
+    #[test]
+    fn test_migrate() {
+        let mut config = ConfigFile::parse("port = 9587\n").unwrap();
+        assert_eq!(config.version, 0);
+        assert_eq!(config.migrate(), Ok(Vec::new()));
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert!(config.to_toml().starts_with("version = 1\nport = 9587\n"));
+
+        let mut config = ConfigFile::parse("version = 99\n").unwrap();
+        assert_eq!(
+            config.migrate().unwrap_err().to_string(),
+            "the config file is of version 99, this release supports up to version 1"
+        );
+    }
+
+    #[test]
+    fn test_to_args() {
+        let config = ConfigFile::parse(
+            "verbose = true\ndaemonize = false\nport = 9587\nextract_names_config_files = [\"wg0:/etc/wireguard/wg0.conf\", \"/etc/wireguard/wg1.conf\"]\nusage_retention_days = 30\n\n[alert_rules]\nmin_peers = 2\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.to_args(|_| false).unwrap(),
+            vec![
+                "-v",
+                "-p",
+                "9587",
+                "-n",
+                "wg0:/etc/wireguard/wg0.conf",
+                "-n",
+                "/etc/wireguard/wg1.conf",
+                "--usage-retention",
+                "30",
+                "--alert-min-peers",
+                "2",
+            ]
+        );
+        // the command line wins
+        assert_eq!(
+            config
+                .to_args(|name| name == "port" || name == "extract_names_config_file")
+                .unwrap(),
+            vec!["-v", "--usage-retention", "30", "--alert-min-peers", "2"]
+        );
+
+        assert_eq!(
+            ConfigFile::parse("prot = 9587\n")
+                .unwrap()
+                .to_args(|_| false)
+                .unwrap_err(),
+            ConfigFileError::UnknownKey {
+                key: "prot".to_owned()
+            }
+        );
+        assert!(ConfigFile::parse("verbose = 1\n")
+            .unwrap()
+            .to_args(|_| false)
+            .is_err());
+    }
+
+    #[test]
+    fn test_print_config_round_trip() {
+        let options = Options {
+            port: 9586,
+            extract_names_config_files: vec![NamesFile::from("wg0:/etc/wireguard/wg0.conf")],
+            pid_file: Some("/run/wg.pid".to_owned()),
+            rate_limit: Some(10),
+            sample_interval: Some(Duration::from_secs(5)),
+            handshake_timeout: Duration::from_secs(180),
+            ..Options::default()
+        };
+
+        let mut config = ConfigFile::parse(&options.to_toml()).unwrap();
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.migrate(), Ok(Vec::new()));
+        // every key printed is known
+        let args = config.to_args(|_| false).unwrap();
+        assert!(args.windows(2).any(|w| w == ["--pidfile", "/run/wg.pid"]));
+        assert!(args.windows(2).any(|w| w == ["--sample-interval", "5"]));
+    }
+}
@@ -0,0 +1,201 @@
+// Encryption of the state files with the ChaCha20-Poly1305 of RFC 8439,
+// that of the chacha20poly1305 crate, built with the encryption feature,
+// and the X25519 of RFC 7748, that of the x25519-dalek crate, deriving the
+// public key of an interface from its private key, which is all the UAPI
+// reports.
+#[cfg(feature = "encryption")]
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+#[cfg(feature = "encryption")]
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+#[cfg(feature = "encryption")]
+use std::convert::TryInto;
+use std::fmt;
+#[cfg(feature = "encryption")]
+use std::fs::File;
+use std::io;
+#[cfg(feature = "encryption")]
+use std::io::Read;
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+
+/// Prepended to the encrypted files, and authenticated along with them.
+const MAGIC: &[u8] = b"wgx-enc1";
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 12;
+
+/// A 256 bit key, given as 64 hexadecimal digits.
+#[derive(Clone, PartialEq)]
+pub(crate) struct Key([u8; 32]);
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+impl Key {
+    pub fn from_hex(s: &str) -> Result<Key, String> {
+        let s = s.trim();
+        if s.len() != 64 || !s.is_ascii() {
+            return Err("the key must be 64 hexadecimal digits".to_owned());
+        }
+        let mut key = [0; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16)
+                .map_err(|_| "the key must be 64 hexadecimal digits".to_owned())?;
+        }
+        Ok(Key(key))
+    }
+}
+
+#[cfg(feature = "encryption")]
+fn seal_with_nonce(key: &Key, nonce: &[u8; NONCE_LEN], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new((&key.0).into())
+        .encrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        // only fails past 256 GiB
+        .expect("the state is too large to encrypt")
+}
+
+#[cfg(feature = "encryption")]
+fn open_with_nonce(
+    key: &Key,
+    nonce: &[u8; NONCE_LEN],
+    aad: &[u8],
+    sealed: &[u8],
+) -> Option<Vec<u8>> {
+    ChaCha20Poly1305::new((&key.0).into())
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: sealed, aad })
+        .ok()
+}
+
+/// The public key of a WireGuard `private_key`.
+pub(crate) fn public_key(private_key: &[u8; 32]) -> [u8; 32] {
+    x25519(*private_key, X25519_BASEPOINT_BYTES)
+}
+
+/// Whether `content` was produced by `encrypt`.
+pub(crate) fn is_encrypted(content: &[u8]) -> bool {
+    content.starts_with(MAGIC)
+}
+
+/// Encrypts `plaintext` under a random nonce.
+#[cfg(feature = "encryption")]
+pub(crate) fn encrypt(key: &Key, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    let mut nonce = [0; NONCE_LEN];
+    File::open("/dev/urandom")?.read_exact(&mut nonce)?;
+
+    let mut content = MAGIC.to_vec();
+    content.extend_from_slice(&nonce);
+    content.extend(seal_with_nonce(key, &nonce, MAGIC, plaintext));
+    Ok(content)
+}
+
+/// Decrypts the output of `encrypt`, failing if it was tampered with or
+/// encrypted with another key.
+#[cfg(feature = "encryption")]
+pub(crate) fn decrypt(key: &Key, content: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "cannot decrypt, wrong key or corrupted file",
+        )
+    };
+    if !is_encrypted(content) || content.len() < MAGIC.len() + NONCE_LEN {
+        return Err(invalid());
+    }
+    let (nonce, sealed) = content[MAGIC.len()..].split_at(NONCE_LEN);
+    open_with_nonce(key, nonce.try_into().unwrap(), MAGIC, sealed).ok_or_else(invalid)
+}
+
+// no key is read without the encryption feature
+#[cfg(not(feature = "encryption"))]
+fn unsupported() -> io::Error {
+    io::Error::other("the exporter is built without the encryption feature")
+}
+
+#[cfg(not(feature = "encryption"))]
+pub(crate) fn encrypt(_key: &Key, _plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "encryption"))]
+pub(crate) fn decrypt(_key: &Key, _content: &[u8]) -> io::Result<Vec<u8>> {
+    Err(unsupported())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn test_aead() {
+        // RFC 8439, 2.8.2, the layout of the files depending on it
+        let key = Key::from_hex("808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f")
+            .unwrap();
+        let nonce: [u8; 12] = hex("070000004041424344454647").try_into().unwrap();
+        let aad = hex("50515253c0c1c2c3c4c5c6c7");
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+
+        let sealed = seal_with_nonce(&key, &nonce, &aad, plaintext);
+        assert_eq!(
+            sealed[..16].to_vec(),
+            hex("d31a8d34648e60db7b86afbc53ef7ec2")
+        );
+        assert_eq!(
+            sealed[sealed.len() - 16..].to_vec(),
+            hex("1ae10b594f09e26a7e902ecbd0600691")
+        );
+        assert_eq!(
+            open_with_nonce(&key, &nonce, &aad, &sealed),
+            Some(plaintext.to_vec())
+        );
+
+        let mut tampered = sealed.clone();
+        tampered[0] ^= 1;
+        assert_eq!(open_with_nonce(&key, &nonce, &aad, &tampered), None);
+    }
+
+    #[test]
+    fn test_public_key() {
+        // RFC 7748, 6.1
+        let private_key: [u8; 32] =
+            hex("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a")
+                .try_into()
+                .unwrap();
+        assert_eq!(
+            public_key(&private_key).to_vec(),
+            hex("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a")
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn test_encrypt() {
+        let key = Key::from_hex(&"2a".repeat(32)).unwrap();
+        let content = encrypt(&key, b"{\"hours\":[]}").unwrap();
+        assert!(is_encrypted(&content));
+        assert_eq!(decrypt(&key, &content).unwrap(), b"{\"hours\":[]}");
+        // a random nonce every time
+        assert_ne!(content, encrypt(&key, b"{\"hours\":[]}").unwrap());
+
+        let other = Key::from_hex(&"2b".repeat(32)).unwrap();
+        assert!(decrypt(&other, &content).is_err());
+        assert!(Key::from_hex("2a2a").is_err());
+        assert_eq!(format!("{:?}", key), "Key(..)");
+    }
+}
@@ -0,0 +1,147 @@
+// Support for the init systems that expect the services to detach
+// themselves (older SysV init scripts, BSD rc.d).
+use log::{error, info};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+static TERMINATION_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+fn fork_and_exit_parent() -> io::Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+/// Detaches the process from its terminal with the usual double fork:
+/// the grandchild returns from this function while the caller exits.
+/// The working directory becomes `/` and the standard streams are
+/// redirected to `/dev/null`. Must be called before spawning any thread.
+pub(crate) fn daemonize() -> io::Result<()> {
+    fork_and_exit_parent()?;
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // the session leader exits so the daemon can never reacquire a terminal
+    fork_and_exit_parent()?;
+
+    std::env::set_current_dir("/")?;
+    let dev_null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..3 {
+        if unsafe { libc::dup2(dev_null.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// A file holding the pid of the running exporter.
+#[derive(Debug)]
+pub(crate) struct PidFile {
+    path: String,
+}
+
+fn is_running(pid: libc::pid_t) -> bool {
+    pid > 0 && unsafe { libc::kill(pid, 0) } == 0
+}
+
+impl PidFile {
+    /// Writes the pid of this process to `path`. Fails if the file
+    /// already holds the pid of a running process; a stale file left by
+    /// a crash is overwritten.
+    pub fn create(path: &str) -> io::Result<PidFile> {
+        if let Ok(content) = fs::read_to_string(path) {
+            if let Ok(pid) = content.trim().parse::<libc::pid_t>() {
+                if is_running(pid) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("another instance is running with pid {}", pid),
+                    ));
+                }
+            }
+        }
+
+        let mut file = fs::File::create(path)?;
+        writeln!(file, "{}", process::id())?;
+        Ok(PidFile {
+            path: path.to_owned(),
+        })
+    }
+
+    pub fn remove(&self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            error!("cannot remove the pid file {}: {}", self.path, e);
+        }
+    }
+}
+
+extern "C" fn on_termination(_: libc::c_int) {
+    TERMINATION_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Removes `pid_file` and exits when SIGTERM or SIGINT is received.
+pub(crate) fn spawn_cleanup_on_termination(pid_file: PidFile) {
+    unsafe {
+        libc::signal(
+            libc::SIGTERM,
+            on_termination as *const () as libc::sighandler_t,
+        );
+        libc::signal(
+            libc::SIGINT,
+            on_termination as *const () as libc::sighandler_t,
+        );
+    }
+
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_millis(250));
+
+        if TERMINATION_REQUESTED.load(Ordering::SeqCst) {
+            info!("terminating");
+            pid_file.remove();
+            process::exit(0);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pid_file() {
+        let path = std::env::temp_dir().join(format!(
+            "prometheus_wireguard_exporter_pid_file_{}",
+            process::id()
+        ));
+        let path = path.to_str().unwrap();
+
+        let pid_file = PidFile::create(path).unwrap();
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            format!("{}\n", process::id())
+        );
+
+        // this process is running
+        assert_eq!(
+            PidFile::create(path).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+
+        pid_file.remove();
+        assert!(!std::path::Path::new(path).exists());
+
+        // a stale pid file is overwritten
+        fs::write(path, "-1\n").unwrap();
+        PidFile::create(path).unwrap().remove();
+    }
+}
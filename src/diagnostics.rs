@@ -0,0 +1,404 @@
+use crate::options::Options;
+#[cfg(feature = "otlp")]
+use crate::otlp;
+use crate::wireguard::{Endpoint, WireGuard};
+use log::{debug, error, info};
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// how many errors we keep around for the dump. Older ones are discarded.
+const MAX_RECENT_ERRORS: usize = 16;
+
+// how many collection timings are kept for the debug endpoint.
+const MAX_COLLECTION_TIMINGS: usize = 64;
+
+// set by the signal handler, consumed by the dump thread. The handler
+// itself must be async-signal-safe so it cannot do anything more than this.
+static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Default)]
+struct DiagnosticsData {
+    last_collection: Option<Instant>,
+    last_collection_duration: Option<Duration>,
+    collections: u64,
+    peers_per_interface: HashMap<String, usize>,
+    name_map_size: Option<usize>,
+    recent_errors: VecDeque<(Instant, String)>,
+    collection_timings: VecDeque<(Instant, Duration)>,
+    last_phases: Vec<(&'static str, Duration)>,
+}
+
+/// Runtime information about the exporter, updated at every scrape
+/// and rendered on demand (see `spawn_dump_on_sigusr1`).
+#[derive(Debug)]
+pub(crate) struct Diagnostics {
+    started: Instant,
+    requests_in_flight: AtomicUsize,
+    requests_total: AtomicUsize,
+    data: Mutex<DiagnosticsData>,
+}
+
+/// Keeps a request accounted as in flight until dropped.
+pub(crate) struct InFlightGuard {
+    diagnostics: Arc<Diagnostics>,
+}
+
+/// The phases of a scrape, timed by its spans. When dropped, once the
+/// response is written, they replace the latest scrape breakdown all
+/// together, so that concurrent scrapes don't mix their phases, and are
+/// exported with `--otlp-endpoint`.
+pub(crate) struct Scrape {
+    diagnostics: Arc<Diagnostics>,
+    phases: Mutex<Vec<(&'static str, Duration)>>,
+    #[cfg(feature = "otlp")]
+    trace: otlp::Trace,
+}
+
+/// Times a phase of the scrape (exec, parse, render, write...). When
+/// dropped the duration is logged and stored in the phases of its scrape.
+pub(crate) struct Span<'a> {
+    scrape: &'a Scrape,
+    phase: &'static str,
+    started: Instant,
+}
+
+impl Scrape {
+    pub fn diagnostics(&self) -> &Diagnostics {
+        &self.diagnostics
+    }
+
+    pub fn span(&self, phase: &'static str) -> Span<'_> {
+        Span {
+            scrape: self,
+            phase,
+            started: Instant::now(),
+        }
+    }
+
+    /// Stores the duration of a phase timed without a span, such as the
+    /// write of the response which ends outside of the handler.
+    pub fn record(&self, phase: &'static str, duration: Duration) {
+        debug!("span {} took {:?}", phase, duration);
+        #[cfg(feature = "otlp")]
+        self.trace.record(phase, duration);
+        let mut phases = self.phases.lock().unwrap();
+        if let Some(entry) = phases.iter_mut().find(|(p, _)| *p == phase) {
+            entry.1 = duration;
+        } else {
+            phases.push((phase, duration));
+        }
+    }
+}
+
+impl Drop for Scrape {
+    fn drop(&mut self) {
+        let phases = std::mem::take(&mut *self.phases.lock().unwrap());
+        self.diagnostics.data.lock().unwrap().last_phases = phases;
+        #[cfg(feature = "otlp")]
+        self.trace.finish();
+    }
+}
+
+impl<'a> Drop for Span<'a> {
+    fn drop(&mut self) {
+        self.scrape.record(self.phase, self.started.elapsed());
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.diagnostics
+            .requests_in_flight
+            .fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Diagnostics {
+    pub fn new() -> Diagnostics {
+        Diagnostics {
+            started: Instant::now(),
+            requests_in_flight: AtomicUsize::new(0),
+            requests_total: AtomicUsize::new(0),
+            data: Mutex::new(DiagnosticsData::default()),
+        }
+    }
+
+    pub fn request_started(self: &Arc<Self>) -> InFlightGuard {
+        self.requests_in_flight.fetch_add(1, Ordering::SeqCst);
+        self.requests_total.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard {
+            diagnostics: self.clone(),
+        }
+    }
+
+    pub fn scrape(self: &Arc<Self>) -> Scrape {
+        Scrape {
+            diagnostics: self.clone(),
+            phases: Mutex::new(Vec::new()),
+            #[cfg(feature = "otlp")]
+            trace: otlp::Trace::new(),
+        }
+    }
+
+    pub fn record_collection(
+        &self,
+        started: Instant,
+        wg: &WireGuard,
+        name_map_size: Option<usize>,
+    ) {
+        let peers_per_interface = wg
+            .interfaces
+            .iter()
+            .map(|(interface, endpoints)| {
+                let peers = endpoints
+                    .iter()
+                    .filter(|endpoint| matches!(endpoint, Endpoint::Remote(_)))
+                    .count();
+                (interface.to_owned(), peers)
+            })
+            .collect();
+
+        let duration = started.elapsed();
+
+        let mut data = self.data.lock().unwrap();
+        data.last_collection = Some(started);
+        data.last_collection_duration = Some(duration);
+        if data.collection_timings.len() == MAX_COLLECTION_TIMINGS {
+            data.collection_timings.pop_front();
+        }
+        data.collection_timings.push_back((started, duration));
+        data.collections += 1;
+        data.peers_per_interface = peers_per_interface;
+        data.name_map_size = name_map_size;
+    }
+
+    pub fn record_error<E: Display>(&self, e: &E) {
+        let mut data = self.data.lock().unwrap();
+        if data.recent_errors.len() == MAX_RECENT_ERRORS {
+            data.recent_errors.pop_front();
+        }
+        data.recent_errors
+            .push_back((Instant::now(), e.to_string()));
+    }
+
+    pub fn render(&self, options: &Options) -> String {
+        let data = self.data.lock().unwrap();
+
+        // writing to a String cannot fail so we ignore the results
+        let mut s = String::new();
+        let _ = writeln!(s, "diagnostic dump");
+        let _ = writeln!(s, "options: {:?}", options);
+        let _ = writeln!(s, "uptime: {}s", self.started.elapsed().as_secs());
+        let _ = writeln!(s, "collections: {}", data.collections);
+
+        match (data.last_collection, data.last_collection_duration) {
+            (Some(last), Some(duration)) => {
+                let _ = writeln!(
+                    s,
+                    "last collection: {}s ago (took {}ms)",
+                    last.elapsed().as_secs(),
+                    duration.as_millis()
+                );
+            }
+            _ => {
+                let _ = writeln!(s, "last collection: never");
+            }
+        }
+
+        for (phase, duration) in data.last_phases.iter() {
+            let _ = writeln!(s, "  {}: {}ms", phase, duration.as_millis());
+        }
+
+        let mut interfaces: Vec<_> = data.peers_per_interface.iter().collect();
+        interfaces.sort();
+        for (interface, peers) in interfaces {
+            let _ = writeln!(s, "interface {}: {} peers", interface, peers);
+        }
+
+        match data.name_map_size {
+            Some(size) => {
+                let _ = writeln!(s, "name map size: {}", size);
+            }
+            None => {
+                let _ = writeln!(s, "name map size: (not loaded)");
+            }
+        }
+
+        let _ = writeln!(s, "recent errors: {}", data.recent_errors.len());
+        for (when, e) in data.recent_errors.iter() {
+            let _ = writeln!(s, "  {}s ago: {}", when.elapsed().as_secs(), e);
+        }
+
+        for (key, value) in memory_stats() {
+            let _ = writeln!(s, "memory {}: {}", key, value);
+        }
+
+        s
+    }
+
+    pub fn render_collections_json(&self) -> serde_json::Value {
+        let data = self.data.lock().unwrap();
+
+        let timings: Vec<serde_json::Value> = data
+            .collection_timings
+            .iter()
+            .map(|(started, duration)| {
+                json!({
+                    "seconds_ago": started.elapsed().as_secs(),
+                    "duration_ms": duration.as_secs_f64() * 1000.0,
+                })
+            })
+            .collect();
+
+        let last_phases: serde_json::Map<String, serde_json::Value> = data
+            .last_phases
+            .iter()
+            .map(|(phase, duration)| ((*phase).to_owned(), json!(duration.as_secs_f64() * 1000.0)))
+            .collect();
+
+        json!({
+            "collections": data.collections,
+            "timings": timings,
+            "last_phases_ms": last_phases,
+        })
+    }
+
+    pub fn render_tasks_json(&self) -> serde_json::Value {
+        json!({
+            "uptime_seconds": self.started.elapsed().as_secs(),
+            "requests_in_flight": self.requests_in_flight.load(Ordering::SeqCst),
+            "requests_total": self.requests_total.load(Ordering::SeqCst),
+        })
+    }
+}
+
+// Memory usage as reported by the kernel. Only the interesting
+// lines of /proc/self/status are returned; on systems without procfs
+// the list is empty.
+fn memory_stats() -> Vec<(String, String)> {
+    let status = match ::std::fs::read_to_string("/proc/self/status") {
+        Ok(status) => status,
+        Err(_) => return Vec::new(),
+    };
+
+    status
+        .lines()
+        .filter(|line| {
+            line.starts_with("VmRSS") || line.starts_with("VmHWM") || line.starts_with("VmSize")
+        })
+        .filter_map(|line| {
+            let mut tok = line.splitn(2, ':');
+            match (tok.next(), tok.next()) {
+                (Some(key), Some(value)) => Some((key.to_owned(), value.trim().to_owned())),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+extern "C" fn on_sigusr1(_: libc::c_int) {
+    DUMP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Installs a SIGUSR1 handler and spawns a thread that, whenever the
+/// signal is received, writes the diagnostic dump to the log (or to
+/// `options.diagnostics_file` if specified).
+pub(crate) fn spawn_dump_on_sigusr1(diagnostics: Arc<Diagnostics>, options: Arc<Options>) {
+    unsafe {
+        libc::signal(libc::SIGUSR1, on_sigusr1 as *const () as libc::sighandler_t);
+    }
+
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_millis(250));
+
+        if DUMP_REQUESTED.swap(false, Ordering::SeqCst) {
+            let dump = diagnostics.render(&options);
+
+            if let Some(diagnostics_file) = &options.diagnostics_file {
+                match ::std::fs::write(diagnostics_file, &dump) {
+                    Ok(_) => info!("diagnostic dump written to {}", diagnostics_file),
+                    Err(e) => error!(
+                        "cannot write diagnostic dump to {}: {}",
+                        diagnostics_file, e
+                    ),
+                }
+            } else {
+                for line in dump.lines() {
+                    info!("{}", line);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    const TEXT: &str = "wg0\t000q4qAC0ExW/BuGSmVR1nxH9JAXT6g9Wd3oEGy5lA=\t0000u8LWR682knVm350lnuqlCJzw5SNLW9Nf96P+m8=\t51820\toff
+wg0\t2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=\t(none)\t37.159.76.245:29159\t10.70.0.2/32\t1555771458\t10288508\t139524160\toff
+wg0\tqnoxQoQI8KKMupLnSSureORV0wMmH7JryZNsmGVISzU=\t(none)\t(none)\t10.70.0.3/32\t0\t0\t0\toff
+wg2\tMdVOIPKt9K2MPj/sO2NlWQbOnFJcL/qX80mmhQwsUlA=\t(none)\t(none)\t10.70.5.50/32\t0\t0\t0\toff
+";
+
+    #[test]
+    fn test_render() {
+        let options = Options::default();
+
+        let diagnostics = Arc::new(Diagnostics::new());
+        let wg = WireGuard::try_from(TEXT).unwrap();
+        diagnostics.record_collection(Instant::now(), &wg, Some(7));
+        {
+            let scrape = diagnostics.scrape();
+            let _span = scrape.span("render");
+        }
+        for i in 0..MAX_RECENT_ERRORS + 2 {
+            diagnostics.record_error(&format!("error {}", i));
+        }
+
+        let dump = diagnostics.render(&options);
+        println!("{}", dump);
+
+        assert!(dump.contains("collections: 1\n"));
+        assert!(dump.contains("  render: 0ms\n"));
+        assert!(dump.contains("interface wg0: 2 peers\n"));
+        assert!(dump.contains("interface wg2: 1 peers\n"));
+        assert!(dump.contains("name map size: 7\n"));
+        assert!(dump.contains(&format!("recent errors: {}\n", MAX_RECENT_ERRORS)));
+        assert!(!dump.contains("error 1\n"));
+        assert!(dump.contains(&format!("error {}\n", MAX_RECENT_ERRORS + 1)));
+    }
+
+    #[test]
+    fn test_concurrent_scrapes() {
+        let diagnostics = Arc::new(Diagnostics::new());
+        let phases = || {
+            let data = diagnostics.data.lock().unwrap();
+            data.last_phases
+                .iter()
+                .map(|(phase, _)| *phase)
+                .collect::<Vec<_>>()
+        };
+
+        let first = diagnostics.scrape();
+        let second = diagnostics.scrape();
+        drop(first.span("exec"));
+        drop(second.span("exec"));
+        drop(second.span("parse"));
+        drop(first.span("render"));
+        first.record("write", Duration::from_millis(1));
+        // published when the scrape is over, not phase by phase
+        assert!(phases().is_empty());
+
+        drop(second);
+        assert_eq!(phases(), ["exec", "parse"]);
+        drop(first);
+        assert_eq!(phases(), ["exec", "render", "write"]);
+    }
+}
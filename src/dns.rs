@@ -0,0 +1,285 @@
+// Health of the hostname endpoints of the config files: wg resolves them
+// once, when the interface comes up, so a peer whose address changed
+// keeps being contacted at the old one.
+use crate::metrics::{MetricType, Registry};
+use crate::scheduler::Scheduler;
+use crate::wireguard::{Endpoint, WireGuard};
+use crate::wireguard_config::PeerEntries;
+use log::debug;
+use std::collections::HashMap;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Splits an `Endpoint` into host and port, returning `None` if the
+/// host is an IP address.
+pub(crate) fn hostname_endpoint(endpoint: &str) -> Option<(&str, u16)> {
+    let colon = endpoint.rfind(':')?;
+    let host = endpoint[..colon]
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = endpoint[colon + 1..].parse().ok()?;
+    if host.is_empty() || host.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+    Some((host, port))
+}
+
+// the name of the lookups for the scheduler
+const SUBSYSTEM: &str = "dns";
+
+#[derive(Debug)]
+struct Resolution {
+    // seconds since the epoch
+    due: u64,
+    // empty if the resolution failed
+    addresses: Vec<IpAddr>,
+}
+
+/// Resolves the hostname endpoints, at most once per `interval` each,
+/// so the scrapes are not slowed down by the resolver.
+#[derive(Debug)]
+pub(crate) struct DnsChecker {
+    interval: Duration,
+    scheduler: Arc<Scheduler>,
+    resolutions: Mutex<HashMap<(String, u16), Resolution>>,
+}
+
+impl DnsChecker {
+    pub fn new(interval: Duration, scheduler: Arc<Scheduler>) -> DnsChecker {
+        scheduler.register(SUBSYSTEM);
+        DnsChecker {
+            interval,
+            scheduler,
+            resolutions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // the addresses of host, resolved again once due. Without a slot
+    // from the scheduler the previous addresses are returned, if any.
+    fn resolve<F>(&self, host: &str, port: u16, now: u64, resolver: F) -> Option<Vec<IpAddr>>
+    where
+        F: Fn(&str, u16) -> Vec<IpAddr>,
+    {
+        let key = (host.to_owned(), port);
+        let cached = self
+            .resolutions
+            .lock()
+            .unwrap()
+            .get(&key)
+            .map(|resolution| (resolution.due, resolution.addresses.clone()));
+        match cached {
+            // a due time further than the interval and its jitter means
+            // the clock was stepped back, the answer is renewed then
+            Some((due, addresses)) if now < due && due - now <= 2 * self.interval.as_secs() => {
+                return Some(addresses)
+            }
+            _ => (),
+        }
+
+        // the lock is not held during the lookup, for the others to
+        // proceed in parallel
+        let _permit = match self.scheduler.try_start(SUBSYSTEM) {
+            Some(permit) => permit,
+            None => {
+                debug!("lookup of {} deferred", host);
+                return cached.map(|(_, addresses)| addresses);
+            }
+        };
+        let addresses = resolver(host, port);
+        debug!("{} resolved to {:?}", host, addresses);
+        self.resolutions.lock().unwrap().insert(
+            key,
+            Resolution {
+                due: self
+                    .scheduler
+                    .next_due(&format!("{}:{}", host, port), now, self.interval),
+                addresses: addresses.clone(),
+            },
+        );
+        Some(addresses)
+    }
+
+    /// Renders, for every peer with a hostname endpoint in the config
+    /// files, whether the hostname resolves and, if the peer has an
+    /// active endpoint, whether it is one of the resolved addresses.
+    pub fn render(&self, registry: &mut Registry, wg: &WireGuard, pehm: &PeerEntries, now: u64) {
+        self.render_with(registry, wg, pehm, now, system_resolver)
+    }
+
+    fn render_with<F>(
+        &self,
+        registry: &mut Registry,
+        wg: &WireGuard,
+        pehm: &PeerEntries,
+        now: u64,
+        resolver: F,
+    ) where
+        F: Fn(&str, u16) -> Vec<IpAddr>,
+    {
+        let mut resolved = registry.family(
+            "wireguard_peer_endpoint_resolved",
+            MetricType::Gauge,
+            "Whether the hostname endpoint of the peer resolves",
+        );
+        let mut matches = registry.family(
+            "wireguard_peer_endpoint_matches_dns",
+            MetricType::Gauge,
+            "Whether the active endpoint of the peer is an address its hostname resolves to",
+        );
+
+        let mut interfaces: Vec<_> = wg.interfaces.iter().collect();
+        interfaces.sort_by_key(|(interface, _)| *interface);
+
+        for (interface, endpoints) in interfaces {
+            for endpoint in endpoints {
+                if let Endpoint::Remote(ep) = endpoint {
+                    let (host, port) = match pehm
+                        .get(interface, &ep.public_key)
+                        .and_then(|peer_entry| peer_entry.endpoint)
+                        .and_then(hostname_endpoint)
+                    {
+                        Some(host_and_port) => host_and_port,
+                        None => continue,
+                    };
+
+                    let addresses = match self.resolve(host, port, now, &resolver) {
+                        Some(addresses) => addresses,
+                        None => continue,
+                    };
+                    let attributes = [
+                        ("interface", interface as &str),
+                        ("public_key", &ep.public_key),
+                        ("hostname", host),
+                    ];
+                    resolved.push(&attributes, if addresses.is_empty() { 0.0 } else { 1.0 });
+
+                    let remote_ip = ep
+                        .remote_ip
+                        .as_ref()
+                        .and_then(|remote_ip| remote_ip.parse::<IpAddr>().ok());
+                    // nothing to compare without an answer or a handshake
+                    if let Some(remote_ip) = remote_ip.filter(|_| !addresses.is_empty()) {
+                        matches.push(
+                            &attributes,
+                            if addresses.contains(&remote_ip) {
+                                1.0
+                            } else {
+                                0.0
+                            },
+                        );
+                    }
+                }
+            }
+        }
+
+        registry.register(resolved);
+        registry.register(matches);
+    }
+}
+
+fn system_resolver(host: &str, port: u16) -> Vec<IpAddr> {
+    match (host, port).to_socket_addrs() {
+        Ok(addrs) => addrs.map(|addr| addr.ip()).collect(),
+        Err(e) => {
+            debug!("cannot resolve {}: {}", host, e);
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wireguard_config::{PeerEntry, PeerEntryHashMap};
+    use std::cell::Cell;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn test_hostname_endpoint() {
+        assert_eq!(
+            hostname_endpoint("home.example.org:51820"),
+            Some(("home.example.org", 51820))
+        );
+        assert_eq!(hostname_endpoint("203.0.113.7:51820"), None);
+        assert_eq!(hostname_endpoint("[2001:db8::1]:51820"), None);
+        assert_eq!(hostname_endpoint("home.example.org"), None);
+    }
+
+    #[test]
+    fn test_render() {
+        let wg = WireGuard::try_from(
+            "wg0\tA=\t(none)\t203.0.113.7:51820\t10.70.0.2/32\t0\t0\t0\toff
+wg0\tB=\t(none)\t(none)\t10.70.0.3/32\t0\t0\t0\toff
+wg0\tC=\t(none)\t203.0.113.9:51820\t10.70.0.4/32\t0\t0\t0\toff
+",
+        )
+        .unwrap();
+        let mut pehm = PeerEntryHashMap::new();
+        for (public_key, endpoint) in &[
+            ("A=", "home.example.org:51820"),
+            ("B=", "gone.example.org:51820"),
+            ("C=", "203.0.113.9:51820"),
+        ] {
+            pehm.insert(
+                public_key,
+                PeerEntry {
+                    public_key,
+                    endpoint: Some(endpoint),
+                    ..PeerEntry::default()
+                },
+            );
+        }
+        let pehm = pehm.into();
+
+        let resolutions = Cell::new(0);
+        let resolver = |host: &str, _| {
+            resolutions.set(resolutions.get() + 1);
+            match host {
+                "home.example.org" => vec!["198.51.100.1".parse().unwrap()],
+                _ => Vec::new(),
+            }
+        };
+
+        let scheduler = Arc::new(Scheduler::new(1, 0));
+        let checker = DnsChecker::new(Duration::from_secs(300), scheduler.clone());
+        let mut registry = Registry::default();
+        checker.render_with(&mut registry, &wg, &pehm, 1000, resolver);
+        let text = registry.render_text();
+        assert!(text.contains("wireguard_peer_endpoint_resolved{interface=\"wg0\",public_key=\"A=\",hostname=\"home.example.org\"} 1\n"));
+        assert!(text.contains("wireguard_peer_endpoint_matches_dns{interface=\"wg0\",public_key=\"A=\",hostname=\"home.example.org\"} 0\n"));
+        assert!(text.contains("wireguard_peer_endpoint_resolved{interface=\"wg0\",public_key=\"B=\",hostname=\"gone.example.org\"} 0\n"));
+        // B has no active endpoint to compare
+        assert_eq!(
+            text.matches("wireguard_peer_endpoint_matches_dns{").count(),
+            1
+        );
+        assert!(!text.contains("C="));
+        assert_eq!(resolutions.get(), 2);
+
+        // cached until the interval elapses
+        let mut registry = Registry::default();
+        checker.render_with(&mut registry, &wg, &pehm, 1299, resolver);
+        assert_eq!(resolutions.get(), 2);
+        let mut registry = Registry::default();
+        checker.render_with(&mut registry, &wg, &pehm, 1300, resolver);
+        assert_eq!(resolutions.get(), 4);
+
+        // without a slot the lookups are deferred, the previous answers
+        // are kept and the hostnames never resolved are left out
+        let permit = scheduler.try_start("ping");
+        let mut registry = Registry::default();
+        checker.render_with(&mut registry, &wg, &pehm, 1600, resolver);
+        assert_eq!(resolutions.get(), 4);
+        assert!(registry
+            .render_text()
+            .contains("hostname=\"home.example.org\"} 1\n"));
+        drop(permit);
+
+        let checker = DnsChecker::new(Duration::from_secs(300), scheduler.clone());
+        let _permit = scheduler.try_start("ping");
+        let mut registry = Registry::default();
+        checker.render_with(&mut registry, &wg, &pehm, 1600, resolver);
+        assert!(!registry.render_text().contains("home.example.org"));
+    }
+}
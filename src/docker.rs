@@ -0,0 +1,171 @@
+// Discovery of the Docker containers running WireGuard, for
+// --docker-socket. The running containers are listed over the Docker API,
+// and those with a WireGuard interface in their network namespace are
+// collected by running wg in that namespace with nsenter, so wg does not
+// have to be installed in the containers.
+use crate::metrics::{MetricType, Registry};
+use crate::netlink;
+use log::debug;
+use serde_json::Value;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// how long the Docker API gets to answer a request
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// the containers found by the latest discovery, none if it failed
+static DISCOVERED: Mutex<Option<usize>> = Mutex::new(None);
+
+/// A running container with WireGuard interfaces.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Container {
+    pub name: String,
+    /// the pid of its main process, whose network namespace is entered
+    pub pid: u32,
+}
+
+/// GETs `path` from the Docker API listening on `socket`, as JSON.
+fn get(socket: &str, path: &str) -> io::Result<Value> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    // HTTP/1.0, so that the body is neither chunked nor kept alive
+    write!(stream, "GET {} HTTP/1.0\r\nHost: docker\r\n\r\n", path)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    parse_response(&response)
+}
+
+fn parse_response(response: &[u8]) -> io::Result<Value> {
+    let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidData, reason);
+    let split = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| invalid("truncated response".to_owned()))?;
+    let head = String::from_utf8_lossy(&response[..split]);
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(invalid(format!("the Docker API answered {}", status)));
+    }
+    serde_json::from_slice(&response[split + 4..]).map_err(|e| invalid(e.to_string()))
+}
+
+/// The running containers having a WireGuard interface. A container gone
+/// since listed is skipped.
+pub(crate) fn containers(socket: &str) -> io::Result<Vec<Container>> {
+    let discovered = discover(socket);
+    *DISCOVERED.lock().unwrap() = discovered.as_ref().ok().map(|containers| containers.len());
+    discovered
+}
+
+fn discover(socket: &str) -> io::Result<Vec<Container>> {
+    let mut containers = Vec::new();
+    for summary in get(socket, "/containers/json")?
+        .as_array()
+        .into_iter()
+        .flatten()
+    {
+        let (id, name) = match (summary["Id"].as_str(), summary["Names"][0].as_str()) {
+            (Some(id), Some(name)) => (id, name.trim_start_matches('/')),
+            _ => continue,
+        };
+        // the pid is only in the details
+        let pid = match get(socket, &format!("/containers/{}/json", id)) {
+            Ok(details) => details["State"]["Pid"].as_u64().unwrap_or_default(),
+            Err(e) => {
+                debug!("cannot inspect the container {}: {}", name, e);
+                continue;
+            }
+        };
+        if pid == 0 {
+            continue;
+        }
+        // the sysfs of a container shows the devices of its namespace
+        match netlink::interfaces_in(&format!("/proc/{}/root/sys/class/net", pid)) {
+            Ok(interfaces) if !interfaces.is_empty() => containers.push(Container {
+                name: name.to_owned(),
+                pid: pid as u32,
+            }),
+            Ok(_) => {}
+            Err(e) => debug!(
+                "cannot list the interfaces of the container {}: {}",
+                name, e
+            ),
+        }
+    }
+    Ok(containers)
+}
+
+/// Renders whether the latest discovery succeeded and how many containers
+/// it found.
+pub(crate) fn render(registry: &mut Registry) {
+    let discovered = *DISCOVERED.lock().unwrap();
+
+    let mut up = registry.family(
+        "wireguard_exporter_docker_up",
+        MetricType::Gauge,
+        "Whether the latest discovery of the containers over the Docker API succeeded",
+    );
+    up.push(&[], if discovered.is_some() { 1.0 } else { 0.0 });
+    registry.register(up);
+
+    let mut containers = registry.family(
+        "wireguard_exporter_docker_containers",
+        MetricType::Gauge,
+        "Running containers with a WireGuard interface found by the latest discovery",
+    );
+    containers.push(&[], discovered.unwrap_or_default() as f64);
+    registry.register(containers);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    #[test]
+    fn test_parse_response() {
+        let value = parse_response(
+            b"HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n[{\"Id\":\"abc\"}]\n",
+        )
+        .unwrap();
+        assert_eq!(value[0]["Id"], "abc");
+
+        let e =
+            parse_response(b"HTTP/1.0 404 Not Found\r\n\r\n{\"message\":\"gone\"}").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "the Docker API answered HTTP/1.0 404 Not Found"
+        );
+        assert!(parse_response(b"HTTP/1.0 200 OK\r\n").is_err());
+    }
+
+    #[test]
+    fn test_get() {
+        let dir = std::env::temp_dir().join(format!("wgx_docker_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("docker.sock");
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 256];
+            let len = stream.read(&mut request).unwrap();
+            stream
+                .write_all(b"HTTP/1.0 200 OK\r\n\r\n[{\"Id\":\"abc\",\"Names\":[\"/wg-easy\"]}]")
+                .unwrap();
+            String::from_utf8_lossy(&request[..len]).into_owned()
+        });
+
+        let value = get(socket.to_str().unwrap(), "/containers/json").unwrap();
+        assert_eq!(value[0]["Names"][0], "/wg-easy");
+        assert!(server
+            .join()
+            .unwrap()
+            .starts_with("GET /containers/json HTTP/1.0\r\n"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,3 +1,5 @@
+#![allow(non_local_definitions)]
+
 #[derive(Debug, Fail)]
 pub enum PeerEntryParseError {
     #[fail(display = "PublicKey entry not found in lines: {:?}", lines)]
@@ -7,6 +9,41 @@ pub enum PeerEntryParseError {
     AllowedIPsEntryNotFound { lines: Vec<String> },
 }
 
+#[derive(Debug, Fail, PartialEq)]
+pub enum OptionsError {
+    #[fail(display = "invalid value {:?} for {}: {}", value, flag, reason)]
+    InvalidValue {
+        flag: String,
+        value: String,
+        reason: String,
+    },
+
+    #[fail(display = "{} conflicts with {}: {}", flag, other_flag, reason)]
+    Conflict {
+        flag: String,
+        other_flag: String,
+        reason: String,
+    },
+}
+
+#[derive(Debug, Fail, PartialEq)]
+pub enum ConfigFileError {
+    #[fail(display = "line {}: {}", line, reason)]
+    Syntax { line: usize, reason: String },
+
+    #[fail(display = "unknown key {}", key)]
+    UnknownKey { key: String },
+
+    #[fail(display = "invalid value for {}: {}", key, reason)]
+    InvalidValue { key: String, reason: String },
+
+    #[fail(
+        display = "the config file is of version {}, this release supports up to version {}",
+        version, supported
+    )]
+    UnsupportedVersion { version: u32, supported: u32 },
+}
+
 #[derive(Debug, Fail)]
 pub enum ExporterError {
     #[allow(dead_code)]
@@ -19,11 +56,14 @@ pub enum ExporterError {
     #[fail(display = "http error: {}", e)]
     Http { e: http::Error },
 
+    #[fail(display = "Invalid URI: {}", e)]
+    InvalidUri { e: http::uri::InvalidUri },
+
     #[fail(display = "UTF-8 error: {}", e)]
     UTF8 { e: std::string::FromUtf8Error },
 
     #[fail(display = "JSON format error: {}", e)]
-    JSON { e: serde_json::error::Error },
+    Json { e: serde_json::error::Error },
 
     #[fail(display = "IO Error: {}", e)]
     IO { e: std::io::Error },
@@ -36,6 +76,18 @@ pub enum ExporterError {
 
     #[fail(display = "PeerEntry parse error: {}", e)]
     PeerEntryParseError { e: PeerEntryParseError },
+
+    #[fail(display = "cannot read {}: {}", path, e)]
+    NamesFile { path: String, e: std::io::Error },
+
+    #[fail(display = "line {} of the dump: {}", line, reason)]
+    Dump { line: usize, reason: String },
+
+    #[fail(display = "wg failed ({}): {}", status, stderr)]
+    WireGuard {
+        status: std::process::ExitStatus,
+        stderr: String,
+    },
 }
 
 impl From<PeerEntryParseError> for ExporterError {
@@ -62,6 +114,12 @@ impl From<http::Error> for ExporterError {
     }
 }
 
+impl From<http::uri::InvalidUri> for ExporterError {
+    fn from(e: http::uri::InvalidUri) -> Self {
+        ExporterError::InvalidUri { e }
+    }
+}
+
 impl From<std::string::FromUtf8Error> for ExporterError {
     fn from(e: std::string::FromUtf8Error) -> Self {
         ExporterError::UTF8 { e }
@@ -70,7 +128,7 @@ impl From<std::string::FromUtf8Error> for ExporterError {
 
 impl From<serde_json::error::Error> for ExporterError {
     fn from(e: serde_json::error::Error) -> Self {
-        ExporterError::JSON { e }
+        ExporterError::Json { e }
     }
 }
 
@@ -0,0 +1,744 @@
+// Firewall accounting counters of the peers, to tell the traffic
+// forwarded after decryption from the one carried by the tunnel.
+use crate::exporter_error::ExporterError;
+use crate::metrics::{MetricType, Registry};
+use crate::outputs::Sink;
+use crate::wireguard::{canonical_allowed_ip, Endpoint, WireGuard};
+use crate::wireguard_config::PeerEntries;
+use log::warn;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// the prefix of the comments tagging the counters
+const TAG_PREFIX: &str = "wireguard_exporter:";
+
+/// The nftables table of the counters installed by
+/// `--install-firewall-counters`.
+const TABLE: &str = "wireguard_exporter";
+
+/// How often the allowed IPs of the peers are checked for changes.
+const INSTALL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The longest wait before a failed installation is attempted again.
+const MAX_BACKOFF: Duration = Duration::from_secs(3600);
+
+/// Where the counters are read from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Firewall {
+    /// the rules listed by `iptables-save -c` and `ip6tables-save -c`
+    Iptables,
+    /// the named counters listed by `nft -j list counters`
+    Nftables,
+}
+
+impl fmt::Display for Firewall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Firewall::Iptables => "iptables",
+            Firewall::Nftables => "nftables",
+        })
+    }
+}
+
+impl FromStr for Firewall {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "iptables" => Ok(Firewall::Iptables),
+            "nftables" => Ok(Firewall::Nftables),
+            _ => Err(format!(
+                "invalid firewall {}, valid values are iptables and nftables",
+                s
+            )),
+        }
+    }
+}
+
+/// The direction of the traffic, from the point of view of the
+/// interface as for `wireguard_sent_bytes_total`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    fn as_str(self) -> &'static str {
+        match self {
+            Direction::Sent => "sent",
+            Direction::Received => "received",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) struct Counter {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// (address range, direction) -> counter, summed over the counters
+/// sharing a tag.
+pub(crate) type Counters = BTreeMap<(String, Direction), Counter>;
+
+// parses a tag such as wireguard_exporter:received:10.70.0.2/32
+fn parse_tag(comment: &str) -> Option<(String, Direction)> {
+    let mut split = comment.strip_prefix(TAG_PREFIX)?.splitn(2, ':');
+    let direction = match split.next()? {
+        "sent" => Direction::Sent,
+        "received" => Direction::Received,
+        _ => return None,
+    };
+    Some((canonical_allowed_ip(split.next()?), direction))
+}
+
+fn add(counters: &mut Counters, tag: (String, Direction), packets: u64, bytes: u64) {
+    let counter = counters.entry(tag).or_default();
+    counter.packets += packets;
+    counter.bytes += bytes;
+}
+
+/// Parses the output of `iptables-save -c`, keeping the rules tagged
+/// with `-m comment --comment wireguard_exporter:<direction>:<range>`.
+pub(crate) fn parse_iptables(text: &str, counters: &mut Counters) {
+    for line in text.lines() {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        // [packets:bytes] -A CHAIN ...
+        let (packets, bytes) = match tokens
+            .first()
+            .and_then(|first| first.strip_prefix('['))
+            .and_then(|first| first.strip_suffix(']'))
+            .and_then(|first| {
+                let mut split = first.split(':');
+                Some((split.next()?.parse().ok()?, split.next()?.parse().ok()?))
+            }) {
+            Some(counter) => counter,
+            None => continue,
+        };
+
+        let tag = tokens
+            .iter()
+            .position(|token| *token == "--comment")
+            .and_then(|i| tokens.get(i + 1))
+            .and_then(|comment| parse_tag(comment.trim_matches('"')));
+        if let Some(tag) = tag {
+            add(counters, tag, packets, bytes);
+        }
+    }
+}
+
+/// Parses the output of `nft -j list counters`, keeping the counters
+/// whose comment is `wireguard_exporter:<direction>:<range>`.
+pub(crate) fn parse_nftables(text: &str, counters: &mut Counters) -> serde_json::Result<()> {
+    let value: Value = serde_json::from_str(text)?;
+    let objects = match value["nftables"].as_array() {
+        Some(objects) => objects,
+        None => return Ok(()),
+    };
+
+    for counter in objects.iter().map(|object| &object["counter"]) {
+        let tag = counter["comment"].as_str().and_then(parse_tag);
+        if let Some(tag) = tag {
+            add(
+                counters,
+                tag,
+                counter["packets"].as_u64().unwrap_or(0),
+                counter["bytes"].as_u64().unwrap_or(0),
+            );
+        }
+    }
+    Ok(())
+}
+
+fn run(program: &str, args: &[&str]) -> io::Result<String> {
+    let output = Command::new(program).args(args).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// the family label of an allowed IP, None if it is not an address range
+fn family(range: &str) -> Option<&'static str> {
+    match range.split('/').next()?.parse::<IpAddr>().ok()? {
+        IpAddr::V4(_) => Some("ipv4"),
+        IpAddr::V6(_) => Some("ipv6"),
+    }
+}
+
+// the name of the counter of `range` in `direction`, derived from the range
+// alone so that it is the same whatever the other peers are
+fn counter_name(direction: Direction, range: &str) -> String {
+    let range: String = range
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("{}_{}", direction.as_str(), range)
+}
+
+/// (interface, allowed IP) of the ranges counted by the installed counters.
+pub(crate) type Ranges = BTreeSet<(String, String)>;
+
+// runs an nft script
+type Nft = dyn Fn(&str) -> io::Result<()> + Send + Sync;
+
+/// The ranges of the peers of the local interfaces of `wg`.
+pub(crate) fn ranges(wg: &WireGuard) -> Ranges {
+    let mut ranges = BTreeSet::new();
+    for (interface, endpoints) in &wg.interfaces {
+        // the interfaces of the --target hosts, of the --netns namespaces
+        // and of the Docker containers are not there
+        if interface.contains(['@', '%', '#']) {
+            continue;
+        }
+        for endpoint in endpoints {
+            if let Endpoint::Remote(ep) = endpoint {
+                for allowed_ip in &ep.allowed_ips {
+                    if family(allowed_ip).is_some() {
+                        ranges.insert((interface.to_owned(), allowed_ip.to_owned()));
+                    }
+                }
+            }
+        }
+    }
+    ranges
+}
+
+/// Renders the nft script bringing the table of the installed counters to
+/// one tagged counter per direction and allowed IP of `ranges`, fed by the
+/// rules of the prerouting hook for the traffic received from the peers and
+/// of the postrouting hook for the traffic sent to them, so that both the
+/// forwarded and the local traffic are counted. The rules only count, they
+/// never drop. The table is never deleted: the counters missing are added,
+/// the chains are refilled and the `installed` counters no longer used are
+/// deleted, so the counters of the ranges still allowed keep counting.
+pub(crate) fn ruleset(ranges: &Ranges, installed: &BTreeSet<String>) -> String {
+    // the counters are shared by the interfaces allowing the same range
+    let counters: BTreeSet<&str> = ranges.iter().map(|(_, range)| range as &str).collect();
+    let directions = [Direction::Received, Direction::Sent];
+
+    let mut s = format!("table inet {} {{\n", TABLE);
+    for range in &counters {
+        for direction in &directions {
+            s.push_str(&format!(
+                "\tcounter {} {{\n\t\tcomment \"{}{}:{}\"\n\t}}\n",
+                counter_name(*direction, range),
+                TAG_PREFIX,
+                direction.as_str(),
+                range
+            ));
+        }
+    }
+    let chains = [
+        ("prerouting", Direction::Received, "iifname", "saddr"),
+        ("postrouting", Direction::Sent, "oifname", "daddr"),
+    ];
+    for (chain, ..) in &chains {
+        s.push_str(&format!(
+            "\tchain {chain} {{\n\t\ttype filter hook {chain} priority mangle; policy accept;\n\t}}\n",
+            chain = chain
+        ));
+    }
+    s.push_str("}\n");
+    for (chain, direction, interface_match, address_match) in &chains {
+        s.push_str(&format!("flush chain inet {} {}\n", TABLE, chain));
+        for (interface, range) in ranges {
+            s.push_str(&format!(
+                "add rule inet {} {} {} \"{}\" {} {} {} counter name {}\n",
+                TABLE,
+                chain,
+                interface_match,
+                interface,
+                if family(range) == Some("ipv4") {
+                    "ip"
+                } else {
+                    "ip6"
+                },
+                address_match,
+                range,
+                counter_name(*direction, range)
+            ));
+        }
+    }
+    let used: BTreeSet<String> = counters
+        .iter()
+        .flat_map(|range| {
+            directions
+                .iter()
+                .map(move |direction| counter_name(*direction, range))
+        })
+        .collect();
+    for name in installed.difference(&used) {
+        s.push_str(&format!("delete counter inet {} {}\n", TABLE, name));
+    }
+    s
+}
+
+/// Parses the names of the counters of the output of `nft -j list
+/// counters`.
+pub(crate) fn parse_counter_names(text: &str) -> serde_json::Result<BTreeSet<String>> {
+    let value: Value = serde_json::from_str(text)?;
+    Ok(value["nftables"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|object| object["counter"]["name"].as_str())
+        .map(str::to_owned)
+        .collect())
+}
+
+fn nft(script: &str) -> io::Result<()> {
+    let mut child = Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .expect("the stdin of nft is piped")
+        .write_all(script.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "nft failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct InstallerState {
+    // the ranges and the counters installed last, None before the first
+    // installation of this run
+    installed: Option<(Ranges, BTreeSet<String>)>,
+    // the failures in a row and when the next installation is attempted
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+/// The output installing the counters of `--install-firewall-counters` at
+/// startup and again whenever the allowed IPs of the peers change, in the
+/// background rather than on the scrapes. A failed installation is retried
+/// after `INSTALL_INTERVAL`, doubled at every failure in a row up to
+/// `MAX_BACKOFF`.
+pub(crate) struct Installer {
+    interval: Duration,
+    run: Box<Nft>,
+    state: Mutex<InstallerState>,
+}
+
+impl Installer {
+    pub(crate) fn new() -> Self {
+        Installer {
+            interval: INSTALL_INTERVAL,
+            run: Box::new(nft),
+            state: Mutex::default(),
+        }
+    }
+
+    // the counters left by a previous run, deleted if no longer used
+    fn listed() -> BTreeSet<String> {
+        run("nft", &["-j", "list", "counters", "table", "inet", TABLE])
+            .ok()
+            .and_then(|text| parse_counter_names(&text).ok())
+            .unwrap_or_default()
+    }
+
+    fn install(
+        &self,
+        ranges: Ranges,
+        listed: impl FnOnce() -> BTreeSet<String>,
+        now: Instant,
+    ) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.installed.as_ref().map(|(installed, _)| installed) == Some(&ranges) {
+            return Ok(());
+        }
+        if state.retry_at.is_some_and(|retry_at| now < retry_at) {
+            return Ok(());
+        }
+
+        let installed = match &state.installed {
+            Some((_, counters)) => counters.clone(),
+            None => listed(),
+        };
+        let ruleset = ruleset(&ranges, &installed);
+        match (self.run)(&ruleset) {
+            Ok(()) => {
+                let counters = ranges
+                    .iter()
+                    .flat_map(|(_, range)| {
+                        [Direction::Received, Direction::Sent]
+                            .iter()
+                            .map(move |direction| counter_name(*direction, range))
+                    })
+                    .collect();
+                state.installed = Some((ranges, counters));
+                state.failures = 0;
+                state.retry_at = None;
+                Ok(())
+            }
+            Err(e) => {
+                let backoff = self
+                    .interval
+                    .saturating_mul(1 << state.failures.min(16))
+                    .min(MAX_BACKOFF);
+                state.failures += 1;
+                state.retry_at = Some(now + backoff);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Sink for Installer {
+    fn name(&self) -> &'static str {
+        "firewall"
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn emit(&self, wg: &WireGuard, _: Option<&PeerEntries>) -> Result<(), ExporterError> {
+        self.install(ranges(wg), Installer::listed, Instant::now())?;
+        Ok(())
+    }
+
+    // the counters count the traffic whether they are scraped or not
+    fn busy(&self) -> bool {
+        true
+    }
+}
+
+/// Reads the tagged counters of `firewall`. With iptables a missing
+/// `ip6tables-save` is not an error, as IPv4 only hosts are common.
+pub(crate) fn read(firewall: Firewall) -> io::Result<Counters> {
+    let mut counters = Counters::new();
+    match firewall {
+        Firewall::Iptables => {
+            parse_iptables(&run("iptables-save", &["-c"])?, &mut counters);
+            match run("ip6tables-save", &["-c"]) {
+                Ok(text) => parse_iptables(&text, &mut counters),
+                Err(e) => warn!("cannot read the IPv6 firewall counters: {}", e),
+            }
+        }
+        Firewall::Nftables => {
+            parse_nftables(&run("nft", &["-j", "list", "counters"])?, &mut counters)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+    }
+    Ok(counters)
+}
+
+/// Renders the counters tagged with an allowed IP of a peer, attributed
+/// to that peer, in total and by the family of the allowed IPs, which is
+/// the one of the traffic inside the tunnel. The counters of the ranges no
+/// peer is allowed are ignored.
+pub(crate) fn render(registry: &mut Registry, wg: &WireGuard, counters: &Counters) {
+    let mut bytes = registry.family(
+        "wireguard_peer_firewall_bytes_total",
+        MetricType::Counter,
+        "Bytes counted by the firewall rules tagged with the allowed IPs of the peer",
+    );
+    let mut packets = registry.family(
+        "wireguard_peer_firewall_packets_total",
+        MetricType::Counter,
+        "Packets counted by the firewall rules tagged with the allowed IPs of the peer",
+    );
+    let mut bytes_by_family = registry.family(
+        "wireguard_peer_bytes_total",
+        MetricType::Counter,
+        "Bytes counted by the firewall rules tagged with the allowed IPs of the peer, by IP family",
+    );
+
+    let mut interfaces: Vec<_> = wg.interfaces.iter().collect();
+    interfaces.sort_by_key(|(interface, _)| *interface);
+
+    for (interface, endpoints) in interfaces {
+        for endpoint in endpoints {
+            if let Endpoint::Remote(ep) = endpoint {
+                for direction in &[Direction::Sent, Direction::Received] {
+                    let mut total = Counter::default();
+                    let mut by_family: BTreeMap<&str, u64> = BTreeMap::new();
+                    let mut found = false;
+                    for allowed_ip in &ep.allowed_ips {
+                        if let Some(counter) = counters.get(&(allowed_ip.to_owned(), *direction)) {
+                            total.packets += counter.packets;
+                            total.bytes += counter.bytes;
+                            if let Some(family) = family(allowed_ip) {
+                                *by_family.entry(family).or_default() += counter.bytes;
+                            }
+                            found = true;
+                        }
+                    }
+                    if !found {
+                        continue;
+                    }
+
+                    let attributes = [
+                        ("interface", interface as &str),
+                        ("public_key", &ep.public_key),
+                        ("direction", direction.as_str()),
+                    ];
+                    bytes.push(&attributes, total.bytes as f64);
+                    packets.push(&attributes, total.packets as f64);
+                    for (family, family_bytes) in by_family {
+                        let attributes = [
+                            ("interface", interface as &str),
+                            ("public_key", &ep.public_key),
+                            ("direction", direction.as_str()),
+                            ("family", family),
+                        ];
+                        bytes_by_family.push(&attributes, family_bytes as f64);
+                    }
+                }
+            }
+        }
+    }
+
+    registry.register(bytes);
+    registry.register(packets);
+    registry.register(bytes_by_family);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_parse() {
+        let mut counters = Counters::new();
+        parse_iptables(
+            "# Generated by iptables-save v1.8.7
+*filter
+:FORWARD ACCEPT [0:0]
+[10:1000] -A FORWARD -s 10.70.0.2/32 -i wg0 -m comment --comment \"wireguard_exporter:received:10.70.0.2/32\" -j ACCEPT
+[4:400] -A FORWARD -d 10.70.0.2/32 -o wg0 -m comment --comment wireguard_exporter:sent:10.70.0.2/32 -j ACCEPT
+[1:100] -A FORWARD -s 10.70.0.2/32 -p udp -m comment --comment \"wireguard_exporter:received:10.70.0.2/32\" -j ACCEPT
+[7:700] -A FORWARD -m comment --comment \"unrelated\" -j ACCEPT
+COMMIT
+",
+            &mut counters,
+        );
+        parse_nftables(
+            r#"{"nftables": [{"metainfo": {"version": "1.0.2", "json_schema_version": 1}}, {"counter": {"family": "inet", "name": "peer3_rx", "table": "filter", "handle": 2, "comment": "wireguard_exporter:received:fd00::3/128", "packets": 3, "bytes": 300}}, {"counter": {"family": "inet", "name": "other", "table": "filter", "handle": 3, "packets": 9, "bytes": 900}}]}"#,
+            &mut counters,
+        )
+        .unwrap();
+
+        let mut expected = Counters::new();
+        expected.insert(
+            ("10.70.0.2/32".to_owned(), Direction::Received),
+            Counter {
+                packets: 11,
+                bytes: 1100,
+            },
+        );
+        expected.insert(
+            ("10.70.0.2/32".to_owned(), Direction::Sent),
+            Counter {
+                packets: 4,
+                bytes: 400,
+            },
+        );
+        expected.insert(
+            ("fd00::3/128".to_owned(), Direction::Received),
+            Counter {
+                packets: 3,
+                bytes: 300,
+            },
+        );
+        assert_eq!(counters, expected);
+
+        assert!(parse_nftables("not json", &mut counters).is_err());
+        assert_eq!(parse_tag("wireguard_exporter:forwarded:10.70.0.2/32"), None);
+    }
+
+    #[test]
+    fn test_render() {
+        let wg = WireGuard::try_from(
+            "wg0\tA=\t(none)\t(none)\t10.70.0.2/32,fd00::2/128\t0\t0\t0\toff
+wg0\tB=\t(none)\t(none)\t10.70.0.3/32\t0\t0\t0\toff
+",
+        )
+        .unwrap();
+        let mut counters = Counters::new();
+        add(
+            &mut counters,
+            ("10.70.0.2/32".to_owned(), Direction::Received),
+            10,
+            1000,
+        );
+        add(
+            &mut counters,
+            ("fd00::2/128".to_owned(), Direction::Received),
+            1,
+            100,
+        );
+        add(
+            &mut counters,
+            ("10.70.9.0/24".to_owned(), Direction::Sent),
+            1,
+            100,
+        );
+
+        let mut registry = Registry::default();
+        render(&mut registry, &wg, &counters);
+        let text = registry.render_text();
+        assert!(text.contains("wireguard_peer_firewall_bytes_total{interface=\"wg0\",public_key=\"A=\",direction=\"received\"} 1100\n"));
+        assert!(text.contains("wireguard_peer_firewall_packets_total{interface=\"wg0\",public_key=\"A=\",direction=\"received\"} 11\n"));
+        assert!(text.contains("wireguard_peer_bytes_total{interface=\"wg0\",public_key=\"A=\",direction=\"received\",family=\"ipv4\"} 1000\n"));
+        assert!(text.contains("wireguard_peer_bytes_total{interface=\"wg0\",public_key=\"A=\",direction=\"received\",family=\"ipv6\"} 100\n"));
+        assert!(!text.contains("direction=\"sent\""));
+        assert!(!text.contains("B="));
+    }
+
+    #[test]
+    fn test_ruleset() {
+        let wg = WireGuard::try_from(
+            "wg0\tA=\t(none)\t(none)\t10.70.0.2/32,fd00::2/128\t0\t0\t0\toff
+wg0\tB=\t(none)\t(none)\t(none)\t0\t0\t0\toff
+wg0@gw1\tC=\t(none)\t(none)\t10.70.0.3/32\t0\t0\t0\toff
+",
+        )
+        .unwrap();
+        let installed = ["received_10_70_0_2_32", "received_10_70_0_9_32", "sent_0"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        assert_eq!(
+            ruleset(&ranges(&wg), &installed),
+            "table inet wireguard_exporter {
+\tcounter received_10_70_0_2_32 {
+\t\tcomment \"wireguard_exporter:received:10.70.0.2/32\"
+\t}
+\tcounter sent_10_70_0_2_32 {
+\t\tcomment \"wireguard_exporter:sent:10.70.0.2/32\"
+\t}
+\tcounter received_fd00__2_128 {
+\t\tcomment \"wireguard_exporter:received:fd00::2/128\"
+\t}
+\tcounter sent_fd00__2_128 {
+\t\tcomment \"wireguard_exporter:sent:fd00::2/128\"
+\t}
+\tchain prerouting {
+\t\ttype filter hook prerouting priority mangle; policy accept;
+\t}
+\tchain postrouting {
+\t\ttype filter hook postrouting priority mangle; policy accept;
+\t}
+}
+flush chain inet wireguard_exporter prerouting
+add rule inet wireguard_exporter prerouting iifname \"wg0\" ip saddr 10.70.0.2/32 counter name received_10_70_0_2_32
+add rule inet wireguard_exporter prerouting iifname \"wg0\" ip6 saddr fd00::2/128 counter name received_fd00__2_128
+flush chain inet wireguard_exporter postrouting
+add rule inet wireguard_exporter postrouting oifname \"wg0\" ip daddr 10.70.0.2/32 counter name sent_10_70_0_2_32
+add rule inet wireguard_exporter postrouting oifname \"wg0\" ip6 daddr fd00::2/128 counter name sent_fd00__2_128
+delete counter inet wireguard_exporter received_10_70_0_9_32
+delete counter inet wireguard_exporter sent_0
+"
+        );
+
+        assert_eq!(
+            parse_counter_names(
+                r#"{"nftables": [{"metainfo": {"version": "1.0.2", "json_schema_version": 1}}, {"counter": {"family": "inet", "name": "sent_0", "table": "wireguard_exporter", "handle": 2, "packets": 3, "bytes": 300}}]}"#
+            )
+            .unwrap(),
+            ["sent_0".to_owned()].iter().cloned().collect()
+        );
+    }
+
+    #[test]
+    fn test_installer() {
+        let scripts = Arc::new(Mutex::new(Vec::new()));
+        let failing = Arc::new(AtomicBool::new(true));
+        let installer = Installer {
+            interval: Duration::from_secs(30),
+            run: {
+                let scripts = scripts.clone();
+                let failing = failing.clone();
+                Box::new(move |script| {
+                    scripts.lock().unwrap().push(script.to_owned());
+                    if failing.load(Ordering::SeqCst) {
+                        Err(io::Error::other("nft failed"))
+                    } else {
+                        Ok(())
+                    }
+                })
+            },
+            state: Mutex::default(),
+        };
+        let range = |range: &str| {
+            [("wg0".to_owned(), range.to_owned())]
+                .iter()
+                .cloned()
+                .collect::<BTreeSet<_>>()
+        };
+        let listed = || ["sent_0".to_owned()].iter().cloned().collect();
+        let start = Instant::now();
+        let after = |secs| start + Duration::from_secs(secs);
+
+        // a failure waits the interval, then twice as long
+        assert!(installer
+            .install(range("10.70.0.2/32"), listed, start)
+            .is_err());
+        assert!(installer
+            .install(range("10.70.0.2/32"), listed, after(29))
+            .is_ok());
+        assert_eq!(scripts.lock().unwrap().len(), 1);
+        assert!(installer
+            .install(range("10.70.0.2/32"), listed, after(30))
+            .is_err());
+        assert!(installer
+            .install(range("10.70.0.2/32"), listed, after(89))
+            .is_ok());
+        assert_eq!(scripts.lock().unwrap().len(), 2);
+
+        // the counters of the previous run are deleted once installed
+        failing.store(false, Ordering::SeqCst);
+        assert!(installer
+            .install(range("10.70.0.2/32"), listed, after(90))
+            .is_ok());
+        assert_eq!(scripts.lock().unwrap().len(), 3);
+        assert!(
+            scripts.lock().unwrap()[2].ends_with("delete counter inet wireguard_exporter sent_0\n")
+        );
+
+        // installed again only when the ranges change, deleting the
+        // counters of the range gone
+        assert!(installer
+            .install(range("10.70.0.2/32"), listed, after(91))
+            .is_ok());
+        assert_eq!(scripts.lock().unwrap().len(), 3);
+        assert!(installer
+            .install(range("10.70.0.3/32"), listed, after(92))
+            .is_ok());
+        let scripts = scripts.lock().unwrap();
+        assert_eq!(scripts.len(), 4);
+        assert!(!scripts[3].contains("delete table"));
+        assert!(scripts[3].ends_with(
+            "delete counter inet wireguard_exporter received_10_70_0_2_32
+delete counter inet wireguard_exporter sent_10_70_0_2_32
+"
+        ));
+    }
+}
@@ -0,0 +1,220 @@
+// Golden snapshots of the exposition: known inputs rendered with a set of
+// flag combinations in every format. `--write-golden` writes them to a
+// directory and `--verify-golden` renders them again and compares them
+// byte for byte, so a downstream build can be checked against the
+// snapshots of a reference build.
+use crate::metrics::{Format, Registry};
+use crate::options::Options;
+use crate::wireguard::WireGuard;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::iter;
+use std::path::Path;
+
+// the time the input is rendered at, a minute after the latest handshake
+const NOW: u64 = 1_555_771_518;
+
+/// The `wg show all dump` rendered, also collected by
+/// `--with-fake-backend`.
+pub(crate) const DUMP: &str = "wg0\t000q4qAC0ExW/BuGSmVR1nxH9JAXT6g9Wd3oEGy5lA=\t0000AC0ExW/BuGSmVR1nxH9JAXT6g9Wd3oEGy5lA=\t51820\toff
+wg0\t2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=\t(none)\t37.159.76.245:29159\t10.70.0.2/32\t1555771458\t10288508\t139524160\toff
+wg0\tqnoxQoQI8KKMupLnSSureORV0wMmH7JryZNsmGVISzU=\tpskpskAC0ExW/BuGSmVR1nxH9JAXT6g9Wd3oEGy5lA=\t(none)\t10.70.0.3/32,fd00::3/128\t1555771000\t1024\t2048\t25
+wg0\tL2UoJZN7RmEKsMmqaJgKG0m1S2Zs2wd2ptAf+kb3008=\t(none)\t(none)\t10.70.0.4/32\t0\t0\t0\toff
+wg1\t111q4qAC0ExW/BuGSmVR1nxH9JAXT6g9Wd3oEGy5lA=\t1111AC0ExW/BuGSmVR1nxH9JAXT6g9Wd3oEGy5lA=\t51821\toff
+wg1\tMdVOIPKt9K2MPj/sO2NlWQbOnFJcL/qX80mmhQwsUlA=\t(none)\t[2001:db8::1]:51820\t10.80.0.2/32\t1555771400\t500000\t700000\toff
+";
+
+const NAMES: &str = "[Peer]
+# PeerID = phone-01
+# Name = phone
+PublicKey = 2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=
+AllowedIPs = 10.70.0.2/32
+
+[Peer]
+# laptop
+PublicKey = qnoxQoQI8KKMupLnSSureORV0wMmH7JryZNsmGVISzU=
+AllowedIPs = 10.70.0.3/32, fd00::3/128
+
+[Peer]
+PublicKey = MdVOIPKt9K2MPj/sO2NlWQbOnFJcL/qX80mmhQwsUlA=
+AllowedIPs = 10.80.0.2/32
+";
+
+// the names file given to the cases, never read: `NAMES` stands for it
+const NAMES_FILE: &str = "golden.conf";
+
+/// The flag combinations rendered, by name.
+const CASES: &[(&str, &[&str])] = &[
+    ("default", &[]),
+    ("names", &["-n", NAMES_FILE]),
+    (
+        "name-comment-key",
+        &["-n", NAMES_FILE, "--name-comment-key", "Name"],
+    ),
+    ("compat-none", &["-n", NAMES_FILE, "--compat", "none"]),
+    (
+        "compat-mindflavor",
+        &["-n", NAMES_FILE, "--compat", "mindflavor"],
+    ),
+    (
+        "compat-prometheus-wireguard-exporter-go",
+        &[
+            "-n",
+            NAMES_FILE,
+            "--compat",
+            "prometheus_wireguard_exporter_go",
+        ],
+    ),
+    (
+        "compat-wgexporter",
+        &["-n", NAMES_FILE, "--compat", "wgexporter"],
+    ),
+    (
+        "cardinality-low",
+        &["-n", NAMES_FILE, "--cardinality", "low"],
+    ),
+    (
+        "cardinality-full",
+        &["-n", NAMES_FILE, "--cardinality", "full"],
+    ),
+    ("min-bytes-threshold", &["--min-bytes-threshold", "100000"]),
+    ("interface-label", &["--interface-label", "wg0:env=prod"]),
+    (
+        "peer-ids",
+        &[
+            "-n",
+            NAMES_FILE,
+            "--name-comment-key",
+            "Name",
+            "--peer-id-comment-key",
+            "PeerID",
+        ],
+    ),
+    ("max-label-values", &["--max-label-values", "public_key=2"]),
+    (
+        "alerts",
+        &[
+            "--alert-handshake-max-age",
+            "300",
+            "--alert-min-peers",
+            "2",
+            "--alert-max-peers",
+            "2",
+        ],
+    ),
+];
+
+const FORMATS: &[(Format, &str)] = &[
+    (Format::Text, "prom"),
+    (Format::OpenMetrics, "openmetrics"),
+    (Format::Protobuf, "pb"),
+];
+
+/// Renders every case in every format, as file name and content.
+fn render_all() -> Result<Vec<(String, Vec<u8>)>, String> {
+    let mut files = Vec::new();
+    for (name, args) in CASES {
+        let matches = crate::app()
+            .get_matches_from_safe(iter::once("golden").chain(args.iter().copied()))
+            .map_err(|e| format!("{}: {}", name, e.message))?;
+        let options = Options::from_claps(&matches).map_err(|e| format!("{}: {}", name, e))?;
+        let wg = WireGuard::try_from(DUMP).map_err(|e| format!("{}: {}", name, e))?;
+        let pehm = if options.extract_names_config_files.is_empty() {
+            None
+        } else {
+            Some(
+                crate::peer_entries(&options, &[(None, NAMES)])
+                    .map_err(|e| format!("{}: {}", name, e))?,
+            )
+        };
+
+        for (format, extension) in FORMATS {
+            let mut registry = Registry::default();
+            crate::render_wireguard(&mut registry, &options, &wg, pehm.as_ref(), NOW);
+            crate::finish_render(&mut registry, &options, &wg, pehm.as_ref());
+            files.push((format!("{}.{}", name, extension), registry.encode(*format)));
+        }
+    }
+    Ok(files)
+}
+
+/// Writes the snapshots to `dir`, returning how many were written.
+pub(crate) fn write(dir: &str) -> Result<usize, String> {
+    let files = render_all()?;
+    fs::create_dir_all(dir).map_err(|e| format!("cannot create {}: {}", dir, e))?;
+    for (name, content) in files.iter() {
+        let path = Path::new(dir).join(name);
+        fs::write(&path, content).map_err(|e| format!("cannot write {:?}: {}", path, e))?;
+    }
+    Ok(files.len())
+}
+
+/// Compares the snapshots in `dir` with the ones rendered now, returning
+/// the names of those which differ or are missing, along with how many
+/// were compared.
+pub(crate) fn verify(dir: &str) -> Result<(usize, Vec<String>), String> {
+    let files = render_all()?;
+    let mut mismatches = Vec::new();
+    for (name, content) in files.iter() {
+        let path = Path::new(dir).join(name);
+        match fs::read(&path) {
+            Ok(expected) if expected == *content => (),
+            Ok(_) => mismatches.push(format!("{} differs", name)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                mismatches.push(format!("{} is missing", name))
+            }
+            Err(e) => return Err(format!("cannot read {:?}: {}", path, e)),
+        }
+    }
+    Ok((files.len(), mismatches))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_verify() {
+        let dir = std::env::temp_dir().join(format!(
+            "prometheus_wireguard_exporter_golden_{}",
+            std::process::id()
+        ));
+        let dir = dir.to_str().unwrap();
+
+        let written = write(dir).unwrap();
+        assert_eq!(written, CASES.len() * FORMATS.len());
+        assert_eq!(verify(dir).unwrap(), (written, vec![]));
+
+        fs::write(Path::new(dir).join("default.prom"), "changed").unwrap();
+        fs::remove_file(Path::new(dir).join("peer-ids.pb")).unwrap();
+        assert_eq!(
+            verify(dir).unwrap().1,
+            vec!["default.prom differs", "peer-ids.pb is missing"]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_cases() {
+        let files = render_all().unwrap();
+        let text = |name: &str| {
+            let (_, content) = files.iter().find(|(file, _)| file == name).unwrap();
+            String::from_utf8(content.clone()).unwrap()
+        };
+        assert!(text("peer-ids.prom").contains("peer_id=\"phone-01\""));
+        assert!(text("name-comment-key.prom").contains("friendly_name=\"phone\""));
+        assert!(text("max-label-values.prom").contains("public_key=\"__overflow__\""));
+        assert!(text("default.openmetrics").ends_with("# EOF\n"));
+        // every case renders something of its own
+        for (name, _) in CASES.iter().skip(1) {
+            assert_ne!(
+                text(&format!("{}.prom", name)),
+                text("default.prom"),
+                "{}",
+                name
+            );
+        }
+    }
+}
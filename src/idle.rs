@@ -0,0 +1,130 @@
+// The idle mode of --idle-after: once no request came for that long, the
+// background outputs stop collecting until the next request, so that a
+// node nobody looks at spares its CPU. The outputs with consumers of their
+// own, such as the websocket clients of the sampler, keep running.
+use crate::metrics::{MetricType, Registry};
+use log::info;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct State {
+    after: Option<Duration>,
+    last_request: Option<Instant>,
+    // whether the exporter was idle until the latest request
+    woken: bool,
+    idle_time: Duration,
+}
+
+static STATE: Mutex<State> = Mutex::new(State::new());
+static WAKE: Condvar = Condvar::new();
+
+impl State {
+    const fn new() -> State {
+        State {
+            after: None,
+            last_request: None,
+            woken: false,
+            idle_time: Duration::from_secs(0),
+        }
+    }
+
+    fn is_idle(&self, now: Instant) -> bool {
+        match (self.after, self.last_request) {
+            (Some(after), Some(last_request)) => now.duration_since(last_request) >= after,
+            _ => false,
+        }
+    }
+
+    // records a request at `now`, returning whether it woke the exporter
+    fn request(&mut self, now: Instant) -> bool {
+        self.woken = self.is_idle(now);
+        if self.woken {
+            let since = self.last_request.unwrap() + self.after.unwrap();
+            self.idle_time += now.duration_since(since);
+        }
+        self.last_request = Some(now);
+        self.woken
+    }
+}
+
+/// Enables the idle mode, the exporter going idle `after` without requests
+/// from now on.
+pub(crate) fn enable(after: Duration) {
+    let mut state = STATE.lock().unwrap();
+    state.after = Some(after);
+    state.last_request = Some(Instant::now());
+}
+
+/// Records a request, which wakes the exporter if idle.
+pub(crate) fn record_request() {
+    if STATE.lock().unwrap().request(Instant::now()) {
+        info!("resuming the background outputs");
+        WAKE.notify_all();
+    }
+}
+
+/// Blocks while the exporter is idle, unless `busy`.
+pub(crate) fn wait_while_idle(busy: bool) {
+    let mut state = STATE.lock().unwrap();
+    if busy || !state.is_idle(Instant::now()) {
+        return;
+    }
+    info!(
+        "no request for {:?}, suspending the background outputs",
+        state.after.unwrap()
+    );
+    while state.is_idle(Instant::now()) {
+        state = WAKE.wait(state).unwrap();
+    }
+}
+
+/// Renders whether the exporter was idle until the latest request, and
+/// the time it spent idle. Nothing is rendered without `--idle-after`.
+pub(crate) fn render(registry: &mut Registry) {
+    let state = STATE.lock().unwrap();
+    if state.after.is_none() {
+        return;
+    }
+
+    let mut idle = registry.family(
+        "wireguard_exporter_idle",
+        MetricType::Gauge,
+        "Whether the exporter was idle, its background outputs suspended, until this request",
+    );
+    idle.push(&[], if state.woken { 1.0 } else { 0.0 });
+    registry.register(idle);
+
+    let mut idle_time = registry.family(
+        "wireguard_exporter_idle_seconds_total",
+        MetricType::Counter,
+        "Time the exporter spent idle for lack of requests",
+    );
+    idle_time.push(&[], state.idle_time.as_secs_f64());
+    registry.register(idle_time);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request() {
+        let start = Instant::now();
+        let mut state = State::new();
+
+        // never idle without --idle-after
+        assert!(!state.request(start + Duration::from_secs(3600)));
+
+        state.after = Some(Duration::from_secs(600));
+        assert!(!state.is_idle(start + Duration::from_secs(3900)));
+        assert!(state.is_idle(start + Duration::from_secs(4200)));
+
+        assert!(state.request(start + Duration::from_secs(4500)));
+        assert_eq!(state.idle_time, Duration::from_secs(300));
+        assert!(!state.is_idle(start + Duration::from_secs(4500)));
+
+        assert!(!state.request(start + Duration::from_secs(4600)));
+        assert_eq!(state.idle_time, Duration::from_secs(300));
+    }
+}
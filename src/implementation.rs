@@ -0,0 +1,163 @@
+// Which implementation of WireGuard serves an interface. The kernel module
+// is driven over netlink, while the userspace implementations expose the
+// cross-platform UAPI on a socket named after the interface; the process
+// listening on it tells wireguard-go from boringtun.
+use log::debug;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// where the userspace implementations create their UAPI sockets
+pub(crate) const UAPI_DIRS: &[&str] = &["/var/run/wireguard", "/run/wireguard"];
+
+// present when the module is loaded or built in the kernel
+pub(crate) const KERNEL_MODULE_PATH: &str = "/sys/module/wireguard";
+
+// cleared when the interfaces collected are not those of the host
+static LOCAL: AtomicBool = AtomicBool::new(true);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Implementation {
+    Kernel,
+    WireGuardGo,
+    Boringtun,
+    // a UAPI socket served by an unknown or unidentified process
+    Userspace,
+    // neither the kernel module nor a UAPI socket serves the interface,
+    // or the interface is not one of the host
+    Unknown,
+}
+
+impl Implementation {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Implementation::Kernel => "kernel",
+            Implementation::WireGuardGo => "wireguard-go",
+            Implementation::Boringtun => "boringtun",
+            Implementation::Userspace => "userspace",
+            Implementation::Unknown => "unknown",
+        }
+    }
+
+    /// The implementation run by a process of this name, from
+    /// `/proc/<pid>/comm`.
+    fn from_command(command: &str) -> Implementation {
+        let command = command.trim();
+        if command.starts_with("wireguard-go") {
+            Implementation::WireGuardGo
+        } else if command.starts_with("boringtun") {
+            Implementation::Boringtun
+        } else {
+            Implementation::Userspace
+        }
+    }
+}
+
+/// Tells that the interfaces are not those of the host, as with
+/// `--with-fake-backend`, `--dump-file` or `--ssh-target`: their
+/// implementation is unknown.
+pub(crate) fn disable() {
+    LOCAL.store(false, Ordering::Relaxed);
+}
+
+/// Finds out which implementation serves `interface`. The interfaces of
+/// the `--target` hosts are of an unknown implementation.
+pub(crate) fn detect(interface: &str) -> Implementation {
+    if !LOCAL.load(Ordering::Relaxed) || interface.contains('@') {
+        return Implementation::Unknown;
+    }
+    detect_in(UAPI_DIRS, Path::new(KERNEL_MODULE_PATH).exists(), interface)
+}
+
+fn detect_in(dirs: &[&str], kernel_module: bool, interface: &str) -> Implementation {
+    let socket = dirs
+        .iter()
+        .map(|dir| Path::new(dir).join(format!("{}.sock", interface)))
+        .find(|socket| socket.exists());
+    let socket = match socket {
+        Some(socket) => socket,
+        None if kernel_module => return Implementation::Kernel,
+        None => return Implementation::Unknown,
+    };
+
+    match peer_command(&socket) {
+        Ok(command) => Implementation::from_command(&command),
+        Err(e) => {
+            debug!("cannot identify the process serving {:?}: {}", socket, e);
+            Implementation::Userspace
+        }
+    }
+}
+
+/// The name of the process listening on the UAPI `socket`. The connection
+/// is closed without a request.
+fn peer_command(socket: &Path) -> io::Result<String> {
+    let stream = UnixStream::connect(socket)?;
+    let mut credentials: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut credentials as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    fs::read_to_string(format!("/proc/{}/comm", credentials.pid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn test_from_command() {
+        assert_eq!(
+            Implementation::from_command("wireguard-go\n"),
+            Implementation::WireGuardGo
+        );
+        assert_eq!(
+            Implementation::from_command("boringtun-cli\n"),
+            Implementation::Boringtun
+        );
+        assert_eq!(
+            Implementation::from_command("wgd\n"),
+            Implementation::Userspace
+        );
+    }
+
+    #[test]
+    fn test_detect() {
+        let dir = std::env::temp_dir().join(format!(
+            "prometheus_wireguard_exporter_implementation_{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        assert_eq!(detect_in(&[dir_str], true, "wg0"), Implementation::Kernel);
+        // neither the kernel module nor a socket
+        assert_eq!(detect_in(&[dir_str], false, "wg0"), Implementation::Unknown);
+
+        // served by the test itself, an unknown implementation
+        let _listener = UnixListener::bind(dir.join("wg0.sock")).unwrap();
+        assert_eq!(
+            detect_in(&[dir_str], false, "wg0"),
+            Implementation::Userspace
+        );
+        assert_eq!(detect_in(&[dir_str], true, "wg1"), Implementation::Kernel);
+
+        assert_eq!(detect("wg0@gw1"), Implementation::Unknown);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
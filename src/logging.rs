@@ -0,0 +1,288 @@
+use crate::options::Options;
+use crate::rotating_file::RotatingFile;
+use clap::crate_name;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::env;
+use std::fmt;
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+// syslog facility used for our messages (LOG_DAEMON)
+const SYSLOG_FACILITY_DAEMON: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum LogTarget {
+    #[default]
+    Stderr,
+    Syslog,
+    Journald,
+}
+
+impl fmt::Display for LogTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogTarget::Stderr => "stderr",
+            LogTarget::Syslog => "syslog",
+            LogTarget::Journald => "journald",
+        })
+    }
+}
+
+impl FromStr for LogTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stderr" => Ok(LogTarget::Stderr),
+            "syslog" => Ok(LogTarget::Syslog),
+            "journald" => Ok(LogTarget::Journald),
+            _ => Err(format!(
+                "invalid log target {}, valid values are stderr, syslog and journald",
+                s
+            )),
+        }
+    }
+}
+
+// syslog severities, also used by journald in the PRIORITY field
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+fn is_enabled(level: LevelFilter, metadata: &Metadata<'_>) -> bool {
+    metadata.level() <= level && metadata.target().starts_with(crate_name!())
+}
+
+/// Sends the log records to the local syslog daemon or to journald
+/// using their datagram sockets.
+struct SocketLogger {
+    target: LogTarget,
+    level: LevelFilter,
+    socket: UnixDatagram,
+}
+
+impl SocketLogger {
+    fn format_syslog(&self, record: &Record<'_>) -> Vec<u8> {
+        format!(
+            "<{}>{}[{}]: {}",
+            SYSLOG_FACILITY_DAEMON * 8 + severity(record.level()),
+            crate_name!(),
+            std::process::id(),
+            record.args()
+        )
+        .into_bytes()
+    }
+
+    fn format_journald(&self, record: &Record<'_>) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let _ = writeln!(buf, "PRIORITY={}", severity(record.level()));
+        let _ = writeln!(buf, "SYSLOG_IDENTIFIER={}", crate_name!());
+        let _ = writeln!(buf, "CODE_MODULE={}", record.target());
+
+        let message = record.args().to_string();
+        if message.contains('\n') {
+            // multiline values must be sent length-prefixed
+            buf.extend_from_slice(b"MESSAGE\n");
+            buf.extend_from_slice(&(message.len() as u64).to_le_bytes());
+            buf.extend_from_slice(message.as_bytes());
+            buf.push(b'\n');
+        } else {
+            let _ = writeln!(buf, "MESSAGE={}", message);
+        }
+        buf
+    }
+}
+
+impl Log for SocketLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        is_enabled(self.level, metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let buf = match self.target {
+            LogTarget::Journald => self.format_journald(record),
+            _ => self.format_syslog(record),
+        };
+
+        if let Err(e) = self.socket.send(&buf) {
+            // there is nowhere else to log this
+            eprintln!("cannot send log message: {}", e);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Appends the log records to a file, rotating it as needed.
+struct FileLogger {
+    level: LevelFilter,
+    file: Mutex<RotatingFile>,
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        is_enabled(self.level, metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = format!(
+            "[{} {:<5} {}] {}\n",
+            humantime::format_rfc3339_seconds(SystemTime::now()),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            eprintln!("cannot write log message: {}", e);
+        }
+    }
+
+    fn flush(&self) {
+        let _ = self.file.lock().unwrap().flush();
+    }
+}
+
+fn init_stderr(verbose: bool) {
+    let level = if verbose { "trace" } else { "info" };
+    env::set_var("RUST_LOG", format!("{}={}", crate_name!(), level));
+    env_logger::init();
+}
+
+/// Initializes the global logger. If `--log-file` has been specified the
+/// records are written there, otherwise they go to `--log-target`. If the
+/// syslog or journald socket cannot be reached (or the log file cannot be
+/// opened) the exporter falls back to logging on stderr.
+pub(crate) fn init(options: &Options) {
+    let verbose = options.verbose;
+    let level = if verbose {
+        LevelFilter::Trace
+    } else {
+        LevelFilter::Info
+    };
+
+    let logger: Box<dyn Log> = if let Some(log_file) = &options.log_file {
+        match RotatingFile::open(log_file, options.log_file_rotation) {
+            Ok(file) => Box::new(FileLogger {
+                level,
+                file: Mutex::new(file),
+            }),
+            Err(e) => {
+                eprintln!(
+                    "cannot open log file {} ({}), logging to stderr instead",
+                    log_file, e
+                );
+                return init_stderr(verbose);
+            }
+        }
+    } else {
+        let target = options.log_target;
+        let path = match target {
+            LogTarget::Stderr => return init_stderr(verbose),
+            LogTarget::Syslog => SYSLOG_SOCKET,
+            LogTarget::Journald => JOURNALD_SOCKET,
+        };
+
+        match UnixDatagram::unbound().and_then(|socket| {
+            socket.connect(path)?;
+            Ok(socket)
+        }) {
+            Ok(socket) => Box::new(SocketLogger {
+                target,
+                level,
+                socket,
+            }),
+            Err(e) => {
+                eprintln!(
+                    "cannot connect to {} ({}), logging to stderr instead",
+                    path, e
+                );
+                return init_stderr(verbose);
+            }
+        }
+    };
+
+    log::set_max_level(level);
+    log::set_boxed_logger(logger).expect("logger already initialized");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logger(target: LogTarget) -> SocketLogger {
+        SocketLogger {
+            target,
+            level: LevelFilter::Info,
+            socket: UnixDatagram::unbound().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_log_target_from_str() {
+        assert_eq!(LogTarget::from_str("syslog").unwrap(), LogTarget::Syslog);
+        assert_eq!(
+            LogTarget::from_str("journald").unwrap(),
+            LogTarget::Journald
+        );
+        assert_eq!(LogTarget::from_str("stderr").unwrap(), LogTarget::Stderr);
+        assert!(LogTarget::from_str("file").is_err());
+    }
+
+    #[test]
+    fn test_format_syslog() {
+        let buf = logger(LogTarget::Syslog).format_syslog(
+            &Record::builder()
+                .args(format_args!("hello"))
+                .level(Level::Warn)
+                .target(crate_name!())
+                .build(),
+        );
+
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            format!("<28>{}[{}]: hello", crate_name!(), std::process::id())
+        );
+    }
+
+    #[test]
+    fn test_format_journald_multiline() {
+        let buf = logger(LogTarget::Journald).format_journald(
+            &Record::builder()
+                .args(format_args!("a\nb"))
+                .level(Level::Error)
+                .target(crate_name!())
+                .build(),
+        );
+
+        let mut expected = format!(
+            "PRIORITY=3\nSYSLOG_IDENTIFIER={}\nCODE_MODULE={}\nMESSAGE\n",
+            crate_name!(),
+            crate_name!()
+        )
+        .into_bytes();
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(b"a\nb\n");
+
+        assert_eq!(buf, expected);
+    }
+}
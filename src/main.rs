@@ -1,129 +1,2829 @@
 extern crate serde_json;
 #[macro_use]
 extern crate failure;
-use clap;
-use clap::{crate_authors, crate_name, crate_version, Arg};
-use futures::future::{done, ok, Either, Future};
+use clap::{crate_name, crate_version, Arg, SubCommand};
+use futures::future::{ok, poll_fn, Either, Future};
+use http::header::{ACCEPT, AGE, CONTENT_DISPOSITION, CONTENT_TYPE, ORIGIN};
+use http::{Method, StatusCode};
 use hyper::{Body, Request, Response};
-use log::{info, trace};
-use std::env;
+use log::{debug, error, info, trace, warn};
 mod options;
-use options::Options;
+mod peer_tracker;
+use options::{Cardinality, Collector, Options, Target};
+use peer_tracker::PeerTracker;
 mod wireguard;
-use std::convert::TryFrom;
-use std::process::Command;
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::{self, Command};
 use std::string::String;
-use wireguard::WireGuard;
+use wireguard::{Endpoint, ExportFormat, WireGuard};
+mod alerts;
+#[cfg(feature = "heap-profiling")]
+mod allocator;
+mod clock;
+mod compat;
+mod config_file;
+mod crypto;
+mod daemon;
+mod diagnostics;
+mod dns;
+mod docker;
 mod exporter_error;
+mod firewall;
+mod golden;
+mod idle;
+mod implementation;
+mod logging;
+mod memory_budget;
+mod metrics;
+mod netlink;
+#[cfg(feature = "otlp")]
+mod otlp;
+mod outputs;
+mod published;
+mod rate_limiter;
+mod rotating_file;
+mod routes;
+mod sampler;
+mod sandbox;
+mod scheduler;
+mod scrape_config;
+mod server;
+mod service;
+mod tc;
+mod tools;
+mod uapi;
+mod usage;
+mod watch;
+mod websocket;
 mod wireguard_config;
-use wireguard_config::peer_entry_hashmap_try_from;
-extern crate prometheus_exporter_base;
-use crate::exporter_error::ExporterError;
-use prometheus_exporter_base::render_prometheus;
-use std::sync::Arc;
-
-fn wg_with_text(
-    wg_config_str: &str,
-    wg_output: ::std::process::Output,
-) -> Result<Response<Body>, ExporterError> {
-    let pehm = peer_entry_hashmap_try_from(wg_config_str)?;
+use crate::exporter_error::{ExporterError, OptionsError};
+use config_file::ConfigFile;
+use crypto::Key;
+use diagnostics::{Diagnostics, Scrape};
+use dns::DnsChecker;
+use memory_budget::MemoryBudget;
+use metrics::{Format, MetricType, Registry, RegistryPool};
+use outputs::Sink;
+mod textfile;
+use rate_limiter::RateLimiter;
+use rotating_file::RotatingFile;
+use sampler::Sampler;
+use scheduler::Scheduler;
+use server::{empty_response, json_response, ResponseFuture};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use textfile::Textfile;
+use tokio_threadpool::blocking;
+use usage::{Period, UsageStore};
+use wireguard_config::{
+    peer_entry_hashmap_try_from, read_config_fragments, CommentKeys, PeerEntries,
+};
+
+#[cfg(feature = "heap-profiling")]
+#[global_allocator]
+static GLOBAL: allocator::CountingAllocator = allocator::CountingAllocator;
+
+/// Reads the `-n` files, along with their fragments, tagged with the
+/// interface they apply to.
+fn read_names_files(options: &Options) -> Result<Vec<(Option<&str>, String)>, ExporterError> {
+    let mut wg_config_strings = Vec::new();
+    for names_file in options.extract_names_config_files.iter() {
+        let fragments =
+            read_config_fragments(&names_file.path).map_err(|e| ExporterError::NamesFile {
+                path: names_file.path.clone(),
+                e,
+            })?;
+        for fragment in fragments {
+            wg_config_strings.push((names_file.interface.as_ref().map(|s| s as &str), fragment));
+        }
+    }
+    Ok(wg_config_strings)
+}
+
+fn peer_entries<'a>(
+    options: &Options,
+    wg_configs: &[(Option<&'a str>, &'a str)],
+) -> Result<PeerEntries<'a>, ExporterError> {
+    let keys = CommentKeys {
+        name: options.name_comment_key.as_deref(),
+        id: options.peer_id_comment_key.as_deref(),
+        site: options.site_comment_key.as_deref(),
+        client: options.client_comment_key.as_deref(),
+        group: options.group_comment_key.as_deref(),
+    };
+    let mut pehm = PeerEntries::default();
+    for (interface, wg_config_str) in wg_configs {
+        pehm.insert(
+            *interface,
+            peer_entry_hashmap_try_from(wg_config_str, &keys)?,
+        );
+    }
+    wireguard_config::record_unknown_keys(&pehm);
+    Ok(pehm)
+}
+
+fn wg_with_text<F, T>(
+    options: &Options,
+    wg_configs: &[(Option<&str>, &str)],
+    dump: WgDump,
+    started: Instant,
+    scrape: &Scrape,
+    peer_tracker: &PeerTracker,
+    render: F,
+) -> Result<T, ExporterError>
+where
+    F: FnOnce(&WireGuard, Option<&PeerEntries>) -> T,
+{
+    let pehm = if wg_configs.is_empty() {
+        None
+    } else {
+        let _span = scrape.span("names");
+        Some(
+            peer_entries(options, wg_configs)
+                .inspect_err(|_| wireguard_config::record_failure(true))?,
+        )
+    };
     trace!("pehm == {:?}", pehm);
 
-    let wg_output_string = String::from_utf8(wg_output.stdout)?;
-    let wg = WireGuard::try_from(&wg_output_string as &str)?;
-    Ok(Response::new(Body::from(wg.render_with_names(Some(&pehm)))))
+    let wg_output_string = String::from_utf8(dump.text)?;
+    trace!("{}", wg_output_string);
+    let mut wg = {
+        let _span = scrape.span("parse");
+        WireGuard::parse(&wg_output_string, options.parse_threads)?
+    };
+    wg.collect_durations = dump.durations;
+    wg.cached_age = dump.cached_age;
+    scrape
+        .diagnostics()
+        .record_collection(started, &wg, pehm.as_ref().map(|pehm| pehm.len()));
+    let now = clock::now();
+    for event in peer_tracker.observe(&wg, now) {
+        info!("{}", event);
+    }
+    peer_tracker.update_sessions(&wg, now, options.handshake_timeout);
+
+    if options.only_configured_peers {
+        if let Some(pehm) = &pehm {
+            wg.retain_configured_peers(pehm);
+        }
+    }
+
+    let _span = scrape.span("render");
+    Ok(render(&wg, pehm.as_ref()))
 }
 
-fn perform_request(
-    _req: Request<Body>,
-    options: &Arc<Options>,
-) -> impl Future<Item = Response<Body>, Error = failure::Error> {
-    trace!("perform_request");
+/// The output of `wg`, in the `wg show all dump` format, with the time
+/// every interface took if they were dumped one at a time, and how old it
+/// is if it was served from the cache of `--min-collect-interval`.
+#[derive(Clone)]
+struct WgDump {
+    text: Vec<u8>,
+    durations: Vec<(String, Duration)>,
+    cached_age: Option<Duration>,
+}
 
-    // this is needed to satisfy the borrow checker
-    let options = options.clone();
+/// The `wg` run without `--wg-path`: on Windows the `wg.exe` installed by
+/// WireGuard for Windows, which the `PATH` of a service can lack, else the
+/// one found in the `PATH`.
+fn default_wg() -> String {
+    if cfg!(windows) {
+        if let Some(program_files) = env::var_os("ProgramFiles") {
+            let wg = Path::new(&program_files).join("WireGuard").join("wg.exe");
+            if wg.is_file() {
+                return wg.to_string_lossy().into_owned();
+            }
+        }
+    }
+    "wg".to_owned()
+}
 
-    done(
-        Command::new("wg")
-            .arg("show")
-            .arg("all")
-            .arg("dump")
-            .output(),
-    )
-    .from_err()
-    .and_then(move |output| {
-        if let Some(extract_names_config_file) = &options.extract_names_config_file {
-            Either::A(
-                done(::std::fs::read_to_string(extract_names_config_file))
-                    .from_err()
-                    .and_then(|wg_config_string| wg_with_text(&wg_config_string as &str, output)),
-            )
-        } else {
-            Either::B(
-                done(String::from_utf8(output.stdout))
-                    .from_err()
-                    .and_then(|output_str| {
-                        trace!("{}", output_str);
-                        done(WireGuard::try_from(&output_str as &str))
-                            .from_err()
-                            .and_then(|wg| {
-                                ok(Response::new(Body::from(wg.render_with_names(None))))
-                            })
-                    }),
-            )
+/// The command running `wg` with `args`, the one of `--wg-path` if set,
+/// through the `--exec-prefix` wrapper if set, on the `--ssh-target` host
+/// if set.
+pub(crate) fn wg_command(options: &Options, args: &[&str]) -> Command {
+    let wg = match (&options.wg_path, &options.ssh_target) {
+        (Some(wg_path), _) => wg_path.to_owned(),
+        // the remote host is not the one to look at
+        (None, Some(_)) => "wg".to_owned(),
+        (None, None) => default_wg(),
+    };
+    let mut words = Vec::new();
+    if let Some(ssh_target) = &options.ssh_target {
+        // never prompt, the exporter has no one to answer
+        words.extend(&["ssh", "-o", "BatchMode=yes", "-o", "ConnectTimeout=10"]);
+        if let Some(ssh_identity) = &options.ssh_identity {
+            words.extend(&["-i", ssh_identity as &str]);
         }
-    })
-    .from_err()
+        words.extend(&["--", ssh_target as &str]);
+    }
+    words.extend(
+        options
+            .exec_prefix
+            .iter()
+            .flat_map(|exec_prefix| exec_prefix.split_whitespace()),
+    );
+    words.push(&wg);
+    words.extend(args);
+    command_of(&words)
 }
 
-fn main() {
-    let matches = clap::App::new(crate_name!())
-        .version(crate_version!())
-        .author(crate_authors!("\n"))
-        .arg(
-            Arg::with_name("port")
-                .short("p")
-                .help("exporter port")
-                .default_value("9586")
-                .takes_value(true),
+/// The command running `wg` with `args` through `prefix`, if any.
+fn wrapped_command(prefix: Option<&str>, wg: &str, args: &[&str]) -> Command {
+    let mut words: Vec<_> = prefix
+        .iter()
+        .flat_map(|prefix| prefix.split_whitespace())
+        .collect();
+    words.push(wg);
+    words.extend(args);
+    command_of(&words)
+}
+
+// the command running the program and arguments of `words`
+fn command_of(words: &[&str]) -> Command {
+    let mut command = Command::new(words[0]);
+    command.args(&words[1..]);
+    command
+}
+
+fn run_wg(options: &Options, args: &[&str]) -> Result<Vec<u8>, ExporterError> {
+    run(wg_command(options, args))
+}
+
+fn run(mut command: Command) -> Result<Vec<u8>, ExporterError> {
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(ExporterError::WireGuard {
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        });
+    }
+    Ok(output.stdout)
+}
+
+/// Dumps the interfaces over netlink, in the format of wg, timing each if
+/// `timed`. An interface gone since it was listed is skipped.
+fn dump_netlink(timed: bool) -> Result<WgDump, ExporterError> {
+    let mut connection = netlink::Connection::open()?;
+    let mut dump = WgDump {
+        text: Vec::new(),
+        durations: Vec::new(),
+        cached_age: None,
+    };
+    for interface in netlink::interfaces()? {
+        let started = Instant::now();
+        match connection.device(&interface) {
+            Ok(device) => {
+                dump.text.extend_from_slice(device.to_dump().as_bytes());
+                if timed {
+                    dump.durations.push((interface, started.elapsed()));
+                }
+            }
+            Err(e) if e.raw_os_error() == Some(libc::ENODEV) => {
+                debug!("{} is gone since listed", interface)
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(dump)
+}
+
+/// Dumps the interfaces of the userspace implementations over their UAPI
+/// sockets, timing each if `timed`. An interface whose implementation
+/// stopped since it was listed is skipped.
+fn dump_uapi(timed: bool) -> Result<WgDump, ExporterError> {
+    let mut dump = WgDump {
+        text: Vec::new(),
+        durations: Vec::new(),
+        cached_age: None,
+    };
+    for (interface, socket) in uapi::sockets()? {
+        let started = Instant::now();
+        match uapi::get(&socket) {
+            Ok(lines) => {
+                dump.text
+                    .extend_from_slice(uapi::to_dump(&interface, &lines)?.as_bytes());
+                if timed {
+                    dump.durations.push((interface, started.elapsed()));
+                }
+            }
+            // a stale socket left by a crashed implementation
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                debug!("{} is gone since listed", interface)
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(dump)
+}
+
+/// Dumps the interfaces one at a time, timing each. An interface gone
+/// since it was listed is skipped.
+fn dump_per_interface(options: &Options) -> Result<WgDump, ExporterError> {
+    let interfaces = String::from_utf8(run_wg(options, &["show", "interfaces"])?)?;
+    let mut dump = WgDump {
+        text: Vec::new(),
+        durations: Vec::new(),
+        cached_age: None,
+    };
+    for interface in interfaces.split_whitespace() {
+        let started = Instant::now();
+        match run_wg(options, &["show", interface, "dump"]) {
+            Ok(text) => {
+                dump.text.extend_from_slice(
+                    wireguard::prefix_interface_dump(interface, &String::from_utf8(text)?)
+                        .as_bytes(),
+                );
+                dump.durations
+                    .push((interface.to_owned(), started.elapsed()));
+            }
+            Err(e) => warn!("cannot dump {}: {}", interface, e),
+        }
+    }
+    Ok(dump)
+}
+
+// the latest dump and when it was taken, reused within --min-collect-interval
+static LATEST_DUMP: Mutex<Option<(Instant, WgDump)>> = Mutex::new(None);
+
+static CACHED_COLLECTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Dumps the interfaces with the collector configured, at most once every
+/// `--min-collect-interval`. The lock is held while dumping, so that the
+/// concurrent requests wait for the dump in flight rather than start their
+/// own.
+fn dump(options: &Options) -> Result<WgDump, ExporterError> {
+    let min_collect_interval = match options.min_collect_interval {
+        Some(min_collect_interval) => min_collect_interval,
+        None => return dump_now(options),
+    };
+
+    let mut latest = LATEST_DUMP.lock().unwrap();
+    if let Some((taken, dump)) = &*latest {
+        if taken.elapsed() < min_collect_interval {
+            CACHED_COLLECTIONS.fetch_add(1, Ordering::Relaxed);
+            return Ok(WgDump {
+                cached_age: Some(taken.elapsed()),
+                ..dump.clone()
+            });
+        }
+    }
+    let dump = dump_now(options)?;
+    *latest = Some((Instant::now(), dump.clone()));
+    Ok(dump)
+}
+
+// the dump read from the standard input by `--dump-file -`, which can only
+// be read once
+static STDIN_DUMP: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+
+/// Reads the dump of `--dump-file`, the file again at every collection so
+/// that it can be replaced meanwhile, or the standard input once.
+fn read_dump_file(path: &str) -> Result<Vec<u8>, ExporterError> {
+    if path != "-" {
+        return Ok(fs::read(path)?);
+    }
+    let mut stdin_dump = STDIN_DUMP.lock().unwrap();
+    if stdin_dump.is_none() {
+        let mut text = Vec::new();
+        io::stdin().lock().read_to_end(&mut text)?;
+        *stdin_dump = Some(text);
+    }
+    Ok(stdin_dump.as_ref().unwrap().clone())
+}
+
+fn dump_now(options: &Options) -> Result<WgDump, ExporterError> {
+    let mut dump = if let Some(path) = &options.dump_file {
+        WgDump {
+            text: read_dump_file(path)?,
+            durations: Vec::new(),
+            cached_age: None,
+        }
+    } else if options.fake_backend {
+        WgDump {
+            text: golden::DUMP.as_bytes().to_vec(),
+            durations: Vec::new(),
+            cached_age: None,
+        }
+    } else if options.collector == Collector::Netlink {
+        dump_netlink(options.collect_per_interface)?
+    } else if options.collector == Collector::Uapi {
+        dump_uapi(options.collect_per_interface)?
+    } else if options.collect_per_interface {
+        dump_per_interface(options)?
+    } else {
+        WgDump {
+            text: run_wg(options, &["show", "all", "dump"])?,
+            durations: Vec::new(),
+            cached_age: None,
+        }
+    };
+    dump_remotes(options, &mut dump);
+    Ok(dump)
+}
+
+// the separators of the interface and the host of a --target, the
+// namespace of a --netns, or the container found with --docker-socket, in
+// the names of the interfaces
+const HOST_SEPARATOR: char = '@';
+const NETNS_SEPARATOR: char = '%';
+const CONTAINER_SEPARATOR: char = '#';
+
+/// Interfaces dumped besides the local ones, those of a `--target` host
+/// (labeled `host`), of a `--netns` namespace (labeled `netns`) or of a
+/// Docker container (labeled `container`).
+struct Remote<'a> {
+    label: &'static str,
+    name: String,
+    separator: char,
+    // the command wg is run through
+    prefix: String,
+    wg: &'a str,
+}
+
+impl Remote<'_> {
+    fn target(target: &Target) -> Remote<'_> {
+        Remote {
+            label: "host",
+            name: target.host.clone(),
+            separator: HOST_SEPARATOR,
+            prefix: target.command.clone(),
+            wg: "wg",
+        }
+    }
+
+    fn netns<'a>(options: &'a Options, netns: &'a str) -> Remote<'a> {
+        let prefix = match &options.exec_prefix {
+            Some(exec_prefix) => format!("{} ip netns exec {}", exec_prefix, netns),
+            None => format!("ip netns exec {}", netns),
+        };
+        Remote {
+            label: "netns",
+            name: netns.to_owned(),
+            separator: NETNS_SEPARATOR,
+            prefix,
+            wg: options.wg_path.as_deref().unwrap_or("wg"),
+        }
+    }
+
+    fn container<'a>(options: &'a Options, container: &docker::Container) -> Remote<'a> {
+        let prefix = match &options.exec_prefix {
+            Some(exec_prefix) => format!("{} nsenter -t {} -n", exec_prefix, container.pid),
+            None => format!("nsenter -t {} -n", container.pid),
+        };
+        Remote {
+            label: "container",
+            name: container.name.clone(),
+            separator: CONTAINER_SEPARATOR,
+            prefix,
+            wg: options.wg_path.as_deref().unwrap_or("wg"),
+        }
+    }
+
+    /// Dumps the interfaces, renamed `<interface><separator><name>`.
+    fn dump(&self) -> Result<String, ExporterError> {
+        let text = run(wrapped_command(
+            Some(&self.prefix),
+            self.wg,
+            &["show", "all", "dump"],
+        ))?;
+        Ok(wireguard::qualify_interface_dump(
+            &format!("{}{}", self.separator, self.name),
+            &String::from_utf8(text)?,
+        ))
+    }
+}
+
+// whether the latest dump of every --target and --netns succeeded, by
+// label and name
+static REMOTES_UP: Mutex<BTreeMap<(&'static str, String), bool>> = Mutex::new(BTreeMap::new());
+
+/// Appends the dumps of the `--target` hosts, the `--netns` namespaces and
+/// the Docker containers to `dump`, taken in parallel. A remote failing is
+/// left out with a warning, rather than failing the collection.
+fn dump_remotes(options: &Options, dump: &mut WgDump) {
+    let containers = match &options.docker_socket {
+        Some(socket) => docker::containers(socket).unwrap_or_else(|e| {
+            warn!("cannot list the containers over {}: {}", socket, e);
+            Vec::new()
+        }),
+        None => Vec::new(),
+    };
+    let remotes: Vec<_> = options
+        .targets
+        .iter()
+        .map(Remote::target)
+        .chain(
+            options
+                .netns
+                .iter()
+                .map(|netns| Remote::netns(options, netns)),
         )
-        .arg(
-            Arg::with_name("verbose")
-                .short("v")
-                .help("verbose logging")
-                .takes_value(false),
+        .chain(
+            containers
+                .iter()
+                .map(|container| Remote::container(options, container)),
         )
-        .arg(
-            Arg::with_name("extract_names_config_file")
-                .short("n")
-                .help("If set, the exporter will look in the specified WireGuard config file for peer names (must be in [Peer] definition and be a comment)")
-                .takes_value(true))
-        .get_matches();
+        .collect();
+    if remotes.is_empty() {
+        return;
+    }
+
+    let dumps: Vec<_> = std::thread::scope(|scope| {
+        let handles: Vec<_> = remotes
+            .iter()
+            .map(|remote| scope.spawn(move || remote.dump()))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect()
+    });
+
+    let mut up = REMOTES_UP.lock().unwrap();
+    for (remote, text) in remotes.iter().zip(dumps) {
+        // the containers come and go, their discovery has a gauge of its own
+        if remote.label != "container" {
+            up.insert((remote.label, remote.name.clone()), text.is_ok());
+        }
+        match text {
+            Ok(text) => dump.text.extend_from_slice(text.as_bytes()),
+            Err(e) => warn!(
+                "cannot dump the interfaces of the {} {}: {}",
+                remote.label, remote.name, e
+            ),
+        }
+    }
+}
+
+/// Renders whether the latest dump of every `--target` host and `--netns`
+/// namespace succeeded.
+fn render_remotes(registry: &mut Registry, options: &Options) {
+    let mut targets_up = registry.family(
+        "wireguard_exporter_target_up",
+        MetricType::Gauge,
+        "Whether the latest dump of the interfaces of the --target host succeeded",
+    );
+    let mut netns_up = registry.family(
+        "wireguard_exporter_netns_up",
+        MetricType::Gauge,
+        "Whether the latest dump of the interfaces of the --netns namespace succeeded",
+    );
+    for ((label, name), ok) in REMOTES_UP.lock().unwrap().iter() {
+        let up = if *label == "host" {
+            &mut targets_up
+        } else {
+            &mut netns_up
+        };
+        up.push(&[(label, name)], if *ok { 1.0 } else { 0.0 });
+    }
+    if !options.targets.is_empty() {
+        registry.register(targets_up);
+    }
+    if !options.netns.is_empty() {
+        registry.register(netns_up);
+    }
+    if options.docker_socket.is_some() {
+        docker::render(registry);
+    }
+}
+
+/// The name of the local host, the `host` label of the local interfaces
+/// along the `--target` ones.
+fn local_host() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|hostname| hostname.trim().to_owned())
+        .ok()
+        .filter(|hostname| !hostname.is_empty())
+        .unwrap_or_else(|| "localhost".to_owned())
+}
+
+/// Renders how many collections were served the latest dump rather than
+/// a new one.
+fn render_cached_collections(registry: &mut Registry) {
+    let mut cached = registry.family(
+        "wireguard_exporter_cached_collections_total",
+        MetricType::Counter,
+        "Collections served the latest dump because of --min-collect-interval",
+    );
+    cached.push(&[], CACHED_COLLECTIONS.load(Ordering::Relaxed) as f64);
+    registry.register(cached);
+}
+
+/// Renders the options a review of the exporter looks for, such as
+/// whether it runs `--read-only`.
+fn render_config_info(registry: &mut Registry, options: &Options) {
+    let mut info = registry.family(
+        "wireguard_exporter_config_info",
+        MetricType::Gauge,
+        "The collector and the restrictions the exporter runs with",
+    );
+    info.push(
+        &[
+            ("collector", &options.collector.to_string()),
+            ("read_only", &options.read_only.to_string()),
+            ("seccomp", &options.seccomp.to_string()),
+            ("landlock", &options.landlock.to_string()),
+        ],
+        1.0,
+    );
+    registry.register(info);
+}
+
+/// Collects the WireGuard status (and the friendly names, if requested)
+/// and passes them to `render`.
+fn collect<F, T>(
+    options: &Options,
+    scrape: &Scrape,
+    peer_tracker: &PeerTracker,
+    render: F,
+) -> Result<T, ExporterError>
+where
+    F: FnOnce(&WireGuard, Option<&PeerEntries>) -> T,
+{
+    let started = Instant::now();
+
+    let dump = {
+        let _span = scrape.span("exec");
+        dump(options)?
+    };
+
+    let wg_config_strings =
+        read_names_files(options).inspect_err(|_| wireguard_config::record_failure(false))?;
+    let wg_configs: Vec<_> = wg_config_strings
+        .iter()
+        .map(|(interface, s)| (*interface, s as &str))
+        .collect();
+
+    wg_with_text(
+        options,
+        &wg_configs,
+        dump,
+        started,
+        scrape,
+        peer_tracker,
+        render,
+    )
+}
+
+/// Renders everything exported at `/metrics` to `registry`.
+fn render_metrics(
+    registry: &mut Registry,
+    options: &Options,
+    peer_tracker: &PeerTracker,
+    scheduler: &Scheduler,
+    dns: Option<&DnsChecker>,
+    wg: &WireGuard,
+    pehm: Option<&PeerEntries>,
+) {
+    let now = clock::now();
+    render_wireguard(registry, options, wg, pehm, now);
+    peer_tracker.render(registry);
+    if pehm.is_some() {
+        wireguard_config::render_failures(registry);
+        wireguard_config::render_unknown_keys(registry);
+    }
+    if options.tc_stats {
+        tc::render(registry, wg, &tc::read_all(wg));
+    }
+    if let Some(firewall) = options.firewall_counters {
+        match firewall::read(firewall) {
+            Ok(counters) => firewall::render(registry, wg, &counters),
+            Err(e) => warn!("cannot read the {} counters: {}", firewall, e),
+        }
+    }
+    if options.check_routes {
+        match routes::read() {
+            Ok(routes) => routes::render(registry, wg, &routes),
+            Err(e) => warn!("cannot read the routes: {}", e),
+        }
+    }
+    if let (Some(dns), Some(pehm)) = (dns, pehm) {
+        dns.render(registry, wg, pehm, now);
+    }
+    scheduler.render(registry);
+    clock::render(registry);
+    tools::render(registry);
+    outputs::render(registry);
+    idle::render(registry);
+    if options.min_collect_interval.is_some() {
+        render_cached_collections(registry);
+        wg.render_data_age(registry);
+    }
+    render_remotes(registry, options);
+    render_config_info(registry, options);
+    #[cfg(feature = "heap-profiling")]
+    allocator::render(registry);
+    finish_render(registry, options, wg, pehm);
+    if let Some(max_response_size) = options.max_response_size {
+        guard_response_size(registry, max_response_size);
+    }
+}
 
-    let options = Options::from_claps(&matches);
+/// Renders the series derived from `wg`, collected at `now`, and from
+/// the friendly names only, which the same input always renders the same.
+fn render_wireguard(
+    registry: &mut Registry,
+    options: &Options,
+    wg: &WireGuard,
+    pehm: Option<&PeerEntries>,
+    now: u64,
+) {
+    // the low cardinality aggregates all the peers as if below the threshold
+    let min_bytes_threshold = match options.cardinality {
+        Cardinality::Low => u128::MAX,
+        _ => options.min_bytes_threshold,
+    };
+    wg.render_with_names(
+        registry,
+        pehm,
+        min_bytes_threshold,
+        options.compat,
+        options.export_bits,
+    );
+    wg.render_peer_counts(registry, now, options.handshake_timeout);
+    wg.render_collect_durations(registry);
+    if let Some(pehm) = pehm {
+        wg.render_unnamed_peers(registry, pehm, options.unnamed_peers_info);
+        wg.render_name_sources(registry, pehm);
+        wg.render_unknown_peers(registry, pehm);
+        if options.site_comment_key.is_some() {
+            wg.render_sites(registry, pehm);
+        }
+        if options.client_comment_key.is_some() {
+            wg.render_clients(registry, pehm);
+        }
+        if !options.group_slas.is_empty() {
+            wg.render_group_slas(registry, pehm, &options.group_slas, now);
+        }
+    }
+    if !options.alert_rules.is_empty() {
+        options
+            .alert_rules
+            .render(registry, wg, now, options.handshake_timeout);
+    }
+}
 
-    if options.verbose {
-        env::set_var(
-            "RUST_LOG",
-            format!("{}=trace,prometheus_exporter_base=trace", crate_name!()),
+/// Applies the options reshaping the rendered peer series: the
+/// cardinality, the interface labels, the peer IDs, the label budgets and
+/// the hosts of the interfaces.
+fn finish_render(
+    registry: &mut Registry,
+    options: &Options,
+    wg: &WireGuard,
+    pehm: Option<&PeerEntries>,
+) {
+    if options.cardinality == Cardinality::Low {
+        remove_peer_series(registry, wg);
+    }
+    label_peers(registry, options);
+    if let (Some(_), Some(pehm)) = (&options.peer_id_comment_key, pehm) {
+        if options.cardinality != Cardinality::Low {
+            key_peers_by_id(registry, options, wg, pehm);
+        }
+    }
+    if !options.label_budgets.is_empty() {
+        limit_label_values(registry, options);
+    }
+    let names = options.compat.metric_names();
+    if !options.targets.is_empty() {
+        registry.split_label(
+            &[names.interface, "interface"],
+            HOST_SEPARATOR,
+            "host",
+            Some(&local_host()),
         );
-    } else {
-        env::set_var(
-            "RUST_LOG",
-            format!("{}=info,prometheus_exporter_base=info", crate_name!()),
+    }
+    // the interfaces of the namespace of the exporter are not labeled
+    if !options.netns.is_empty() {
+        registry.split_label(
+            &[names.interface, "interface"],
+            NETNS_SEPARATOR,
+            "netns",
+            None,
         );
     }
-    env_logger::init();
+    if options.docker_socket.is_some() {
+        registry.split_label(
+            &[names.interface, "interface"],
+            CONTAINER_SEPARATOR,
+            "container",
+            None,
+        );
+    }
+}
 
-    info!("using options: {:?}", options);
+/// The labels of the peer series which a scrape can leave out, see
+/// `select_labels`.
+fn optional_labels(options: &Options) -> Vec<&str> {
+    let names = options.compat.metric_names();
+    let mut labels = vec![names.friendly_name];
+    if options.client_comment_key.is_some() {
+        labels.push("client");
+    }
+    if let Some((local_ip, local_subnet)) = names.local_ip {
+        labels.push(local_ip);
+        labels.push(local_subnet);
+    }
+    labels.extend(names.allowed_ips);
+    labels.extend(
+        options
+            .interface_labels
+            .iter()
+            .map(|label| &label.name as &str),
+    );
+    labels
+}
+
+/// Parses the `labels` parameter of `/metrics`, the comma separated labels
+/// to keep, into the optional labels to leave out of the peer series.
+fn unselected_labels(options: &Options, selected: &str) -> Result<Vec<String>, String> {
+    let names = options.compat.metric_names();
+    let optional = optional_labels(options);
+    let selected: Vec<&str> = selected
+        .split(',')
+        .map(|label| label.trim())
+        .filter(|label| !label.is_empty())
+        .collect();
+    for label in selected.iter() {
+        let required = [
+            names.interface,
+            "interface",
+            "host",
+            names.public_key,
+            "peer_id",
+        ];
+        if !optional.contains(label) && !required.contains(label) {
+            return Err(format!("{} is not a label of the peer series", label));
+        }
+    }
+    Ok(optional
+        .into_iter()
+        .filter(|label| !selected.contains(label))
+        .map(|label| label.to_owned())
+        .collect())
+}
+
+/// Leaves the `unselected` labels out of the peer series, for a scrape
+/// with the `labels` parameter.
+fn select_labels(registry: &mut Registry, options: &Options, unselected: &[String]) {
+    let names = options.compat.metric_names();
+    let unselected: Vec<&str> = unselected.iter().map(|label| label as &str).collect();
+    registry.remove_labels(&unselected, &[names.public_key, "peer_id"]);
+}
+
+/// Aggregates the series past the `--max-label-values` budgets per
+/// interface, and renders which labels were limited.
+fn limit_label_values(registry: &mut Registry, options: &Options) {
+    let names = options.compat.metric_names();
+    let mut kept = vec![names.interface, "interface"];
+    kept.extend(
+        options
+            .interface_labels
+            .iter()
+            .map(|label| &label.name as &str),
+    );
+
+    let mut overflows = Vec::new();
+    for budget in options.label_budgets.iter() {
+        let overflow = registry.limit_label_values(&budget.name, budget.max_values, &kept);
+        if overflow > 0 {
+            warn!(
+                "{} values of the {} label exceed --max-label-values {}, their series are aggregated",
+                overflow, budget.name, budget
+            );
+        }
+        overflows.push((&budget.name, overflow));
+    }
+
+    let mut limited = registry.family(
+        "wireguard_exporter_cardinality_limited",
+        MetricType::Gauge,
+        "Whether the values of the label exceed --max-label-values",
+    );
+    let mut overflow_values = registry.family(
+        "wireguard_exporter_cardinality_overflow_values",
+        MetricType::Gauge,
+        "Values of the label whose series are aggregated by --max-label-values",
+    );
+    for (label, overflow) in overflows {
+        limited.push(&[("label", label)], if overflow > 0 { 1.0 } else { 0.0 });
+        overflow_values.push(&[("label", label)], overflow as f64);
+    }
+    registry.register(limited);
+    registry.register(overflow_values);
+}
+
+// the bytes kept for the counter of the truncations, rendered after them,
+// and the end of an OpenMetrics response
+const RESPONSE_SIZE_RESERVE: usize = 512;
+
+static TRUNCATED_RESPONSES: AtomicU64 = AtomicU64::new(0);
+
+/// Drops the series past `max_response_size` bytes, so that a runaway
+/// number of peers cannot produce a response the scrapers choke on, and
+/// renders how many responses were truncated.
+fn guard_response_size(registry: &mut Registry, max_response_size: usize) {
+    let dropped = registry.truncate(max_response_size - RESPONSE_SIZE_RESERVE);
+    if dropped > 0 {
+        warn!(
+            "the metrics exceed --max-response-size ({} bytes), {} samples left out",
+            max_response_size, dropped
+        );
+        TRUNCATED_RESPONSES.fetch_add(1, Ordering::Relaxed);
+    }
 
-    let bind = matches.value_of("port").unwrap();
-    let bind = u16::from_str_radix(&bind, 10).expect("port must be a valid number");
-    let addr = ([0, 0, 0, 0], bind).into();
+    let mut truncated = registry.family(
+        "wireguard_exporter_truncated_responses_total",
+        MetricType::Counter,
+        "Responses of /metrics truncated to --max-response-size",
+    );
+    truncated.push(&[], TRUNCATED_RESPONSES.load(Ordering::Relaxed) as f64);
+    registry.register(truncated);
+}
 
-    info!("starting exporter on {}", addr);
+/// Replaces the public key of the peers with a stable ID by the ID in the
+/// peer series, whichever renderer they come from, and renders the public
+/// keys as info series.
+fn key_peers_by_id(registry: &mut Registry, options: &Options, wg: &WireGuard, pehm: &PeerEntries) {
+    let names = options.compat.metric_names();
+    let ids = wg.peer_ids(pehm);
+    registry.replace_label(names.public_key, "peer_id", |labels| {
+        let label = |wanted: &[&str]| {
+            labels
+                .iter()
+                .find(|(name, _)| wanted.contains(&(name as &str)))
+                .map(|(_, value)| value as &str)
+        };
+        let interface = label(&[names.interface, "interface"])?;
+        let public_key = label(&[names.public_key])?;
+        ids.get(&(interface, public_key)).map(|id| id.to_string())
+    });
+    WireGuard::render_peer_info(registry, &ids);
+}
 
-    render_prometheus(&addr, options, |request, options| {
-        Box::new(perform_request(request, options))
+/// Removes the series of single peers, whichever renderer they come from,
+/// for `--cardinality low`.
+fn remove_peer_series(registry: &mut Registry, wg: &WireGuard) {
+    let peers: HashSet<&str> = wg
+        .interfaces
+        .values()
+        .flatten()
+        .filter_map(|endpoint| match endpoint {
+            Endpoint::Remote(ep) => Some(&ep.public_key as &str),
+            Endpoint::Local(_) => None,
+        })
+        .collect();
+    registry.remove_samples(|labels| {
+        labels
+            .iter()
+            .any(|(name, value)| name == "public_key" && peers.contains(value as &str))
     });
 }
+
+/// Adds the `--interface-label`s to the peer series of their interface,
+/// whichever renderer they come from.
+fn label_peers(registry: &mut Registry, options: &Options) {
+    let names = options.compat.metric_names();
+    let mut labels: BTreeMap<&str, Vec<(&str, &str)>> = BTreeMap::new();
+    for label in &options.interface_labels {
+        labels
+            .entry(&label.interface)
+            .or_default()
+            .push((&label.name, &label.value));
+    }
+    for (interface, labels) in labels {
+        // the compat presets only rename the labels of the main series
+        registry.add_labels(
+            &[(names.interface, interface), ("interface", interface)],
+            names.public_key,
+            &labels,
+        );
+    }
+}
+
+// the environment variable holding the state key, if not in a file
+const STATE_KEY_ENV: &str = "WIREGUARD_EXPORTER_STATE_KEY";
+
+/// Reads the key the state files are encrypted with, from
+/// `--state-key-file` or else from the environment.
+fn state_key(options: &Options) -> Result<Option<Key>, String> {
+    let hex = match &options.state_key_file {
+        Some(state_key_file) => fs::read_to_string(state_key_file)
+            .map_err(|e| format!("cannot read the key file {}: {}", state_key_file, e))?,
+        None => match env::var(STATE_KEY_ENV) {
+            Ok(hex) => hex,
+            Err(_) => return Ok(None),
+        },
+    };
+    if cfg!(not(feature = "encryption")) {
+        return Err(
+            "the state key requires the exporter built with the encryption feature".to_owned(),
+        );
+    }
+    Key::from_hex(&hex).map(Some)
+}
+
+/// Renders the usage between `since` and `until` as CSV, along with the
+/// friendly names of the peers.
+fn usage_report(
+    options: &Options,
+    usage: &UsageStore,
+    since: u64,
+    until: u64,
+    period: Period,
+) -> Result<String, ExporterError> {
+    let wg_config_strings = read_names_files(options)?;
+    let wg_configs: Vec<_> = wg_config_strings
+        .iter()
+        .map(|(interface, s)| (*interface, s as &str))
+        .collect();
+    let pehm = peer_entries(options, &wg_configs)?;
+
+    Ok(usage::render_csv(
+        &usage.rows(since, until, period),
+        |interface, public_key| {
+            pehm.get(interface, public_key)
+                .and_then(|peer_entry| peer_entry.name)
+        },
+    ))
+}
+
+/// Performs one collection and one render, for `--self-test`.
+fn self_test(options: &Options) -> Result<String, ExporterError> {
+    let diagnostics = Arc::new(Diagnostics::new());
+    let peer_tracker = PeerTracker::default();
+    let scheduler = Arc::new(Scheduler::new(
+        options.active_concurrency,
+        options.active_jitter,
+    ));
+    let dns = options
+        .dns_check_interval
+        .map(|interval| DnsChecker::new(interval, scheduler.clone()));
+    collect(options, &diagnostics.scrape(), &peer_tracker, |wg, pehm| {
+        let mut registry = Registry::default();
+        render_metrics(
+            &mut registry,
+            options,
+            &peer_tracker,
+            &scheduler,
+            dns.as_ref(),
+            wg,
+            pehm,
+        );
+        let rendered = registry.render_text();
+        format!(
+            "self-test passed: {} interfaces, {} bytes of metrics rendered",
+            wg.interfaces.len(),
+            rendered.len()
+        )
+    })
+}
+
+// rough estimates of the memory needed by a collection and its
+// rendering, which are accounted in the memory budget
+const RENDER_BASE_BYTES: usize = 64 * 1024;
+const RENDER_BYTES_PER_PEER: usize = 4 * 1024;
+// suggested to the clients turned down for lack of memory
+const BUSY_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+fn perform_request<F>(
+    exporter: &Exporter,
+    render: F,
+) -> impl Future<Item = Response<Body>, Error = failure::Error>
+where
+    F: FnOnce(&WireGuard, Option<&PeerEntries>) -> Response<Body> + Send + 'static,
+{
+    trace!("perform_request");
+
+    let reservation = match &exporter.memory_budget {
+        Some(memory_budget) => {
+            let bytes =
+                RENDER_BASE_BYTES + exporter.peer_tracker.peer_count() * RENDER_BYTES_PER_PEER;
+            match memory_budget.try_reserve(bytes) {
+                Some(reservation) => Some(reservation),
+                None => {
+                    warn!(
+                        "memory budget exceeded ({} bytes in use), rejecting the request",
+                        memory_budget.used()
+                    );
+                    return Either::A(ok(Response::builder()
+                        .status(StatusCode::SERVICE_UNAVAILABLE)
+                        .header("Retry-After", BUSY_RETRY_AFTER.as_secs().to_string())
+                        .body(Body::empty())
+                        .unwrap()));
+                }
+            }
+        }
+        None => None,
+    };
+
+    let options = exporter.options.clone();
+    let diagnostics = exporter.diagnostics.clone();
+    let peer_tracker = exporter.peer_tracker.clone();
+    // a response served the latest dump tells how old it is, as a cache
+    // would
+    let render = move |wg: &WireGuard, pehm: Option<&PeerEntries>| {
+        let mut response = render(wg, pehm);
+        if let Some(cached_age) = wg.cached_age {
+            response
+                .headers_mut()
+                .insert(AGE, cached_age.as_secs().to_string().parse().unwrap());
+        }
+        response
+    };
+    let mut job = Some((render, reservation, diagnostics.scrape()));
+
+    // the collection waits for wg, so it runs on the blocking pool
+    // rather than tying up a worker thread
+    Either::B(
+        poll_fn(move || {
+            blocking(|| {
+                let (render, reservation, scrape) = job.take().unwrap();
+                let collected = collect(&options, &scrape, &peer_tracker, render);
+                drop(reservation);
+                collected
+                    .map(|response| (response, scrape))
+                    .inspect_err(|e| diagnostics.record_error(e))
+            })
+        })
+        .from_err()
+        .and_then(|collected| {
+            // the phases of the scrape are published once its response
+            // is written
+            collected
+                .map(|(response, scrape)| {
+                    server::time_body(response, move |written| scrape.record("write", written))
+                })
+                .map_err(failure::Error::from)
+        }),
+    )
+}
+
+/// The host `/probe` is asked for, and the command running `wg` there:
+/// that of `module`, else that of the `--target` of the host, else that
+/// of the only module.
+fn probe_target(
+    options: &Options,
+    target: Option<&str>,
+    module: Option<&str>,
+) -> Result<Target, String> {
+    let host = target.ok_or("the target is missing")?;
+    if !options::is_host_name(host) {
+        return Err(format!("{} is not a valid host name", host));
+    }
+    let module = match module {
+        Some(name) => Some(
+            options
+                .probe_modules
+                .iter()
+                .find(|module| module.name == name)
+                .ok_or_else(|| format!("{} is not a module", name))?,
+        ),
+        None => None,
+    };
+    let command = match module {
+        Some(module) => module.command(host),
+        None => match options.targets.iter().find(|target| target.host == host) {
+            Some(target) => target.command.clone(),
+            None => match options.probe_modules.as_slice() {
+                [module] => module.command(host),
+                _ => return Err("the module is missing".to_owned()),
+            },
+        },
+    };
+    Ok(Target {
+        host: host.to_owned(),
+        command,
+    })
+}
+
+/// Answers `/probe` with the series of `target` alone, as they would be
+/// with `target` as the only `--target`, and whether the probe succeeded.
+/// The peer tracker and the diagnostics only follow the local host, so
+/// the probes leave them alone.
+fn probe_response(options: &Options, target: Target, format: Format) -> Response<Body> {
+    let started = Instant::now();
+    let options = Options {
+        targets: vec![target],
+        ..options.clone()
+    };
+    let target = &options.targets[0];
+
+    let mut registry = Registry::default();
+    let probed = probe(&mut registry, &options, target);
+    if let Err(e) = &probed {
+        warn!("cannot probe {}: {}", target.host, e);
+    }
+
+    let mut success = registry.family(
+        "wireguard_exporter_probe_success",
+        MetricType::Gauge,
+        "Whether the interfaces of the target could be dumped",
+    );
+    success.push(&[], if probed.is_ok() { 1.0 } else { 0.0 });
+    registry.register(success);
+    let mut duration = registry.family(
+        "wireguard_exporter_probe_duration_seconds",
+        MetricType::Gauge,
+        "How long the probe took",
+    );
+    duration.push(&[], started.elapsed().as_secs_f64());
+    registry.register(duration);
+
+    Response::builder()
+        .header(CONTENT_TYPE, format.content_type())
+        .body(Body::from(registry.encode(format)))
+        .unwrap()
+}
+
+fn probe(registry: &mut Registry, options: &Options, target: &Target) -> Result<(), ExporterError> {
+    let dump = Remote::target(target).dump()?;
+    let mut wg = WireGuard::parse(&dump, options.parse_threads)?;
+
+    let wg_config_strings = read_names_files(options)?;
+    let wg_configs: Vec<_> = wg_config_strings
+        .iter()
+        .map(|(interface, s)| (*interface, s as &str))
+        .collect();
+    let pehm = if wg_configs.is_empty() {
+        None
+    } else {
+        Some(peer_entries(options, &wg_configs)?)
+    };
+    if options.only_configured_peers {
+        if let Some(pehm) = &pehm {
+            wg.retain_configured_peers(pehm);
+        }
+    }
+
+    render_wireguard(registry, options, &wg, pehm.as_ref(), clock::now());
+    finish_render(registry, options, &wg, pehm.as_ref());
+    Ok(())
+}
+
+/// Answers `/api/v1/export` with the peers of `interface` in `format`.
+/// The interface can be left out when there is only one.
+fn export_response(
+    wg: &WireGuard,
+    pehm: Option<&PeerEntries>,
+    format: ExportFormat,
+    interface: Option<&str>,
+) -> Response<Body> {
+    let interface = match interface {
+        Some(interface) if wg.interfaces.contains_key(interface) => interface,
+        Some(interface) => {
+            trace!("unknown interface {} to export", interface);
+            return empty_response(StatusCode::NOT_FOUND);
+        }
+        None if wg.interfaces.len() == 1 => wg.interfaces.keys().next().unwrap(),
+        None => {
+            trace!(
+                "{} interfaces, which to export is missing",
+                wg.interfaces.len()
+            );
+            return empty_response(StatusCode::BAD_REQUEST);
+        }
+    };
+    match format {
+        ExportFormat::WgEasy => {
+            json_response(&wg.render_wg_easy_json(interface, pehm, clock::now()))
+        }
+        ExportFormat::WgQuick => Response::builder()
+            .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+            .header(
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}.conf\"", interface),
+            )
+            .body(Body::from(wg.render_wg_quick(interface, pehm)))
+            .unwrap(),
+    }
+}
+
+/// State shared by all the request handlers.
+struct Exporter {
+    options: Arc<Options>,
+    diagnostics: Arc<Diagnostics>,
+    peer_tracker: Arc<PeerTracker>,
+    sampler: Option<Arc<Sampler>>,
+    memory_budget: Option<Arc<MemoryBudget>>,
+    registries: Arc<RegistryPool>,
+    usage: Option<Arc<UsageStore>>,
+    scheduler: Arc<Scheduler>,
+    dns: Option<Arc<DnsChecker>>,
+}
+
+// the window of /api/v1/history without the minutes parameter
+const DEFAULT_HISTORY_MINUTES: u64 = 15;
+
+fn route_api(req: Request<Body>, exporter: &Exporter) -> ResponseFuture {
+    let options = &exporter.options;
+
+    let origin = req
+        .headers()
+        .get(ORIGIN)
+        .and_then(|origin| origin.to_str().ok());
+    let allowed_origin = server::cors_allowed_origin(origin, &options.cors_allowed_origins);
+
+    if req.method() == Method::OPTIONS {
+        return Box::new(ok(server::preflight_response(allowed_origin)));
+    }
+    if req.method() != Method::GET {
+        trace!("method not allowed {}", req.method());
+        return Box::new(ok(empty_response(StatusCode::METHOD_NOT_ALLOWED)));
+    }
+
+    let response: ResponseFuture = match req.uri().path() {
+        "/api/v1/peers" => Box::new(perform_request(exporter, |wg, pehm| {
+            json_response(&wg.render_json_with_names(pehm))
+        })),
+        "/api/v1/topology" => {
+            let handshake_timeout = options.handshake_timeout;
+            Box::new(perform_request(exporter, move |wg, pehm| {
+                json_response(&wg.render_topology_json(pehm, clock::now(), handshake_timeout))
+            }))
+        }
+        "/api/v1/export" => {
+            let uri = req.uri();
+            let format = server::query_param(uri, "format").map(|format| format.parse());
+            let interface = server::query_param(uri, "interface");
+            match format {
+                Some(Ok(format)) => Box::new(perform_request(exporter, move |wg, pehm| {
+                    export_response(wg, pehm, format, interface.as_deref())
+                })),
+                _ => {
+                    trace!("missing or invalid format in {}", uri);
+                    Box::new(ok(empty_response(StatusCode::BAD_REQUEST)))
+                }
+            }
+        }
+        "/api/v1/diff" => {
+            let since =
+                server::query_param(req.uri(), "since").and_then(|since| since.parse::<u64>().ok());
+            match since {
+                Some(since) => {
+                    let peer_tracker = exporter.peer_tracker.clone();
+                    Box::new(perform_request(exporter, move |_, _| {
+                        // there has just been a collection
+                        json_response(&peer_tracker.diff(since).unwrap())
+                    }))
+                }
+                None => {
+                    trace!("missing or invalid since in {}", req.uri());
+                    Box::new(ok(empty_response(StatusCode::BAD_REQUEST)))
+                }
+            }
+        }
+        "/api/v1/usage" if exporter.usage.is_some() => {
+            let uri = req.uri();
+            let param = |name| server::query_param(uri, name);
+            let since = param("since").map(|since| since.parse::<u64>());
+            let until = param("until").map(|until| until.parse::<u64>());
+            let period = param("period").map(|period| period.parse::<Period>());
+            match (
+                since.unwrap_or(Ok(0)),
+                until.unwrap_or(Ok(u64::MAX)),
+                period.unwrap_or(Ok(Period::Hour)),
+            ) {
+                (Ok(since), Ok(until), Ok(period)) => {
+                    let usage = exporter.usage.as_ref().unwrap();
+                    Box::new(ok(json_response(&usage.usage(since, until, period))))
+                }
+                _ => {
+                    trace!("invalid since, until or period in {}", uri);
+                    Box::new(ok(empty_response(StatusCode::BAD_REQUEST)))
+                }
+            }
+        }
+        "/api/v1/usage.csv" if exporter.usage.is_some() => {
+            let uri = req.uri();
+            let param = |name| server::query_param(uri, name);
+            let since = param("from").map(|since| usage::parse_time(&since));
+            let until = param("to").map(|until| usage::parse_time(&until));
+            let period = param("period").map(|period| period.parse::<Period>());
+            match (
+                since.unwrap_or(Ok(0)),
+                until.unwrap_or(Ok(u64::MAX)),
+                period.unwrap_or(Ok(Period::Month)),
+            ) {
+                (Ok(since), Ok(until), Ok(period)) => {
+                    let options = options.clone();
+                    let usage = exporter.usage.clone().unwrap();
+                    // the report reads the names files, so it runs on the
+                    // blocking pool like a collection
+                    Box::new(
+                        poll_fn(move || {
+                            blocking(|| usage_report(&options, &usage, since, until, period))
+                        })
+                        .from_err()
+                        .and_then(|report| {
+                            report
+                                .map(|csv| {
+                                    Response::builder()
+                                        .header(CONTENT_TYPE, "text/csv; charset=utf-8")
+                                        .header(
+                                            CONTENT_DISPOSITION,
+                                            "attachment; filename=\"usage.csv\"",
+                                        )
+                                        .body(Body::from(csv))
+                                        .unwrap()
+                                })
+                                .map_err(failure::Error::from)
+                        }),
+                    )
+                }
+                _ => {
+                    trace!("invalid from, to or period in {}", uri);
+                    Box::new(ok(empty_response(StatusCode::BAD_REQUEST)))
+                }
+            }
+        }
+        "/api/v1/history" if options.history_size > 0 => {
+            let minutes = match server::query_param(req.uri(), "minutes") {
+                Some(minutes) => minutes.parse::<u64>().ok(),
+                None => Some(DEFAULT_HISTORY_MINUTES),
+            };
+            match minutes {
+                Some(minutes) => {
+                    let since = clock::now().saturating_sub(minutes * 60);
+                    Box::new(ok(json_response(&exporter.peer_tracker.history(since))))
+                }
+                None => {
+                    trace!("invalid minutes in {}", req.uri());
+                    Box::new(ok(empty_response(StatusCode::BAD_REQUEST)))
+                }
+            }
+        }
+        _ => {
+            trace!("uri not allowed {}", req.uri());
+            Box::new(ok(empty_response(StatusCode::NOT_FOUND)))
+        }
+    };
+
+    Box::new(response.map(move |mut response| {
+        server::add_cors_headers(&mut response, allowed_origin);
+        response
+    }))
+}
+
+fn route(req: Request<Body>, exporter: &Exporter) -> ResponseFuture {
+    idle::record_request();
+    if req.uri().path().starts_with("/api/") {
+        return route_api(req, exporter);
+    }
+
+    let options = &exporter.options;
+    let diagnostics = &exporter.diagnostics;
+
+    if req.method() != Method::GET {
+        trace!("method not allowed {}", req.method());
+        return Box::new(ok(empty_response(StatusCode::METHOD_NOT_ALLOWED)));
+    }
+
+    match req.uri().path() {
+        "/metrics" => {
+            let options = options.clone();
+            let peer_tracker = exporter.peer_tracker.clone();
+            let registries = exporter.registries.clone();
+            let scheduler = exporter.scheduler.clone();
+            let dns = exporter.dns.clone();
+            let format = Format::from_accept(req.headers().get(ACCEPT));
+            let unselected = match server::query_param(req.uri(), "labels") {
+                Some(selected) => match unselected_labels(&options, &selected) {
+                    Ok(unselected) => Some(unselected),
+                    Err(e) => {
+                        trace!("invalid labels in {}: {}", req.uri(), e);
+                        return Box::new(ok(empty_response(StatusCode::BAD_REQUEST)));
+                    }
+                },
+                None => None,
+            };
+            Box::new(perform_request(exporter, move |wg, pehm| {
+                let mut registry = registries.take();
+                render_metrics(
+                    &mut registry,
+                    &options,
+                    &peer_tracker,
+                    &scheduler,
+                    dns.as_deref(),
+                    wg,
+                    pehm,
+                );
+                if let Some(unselected) = &unselected {
+                    select_labels(&mut registry, &options, unselected);
+                }
+                let body = registry.encode(format);
+                registries.give_back(registry);
+                Response::builder()
+                    .header(CONTENT_TYPE, format.content_type())
+                    .body(Body::from(body))
+                    .unwrap()
+            }))
+        }
+        "/probe" if !options.targets.is_empty() || !options.probe_modules.is_empty() => {
+            let uri = req.uri();
+            let target = probe_target(
+                options,
+                server::query_param(uri, "target").as_deref(),
+                server::query_param(uri, "module").as_deref(),
+            );
+            match target {
+                Ok(target) => {
+                    let options = options.clone();
+                    let format = Format::from_accept(req.headers().get(ACCEPT));
+                    let mut target = Some(target);
+                    // the probe waits for the remote wg like a collection
+                    Box::new(
+                        poll_fn(move || {
+                            blocking(|| probe_response(&options, target.take().unwrap(), format))
+                        })
+                        .from_err(),
+                    )
+                }
+                Err(e) => {
+                    trace!("invalid probe {}: {}", uri, e);
+                    Box::new(ok(empty_response(StatusCode::BAD_REQUEST)))
+                }
+            }
+        }
+        "/summary" => {
+            let handshake_timeout = options.handshake_timeout;
+            Box::new(perform_request(exporter, move |wg, pehm| {
+                Response::builder()
+                    .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+                    .body(Body::from(wg.render_summary(
+                        pehm,
+                        clock::now(),
+                        handshake_timeout,
+                    )))
+                    .unwrap()
+            }))
+        }
+        "/ws" if exporter.sampler.is_some() => {
+            let messages = exporter.sampler.as_ref().unwrap().subscribe();
+            Box::new(ok(websocket::upgrade(req, messages)))
+        }
+        "/debug/collections" if options.debug_endpoints => {
+            Box::new(ok(json_response(&diagnostics.render_collections_json())))
+        }
+        "/debug/unknown_keys" if options.debug_endpoints => Box::new(ok(json_response(
+            &wireguard_config::render_unknown_keys_json(),
+        ))),
+        "/debug/tasks" if options.debug_endpoints => {
+            Box::new(ok(json_response(&diagnostics.render_tasks_json())))
+        }
+        #[cfg(feature = "heap-profiling")]
+        "/debug/heap" if options.debug_endpoints => {
+            let stats = allocator::heap_stats();
+            Box::new(ok(json_response(&serde_json::json!({
+                "allocated_bytes": stats.allocated_bytes,
+                "peak_allocated_bytes": stats.peak_allocated_bytes,
+                "allocations": stats.allocations,
+                "deallocations": stats.deallocations,
+            }))))
+        }
+        _ => {
+            trace!("uri not allowed {}", req.uri());
+            Box::new(ok(empty_response(StatusCode::NOT_FOUND)))
+        }
+    }
+}
+
+// The exit codes, so that supervisors can tell a misconfiguration,
+// which restarting will not fix, from a transient failure.
+const EXIT_FAILURE: i32 = 1;
+const EXIT_BAD_CONFIG: i32 = 2;
+const EXIT_WG_UNAVAILABLE: i32 = 3;
+const EXIT_BIND_FAILED: i32 = 4;
+
+fn exit_code(options: &Options, e: &ExporterError) -> i32 {
+    match e {
+        // a missing, unreadable or malformed --dump-file, rather than wg
+        // output
+        ExporterError::Dump { .. } | ExporterError::IO { .. } if options.dump_file.is_some() => {
+            EXIT_BAD_CONFIG
+        }
+        ExporterError::WireGuard { .. } => EXIT_WG_UNAVAILABLE,
+        ExporterError::IO { e }
+            if e.kind() == io::ErrorKind::NotFound
+                || e.kind() == io::ErrorKind::PermissionDenied =>
+        {
+            EXIT_WG_UNAVAILABLE
+        }
+        ExporterError::NamesFile { .. } | ExporterError::PeerEntryParseError { .. } => {
+            EXIT_BAD_CONFIG
+        }
+        _ => EXIT_FAILURE,
+    }
+}
+
+/// The `--interval` of the `watch` subcommand.
+fn watch_interval(matches: &clap::ArgMatches<'_>) -> Result<Duration, OptionsError> {
+    let interval = matches.value_of("interval").unwrap();
+    interval
+        .parse()
+        .map(Duration::from_secs)
+        .map_err(|e: std::num::ParseIntError| OptionsError::InvalidValue {
+            flag: "--interval".to_owned(),
+            value: interval.to_owned(),
+            reason: e.to_string(),
+        })
+}
+
+/// The command line interface.
+fn app() -> clap::App<'static, 'static> {
+    clap::App::new(crate_name!())
+        .version(crate_version!())
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .arg(
+            Arg::with_name("port")
+                .short("p")
+                .help("exporter port")
+                .default_value("9586")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bind_family")
+                .long("bind-family")
+                .help("The address families to listen on: ipv4 (0.0.0.0), ipv6 ([::] only) or dual ([::] accepting IPv4 too)")
+                .possible_values(&["ipv4", "ipv6", "dual"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .help("verbose logging")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("extract_names_config_file")
+                .short("n")
+                .help("If set, the exporter will look in the specified WireGuard config file for peer names (must be in [Peer] definition and be a comment). Can be repeated; prefix the path with the interface name, as in wg0:/etc/wireguard/wg0.conf, to use the names for that interface only")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true))
+        .arg(
+            Arg::with_name("name_comment_key")
+                .long("name-comment-key")
+                .help("If set, only the comments assigning this key, as in # Name = laptop, # Name: laptop or #!name=laptop, are used as peer names (requires -n)")
+                .requires("extract_names_config_file")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("peer_id_comment_key")
+                .long("peer-id-comment-key")
+                .help("If set, the comments assigning this key, as in # PeerID = laptop-01, give the stable ID of a peer, used in place of its public key in the peer series (requires -n)")
+                .requires("extract_names_config_file")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("site_comment_key")
+                .long("site-comment-key")
+                .help("If set, the comments assigning this key, as in # Site = paris, give the site of a peer, for the per site series and /api/v1/topology (requires -n)")
+                .requires("extract_names_config_file")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("client_comment_key")
+                .long("client-comment-key")
+                .help("If set, the comments assigning this key, as in # Client = ios, give the client of a peer, exported as the client label and in wireguard_peers_by_client (requires -n)")
+                .requires("extract_names_config_file")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("group_comment_key")
+                .long("group-comment-key")
+                .help("If set, the comments assigning this key, as in # Customer = acme, give the group of a peer, for --group-sla (requires -n)")
+                .requires("extract_names_config_file")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("group_sla")
+                .long("group-sla")
+                .help("The max age of the latest handshake of the peers of a group, as in acme=300, exported as the peers violating it in wireguard_group_sla_violations (can be repeated, requires --group-comment-key)")
+                .requires("group_comment_key")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true))
+        .arg(
+            Arg::with_name("min_bytes_threshold")
+                .long("min-bytes-threshold")
+                .help("Peers that transferred less than this many bytes are only exported as a per interface aggregate")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("export_bits")
+                .long("export-bits")
+                .help("Also exports the transferred bytes as bits, in wireguard_sent_bits_total and wireguard_received_bits_total")
+                .takes_value(false))
+        .arg(
+            Arg::with_name("only_configured_peers")
+                .long("only-configured-peers")
+                .help("Ignores the peers not listed in the WireGuard config file (requires -n)")
+                .requires("extract_names_config_file")
+                .takes_value(false))
+        .arg(
+            Arg::with_name("unnamed_peers_info")
+                .long("unnamed-peers-info")
+                .help("Exports an info series for every peer without a friendly name (requires -n)")
+                .requires("extract_names_config_file")
+                .takes_value(false))
+        .arg(
+            Arg::with_name("tc_stats")
+                .long("tc-stats")
+                .help("Exports the drops and overlimits of the traffic control classes the peers are classified into")
+                .takes_value(false))
+        .arg(
+            Arg::with_name("firewall_counters")
+                .long("firewall-counters")
+                .help("Exports the firewall counters tagged with the allowed IPs of the peers")
+                .possible_values(&["iptables", "nftables"])
+                .takes_value(true))
+        .arg(
+            Arg::with_name("install_firewall_counters")
+                .long("install-firewall-counters")
+                .help("Installs an nftables table counting the traffic of every allowed IP of the peers, for --firewall-counters nftables")
+                .takes_value(false))
+        .arg(
+            Arg::with_name("check_routes")
+                .long("check-routes")
+                .help("Exports whether the allowed IPs of every peer are routed through its interface")
+                .takes_value(false))
+        .arg(
+            Arg::with_name("dns_check_interval")
+                .long("dns-check-interval")
+                .help("Resolves the hostname endpoints of the config files every this many seconds and exports whether they match the active endpoints (requires -n)")
+                .requires("extract_names_config_file")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("active_concurrency")
+                .long("active-concurrency")
+                .help("How many tasks of the active checks, such as the lookups of --dns-check-interval, may run at once; the others are deferred")
+                .default_value("4")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("active_jitter")
+                .long("active-jitter")
+                .help("Percentage of its interval the next run of an active check is delayed by at most, to spread them over time")
+                .default_value("10")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("collector")
+                .long("collector")
+                .help("How the peers are collected: by running wg, over the netlink API of the kernel module, or over the UAPI sockets of the userspace implementations, without wg")
+                .possible_values(&["wg", "netlink", "uapi"])
+                .default_value("wg")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("wg_path")
+                .long("wg-path")
+                .help("The wg binary run by the collections, if not the one in the PATH")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("exec_prefix")
+                .long("exec-prefix")
+                .help("Command wg is run through, as in \"sudo -n\" or doas, to run the exporter unprivileged")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("with_fake_backend")
+                .long("with-fake-backend")
+                .help("Collects a built-in dump rather than the system, to test the exporter where WireGuard is not installed")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("dump_file")
+                .long("dump-file")
+                .help("Reads the output of wg show all dump from this file, or from the standard input if -, rather than running wg")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ssh_target")
+                .long("ssh-target")
+                .help("Collects the interfaces of this host rather than the local ones, by running wg over SSH, as in wg-exporter@gw1")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ssh_identity")
+                .long("ssh-identity")
+                .help("The private key SSH authenticates to the --ssh-target with, if not the default one")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("target")
+                .long("target")
+                .help("Remote host whose interfaces are collected as well, by running wg through a command, as in gw1=\"ssh -o BatchMode=yes gw1\" (can be repeated)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("netns")
+                .long("netns")
+                .help("Network namespace whose interfaces are collected as well, with ip netns exec (can be repeated)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("docker_socket")
+                .long("docker-socket")
+                .help("Docker API socket over which the running containers are listed, those with WireGuard interfaces being collected as well with nsenter, as in /var/run/docker.sock")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("probe_module")
+                .long("probe-module")
+                .help("Command running wg on the hosts /probe is asked for, {target} standing for the host, as in ssh=\"ssh -o BatchMode=yes {target}\" (can be repeated)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("collect_per_interface")
+                .long("collect-per-interface")
+                .help("Dumps the interfaces one at a time and exports how long each took, to find out which one slows the scrapes down")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("min_collect_interval")
+                .long("min-collect-interval")
+                .help("If set, the peers are collected at most once every this many seconds, the requests in between being served the latest collection")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("handshake_timeout")
+                .long("handshake-timeout")
+                .help("Seconds since the latest handshake after which a peer is considered offline")
+                .default_value("180")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("interface_grace_period")
+                .long("interface-grace-period")
+                .help("Seconds the series of an interface that disappeared are still exported for")
+                .default_value("60")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("alert_handshake_max_age")
+                .long("alert-handshake-max-age")
+                .help("If set, the handshake_max_age alert fires when the latest handshake of a peer is older than this many seconds")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("alert_min_peers")
+                .long("alert-min-peers")
+                .help("If set, the min_peers alert fires when fewer peers than this are online on an interface")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("alert_max_peers")
+                .long("alert-max-peers")
+                .help("If set, the max_peers alert fires when more peers than this are online on an interface")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("compat")
+                .long("compat")
+                .help("Renames the per peer metrics and labels to match another exporter")
+                .possible_values(&["none", "mindflavor", "prometheus_wireguard_exporter_go", "wgexporter"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cardinality")
+                .long("cardinality")
+                .help("Which per peer series are exported: low aggregates them per interface, full adds the opt-in ones (--export-bits, --unnamed-peers-info)")
+                .possible_values(&["low", "default", "full"])
+                .default_value("default")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_label_values")
+                .long("max-label-values")
+                .help("Most values a label takes in /metrics, as in public_key=5000; the series of the other values are aggregated per interface (can be repeated)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("diagnostics_file")
+                .long("diagnostics-file")
+                .help("If set, the diagnostic dump triggered by SIGUSR1 is written to this file instead of the log")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("otlp_endpoint")
+                .long("otlp-endpoint")
+                .help("If set, the spans of every scrape are exported to this OTLP/HTTP collector, as in http://localhost:4318 (needs the otlp feature)")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("log_target")
+                .long("log-target")
+                .help("Where to send the log messages")
+                .possible_values(&["stderr", "syslog", "journald"])
+                .default_value("stderr")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log_file")
+                .long("log-file")
+                .help("If set, the log messages are appended to this file (overrides --log-target)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log_file_max_size")
+                .long("log-file-max-size")
+                .help("Size in bytes after which the log file is rotated")
+                .default_value("10485760")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log_file_max_age")
+                .long("log-file-max-age")
+                .help("If set, the log file is rotated after this many seconds")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log_file_keep")
+                .long("log-file-keep")
+                .help("How many rotated log files to retain")
+                .default_value("5")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("audit_log")
+                .long("audit-log")
+                .help("If set, the peers added, removed or changing endpoint are appended to this file as JSON lines")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("audit_log_max_size")
+                .long("audit-log-max-size")
+                .help("If set, the audit log is rotated when it would grow past this size in bytes")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("audit_log_max_age")
+                .long("audit-log-max-age")
+                .help("If set, the audit log is rotated after this many seconds")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("audit_log_keep")
+                .long("audit-log-keep")
+                .help("How many rotated audit logs to retain")
+                .default_value("5")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rate_limit")
+                .long("rate-limit")
+                .help("If set, each client IP can perform at most this many requests per minute")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("worker_threads")
+                .long("worker-threads")
+                .help("How many threads serve the requests (defaults to the number of CPUs)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("blocking_threads")
+                .long("blocking-threads")
+                .help("How many collections can run at once, further requests wait for a free slot (up to 32768, defaults to 100)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("parse_threads")
+                .long("parse-threads")
+                .help("How many threads parse the output of wg, for deployments with tens of thousands of peers (defaults to the number of CPUs)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("memory_budget")
+                .long("memory-budget")
+                .help("If set, the bytes the retained snapshots and the collections in flight can take up (as roughly estimated); the requests exceeding it get 503 Service Unavailable")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_response_size")
+                .long("max-response-size")
+                .help("If set, the bytes of /metrics past this size are left out, dropping the last series, and the truncation is counted")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("history_size")
+                .long("history-size")
+                .help("If set, the byte counters of this many latest collections are retained and served on /api/v1/history")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("usage_file")
+                .long("usage-file")
+                .help("If set, the bytes transferred by every peer are summed by hour, kept in this file (an SQLite database if it ends with .sqlite or .db) and served on /api/v1/usage")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("usage_retention")
+                .long("usage-retention")
+                .help("How many days of usage to retain")
+                .default_value("400")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("state_key_file")
+                .long("state-key-file")
+                .help("File holding the key (64 hexadecimal digits) the usage file is encrypted with. Defaults to the WIREGUARD_EXPORTER_STATE_KEY environment variable")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cors_allowed_origin")
+                .long("cors-allowed-origin")
+                .help("Origin allowed to access the /api/v1/* endpoints from a browser (can be repeated, * allows any origin)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("interface_label")
+                .long("interface-label")
+                .help("Label added to all the peer series of an interface, as in wg0:env=prod (can be repeated)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sample_interval")
+                .long("sample-interval")
+                .help("If set, the peers are sampled in background every this many seconds and the changes are streamed on the /ws websocket endpoint")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("idle_after")
+                .long("idle-after")
+                .help("If set, the background outputs are suspended once no request came for this many seconds, until the next request")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("output_filter")
+                .long("output-filter")
+                .help("The peers emitted by a background output, as in websocket:interface=wg0,online (can be repeated, once per output)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("textfile_output")
+                .long("textfile-output")
+                .help("If set, the peer series are written in background to this .prom file, for the textfile collector of node_exporter")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("textfile_interval")
+                .long("textfile-interval")
+                .help("Seconds between two writes of the --textfile-output file")
+                .default_value("60")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("textfile_max_age")
+                .long("textfile-max-age")
+                .help("Seconds after which the --textfile-output file is stale, exported in it for the alerts (3 times --textfile-interval by default)")
+                .requires("textfile_output")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("daemonize")
+                .long("daemonize")
+                .help("Detaches from the terminal and runs in background (use absolute paths, the working directory becomes /)")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("pid_file")
+                .long("pidfile")
+                .help("If set, the pid of the exporter is written to this file, which is removed on SIGTERM and SIGINT")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("seccomp")
+                .long("seccomp")
+                .help("Installs a seccomp filter denying the system calls the exporter does not need (tracing, kernel modules, mounts, namespaces...)")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("landlock")
+                .long("landlock")
+                .help("Restricts the filesystem access of the exporter with Landlock to the paths it needs, if the kernel supports it")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("sandbox")
+                .long("sandbox")
+                .help("Implies --seccomp and --landlock, and further restricts the sockets to the families the exporter uses and TCP to its listener")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("read_only")
+                .long("read-only")
+                .help("Drops the capabilities the collection does not need and refuses the options and subcommands changing the system, such as --install-firewall-counters")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("config_file")
+                .long("config-file")
+                .help("Reads the options from this TOML file, as printed by --print-config; the command line wins over it")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("print_config")
+                .long("print-config")
+                .help("Prints the resolved configuration as TOML, then exits")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("write_golden")
+                .long("write-golden")
+                .help("Renders known inputs with a set of flag combinations in every format to this directory, then exits")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("verify_golden")
+                .long("verify-golden")
+                .help("Compares the renders of known inputs with the ones written to this directory by --write-golden, then exits with 0 if all are identical")
+                .conflicts_with("write_golden")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("self_test")
+                .long("self-test")
+                .help("Performs one collection and one render, then exits with 0 on success or with a code telling what failed")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("debug_endpoints")
+                .long("debug-endpoints")
+                .help("Enables the /debug/* introspection endpoints")
+                .takes_value(false),
+        )
+        .subcommand(
+            SubCommand::with_name("install-service")
+                .about("Writes a hardened systemd unit running the exporter with the flags given before the subcommand")
+                .arg(
+                    Arg::with_name("unit_path")
+                        .long("unit-path")
+                        .help("Path of the unit")
+                        .default_value(service::DEFAULT_UNIT_PATH)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("uninstall-service")
+                .about("Removes the systemd unit written by install-service")
+                .arg(
+                    Arg::with_name("unit_path")
+                        .long("unit-path")
+                        .help("Path of the unit")
+                        .default_value(service::DEFAULT_UNIT_PATH)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Shows the peers of a running exporter, refreshing them periodically")
+                .arg(
+                    Arg::with_name("url")
+                        .long("url")
+                        .help("Base URL of the exporter")
+                        .default_value("http://localhost:9586")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("interval")
+                        .long("interval")
+                        .help("Seconds between two refreshes")
+                        .default_value("2")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("report")
+                .about("Prints the usage of the peers recorded in the --usage-file as CSV")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .help("Start of the report, as a date (2019-06-01) or seconds since the epoch")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .help("End of the report (excluded), as a date (2019-07-01) or seconds since the epoch")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("period")
+                        .long("period")
+                        .help("The period the usage is summed over")
+                        .possible_values(&["hour", "day", "month"])
+                        .default_value("month")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("scrape-config")
+                .about("Prints a Prometheus scrape config and an Alertmanager route for the exporter run with the flags given before the subcommand")
+                .arg(
+                    Arg::with_name("host")
+                        .long("host")
+                        .help("Name or address Prometheus reaches the exporter at [default: the host name]")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate-config")
+                .about("Prints the config file migrated to the version of this release")
+                .arg(
+                    Arg::with_name("path")
+                        .help("The config file to migrate")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("Prints the completions of the command line for the shell")
+                .arg(
+                    Arg::with_name("shell")
+                        .help("The shell to generate the completions for")
+                        .possible_values(&clap::Shell::variants())
+                        .required(true),
+                ),
+        )
+}
+
+// the subcommands allowed with --read-only, which only print or read:
+// one changing WireGuard or the system, such as install-service writing
+// a unit, must not be added here
+const READ_ONLY_SUBCOMMANDS: &[&str] = &[
+    "watch",
+    "report",
+    "scrape-config",
+    "migrate-config",
+    "completions",
+];
+
+/// The subcommand `--read-only` refuses, if any.
+fn refused_subcommand<'a>(matches: &'a clap::ArgMatches<'_>) -> Option<&'a str> {
+    if !matches.is_present("read_only") {
+        return None;
+    }
+    matches
+        .subcommand_name()
+        .filter(|name| !READ_ONLY_SUBCOMMANDS.contains(name))
+}
+
+fn parse_args<I: IntoIterator<Item = String>>(args: I) -> clap::ArgMatches<'static> {
+    app()
+        .get_matches_from_safe(args)
+        .unwrap_or_else(|e| match e.kind {
+            clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => e.exit(),
+            _ => {
+                eprintln!("{}", e.message);
+                process::exit(EXIT_BAD_CONFIG);
+            }
+        })
+}
+
+/// Reads the config file at `path` and brings it to the current version,
+/// returning it along with its original version and the changes made.
+fn read_config_file(path: &str) -> Result<(ConfigFile, u32, Vec<String>), String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    let mut config = ConfigFile::parse(&text).map_err(|e| format!("{}: {}", path, e))?;
+    let version = config.version;
+    let changes = config.migrate().map_err(|e| format!("{}: {}", path, e))?;
+    Ok((config, version, changes))
+}
+
+fn main() {
+    let mut matches = parse_args(env::args());
+
+    if let Some(matches) = matches.subcommand_matches("migrate-config") {
+        let path = matches.value_of("path").unwrap();
+        match read_config_file(path) {
+            Ok((config, version, changes)) => {
+                for change in changes {
+                    eprintln!("{}", change);
+                }
+                if version == config.version {
+                    eprintln!("{} is already of version {}", path, version);
+                }
+                print!("{}", config.to_toml());
+            }
+            Err(e) => {
+                eprintln!("error: {}", e);
+                process::exit(EXIT_BAD_CONFIG);
+            }
+        }
+        return;
+    }
+
+    // the options of the config file are inserted before the command line
+    // ones, leaving out those given on the command line
+    let mut migrated_config = None;
+    if let Some(path) = matches.value_of("config_file").map(|e| e.to_owned()) {
+        let (config, version, changes) = read_config_file(&path).unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            process::exit(EXIT_BAD_CONFIG);
+        });
+        let config_args = config
+            .to_args(|name| matches.occurrences_of(name) > 0)
+            .unwrap_or_else(|e| {
+                eprintln!("error: {}: {}", path, e);
+                process::exit(EXIT_BAD_CONFIG);
+            });
+
+        let mut args = env::args();
+        matches = parse_args(args.next().into_iter().chain(config_args).chain(args));
+        if version < config.version {
+            migrated_config = Some((path, version, changes));
+        }
+    }
+
+    if let Some(name) = refused_subcommand(&matches) {
+        eprintln!(
+            "error: the {} subcommand is not allowed with --read-only",
+            name
+        );
+        process::exit(EXIT_BAD_CONFIG);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("completions") {
+        let shell = matches.value_of("shell").unwrap().parse().unwrap();
+        app().gen_completions_to(crate_name!(), shell, &mut io::stdout());
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("watch") {
+        let interval = watch_interval(matches).unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            process::exit(EXIT_BAD_CONFIG);
+        });
+
+        if let Err(e) = watch::run(matches.value_of("url").unwrap(), interval) {
+            eprintln!("{}", e);
+            process::exit(EXIT_FAILURE);
+        }
+        return;
+    }
+
+    let options = match Options::from_claps(&matches) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(EXIT_BAD_CONFIG);
+        }
+    };
+
+    if matches.is_present("print_config") {
+        print!("{}", options.to_toml());
+        return;
+    }
+    if let Some(dir) = matches.value_of("write_golden") {
+        match golden::write(dir) {
+            Ok(written) => println!("{} golden files written to {}", written, dir),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                process::exit(EXIT_FAILURE);
+            }
+        }
+        return;
+    }
+    if let Some(dir) = matches.value_of("verify_golden") {
+        match golden::verify(dir) {
+            Ok((compared, mismatches)) if mismatches.is_empty() => {
+                println!("{} golden files identical", compared)
+            }
+            Ok((compared, mismatches)) => {
+                for mismatch in mismatches.iter() {
+                    eprintln!("{}", mismatch);
+                }
+                eprintln!("{} of {} golden files differ", mismatches.len(), compared);
+                process::exit(EXIT_FAILURE);
+            }
+            Err(e) => {
+                eprintln!("error: {}", e);
+                process::exit(EXIT_FAILURE);
+            }
+        }
+        return;
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("install-service") {
+        if options.daemonize {
+            eprintln!("error: --daemonize must not be used with systemd");
+            process::exit(EXIT_BAD_CONFIG);
+        }
+
+        let unit_path = sub_matches.value_of("unit_path").unwrap();
+        let args: Vec<String> = env::args()
+            .skip(1)
+            .take_while(|arg| arg != "install-service")
+            .collect();
+        let installed = env::current_exe().and_then(|exe| {
+            service::install(
+                unit_path,
+                &service::render_unit(&exe.to_string_lossy(), &args, &options),
+            )
+        });
+        match installed {
+            Ok(()) => println!(
+                "{} written, run systemctl daemon-reload && systemctl enable --now {}",
+                unit_path,
+                Path::new(unit_path)
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+            ),
+            Err(e) => {
+                eprintln!("cannot write {}: {}", unit_path, e);
+                process::exit(EXIT_FAILURE);
+            }
+        }
+        return;
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("scrape-config") {
+        let host = sub_matches
+            .value_of("host")
+            .map(|host| host.to_owned())
+            .unwrap_or_else(local_host);
+        print!("{}", scrape_config::render(&options, &host));
+        return;
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("report") {
+        let usage_file = options.usage_file.as_ref().unwrap_or_else(|| {
+            eprintln!("error: report requires --usage-file");
+            process::exit(EXIT_BAD_CONFIG);
+        });
+        let parse_time = |name| {
+            sub_matches
+                .value_of(name)
+                .map(usage::parse_time)
+                .transpose()
+                .unwrap_or_else(|e| {
+                    eprintln!("error: {}", e);
+                    process::exit(EXIT_BAD_CONFIG);
+                })
+        };
+        let since = parse_time("from").unwrap_or(0);
+        let until = parse_time("to").unwrap_or(u64::MAX);
+        let period = sub_matches.value_of("period").unwrap().parse().unwrap();
+        let key = state_key(&options).unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            process::exit(EXIT_BAD_CONFIG);
+        });
+
+        let report = UsageStore::open(usage_file, options.usage_retention, key)
+            .map_err(ExporterError::from)
+            .and_then(|usage| usage_report(&options, &usage, since, until, period));
+        match report {
+            Ok(csv) => print!("{}", csv),
+            Err(e) => {
+                eprintln!("cannot produce the report: {}", e);
+                process::exit(exit_code(&options, &e));
+            }
+        }
+        return;
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("uninstall-service") {
+        let unit_path = sub_matches.value_of("unit_path").unwrap();
+        if let Err(e) = service::uninstall(unit_path) {
+            eprintln!("cannot remove {}: {}", unit_path, e);
+            process::exit(EXIT_FAILURE);
+        }
+        println!("{} removed, run systemctl daemon-reload", unit_path);
+        return;
+    }
+
+    logging::init(&options);
+
+    if let Some((path, version, changes)) = migrated_config {
+        warn!(
+            "{} is of version {}, migrated to version {} for this run: update it with migrate-config",
+            path,
+            version,
+            config_file::CONFIG_VERSION
+        );
+        for change in changes {
+            warn!("{}: {}", path, change);
+        }
+    }
+
+    if matches.is_present("self_test") {
+        match self_test(&options) {
+            Ok(summary) => {
+                println!("{}", summary);
+                return;
+            }
+            Err(e) => {
+                eprintln!("self-test failed: {}", e);
+                process::exit(exit_code(&options, &e));
+            }
+        }
+    }
+
+    if options.daemonize {
+        if let Err(e) = daemon::daemonize() {
+            error!("cannot daemonize: {}", e);
+            process::exit(EXIT_FAILURE);
+        }
+    }
+    // before any thread is started, for them to inherit the restrictions
+    if options.landlock {
+        if let Err(e) = sandbox::apply_landlock(&options) {
+            error!("cannot apply the Landlock rules: {}", e);
+            process::exit(EXIT_FAILURE);
+        }
+    }
+    if options.seccomp {
+        if let Err(e) = sandbox::apply_seccomp(&options) {
+            error!("cannot install the seccomp filter: {}", e);
+            process::exit(EXIT_FAILURE);
+        }
+    }
+    if options.read_only {
+        if let Err(e) = sandbox::drop_capabilities(&sandbox::read_only_capabilities(&options)) {
+            error!("cannot drop the capabilities: {}", e);
+            process::exit(EXIT_FAILURE);
+        }
+    }
+    if let Some(pid_file) = &options.pid_file {
+        match daemon::PidFile::create(pid_file) {
+            Ok(pid_file) => daemon::spawn_cleanup_on_termination(pid_file),
+            Err(e) => {
+                error!("cannot create the pid file {}: {}", pid_file, e);
+                process::exit(EXIT_FAILURE);
+            }
+        }
+    }
+
+    info!("using options: {:?}", options);
+    if let Some(path) = &options.dump_file {
+        info!("collecting the dump of {}, not the system", path);
+    } else if options.fake_backend {
+        warn!("collecting the built-in dump of --with-fake-backend, not the system");
+    } else {
+        tools::probe(&options);
+    }
+
+    let listener = server::bind(options.bind_family, options.port).unwrap_or_else(|e| {
+        error!(
+            "cannot listen on port {} ({}): {}",
+            options.port, options.bind_family, e
+        );
+        process::exit(EXIT_BIND_FAILED);
+    });
+    match server::describe_listener(&listener) {
+        Ok(description) => info!("starting exporter on {}", description),
+        Err(e) => warn!("cannot inspect the listening socket: {}", e),
+    }
+
+    let (worker_threads, blocking_threads) = (options.worker_threads, options.blocking_threads);
+    let options = Arc::new(options);
+    let diagnostics = Arc::new(Diagnostics::new());
+    diagnostics::spawn_dump_on_sigusr1(diagnostics.clone(), options.clone());
+
+    let rate_limiter = options.rate_limit.map(RateLimiter::new);
+
+    let audit_log = options.audit_log.as_ref().map(|audit_log| {
+        RotatingFile::open(audit_log, options.audit_log_rotation).unwrap_or_else(|e| {
+            error!("cannot open the audit log {}: {}", audit_log, e);
+            process::exit(EXIT_BAD_CONFIG);
+        })
+    });
+    let memory_budget = options
+        .memory_budget
+        .map(|memory_budget| Arc::new(MemoryBudget::new(memory_budget)));
+    let key = state_key(&options).unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(EXIT_BAD_CONFIG);
+    });
+    let usage = options.usage_file.as_ref().map(|usage_file| {
+        let usage =
+            UsageStore::open(usage_file, options.usage_retention, key).unwrap_or_else(|e| {
+                error!("cannot open the usage file {}: {}", usage_file, e);
+                process::exit(EXIT_BAD_CONFIG);
+            });
+        Arc::new(usage)
+    });
+    let peer_tracker = Arc::new(PeerTracker::new(
+        audit_log,
+        memory_budget.clone(),
+        options.history_size,
+        usage.clone(),
+        options.interface_grace_period,
+    ));
+
+    let sampler = options
+        .sample_interval
+        .map(|sample_interval| Arc::new(Sampler::new(sample_interval)));
+    if let Some(idle_after) = options.idle_after {
+        idle::enable(idle_after);
+    }
+    if options.fake_backend || options.dump_file.is_some() || options.ssh_target.is_some() {
+        implementation::disable();
+    }
+    #[cfg(feature = "otlp")]
+    if let Some(otlp_endpoint) = &options.otlp_endpoint {
+        if let Err(e) = otlp::enable(otlp_endpoint) {
+            error!("cannot export the spans to {}: {}", otlp_endpoint, e);
+            process::exit(EXIT_BAD_CONFIG);
+        }
+    }
+    let mut sinks: Vec<Arc<dyn Sink>> = Vec::new();
+    if let Some(sampler) = &sampler {
+        sinks.push(sampler.clone());
+    }
+    if let Some(textfile_output) = &options.textfile_output {
+        let render_options = options.clone();
+        sinks.push(Arc::new(Textfile::new(
+            textfile_output,
+            options.textfile_interval,
+            options
+                .textfile_max_age
+                .unwrap_or(3 * options.textfile_interval),
+            Box::new(move |registry, wg, pehm| {
+                render_wireguard(registry, &render_options, wg, pehm, clock::now());
+                finish_render(registry, &render_options, wg, pehm);
+            }),
+        )));
+    }
+    if options.install_firewall_counters {
+        sinks.push(Arc::new(firewall::Installer::new()));
+    }
+    {
+        let options = options.clone();
+        let diagnostics = diagnostics.clone();
+        let peer_tracker = peer_tracker.clone();
+        outputs::spawn(sinks, move |sink| {
+            let filter = options
+                .output_filters
+                .iter()
+                .find(|filter| filter.output == sink.name());
+            collect(
+                &options,
+                &diagnostics.scrape(),
+                &peer_tracker,
+                |wg, pehm| match filter {
+                    Some(filter) => sink.emit(
+                        &outputs::filter(wg, filter, clock::now(), options.handshake_timeout),
+                        pehm,
+                    ),
+                    None => sink.emit(wg, pehm),
+                },
+            )?
+        });
+    }
+
+    let scheduler = Arc::new(Scheduler::new(
+        options.active_concurrency,
+        options.active_jitter,
+    ));
+    let dns = options
+        .dns_check_interval
+        .map(|interval| Arc::new(DnsChecker::new(interval, scheduler.clone())));
+    let exporter = Exporter {
+        options,
+        diagnostics,
+        peer_tracker,
+        sampler,
+        memory_budget,
+        registries: Arc::new(RegistryPool::default()),
+        usage,
+        scheduler,
+        dns,
+    };
+
+    let runtime = server::runtime(worker_threads, blocking_threads).unwrap_or_else(|e| {
+        error!("cannot start the runtime: {}", e);
+        process::exit(EXIT_FAILURE);
+    });
+    let served = server::serve(runtime, listener, move |request, remote_addr| {
+        if let Some(rate_limiter) = &rate_limiter {
+            if let Err(retry_after) = rate_limiter.check(remote_addr.ip()) {
+                trace!("rate limit exceeded by {}", remote_addr);
+                return Box::new(ok(Response::builder()
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .header("Retry-After", retry_after.as_secs().max(1).to_string())
+                    .body(Body::empty())
+                    .unwrap()));
+            }
+        }
+
+        let in_flight = exporter.diagnostics.request_started();
+        Box::new(route(request, &exporter).then(move |res| {
+            drop(in_flight);
+            res
+        }))
+    });
+    if let Err(e) = served {
+        error!("cannot start the server: {}", e);
+        process::exit(EXIT_FAILURE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_self_test_malformed_dump() {
+        let path = env::temp_dir().join(format!("wgx_self_test_{}", process::id()));
+        let options = Options {
+            dump_file: Some(path.to_string_lossy().into_owned()),
+            handshake_timeout: Duration::from_secs(180),
+            parse_threads: 1,
+            ..Options::default()
+        };
+
+        fs::write(&path, golden::DUMP).unwrap();
+        assert!(self_test(&options).is_ok());
+
+        fs::write(&path, "wg0\tA=\t(none)\n").unwrap();
+        let e = self_test(&options).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "line 1 of the dump: expected 5 or 9 fields, found 3"
+        );
+        assert_eq!(exit_code(&options, &e), EXIT_BAD_CONFIG);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_refused_subcommand() {
+        let refused = |args: &[&str]| {
+            let matches = parse_args(args.iter().map(|arg| (*arg).to_owned()));
+            refused_subcommand(&matches).map(|name| name.to_owned())
+        };
+        assert_eq!(
+            refused(&["exporter", "--read-only", "install-service"]).as_deref(),
+            Some("install-service")
+        );
+        assert_eq!(
+            refused(&["exporter", "--read-only", "uninstall-service"]).as_deref(),
+            Some("uninstall-service")
+        );
+        assert_eq!(refused(&["exporter", "--read-only", "watch"]), None);
+        assert_eq!(refused(&["exporter", "install-service"]), None);
+    }
+
+    #[test]
+    fn test_watch_interval() {
+        let interval = |args: &[&str]| {
+            let matches = parse_args(args.iter().map(|arg| (*arg).to_owned()));
+            watch_interval(matches.subcommand_matches("watch").unwrap())
+        };
+        assert_eq!(interval(&["exporter", "watch"]), Ok(Duration::from_secs(2)));
+        assert_eq!(
+            interval(&["exporter", "watch", "--interval", "5"]),
+            Ok(Duration::from_secs(5))
+        );
+        assert_eq!(
+            interval(&["exporter", "watch", "--interval", "x"])
+                .unwrap_err()
+                .to_string(),
+            "invalid value \"x\" for --interval: invalid digit found in string"
+        );
+    }
+
+    #[test]
+    fn test_self_test_missing_dump() {
+        let options = Options {
+            dump_file: Some("/nonexistent".to_owned()),
+            handshake_timeout: Duration::from_secs(180),
+            parse_threads: 1,
+            ..Options::default()
+        };
+        let e = self_test(&options).unwrap_err();
+        assert_eq!(exit_code(&options, &e), EXIT_BAD_CONFIG);
+
+        // without --dump-file a missing wg is not a configuration error
+        let options = Options {
+            dump_file: None,
+            ..options
+        };
+        assert_eq!(exit_code(&options, &e), EXIT_WG_UNAVAILABLE);
+    }
+
+    #[test]
+    fn test_unselected_labels() {
+        let options = Options {
+            client_comment_key: Some("client".to_owned()),
+            ..Options::default()
+        };
+        let uri: http::Uri = "/metrics?labels=interface%2C+friendly_name"
+            .parse()
+            .unwrap();
+        let selected = server::query_param(&uri, "labels").unwrap();
+        assert_eq!(
+            unselected_labels(&options, &selected),
+            Ok(vec![
+                "client".to_owned(),
+                "local_ip".to_owned(),
+                "local_subnet".to_owned()
+            ])
+        );
+        assert!(unselected_labels(&options, "friendly_name%2Cclient").is_err());
+    }
+}
@@ -0,0 +1,876 @@
+// A minimal metric registry: the renderers add their metric families
+// to a `Registry`, which is then encoded in the format negotiated with
+// the scraper (the Prometheus text format, OpenMetrics or the delimited
+// protobuf format).
+use http::header::HeaderValue;
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum MetricType {
+    Counter,
+    Gauge,
+}
+
+impl MetricType {
+    fn as_str(self) -> &'static str {
+        match self {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+        }
+    }
+}
+
+/// The value of a label limited by `Registry::limit_label_values` for the
+/// samples past the limit.
+pub(crate) const OVERFLOW_VALUE: &str = "__overflow__";
+
+#[derive(Debug, Clone, PartialEq)]
+struct Sample {
+    labels: Vec<(String, String)>,
+    value: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MetricFamily {
+    name: String,
+    metric_type: MetricType,
+    help: String,
+    // only the first `len` samples are current, the others are left
+    // over from a previous render to be overwritten
+    samples: Vec<Sample>,
+    len: usize,
+}
+
+fn overwrite(s: &mut String, value: &str) {
+    s.clear();
+    s.push_str(value);
+}
+
+impl MetricFamily {
+    pub fn new(name: &str, metric_type: MetricType, help: &str) -> MetricFamily {
+        MetricFamily {
+            name: name.to_owned(),
+            metric_type,
+            help: help.to_owned(),
+            samples: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// Adds a sample, reusing the strings of a left over one if any.
+    pub fn push(&mut self, labels: &[(&str, &str)], value: f64) {
+        match self.samples.get_mut(self.len) {
+            Some(sample) => {
+                sample.labels.truncate(labels.len());
+                for (i, (name, value)) in labels.iter().enumerate() {
+                    match sample.labels.get_mut(i) {
+                        Some((old_name, old_value)) => {
+                            overwrite(old_name, name);
+                            overwrite(old_value, value);
+                        }
+                        None => sample
+                            .labels
+                            .push(((*name).to_owned(), (*value).to_owned())),
+                    }
+                }
+                sample.value = value;
+            }
+            None => self.samples.push(Sample {
+                labels: labels
+                    .iter()
+                    .map(|(name, value)| ((*name).to_owned(), (*value).to_owned()))
+                    .collect(),
+                value,
+            }),
+        }
+        self.len += 1;
+    }
+
+    fn samples(&self) -> &[Sample] {
+        &self.samples[..self.len]
+    }
+}
+
+/// The exposition formats a `Registry` can be encoded to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Format {
+    Text,
+    OpenMetrics,
+    Protobuf,
+}
+
+impl Format {
+    /// Picks the format from the `Accept` header of a scrape, falling
+    /// back to the text format.
+    pub fn from_accept(accept: Option<&HeaderValue>) -> Format {
+        let accept = accept.and_then(|accept| accept.to_str().ok()).unwrap_or("");
+        if accept.contains("application/vnd.google.protobuf")
+            && accept.contains("io.prometheus.client.MetricFamily")
+        {
+            Format::Protobuf
+        } else if accept.contains("application/openmetrics-text") {
+            Format::OpenMetrics
+        } else {
+            Format::Text
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Text => "text/plain; version=0.0.4; charset=utf-8",
+            Format::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            Format::Protobuf => {
+                "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited"
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Registry {
+    families: Vec<MetricFamily>,
+    // the families of a previous render, see `clear`
+    spare: Vec<MetricFamily>,
+    // the size of the previous encoding, to allocate the next one at once
+    encoded_len: usize,
+}
+
+// the registries retained by a `RegistryPool`, enough for the usual
+// scrapers without holding onto the memory of a burst of scrapes
+const POOL_MAX_LEN: usize = 4;
+
+/// Registries reused between the scrapes, so that a render overwrites the
+/// families, the samples and the label strings of a previous one instead
+/// of allocating them all again.
+#[derive(Debug, Default)]
+pub(crate) struct RegistryPool {
+    registries: Mutex<Vec<Registry>>,
+}
+
+impl RegistryPool {
+    pub fn take(&self) -> Registry {
+        self.registries.lock().unwrap().pop().unwrap_or_default()
+    }
+
+    pub fn give_back(&self, mut registry: Registry) {
+        registry.clear();
+        let mut registries = self.registries.lock().unwrap();
+        if registries.len() < POOL_MAX_LEN {
+            registries.push(registry);
+        }
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_owned()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_owned()
+    } else {
+        value.to_string()
+    }
+}
+
+fn write_sample(s: &mut String, name: &str, sample: &Sample) {
+    s.push_str(name);
+    if !sample.labels.is_empty() {
+        s.push('{');
+        for (i, (label, value)) in sample.labels.iter().enumerate() {
+            if i > 0 {
+                s.push(',');
+            }
+            let _ = write!(s, "{}=\"{}\"", label, escape_label_value(value));
+        }
+        s.push('}');
+    }
+    let _ = writeln!(s, " {}", format_value(sample.value));
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn write_bytes_field(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    write_varint(buf, field << 3 | 2);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+impl Registry {
+    /// Returns an empty family, reusing the allocations of the family
+    /// with the same name from before `clear` if any.
+    pub fn family(&mut self, name: &str, metric_type: MetricType, help: &str) -> MetricFamily {
+        match self.spare.iter().position(|family| family.name == name) {
+            Some(i) => {
+                let mut family = self.spare.swap_remove(i);
+                family.metric_type = metric_type;
+                overwrite(&mut family.help, help);
+                family
+            }
+            None => MetricFamily::new(name, metric_type, help),
+        }
+    }
+
+    pub fn register(&mut self, family: MetricFamily) {
+        self.families.push(family);
+    }
+
+    /// Appends `labels` to the samples of the registered families having
+    /// one of `selectors` and a `required` label, except the labels the
+    /// samples already have.
+    pub fn add_labels(
+        &mut self,
+        selectors: &[(&str, &str)],
+        required: &str,
+        labels: &[(&str, &str)],
+    ) {
+        for family in self.families.iter_mut() {
+            let len = family.len;
+            for sample in family.samples[..len].iter_mut() {
+                let selected = sample.labels.iter().any(|(name, value)| {
+                    selectors
+                        .iter()
+                        .any(|(selector, selected)| name == selector && value == selected)
+                }) && sample.labels.iter().any(|(name, _)| name == required);
+                if !selected {
+                    continue;
+                }
+                for (name, value) in labels {
+                    if !sample.labels.iter().any(|(existing, _)| existing == name) {
+                        sample
+                            .labels
+                            .push(((*name).to_owned(), (*value).to_owned()));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Removes the `labels` of the samples of the registered families
+    /// having one of the `required` labels.
+    pub fn remove_labels(&mut self, labels: &[&str], required: &[&str]) {
+        for family in self.families.iter_mut() {
+            let len = family.len;
+            for sample in family.samples[..len].iter_mut() {
+                if sample
+                    .labels
+                    .iter()
+                    .any(|(name, _)| required.contains(&(name as &str)))
+                {
+                    sample
+                        .labels
+                        .retain(|(name, _)| !labels.contains(&(name as &str)));
+                }
+            }
+        }
+    }
+
+    /// Replaces the `name` label of the samples for which `value`, called
+    /// with their labels, returns a value by a `new_name` label holding it.
+    pub fn replace_label<F>(&mut self, name: &str, new_name: &str, value: F)
+    where
+        F: Fn(&[(String, String)]) -> Option<String>,
+    {
+        for family in self.families.iter_mut() {
+            let len = family.len;
+            for sample in family.samples[..len].iter_mut() {
+                let position = sample.labels.iter().position(|(label, _)| label == name);
+                if let Some(position) = position {
+                    if let Some(value) = value(&sample.labels) {
+                        sample.labels[position] = (new_name.to_owned(), value);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Splits the value of the first of the `names` labels the samples have
+    /// at the last `separator`, the part after it going to a `new_name`
+    /// label, which is `default` if there is no separator, or left out
+    /// without a default.
+    pub fn split_label(
+        &mut self,
+        names: &[&str],
+        separator: char,
+        new_name: &str,
+        default: Option<&str>,
+    ) {
+        for family in self.families.iter_mut() {
+            let len = family.len;
+            for sample in family.samples[..len].iter_mut() {
+                let value = match sample
+                    .labels
+                    .iter_mut()
+                    .find(|(name, _)| names.contains(&(name as &str)))
+                {
+                    Some((_, value)) => value,
+                    None => continue,
+                };
+                let new_value = match value.rfind(separator) {
+                    Some(position) => {
+                        let new_value = value[position + separator.len_utf8()..].to_owned();
+                        value.truncate(position);
+                        new_value
+                    }
+                    None => match default {
+                        Some(default) => default.to_owned(),
+                        None => continue,
+                    },
+                };
+                sample.labels.push((new_name.to_owned(), new_value));
+            }
+        }
+    }
+
+    /// Keeps at most `max_values` values of the `name` label, the first
+    /// ones rendered. The samples with another value are aggregated per
+    /// family into samples labeled `OVERFLOW_VALUE` which only keep the
+    /// `kept` labels besides, summing up the counters and taking the
+    /// maximum of the gauges. Returns the number of values aggregated.
+    pub fn limit_label_values(&mut self, name: &str, max_values: usize, kept: &[&str]) -> usize {
+        let mut values: HashSet<&str> = HashSet::new();
+        let mut overflow: HashSet<&str> = HashSet::new();
+        for family in self.families.iter() {
+            for sample in family.samples().iter() {
+                if let Some((_, value)) = sample.labels.iter().find(|(label, _)| label == name) {
+                    if value != OVERFLOW_VALUE && !values.contains(value as &str) {
+                        if values.len() < max_values {
+                            values.insert(value);
+                        } else {
+                            overflow.insert(value);
+                        }
+                    }
+                }
+            }
+        }
+        let overflow = overflow.len();
+        if overflow == 0 {
+            return 0;
+        }
+        let values: HashSet<String> = values.into_iter().map(|value| value.to_owned()).collect();
+
+        for family in self.families.iter_mut() {
+            let len = family.len;
+            let mut kept_len = 0;
+            // the first sample of every aggregate
+            let mut aggregates: Vec<usize> = Vec::new();
+            for j in 0..len {
+                let sample = &mut family.samples[j];
+                let overflows = sample
+                    .labels
+                    .iter()
+                    .any(|(label, value)| label == name && !values.contains(value));
+                if overflows {
+                    sample
+                        .labels
+                        .retain(|(label, _)| label == name || kept.contains(&(label as &str)));
+                    for (label, value) in sample.labels.iter_mut() {
+                        if label == name {
+                            overwrite(value, OVERFLOW_VALUE);
+                        }
+                    }
+
+                    let sample = &family.samples[j];
+                    let aggregate = aggregates
+                        .iter()
+                        .find(|i| family.samples[**i].labels == sample.labels)
+                        .copied();
+                    if let Some(i) = aggregate {
+                        let value = sample.value;
+                        let aggregate = &mut family.samples[i].value;
+                        *aggregate = match family.metric_type {
+                            MetricType::Counter => *aggregate + value,
+                            MetricType::Gauge => aggregate.max(value),
+                        };
+                        // left over, to be overwritten
+                        continue;
+                    }
+                    aggregates.push(kept_len);
+                }
+                family.samples.swap(kept_len, j);
+                kept_len += 1;
+            }
+            family.len = kept_len;
+        }
+        overflow
+    }
+
+    /// Removes the samples of the registered families whose labels match
+    /// `predicate`, then the families left without samples.
+    pub fn remove_samples<P>(&mut self, predicate: P)
+    where
+        P: Fn(&[(String, String)]) -> bool,
+    {
+        let mut i = 0;
+        while i < self.families.len() {
+            let family = &mut self.families[i];
+            let len = family.len;
+            let mut kept = 0;
+            for j in 0..len {
+                if !predicate(&family.samples[j].labels) {
+                    // the removed samples are left over, to be overwritten
+                    family.samples.swap(kept, j);
+                    kept += 1;
+                }
+            }
+            family.len = kept;
+
+            if kept == 0 && len > 0 {
+                let mut family = self.families.remove(i);
+                family.len = 0;
+                self.spare.push(family);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Drops the samples, and then the families, past the first `max_len`
+    /// bytes of the text encoding, which is larger than the protobuf one.
+    /// Returns the number of samples dropped.
+    pub fn truncate(&mut self, max_len: usize) -> usize {
+        let mut len = 0;
+        let mut sample = String::new();
+        let mut dropped = 0;
+        let mut kept_families = self.families.len();
+        for (i, family) in self.families.iter_mut().enumerate() {
+            let header_len = "# HELP  \n# TYPE  \n".len()
+                + 2 * family.name.len()
+                + family.help.len()
+                + family.metric_type.as_str().len();
+            if len + header_len > max_len {
+                kept_families = i;
+                break;
+            }
+            len += header_len;
+
+            let mut kept = 0;
+            for s in family.samples().iter() {
+                sample.clear();
+                write_sample(&mut sample, &family.name, s);
+                if len + sample.len() > max_len {
+                    break;
+                }
+                len += sample.len();
+                kept += 1;
+            }
+            if kept < family.len {
+                dropped += family.len - kept;
+                family.len = kept;
+                kept_families = i + 1;
+                break;
+            }
+        }
+
+        for mut family in self.families.drain(kept_families..) {
+            dropped += family.len;
+            family.len = 0;
+            self.spare.push(family);
+        }
+        dropped
+    }
+
+    /// Removes all the families, retaining their allocations for the
+    /// families requested with `family` afterwards.
+    pub fn clear(&mut self) {
+        for mut family in self.families.drain(..) {
+            family.len = 0;
+            self.spare.push(family);
+        }
+    }
+
+    pub fn encode(&mut self, format: Format) -> Vec<u8> {
+        let encoded = match format {
+            Format::Text => self.render_text().into_bytes(),
+            Format::OpenMetrics => self.render_openmetrics().into_bytes(),
+            Format::Protobuf => self.render_protobuf(),
+        };
+        self.encoded_len = encoded.len();
+        encoded
+    }
+
+    /// Encodes the families in the Prometheus text format (version 0.0.4).
+    pub fn render_text(&self) -> String {
+        let mut s = String::with_capacity(self.encoded_len);
+        for family in self.families.iter() {
+            let _ = writeln!(s, "# HELP {} {}", family.name, family.help);
+            let _ = writeln!(s, "# TYPE {} {}", family.name, family.metric_type.as_str());
+            for sample in family.samples().iter() {
+                write_sample(&mut s, &family.name, sample);
+            }
+        }
+        s
+    }
+
+    /// Encodes the families in the OpenMetrics text format, where the
+    /// name of a counter family lacks the `_total` suffix of its samples.
+    pub fn render_openmetrics(&self) -> String {
+        let mut s = String::with_capacity(self.encoded_len);
+        for family in self.families.iter() {
+            let (name, sample_name) = match family.metric_type {
+                MetricType::Counter => {
+                    let name = family.name.trim_end_matches("_total");
+                    (name, format!("{}_total", name))
+                }
+                MetricType::Gauge => (&family.name as &str, family.name.clone()),
+            };
+
+            let _ = writeln!(s, "# TYPE {} {}", name, family.metric_type.as_str());
+            let _ = writeln!(s, "# HELP {} {}", name, family.help);
+            for sample in family.samples().iter() {
+                write_sample(&mut s, &sample_name, sample);
+            }
+        }
+        s.push_str("# EOF\n");
+        s
+    }
+
+    /// Encodes the families as length delimited `io.prometheus.client.MetricFamily`
+    /// protobuf messages. Families without samples are left out.
+    pub fn render_protobuf(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.encoded_len);
+        for family in self.families.iter().filter(|f| f.len > 0) {
+            let mut message = Vec::new();
+            write_bytes_field(&mut message, 1, family.name.as_bytes());
+            write_bytes_field(&mut message, 2, family.help.as_bytes());
+            // the MetricType enum: COUNTER = 0, GAUGE = 1
+            write_varint(&mut message, 3 << 3);
+            write_varint(
+                &mut message,
+                match family.metric_type {
+                    MetricType::Counter => 0,
+                    MetricType::Gauge => 1,
+                },
+            );
+
+            for sample in family.samples().iter() {
+                let mut metric = Vec::new();
+                for (name, value) in sample.labels.iter() {
+                    let mut label = Vec::new();
+                    write_bytes_field(&mut label, 1, name.as_bytes());
+                    write_bytes_field(&mut label, 2, value.as_bytes());
+                    write_bytes_field(&mut metric, 1, &label);
+                }
+
+                // Gauge and Counter both hold the value as field 1 (double)
+                let mut value = vec![1 << 3 | 1];
+                value.extend_from_slice(&sample.value.to_le_bytes());
+                let field = match family.metric_type {
+                    MetricType::Counter => 3,
+                    MetricType::Gauge => 2,
+                };
+                write_bytes_field(&mut metric, field, &value);
+
+                write_bytes_field(&mut message, 4, &metric);
+            }
+
+            write_varint(&mut buf, message.len() as u64);
+            buf.extend_from_slice(&message);
+        }
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> Registry {
+        let mut registry = Registry::default();
+
+        let mut sent_bytes = MetricFamily::new(
+            "wireguard_sent_bytes_total",
+            MetricType::Counter,
+            "Bytes sent to the peer",
+        );
+        sent_bytes.push(
+            &[("interface", "wg0"), ("friendly_name", "a \"b\"")],
+            1024.0,
+        );
+        registry.register(sent_bytes);
+
+        let mut peers = MetricFamily::new("wireguard_peers", MetricType::Gauge, "Peers");
+        peers.push(&[], 2.0);
+        registry.register(peers);
+
+        registry.register(MetricFamily::new(
+            "wireguard_empty",
+            MetricType::Gauge,
+            "No samples",
+        ));
+        registry
+    }
+
+    #[test]
+    fn test_render_text() {
+        assert_eq!(
+            registry().render_text(),
+            "# HELP wireguard_sent_bytes_total Bytes sent to the peer\n# TYPE wireguard_sent_bytes_total counter\nwireguard_sent_bytes_total{interface=\"wg0\",friendly_name=\"a \\\"b\\\"\"} 1024\n# HELP wireguard_peers Peers\n# TYPE wireguard_peers gauge\nwireguard_peers 2\n# HELP wireguard_empty No samples\n# TYPE wireguard_empty gauge\n"
+        );
+    }
+
+    #[test]
+    fn test_render_openmetrics() {
+        assert_eq!(
+            registry().render_openmetrics(),
+            "# TYPE wireguard_sent_bytes counter\n# HELP wireguard_sent_bytes Bytes sent to the peer\nwireguard_sent_bytes_total{interface=\"wg0\",friendly_name=\"a \\\"b\\\"\"} 1024\n# TYPE wireguard_peers gauge\n# HELP wireguard_peers Peers\nwireguard_peers 2\n# TYPE wireguard_empty gauge\n# HELP wireguard_empty No samples\n# EOF\n"
+        );
+    }
+
+    #[test]
+    fn test_render_protobuf() {
+        let mut registry = Registry::default();
+        let mut peers = MetricFamily::new("p", MetricType::Gauge, "h");
+        peers.push(&[("i", "w")], 2.0);
+        registry.register(peers);
+        registry.register(MetricFamily::new("empty", MetricType::Gauge, "h"));
+
+        let mut expected = vec![
+            29, // message length
+            0x0a, 1, b'p', // name
+            0x12, 1, b'h', // help
+            0x18, 1, // type: gauge
+            0x22, 19, // metric
+            0x0a, 6, 0x0a, 1, b'i', 0x12, 1, b'w', // label
+            0x12, 9, 0x09, // gauge value
+        ];
+        expected.extend_from_slice(&2.0f64.to_le_bytes());
+        assert_eq!(registry.render_protobuf(), expected);
+    }
+
+    #[test]
+    fn test_reuse() {
+        let pool = RegistryPool::default();
+        let mut registry = pool.take();
+        let mut peers = registry.family("p", MetricType::Gauge, "h");
+        peers.push(&[("interface", "wg0"), ("public_key", "A=")], 1.0);
+        peers.push(&[("interface", "wg1")], 2.0);
+        registry.register(peers);
+        registry.encode(Format::Text);
+        pool.give_back(registry);
+
+        let mut registry = pool.take();
+        assert_eq!(registry.encoded_len, 84);
+        let mut peers = registry.family("p", MetricType::Gauge, "h2");
+        // the samples of the previous render are there to be overwritten
+        assert_eq!(peers.samples.len(), 2);
+        peers.push(&[("interface", "wg2")], 3.0);
+        registry.register(peers);
+        assert_eq!(
+            registry.render_text(),
+            "# HELP p h2\n# TYPE p gauge\np{interface=\"wg2\"} 3\n"
+        );
+
+        let mut fresh = Registry::default();
+        let mut peers = MetricFamily::new("p", MetricType::Gauge, "h2");
+        peers.push(&[("interface", "wg2")], 3.0);
+        fresh.register(peers);
+        assert_eq!(registry.render_protobuf(), fresh.render_protobuf());
+    }
+
+    #[test]
+    fn test_add_labels() {
+        let mut registry = Registry::default();
+        let mut peers = registry.family("p", MetricType::Gauge, "h");
+        peers.push(&[("interface", "wg0"), ("public_key", "A=")], 1.0);
+        peers.push(
+            &[("interface", "wg0"), ("public_key", "B="), ("env", "dev")],
+            1.0,
+        );
+        peers.push(&[("interface", "wg1"), ("public_key", "C=")], 1.0);
+        peers.push(&[("interface", "wg0")], 2.0);
+        registry.register(peers);
+        registry.add_labels(
+            &[("interface", "wg0")],
+            "public_key",
+            &[("env", "prod"), ("site", "ber")],
+        );
+        assert_eq!(
+            registry.render_text(),
+            "# HELP p h\n# TYPE p gauge\np{interface=\"wg0\",public_key=\"A=\",env=\"prod\",site=\"ber\"} 1\np{interface=\"wg0\",public_key=\"B=\",env=\"dev\",site=\"ber\"} 1\np{interface=\"wg1\",public_key=\"C=\"} 1\np{interface=\"wg0\"} 2\n"
+        );
+    }
+
+    #[test]
+    fn test_remove_labels() {
+        let mut registry = Registry::default();
+        let mut peers = registry.family("p", MetricType::Gauge, "h");
+        peers.push(
+            &[
+                ("interface", "wg0"),
+                ("public_key", "A="),
+                ("friendly_name", "a"),
+            ],
+            1.0,
+        );
+        peers.push(&[("interface", "wg0"), ("friendly_name", "b")], 2.0);
+        registry.register(peers);
+
+        registry.remove_labels(&["friendly_name"], &["public_key"]);
+        assert_eq!(
+            registry.render_text(),
+            "# HELP p h\n# TYPE p gauge\np{interface=\"wg0\",public_key=\"A=\"} 1\np{interface=\"wg0\",friendly_name=\"b\"} 2\n"
+        );
+    }
+
+    #[test]
+    fn test_replace_label() {
+        let mut registry = Registry::default();
+        let mut peers = registry.family("p", MetricType::Gauge, "h");
+        peers.push(&[("interface", "wg0"), ("public_key", "A=")], 1.0);
+        peers.push(&[("interface", "wg0"), ("public_key", "B=")], 2.0);
+        registry.register(peers);
+
+        registry.replace_label("public_key", "peer_id", |labels| {
+            labels
+                .iter()
+                .find(|(name, value)| name == "public_key" && value == "A=")
+                .map(|_| "laptop".to_owned())
+        });
+        assert_eq!(
+            registry.render_text(),
+            "# HELP p h\n# TYPE p gauge\np{interface=\"wg0\",peer_id=\"laptop\"} 1\np{interface=\"wg0\",public_key=\"B=\"} 2\n"
+        );
+    }
+
+    #[test]
+    fn test_split_label() {
+        let mut registry = Registry::default();
+        let mut peers = registry.family("p", MetricType::Gauge, "h");
+        peers.push(&[("interface", "wg0@gw1"), ("public_key", "A=")], 1.0);
+        peers.push(&[("interface", "wg0"), ("public_key", "B=")], 2.0);
+        peers.push(&[("public_key", "C=")], 3.0);
+        registry.register(peers);
+
+        registry.split_label(&["interface"], '@', "host", Some("local"));
+        assert_eq!(
+            registry.render_text(),
+            "# HELP p h\n# TYPE p gauge\np{interface=\"wg0\",public_key=\"A=\",host=\"gw1\"} 1\np{interface=\"wg0\",public_key=\"B=\",host=\"local\"} 2\np{public_key=\"C=\"} 3\n"
+        );
+
+        registry.split_label(&["interface"], '%', "netns", None);
+        assert!(!registry.render_text().contains("netns"));
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut registry = registry();
+        let text = registry.render_text();
+        assert_eq!(registry.truncate(text.len()), 0);
+        assert_eq!(registry.render_text(), text);
+
+        // the family without samples does not fit, then the last sample
+        assert_eq!(registry.truncate(text.len() - 1), 0);
+        assert_eq!(
+            registry.render_text(),
+            "# HELP wireguard_sent_bytes_total Bytes sent to the peer\n# TYPE wireguard_sent_bytes_total counter\nwireguard_sent_bytes_total{interface=\"wg0\",friendly_name=\"a \\\"b\\\"\"} 1024\n# HELP wireguard_peers Peers\n# TYPE wireguard_peers gauge\nwireguard_peers 2\n"
+        );
+        let text = registry.render_text();
+        assert_eq!(registry.truncate(text.len() - 1), 1);
+        assert_eq!(
+            registry.render_text(),
+            &text[..text.len() - "wireguard_peers 2\n".len()]
+        );
+
+        assert!(registry.truncate(10) > 0);
+        assert_eq!(registry.render_text(), "");
+    }
+
+    #[test]
+    fn test_limit_label_values() {
+        let mut registry = Registry::default();
+        let mut bytes = registry.family("b_total", MetricType::Counter, "h");
+        let mut handshakes = registry.family("h", MetricType::Gauge, "h");
+        for (interface, public_key, name, value) in &[
+            ("wg0", "A=", "a", 1.0),
+            ("wg0", "B=", "b", 2.0),
+            ("wg0", "C=", "c", 4.0),
+            ("wg1", "D=", "d", 8.0),
+            ("wg1", "A=", "a", 16.0),
+        ] {
+            let labels = [
+                ("interface", *interface),
+                ("public_key", *public_key),
+                ("friendly_name", *name),
+            ];
+            bytes.push(&labels, *value);
+            handshakes.push(&labels, *value);
+        }
+        registry.register(bytes);
+        registry.register(handshakes);
+
+        let mut text = registry.render_text();
+        assert_eq!(
+            registry.limit_label_values("public_key", 4, &["interface"]),
+            0
+        );
+        assert_eq!(registry.render_text(), text);
+
+        assert_eq!(
+            registry.limit_label_values("public_key", 2, &["interface"]),
+            2
+        );
+        text = registry.render_text();
+        assert_eq!(
+            text,
+            "# HELP b_total h\n# TYPE b_total counter\nb_total{interface=\"wg0\",public_key=\"A=\",friendly_name=\"a\"} 1\nb_total{interface=\"wg0\",public_key=\"B=\",friendly_name=\"b\"} 2\nb_total{interface=\"wg0\",public_key=\"__overflow__\"} 4\nb_total{interface=\"wg1\",public_key=\"__overflow__\"} 8\nb_total{interface=\"wg1\",public_key=\"A=\",friendly_name=\"a\"} 16\n# HELP h h\n# TYPE h gauge\nh{interface=\"wg0\",public_key=\"A=\",friendly_name=\"a\"} 1\nh{interface=\"wg0\",public_key=\"B=\",friendly_name=\"b\"} 2\nh{interface=\"wg0\",public_key=\"__overflow__\"} 4\nh{interface=\"wg1\",public_key=\"__overflow__\"} 8\nh{interface=\"wg1\",public_key=\"A=\",friendly_name=\"a\"} 16\n"
+        );
+
+        // the overflow of an interface is aggregated
+        assert_eq!(
+            registry.limit_label_values("public_key", 1, &["interface"]),
+            1
+        );
+        let text = registry.render_text();
+        assert!(text.contains("b_total{interface=\"wg0\",public_key=\"__overflow__\"} 6\n"));
+        assert!(text.contains("h{interface=\"wg0\",public_key=\"__overflow__\"} 4\n"));
+    }
+
+    #[test]
+    fn test_remove_samples() {
+        let mut registry = Registry::default();
+        let mut peers = registry.family("p", MetricType::Gauge, "h");
+        peers.push(&[("interface", "wg0"), ("public_key", "A=")], 1.0);
+        peers.push(&[("interface", "wg0")], 2.0);
+        peers.push(&[("interface", "wg1"), ("public_key", "C=")], 3.0);
+        registry.register(peers);
+        let mut info = registry.family("i", MetricType::Gauge, "h");
+        info.push(&[("interface", "wg0"), ("public_key", "B=")], 1.0);
+        registry.register(info);
+
+        registry.remove_samples(|labels| labels.iter().any(|(name, _)| name == "public_key"));
+        assert_eq!(
+            registry.render_text(),
+            "# HELP p h\n# TYPE p gauge\np{interface=\"wg0\"} 2\n"
+        );
+    }
+
+    #[test]
+    fn test_format_from_accept() {
+        let accept = |s| Format::from_accept(Some(&HeaderValue::from_static(s)));
+
+        assert_eq!(Format::from_accept(None), Format::Text);
+        assert_eq!(accept("text/plain;version=0.0.4"), Format::Text);
+        assert_eq!(
+            accept("application/openmetrics-text;version=1.0.0,text/plain;q=0.5"),
+            Format::OpenMetrics
+        );
+        assert_eq!(
+            accept("application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited;q=0.7,text/plain;q=0.3"),
+            Format::Protobuf
+        );
+    }
+}
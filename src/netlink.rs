@@ -0,0 +1,674 @@
+// Collection over the generic netlink API of the WireGuard kernel module,
+// in place of running `wg`: no process is spawned per collection and
+// wireguard-tools are not needed. The devices are rendered in the
+// `wg show all dump` format, so both collectors share the parsing. The
+// interfaces of the userspace implementations are not in the kernel,
+// they are collected with `wg` or over their UAPI sockets.
+use crate::websocket::base64;
+use std::fs;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::time::Duration;
+
+// linux/netlink.h
+const NLMSG_HDRLEN: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_MULTI: u16 = 0x2;
+const NLM_F_DUMP: u16 = 0x300;
+const NLA_HDRLEN: usize = 4;
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+// linux/genetlink.h
+const GENL_HDRLEN: usize = 4;
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+
+// linux/wireguard.h
+const WG_GENL_NAME: &str = "wireguard";
+const WG_GENL_VERSION: u8 = 1;
+const WG_CMD_GET_DEVICE: u8 = 0;
+const WGDEVICE_A_IFNAME: u16 = 2;
+const WGDEVICE_A_PRIVATE_KEY: u16 = 3;
+const WGDEVICE_A_PUBLIC_KEY: u16 = 4;
+const WGDEVICE_A_LISTEN_PORT: u16 = 6;
+const WGDEVICE_A_FWMARK: u16 = 7;
+const WGDEVICE_A_PEERS: u16 = 8;
+const WGPEER_A_PUBLIC_KEY: u16 = 1;
+const WGPEER_A_PRESHARED_KEY: u16 = 2;
+const WGPEER_A_ENDPOINT: u16 = 4;
+const WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL: u16 = 5;
+const WGPEER_A_LAST_HANDSHAKE_TIME: u16 = 6;
+const WGPEER_A_RX_BYTES: u16 = 7;
+const WGPEER_A_TX_BYTES: u16 = 8;
+const WGPEER_A_ALLOWEDIPS: u16 = 9;
+const WGALLOWEDIP_A_FAMILY: u16 = 1;
+const WGALLOWEDIP_A_IPADDR: u16 = 2;
+const WGALLOWEDIP_A_CIDR_MASK: u16 = 3;
+
+// where the network devices are listed, with their type
+const SYS_CLASS_NET: &str = "/sys/class/net";
+
+// larger than the messages of a dump, which are of a page or two
+const RECV_BUFFER_LEN: usize = 64 * 1024;
+// the kernel answers at once, this only guards against a lost reply
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+// as wg prints the missing keys, endpoints and allowed IPs
+const EMPTY: &str = "(none)";
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// The attributes in `data`, as type and payload.
+fn attributes(mut data: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if data.len() < NLA_HDRLEN {
+            return None;
+        }
+        let len = u16::from_ne_bytes([data[0], data[1]]) as usize;
+        let kind = u16::from_ne_bytes([data[2], data[3]]) & NLA_TYPE_MASK;
+        if len < NLA_HDRLEN || len > data.len() {
+            return None;
+        }
+        let payload = &data[NLA_HDRLEN..len];
+        data = &data[align(len).min(data.len())..];
+        Some((kind, payload))
+    })
+}
+
+/// The netlink messages in `data`, as type, flags, sequence number and
+/// payload.
+fn messages(mut data: &[u8]) -> impl Iterator<Item = (u16, u16, u32, &[u8])> {
+    std::iter::from_fn(move || {
+        if data.len() < NLMSG_HDRLEN {
+            return None;
+        }
+        let len = u32::from_ne_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        let kind = u16::from_ne_bytes([data[4], data[5]]);
+        let flags = u16::from_ne_bytes([data[6], data[7]]);
+        let seq = u32::from_ne_bytes([data[8], data[9], data[10], data[11]]);
+        if len < NLMSG_HDRLEN || len > data.len() {
+            return None;
+        }
+        let payload = &data[NLMSG_HDRLEN..len];
+        data = &data[align(len).min(data.len())..];
+        Some((kind, flags, seq, payload))
+    })
+}
+
+/// Builds a generic netlink request.
+fn request(
+    kind: u16,
+    flags: u16,
+    seq: u32,
+    cmd: u8,
+    version: u8,
+    attrs: &[(u16, &[u8])],
+) -> Vec<u8> {
+    let mut message = vec![0; NLMSG_HDRLEN];
+    message.extend_from_slice(&[cmd, version, 0, 0]);
+    for (attr_kind, payload) in attrs {
+        message.extend_from_slice(&((NLA_HDRLEN + payload.len()) as u16).to_ne_bytes());
+        message.extend_from_slice(&attr_kind.to_ne_bytes());
+        message.extend_from_slice(payload);
+        message.resize(align(message.len()), 0);
+    }
+    let len = message.len() as u32;
+    message[0..4].copy_from_slice(&len.to_ne_bytes());
+    message[4..6].copy_from_slice(&kind.to_ne_bytes());
+    message[6..8].copy_from_slice(&flags.to_ne_bytes());
+    message[8..12].copy_from_slice(&seq.to_ne_bytes());
+    message
+}
+
+fn u16_of(payload: &[u8]) -> u16 {
+    payload
+        .get(..2)
+        .map_or(0, |b| u16::from_ne_bytes([b[0], b[1]]))
+}
+
+fn u32_of(payload: &[u8]) -> u32 {
+    payload
+        .get(..4)
+        .map_or(0, |b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn u64_of(payload: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    if let Some(b) = payload.get(..8) {
+        bytes.copy_from_slice(b);
+    }
+    u64::from_ne_bytes(bytes)
+}
+
+// a key, None if unset (the kernel sends zeros for a missing preshared key)
+fn key_of(payload: &[u8]) -> Option<Vec<u8>> {
+    if payload.iter().all(|b| *b == 0) {
+        None
+    } else {
+        Some(payload.to_vec())
+    }
+}
+
+/// Parses a `struct sockaddr_in` or `struct sockaddr_in6`.
+fn endpoint_of(payload: &[u8]) -> Option<SocketAddr> {
+    let family = libc::c_int::from(u16_of(payload));
+    let port = u16::from_be_bytes([*payload.get(2)?, *payload.get(3)?]);
+    if family == libc::AF_INET && payload.len() >= 8 {
+        let ip = Ipv4Addr::new(payload[4], payload[5], payload[6], payload[7]);
+        Some(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+    } else if family == libc::AF_INET6 && payload.len() >= 24 {
+        let mut ip = [0; 16];
+        ip.copy_from_slice(&payload[8..24]);
+        // the scope of a link local address is left out, as the dump of
+        // wg is read without it
+        Some(SocketAddr::V6(SocketAddrV6::new(
+            Ipv6Addr::from(ip),
+            port,
+            0,
+            0,
+        )))
+    } else {
+        None
+    }
+}
+
+fn allowed_ip_of(payload: &[u8]) -> Option<(IpAddr, u8)> {
+    let (mut family, mut address, mut cidr) = (0, None, None);
+    for (kind, payload) in attributes(payload) {
+        match kind {
+            WGALLOWEDIP_A_FAMILY => family = libc::c_int::from(u16_of(payload)),
+            WGALLOWEDIP_A_IPADDR => address = Some(payload),
+            WGALLOWEDIP_A_CIDR_MASK => cidr = payload.first().copied(),
+            _ => (),
+        }
+    }
+    let address = match (family, address?) {
+        (libc::AF_INET, a) if a.len() == 4 => IpAddr::V4(Ipv4Addr::new(a[0], a[1], a[2], a[3])),
+        (libc::AF_INET6, a) if a.len() == 16 => {
+            let mut ip = [0; 16];
+            ip.copy_from_slice(a);
+            IpAddr::V6(Ipv6Addr::from(ip))
+        }
+        _ => return None,
+    };
+    Some((address, cidr?))
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Peer {
+    public_key: Vec<u8>,
+    preshared_key: Option<Vec<u8>>,
+    endpoint: Option<SocketAddr>,
+    persistent_keepalive: u16,
+    latest_handshake: u64,
+    rx_bytes: u64,
+    tx_bytes: u64,
+    allowed_ips: Vec<(IpAddr, u8)>,
+}
+
+impl Peer {
+    fn parse(payload: &[u8]) -> Peer {
+        let mut peer = Peer::default();
+        for (kind, payload) in attributes(payload) {
+            match kind {
+                WGPEER_A_PUBLIC_KEY => peer.public_key = payload.to_vec(),
+                WGPEER_A_PRESHARED_KEY => peer.preshared_key = key_of(payload),
+                WGPEER_A_ENDPOINT => peer.endpoint = endpoint_of(payload),
+                WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL => {
+                    peer.persistent_keepalive = u16_of(payload)
+                }
+                // a struct __kernel_timespec, of which the seconds are kept
+                WGPEER_A_LAST_HANDSHAKE_TIME => peer.latest_handshake = u64_of(payload),
+                WGPEER_A_RX_BYTES => peer.rx_bytes = u64_of(payload),
+                WGPEER_A_TX_BYTES => peer.tx_bytes = u64_of(payload),
+                WGPEER_A_ALLOWEDIPS => peer
+                    .allowed_ips
+                    .extend(attributes(payload).filter_map(|(_, payload)| allowed_ip_of(payload))),
+                _ => (),
+            }
+        }
+        peer
+    }
+}
+
+/// A WireGuard device, as dumped by the kernel.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Device {
+    name: String,
+    private_key: Option<Vec<u8>>,
+    public_key: Option<Vec<u8>>,
+    listen_port: u16,
+    fwmark: u32,
+    peers: Vec<Peer>,
+}
+
+impl Device {
+    /// Assembles the device from the payloads of the messages of a
+    /// `WG_CMD_GET_DEVICE` dump. The peers are spread over the messages,
+    /// one of them continued in the next message if its allowed IPs do not
+    /// fit.
+    fn parse<'a, I>(payloads: I) -> Device
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let mut device = Device::default();
+        for payload in payloads {
+            for (kind, payload) in attributes(payload) {
+                match kind {
+                    WGDEVICE_A_IFNAME => {
+                        device.name = String::from_utf8_lossy(payload)
+                            .trim_end_matches('\0')
+                            .to_owned()
+                    }
+                    WGDEVICE_A_PRIVATE_KEY => device.private_key = key_of(payload),
+                    WGDEVICE_A_PUBLIC_KEY => device.public_key = key_of(payload),
+                    WGDEVICE_A_LISTEN_PORT => device.listen_port = u16_of(payload),
+                    WGDEVICE_A_FWMARK => device.fwmark = u32_of(payload),
+                    WGDEVICE_A_PEERS => {
+                        for (_, payload) in attributes(payload) {
+                            let peer = Peer::parse(payload);
+                            match device.peers.last_mut() {
+                                Some(last) if last.public_key == peer.public_key => {
+                                    last.allowed_ips.extend(peer.allowed_ips)
+                                }
+                                _ => device.peers.push(peer),
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+        device
+    }
+
+    /// Renders the device as `wg show all dump` does.
+    pub fn to_dump(&self) -> String {
+        let key = |key: &Option<Vec<u8>>| key.as_deref().map_or(EMPTY.to_owned(), base64);
+        let mut dump = format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            self.name,
+            key(&self.private_key),
+            key(&self.public_key),
+            self.listen_port,
+            if self.fwmark == 0 {
+                "off".to_owned()
+            } else {
+                format!("0x{:x}", self.fwmark)
+            }
+        );
+        for peer in self.peers.iter() {
+            let allowed_ips: Vec<_> = peer
+                .allowed_ips
+                .iter()
+                .map(|(address, cidr)| format!("{}/{}", address, cidr))
+                .collect();
+            dump.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                self.name,
+                base64(&peer.public_key),
+                key(&peer.preshared_key),
+                peer.endpoint
+                    .map_or(EMPTY.to_owned(), |endpoint| endpoint.to_string()),
+                if allowed_ips.is_empty() {
+                    EMPTY.to_owned()
+                } else {
+                    allowed_ips.join(",")
+                },
+                peer.latest_handshake,
+                peer.rx_bytes,
+                peer.tx_bytes,
+                if peer.persistent_keepalive == 0 {
+                    "off".to_owned()
+                } else {
+                    peer.persistent_keepalive.to_string()
+                }
+            ));
+        }
+        dump
+    }
+}
+
+/// The WireGuard interfaces of the kernel, by name.
+pub(crate) fn interfaces() -> io::Result<Vec<String>> {
+    interfaces_in(SYS_CLASS_NET)
+}
+
+/// The WireGuard interfaces of the sysfs network devices in `dir`, which
+/// can be that of another namespace.
+pub(crate) fn interfaces_in(dir: &str) -> io::Result<Vec<String>> {
+    let mut interfaces = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        // gone since listed, or not a device
+        let uevent = match fs::read_to_string(entry.path().join("uevent")) {
+            Ok(uevent) => uevent,
+            Err(_) => continue,
+        };
+        if uevent.lines().any(|line| line == "DEVTYPE=wireguard") {
+            interfaces.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    interfaces.sort();
+    Ok(interfaces)
+}
+
+/// A generic netlink socket bound to the WireGuard family.
+pub(crate) struct Connection {
+    fd: libc::c_int,
+    family: u16,
+    seq: u32,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+impl Connection {
+    /// Opens the socket and resolves the id of the WireGuard family,
+    /// failing if the kernel module is not loaded.
+    pub fn open() -> io::Result<Connection> {
+        let fd = cvt(unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_GENERIC,
+            )
+        })?;
+        let mut connection = Connection {
+            fd,
+            family: 0,
+            seq: 0,
+        };
+
+        let timeout = libc::timeval {
+            tv_sec: RECV_TIMEOUT.as_secs() as libc::time_t,
+            tv_usec: 0,
+        };
+        cvt(unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const libc::timeval as *const libc::c_void,
+                mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        })?;
+
+        connection.family =
+            connection
+                .family_id(WG_GENL_NAME)
+                .map_err(|e| match e.raw_os_error() {
+                    Some(libc::ENOENT) => io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "the wireguard netlink family is missing, is the kernel module loaded?",
+                    ),
+                    _ => e,
+                })?;
+        Ok(connection)
+    }
+
+    /// The id of the generic netlink family `name`, failing with `ENOENT`
+    /// if it is not registered.
+    fn family_id(&mut self, name: &str) -> io::Result<u16> {
+        let name = format!("{}\0", name);
+        let replies = self.request(
+            GENL_ID_CTRL,
+            NLM_F_REQUEST,
+            CTRL_CMD_GETFAMILY,
+            1,
+            &[(CTRL_ATTR_FAMILY_NAME, name.as_bytes())],
+        )?;
+        let id = replies
+            .iter()
+            .flat_map(|payload| attributes(payload))
+            .find(|(kind, _)| *kind == CTRL_ATTR_FAMILY_ID)
+            .map(|(_, payload)| u16_of(payload))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no family id in the reply"));
+        id
+    }
+
+    /// Dumps the device `name`, failing with `ENODEV` if it is gone.
+    pub fn device(&mut self, name: &str) -> io::Result<Device> {
+        let name = format!("{}\0", name);
+        let replies = self.request(
+            self.family,
+            NLM_F_REQUEST | NLM_F_DUMP,
+            WG_CMD_GET_DEVICE,
+            WG_GENL_VERSION,
+            &[(WGDEVICE_A_IFNAME, name.as_bytes())],
+        )?;
+        Ok(Device::parse(
+            replies.iter().map(|payload| payload as &[u8]),
+        ))
+    }
+
+    /// Sends a request and returns the attributes of its replies, that is
+    /// their payloads past the generic netlink header.
+    fn request(
+        &mut self,
+        kind: u16,
+        flags: u16,
+        cmd: u8,
+        version: u8,
+        attrs: &[(u16, &[u8])],
+    ) -> io::Result<Vec<Vec<u8>>> {
+        self.seq = self.seq.wrapping_add(1);
+        let message = request(kind, flags, self.seq, cmd, version, attrs);
+        let mut address: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        cvt(unsafe {
+            libc::sendto(
+                self.fd,
+                message.as_ptr() as *const libc::c_void,
+                message.len(),
+                0,
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            ) as libc::c_int
+        })?;
+
+        let mut replies = Vec::new();
+        let mut buffer = vec![0u8; RECV_BUFFER_LEN];
+        loop {
+            let len = cvt(unsafe {
+                libc::recv(
+                    self.fd,
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                    0,
+                ) as libc::c_int
+            })? as usize;
+            // a reply which is not part of a dump is the only one
+            let mut done = false;
+            for (kind, flags, seq, payload) in messages(&buffer[..len]) {
+                if seq != self.seq {
+                    continue;
+                }
+                match kind {
+                    NLMSG_DONE => return Ok(replies),
+                    NLMSG_ERROR => {
+                        // a negative errno, or 0 for an acknowledgment
+                        let errno = u32_of(payload) as i32;
+                        if errno != 0 {
+                            return Err(io::Error::from_raw_os_error(-errno));
+                        }
+                        return Ok(replies);
+                    }
+                    _ => {
+                        replies.push(payload.get(GENL_HDRLEN..).unwrap_or_default().to_vec());
+                        done |= flags & NLM_F_MULTI == 0;
+                    }
+                }
+            }
+            if done {
+                return Ok(replies);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wireguard::{Endpoint, WireGuard};
+    use std::convert::TryFrom;
+
+    fn attr(kind: u16, payload: &[u8]) -> Vec<u8> {
+        let mut attr = ((NLA_HDRLEN + payload.len()) as u16).to_ne_bytes().to_vec();
+        attr.extend_from_slice(&kind.to_ne_bytes());
+        attr.extend_from_slice(payload);
+        attr.resize(align(attr.len()), 0);
+        attr
+    }
+
+    fn allowed_ip(family: libc::c_int, address: &[u8], cidr: u8) -> Vec<u8> {
+        let mut payload = attr(WGALLOWEDIP_A_FAMILY, &(family as u16).to_ne_bytes());
+        payload.extend(attr(WGALLOWEDIP_A_IPADDR, address));
+        payload.extend(attr(WGALLOWEDIP_A_CIDR_MASK, &[cidr]));
+        attr(0, &payload)
+    }
+
+    fn peer(public_key: u8, allowed_ips: &[Vec<u8>]) -> Vec<u8> {
+        let mut payload = attr(WGPEER_A_PUBLIC_KEY, &[public_key; 32]);
+        payload.extend(attr(WGPEER_A_PRESHARED_KEY, &[0; 32]));
+        payload.extend(attr(WGPEER_A_ALLOWEDIPS, &allowed_ips.concat()));
+        attr(0, &payload)
+    }
+
+    #[test]
+    fn test_request() {
+        let message = request(
+            GENL_ID_CTRL,
+            NLM_F_REQUEST,
+            7,
+            CTRL_CMD_GETFAMILY,
+            1,
+            &[(CTRL_ATTR_FAMILY_NAME, b"wireguard\0")],
+        );
+        // the header, the generic header and the name padded to 12 bytes
+        assert_eq!(message.len(), 16 + 4 + 4 + 12);
+
+        let parsed: Vec<_> = messages(&message).collect();
+        assert_eq!(parsed.len(), 1);
+        let (kind, flags, seq, payload) = parsed[0];
+        assert_eq!((kind, flags, seq), (GENL_ID_CTRL, NLM_F_REQUEST, 7));
+        assert_eq!(payload[0], CTRL_CMD_GETFAMILY);
+        assert_eq!(
+            attributes(&payload[GENL_HDRLEN..]).collect::<Vec<_>>(),
+            vec![(CTRL_ATTR_FAMILY_NAME, &b"wireguard\0"[..])]
+        );
+    }
+
+    #[test]
+    fn test_family_id() {
+        // a connection not bound to the WireGuard family, which is missing
+        // unless the kernel module is loaded
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_GENERIC,
+            )
+        };
+        let mut connection = Connection {
+            fd: cvt(fd).unwrap(),
+            family: 0,
+            seq: 0,
+        };
+        // the controller resolves itself
+        assert_eq!(connection.family_id("nlctrl").unwrap(), GENL_ID_CTRL);
+        assert_eq!(
+            connection
+                .family_id("not_a_family")
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::ENOENT)
+        );
+    }
+
+    #[test]
+    fn test_endpoint_of() {
+        let mut sockaddr_in = (libc::AF_INET as u16).to_ne_bytes().to_vec();
+        sockaddr_in.extend_from_slice(&51820u16.to_be_bytes());
+        sockaddr_in.extend_from_slice(&[37, 159, 76, 245]);
+        sockaddr_in.extend_from_slice(&[0; 8]);
+        assert_eq!(
+            endpoint_of(&sockaddr_in),
+            Some("37.159.76.245:51820".parse().unwrap())
+        );
+
+        let mut sockaddr_in6 = (libc::AF_INET6 as u16).to_ne_bytes().to_vec();
+        sockaddr_in6.extend_from_slice(&51820u16.to_be_bytes());
+        sockaddr_in6.extend_from_slice(&[0; 4]);
+        sockaddr_in6.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        sockaddr_in6.extend_from_slice(&[0; 4]);
+        assert_eq!(
+            endpoint_of(&sockaddr_in6),
+            Some("[2001:db8::1]:51820".parse().unwrap())
+        );
+
+        assert_eq!(endpoint_of(&[0; 16]), None);
+    }
+
+    #[test]
+    fn test_device() {
+        // the second peer is continued in the second message
+        let mut first = attr(WGDEVICE_A_IFNAME, b"wg0\0");
+        first.extend(attr(WGDEVICE_A_PRIVATE_KEY, &[1; 32]));
+        first.extend(attr(WGDEVICE_A_PUBLIC_KEY, &[2; 32]));
+        first.extend(attr(WGDEVICE_A_LISTEN_PORT, &51820u16.to_ne_bytes()));
+        first.extend(attr(WGDEVICE_A_FWMARK, &0u32.to_ne_bytes()));
+        let mut peers = peer(3, &[allowed_ip(libc::AF_INET, &[10, 70, 0, 2], 32)]);
+        peers.extend(peer(4, &[allowed_ip(libc::AF_INET, &[10, 70, 0, 3], 32)]));
+        first.extend(attr(WGDEVICE_A_PEERS, &peers));
+
+        let mut second = attr(WGDEVICE_A_IFNAME, b"wg0\0");
+        let v6 = "fd00::3".parse::<Ipv6Addr>().unwrap().octets();
+        second.extend(attr(
+            WGDEVICE_A_PEERS,
+            &peer(4, &[allowed_ip(libc::AF_INET6, &v6, 128)]),
+        ));
+
+        let device = Device::parse(vec![&first as &[u8], &second]);
+        assert_eq!(device.name, "wg0");
+        assert_eq!(device.peers.len(), 2);
+        assert_eq!(device.peers[1].allowed_ips.len(), 2);
+
+        let dump = device.to_dump();
+        assert_eq!(
+            dump,
+            format!(
+                "wg0\t{}\t{}\t51820\toff\nwg0\t{}\t(none)\t(none)\t10.70.0.2/32\t0\t0\t0\toff\nwg0\t{}\t(none)\t(none)\t10.70.0.3/32,fd00::3/128\t0\t0\t0\toff\n",
+                base64(&[1; 32]),
+                base64(&[2; 32]),
+                base64(&[3; 32]),
+                base64(&[4; 32]),
+            )
+        );
+
+        // the dump is read as wg's
+        let wg = WireGuard::try_from(&dump as &str).unwrap();
+        match &wg.interfaces["wg0"][2] {
+            Endpoint::Remote(ep) => assert_eq!(ep.allowed_ips, vec!["10.70.0.3/32", "fd00::3/128"]),
+            Endpoint::Local(_) => panic!("not a peer"),
+        }
+    }
+}
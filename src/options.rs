@@ -1,21 +1,2033 @@
-#[derive(Debug, Clone)]
+use crate::alerts::AlertRules;
+use crate::compat::Compat;
+use crate::config_file::CONFIG_VERSION;
+use crate::exporter_error::OptionsError;
+use crate::firewall::Firewall;
+use crate::logging::LogTarget;
+use crate::outputs;
+use crate::rotating_file::RotationPolicy;
+use crate::server::BindFamily;
+use std::fmt;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+/// A WireGuard config file to read the friendly names from, optionally
+/// bound to an interface, as in `wg0:/etc/wireguard/wg0.conf`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct NamesFile {
+    pub interface: Option<String>,
+    pub path: String,
+}
+
+impl From<&str> for NamesFile {
+    fn from(s: &str) -> Self {
+        // a colon after a slash is part of the path, and so is the one of
+        // a drive letter, as in C:\wg0.conf
+        let drive = |colon: usize| colon == 1 && s[colon + 1..].starts_with('\\');
+        match s.find(':') {
+            Some(colon) if !s[..colon].contains('/') && colon > 0 && !drive(colon) => NamesFile {
+                interface: Some(s[..colon].to_owned()),
+                path: s[colon + 1..].to_owned(),
+            },
+            _ => NamesFile {
+                interface: None,
+                path: s.to_owned(),
+            },
+        }
+    }
+}
+
+impl fmt::Display for NamesFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.interface {
+            Some(interface) => write!(f, "{}:{}", interface, self.path),
+            None => f.write_str(&self.path),
+        }
+    }
+}
+
+/// A label added to all the peer series of an interface, as in
+/// `wg0:env=prod`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct InterfaceLabel {
+    pub interface: String,
+    pub name: String,
+    pub value: String,
+}
+
+impl FromStr for InterfaceLabel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (interface, label) = s
+            .split_once(':')
+            .ok_or("expected <interface>:<name>=<value>")?;
+        let (name, value) = label
+            .split_once('=')
+            .ok_or("expected <interface>:<name>=<value>")?;
+        if interface.is_empty() {
+            return Err("the interface is missing".to_owned());
+        }
+        let valid_name = name
+            .chars()
+            .enumerate()
+            .all(|(i, c)| c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit()));
+        if name.is_empty() || !valid_name || name.starts_with("__") {
+            return Err(format!("{} is not a valid label name", name));
+        }
+        Ok(InterfaceLabel {
+            interface: interface.to_owned(),
+            name: name.to_owned(),
+            value: value.to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for InterfaceLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}={}", self.interface, self.name, self.value)
+    }
+}
+
+/// A remote host whose interfaces are collected besides the local ones,
+/// by running `wg` through a command, as in `gw1=ssh -o BatchMode=yes gw1`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Target {
+    pub host: String,
+    pub command: String,
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, command) = s.split_once('=').ok_or("expected <host>=<command>")?;
+        if !is_host_name(host) {
+            return Err(format!("{} is not a valid host name", host));
+        }
+        if command.trim().is_empty() {
+            return Err("the command is missing".to_owned());
+        }
+        Ok(Target {
+            host: host.to_owned(),
+            command: command.to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.host, self.command)
+    }
+}
+
+/// The service level of the peers of a group of `--group-comment-key`, as
+/// in `acme=300`: the latest handshake of every peer of the group is at
+/// most that many seconds old.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct GroupSla {
+    pub group: String,
+    pub max_handshake_age: Duration,
+}
+
+impl FromStr for GroupSla {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // the group is free text, the age is not
+        let (group, seconds) = s
+            .rsplit_once('=')
+            .ok_or("expected <group>=<max handshake age>")?;
+        if group.is_empty() {
+            return Err("the group is missing".to_owned());
+        }
+        let seconds: u64 = seconds
+            .parse()
+            .map_err(|_| format!("{} is not a number of seconds", seconds))?;
+        if seconds == 0 {
+            return Err("the max handshake age must be greater than 0".to_owned());
+        }
+        Ok(GroupSla {
+            group: group.to_owned(),
+            max_handshake_age: Duration::from_secs(seconds),
+        })
+    }
+}
+
+impl fmt::Display for GroupSla {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.group, self.max_handshake_age.as_secs())
+    }
+}
+
+/// Whether `host` is a host name which can be passed to a command: a
+/// leading dash would make an option of it.
+pub(crate) fn is_host_name(host: &str) -> bool {
+    !host.is_empty()
+        && !host.starts_with('-')
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_')
+}
+
+// the placeholder of the probed host in the command of a probe module
+pub(crate) const PROBE_TARGET: &str = "{target}";
+
+/// How `/probe` reaches the hosts it is asked for, as in
+/// `ssh=ssh -o BatchMode=yes {target}`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ProbeModule {
+    pub name: String,
+    pub command: String,
+}
+
+impl ProbeModule {
+    /// The command running `wg` on `target`.
+    pub fn command(&self, target: &str) -> String {
+        self.command.replace(PROBE_TARGET, target)
+    }
+}
+
+impl FromStr for ProbeModule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, command) = s.split_once('=').ok_or("expected <name>=<command>")?;
+        if !is_host_name(name) {
+            return Err(format!("{} is not a valid module name", name));
+        }
+        if !command.contains(PROBE_TARGET) {
+            return Err(format!("the command must contain {}", PROBE_TARGET));
+        }
+        Ok(ProbeModule {
+            name: name.to_owned(),
+            command: command.to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for ProbeModule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.command)
+    }
+}
+
+/// The most values a label can take in `/metrics`, as in
+/// `public_key=5000`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct LabelBudget {
+    pub name: String,
+    pub max_values: usize,
+}
+
+impl FromStr for LabelBudget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, max_values) = s.split_once('=').ok_or("expected <label>=<count>")?;
+        if name.is_empty() {
+            return Err("the label is missing".to_owned());
+        }
+        match max_values.parse() {
+            Ok(max_values) if max_values > 0 => Ok(LabelBudget {
+                name: name.to_owned(),
+                max_values,
+            }),
+            _ => Err(format!("{} is not a count greater than 0", max_values)),
+        }
+    }
+}
+
+impl fmt::Display for LabelBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.max_values)
+    }
+}
+
+/// Whether a peer completed a handshake within `--handshake-timeout`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum PeerState {
+    Online,
+    Offline,
+}
+
+/// The peers emitted by an output, as in `websocket:interface=wg0,online`:
+/// the peers of any of the interfaces listed (all of them if none is) in
+/// the state given (any if none is).
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct OutputFilter {
+    pub output: String,
+    pub interfaces: Vec<String>,
+    pub state: Option<PeerState>,
+}
+
+impl FromStr for OutputFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (output, terms) = s.split_once(':').ok_or("expected <output>:<filter>")?;
+        if output.is_empty() {
+            return Err("the output is missing".to_owned());
+        }
+        let mut filter = OutputFilter {
+            output: output.to_owned(),
+            interfaces: Vec::new(),
+            state: None,
+        };
+        for term in terms.split(',') {
+            let state = match term {
+                "online" => PeerState::Online,
+                "offline" => PeerState::Offline,
+                _ => match term.strip_prefix("interface=") {
+                    Some(interface) if !interface.is_empty() => {
+                        filter.interfaces.push(interface.to_owned());
+                        continue;
+                    }
+                    _ => {
+                        return Err(format!(
+                            "invalid term {:?}, expected interface=<name>, online or offline",
+                            term
+                        ))
+                    }
+                },
+            };
+            if filter.state.is_some_and(|other| other != state) {
+                return Err("online and offline exclude each other".to_owned());
+            }
+            filter.state = Some(state);
+        }
+        Ok(filter)
+    }
+}
+
+impl fmt::Display for OutputFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut terms: Vec<_> = self
+            .interfaces
+            .iter()
+            .map(|interface| format!("interface={}", interface))
+            .collect();
+        match self.state {
+            Some(PeerState::Online) => terms.push("online".to_owned()),
+            Some(PeerState::Offline) => terms.push("offline".to_owned()),
+            None => (),
+        }
+        write!(f, "{}:{}", self.output, terms.join(","))
+    }
+}
+
+/// How the peers are collected, see `--collector`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum Collector {
+    /// running `wg show all dump`
+    #[default]
+    Wg,
+    /// over the netlink API of the kernel module
+    Netlink,
+    /// over the UAPI sockets of the userspace implementations
+    Uapi,
+}
+
+impl FromStr for Collector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wg" => Ok(Collector::Wg),
+            "netlink" => Ok(Collector::Netlink),
+            "uapi" => Ok(Collector::Uapi),
+            _ => Err(format!(
+                "invalid collector {}, valid values are wg, netlink and uapi",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Collector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Collector::Wg => "wg",
+            Collector::Netlink => "netlink",
+            Collector::Uapi => "uapi",
+        })
+    }
+}
+
+/// Which per peer series are exported, see `--cardinality`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum Cardinality {
+    /// the series aggregated per interface only
+    Low,
+    #[default]
+    Default,
+    /// the opt-in per peer series too
+    Full,
+}
+
+impl FromStr for Cardinality {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "low" => Ok(Cardinality::Low),
+            "default" => Ok(Cardinality::Default),
+            "full" => Ok(Cardinality::Full),
+            _ => Err(format!(
+                "invalid cardinality {}, valid values are low, default and full",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Cardinality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Cardinality::Low => "low",
+            Cardinality::Default => "default",
+            Cardinality::Full => "full",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub(crate) struct Options {
     pub verbose: bool,
-    pub extract_names_config_file: Option<String>,
+    pub port: u16,
+    pub bind_family: BindFamily,
+    pub daemonize: bool,
+    pub pid_file: Option<String>,
+    pub seccomp: bool,
+    pub landlock: bool,
+    pub sandbox: bool,
+    pub read_only: bool,
+    pub extract_names_config_files: Vec<NamesFile>,
+    pub name_comment_key: Option<String>,
+    pub peer_id_comment_key: Option<String>,
+    pub site_comment_key: Option<String>,
+    pub client_comment_key: Option<String>,
+    pub group_comment_key: Option<String>,
+    pub group_slas: Vec<GroupSla>,
+    pub diagnostics_file: Option<String>,
+    pub otlp_endpoint: Option<String>,
+    pub debug_endpoints: bool,
+    pub log_target: LogTarget,
+    pub log_file: Option<String>,
+    pub log_file_rotation: RotationPolicy,
+    pub audit_log: Option<String>,
+    pub audit_log_rotation: RotationPolicy,
+    pub rate_limit: Option<u32>,
+    pub memory_budget: Option<usize>,
+    pub max_response_size: Option<usize>,
+    pub history_size: usize,
+    pub usage_file: Option<String>,
+    pub usage_retention: Duration,
+    pub state_key_file: Option<String>,
+    pub parse_threads: usize,
+    pub worker_threads: Option<usize>,
+    pub blocking_threads: Option<usize>,
+    pub cors_allowed_origins: Vec<String>,
+    pub interface_labels: Vec<InterfaceLabel>,
+    pub sample_interval: Option<Duration>,
+    pub idle_after: Option<Duration>,
+    pub output_filters: Vec<OutputFilter>,
+    pub textfile_output: Option<String>,
+    pub textfile_interval: Duration,
+    pub textfile_max_age: Option<Duration>,
+    pub handshake_timeout: Duration,
+    pub interface_grace_period: Duration,
+    pub collector: Collector,
+    pub wg_path: Option<String>,
+    pub exec_prefix: Option<String>,
+    pub fake_backend: bool,
+    pub dump_file: Option<String>,
+    pub ssh_target: Option<String>,
+    pub ssh_identity: Option<String>,
+    pub targets: Vec<Target>,
+    pub netns: Vec<String>,
+    pub docker_socket: Option<String>,
+    pub probe_modules: Vec<ProbeModule>,
+    pub collect_per_interface: bool,
+    pub min_collect_interval: Option<Duration>,
+    pub unnamed_peers_info: bool,
+    pub tc_stats: bool,
+    pub firewall_counters: Option<Firewall>,
+    pub install_firewall_counters: bool,
+    pub check_routes: bool,
+    pub dns_check_interval: Option<Duration>,
+    pub active_concurrency: usize,
+    pub active_jitter: u8,
+    pub only_configured_peers: bool,
+    pub min_bytes_threshold: u128,
+    pub export_bits: bool,
+    pub alert_rules: AlertRules,
+    pub compat: Compat,
+    pub cardinality: Cardinality,
+    pub label_budgets: Vec<LabelBudget>,
+}
+
+// the smallest --max-response-size, which leaves room for some series
+// besides the counter of the truncations
+pub(crate) const MIN_RESPONSE_SIZE: usize = 4096;
+
+// the limit of the tokio blocking pool
+const MAX_BLOCKING_THREADS: usize = 32768;
+
+// parses the value of the argument `name`, if present. `flag` is the
+// argument as typed by the user, to be reported in the error.
+fn parse<T>(
+    matches: &clap::ArgMatches<'_>,
+    name: &str,
+    flag: &str,
+) -> Result<Option<T>, OptionsError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match matches.value_of(name) {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|e: T::Err| OptionsError::InvalidValue {
+                flag: flag.to_owned(),
+                value: value.to_owned(),
+                reason: e.to_string(),
+            }),
+        None => Ok(None),
+    }
+}
+
+fn parse_secs(
+    matches: &clap::ArgMatches<'_>,
+    name: &str,
+    flag: &str,
+) -> Result<Option<Duration>, OptionsError> {
+    Ok(parse::<u64>(matches, name, flag)?.map(Duration::from_secs))
+}
+
+fn invalid_value<T: fmt::Display>(flag: &str, value: T, reason: &str) -> OptionsError {
+    OptionsError::InvalidValue {
+        flag: flag.to_owned(),
+        value: value.to_string(),
+        reason: reason.to_owned(),
+    }
+}
+
+// reads the <prefix>_max_size, <prefix>_max_age and <prefix>_keep arguments
+fn rotation_policy(
+    matches: &clap::ArgMatches<'_>,
+    prefix: &str,
+) -> Result<RotationPolicy, OptionsError> {
+    let flag = format!("--{}", prefix.replace('_', "-"));
+    Ok(RotationPolicy {
+        max_size: parse(
+            matches,
+            &format!("{}_max_size", prefix),
+            &format!("{}-max-size", flag),
+        )?,
+        max_age: parse_secs(
+            matches,
+            &format!("{}_max_age", prefix),
+            &format!("{}-max-age", flag),
+        )?,
+        keep: parse(
+            matches,
+            &format!("{}_keep", prefix),
+            &format!("{}-keep", flag),
+        )?
+        .unwrap_or_default(),
+    })
+}
+
+// a TOML basic string
+pub(crate) fn toml_string<T: fmt::Display>(value: T) -> String {
+    let mut s = String::from("\"");
+    for c in value.to_string().chars() {
+        match c {
+            '"' => s.push_str("\\\""),
+            '\\' => s.push_str("\\\\"),
+            c if c.is_control() => s.push_str(&format!("\\u{:04X}", c as u32)),
+            c => s.push(c),
+        }
+    }
+    s.push('"');
+    s
+}
+
+pub(crate) fn toml_array<T: fmt::Display>(values: &[T]) -> String {
+    let values: Vec<String> = values.iter().map(toml_string).collect();
+    format!("[{}]", values.join(", "))
+}
+
+// appends `key = value`, unless the value is missing
+fn push_toml(s: &mut String, key: &str, value: Option<String>) {
+    if let Some(value) = value {
+        s.push_str(&format!("{} = {}\n", key, value));
+    }
+}
+
+fn push_toml_rotation(s: &mut String, table: &str, rotation: &RotationPolicy) {
+    s.push_str(&format!("\n[{}]\n", table));
+    push_toml(s, "max_size", rotation.max_size.map(|e| e.to_string()));
+    push_toml(
+        s,
+        "max_age",
+        rotation.max_age.map(|e| e.as_secs().to_string()),
+    );
+    push_toml(s, "keep", Some(rotation.keep.to_string()));
 }
 
 impl Options {
-    pub fn from_claps(matches: &clap::ArgMatches<'_>) -> Options {
-        if let Some(e) = matches.value_of("extract_names_config_file") {
-            Options {
-                verbose: matches.is_present("verbose"),
-                extract_names_config_file: Some(e.to_owned()),
+    /// Builds the options from the command line arguments, reporting the
+    /// first invalid or inconsistent argument found.
+    pub fn from_claps(matches: &clap::ArgMatches<'_>) -> Result<Options, OptionsError> {
+        let cardinality = parse(matches, "cardinality", "--cardinality")?.unwrap_or_default();
+        let options = Options {
+            verbose: matches.is_present("verbose"),
+            port: parse(matches, "port", "-p")?.unwrap_or_default(),
+            bind_family: parse(matches, "bind_family", "--bind-family")?.unwrap_or_default(),
+            daemonize: matches.is_present("daemonize"),
+            pid_file: matches.value_of("pid_file").map(|e| e.to_owned()),
+            // --sandbox implies both
+            seccomp: matches.is_present("seccomp") || matches.is_present("sandbox"),
+            landlock: matches.is_present("landlock") || matches.is_present("sandbox"),
+            sandbox: matches.is_present("sandbox"),
+            read_only: matches.is_present("read_only"),
+            extract_names_config_files: matches
+                .values_of("extract_names_config_file")
+                .map(|files| files.map(NamesFile::from).collect())
+                .unwrap_or_default(),
+            name_comment_key: matches.value_of("name_comment_key").map(|e| e.to_owned()),
+            peer_id_comment_key: matches
+                .value_of("peer_id_comment_key")
+                .map(|e| e.to_owned()),
+            site_comment_key: matches.value_of("site_comment_key").map(|e| e.to_owned()),
+            client_comment_key: matches.value_of("client_comment_key").map(|e| e.to_owned()),
+            group_comment_key: matches.value_of("group_comment_key").map(|e| e.to_owned()),
+            group_slas: matches
+                .values_of("group_sla")
+                .map(|slas| {
+                    slas.map(|sla| {
+                        sla.parse().map_err(|reason| OptionsError::InvalidValue {
+                            flag: "--group-sla".to_owned(),
+                            value: sla.to_owned(),
+                            reason,
+                        })
+                    })
+                    .collect::<Result<_, _>>()
+                })
+                .transpose()?
+                .unwrap_or_default(),
+            min_bytes_threshold: parse(matches, "min_bytes_threshold", "--min-bytes-threshold")?
+                .unwrap_or_default(),
+            alert_rules: AlertRules {
+                handshake_max_age: parse_secs(
+                    matches,
+                    "alert_handshake_max_age",
+                    "--alert-handshake-max-age",
+                )?,
+                min_peers: parse(matches, "alert_min_peers", "--alert-min-peers")?,
+                max_peers: parse(matches, "alert_max_peers", "--alert-max-peers")?,
+            },
+            compat: parse(matches, "compat", "--compat")?.unwrap_or_default(),
+            cardinality,
+            // --cardinality full implies the opt-in per peer series
+            export_bits: matches.is_present("export_bits") || cardinality == Cardinality::Full,
+            only_configured_peers: matches.is_present("only_configured_peers"),
+            unnamed_peers_info: matches.is_present("unnamed_peers_info")
+                || cardinality == Cardinality::Full,
+            tc_stats: matches.is_present("tc_stats"),
+            firewall_counters: parse(matches, "firewall_counters", "--firewall-counters")?,
+            install_firewall_counters: matches.is_present("install_firewall_counters"),
+            check_routes: matches.is_present("check_routes"),
+            dns_check_interval: parse_secs(matches, "dns_check_interval", "--dns-check-interval")?,
+            active_concurrency: parse(matches, "active_concurrency", "--active-concurrency")?
+                .unwrap_or_default(),
+            active_jitter: parse(matches, "active_jitter", "--active-jitter")?.unwrap_or_default(),
+            diagnostics_file: matches.value_of("diagnostics_file").map(|e| e.to_owned()),
+            otlp_endpoint: matches.value_of("otlp_endpoint").map(|e| e.to_owned()),
+            debug_endpoints: matches.is_present("debug_endpoints"),
+            log_target: parse(matches, "log_target", "--log-target")?.unwrap_or_default(),
+            log_file: matches.value_of("log_file").map(|e| e.to_owned()),
+            log_file_rotation: rotation_policy(matches, "log_file")?,
+            audit_log: matches.value_of("audit_log").map(|e| e.to_owned()),
+            audit_log_rotation: rotation_policy(matches, "audit_log")?,
+            rate_limit: parse(matches, "rate_limit", "--rate-limit")?,
+            memory_budget: parse(matches, "memory_budget", "--memory-budget")?,
+            max_response_size: parse(matches, "max_response_size", "--max-response-size")?,
+            history_size: parse(matches, "history_size", "--history-size")?.unwrap_or_default(),
+            usage_file: matches.value_of("usage_file").map(|e| e.to_owned()),
+            state_key_file: matches.value_of("state_key_file").map(|e| e.to_owned()),
+            usage_retention: parse::<u64>(matches, "usage_retention", "--usage-retention")?
+                .map(|days| Duration::from_secs(days * 86400))
+                .unwrap_or_default(),
+            worker_threads: parse(matches, "worker_threads", "--worker-threads")?,
+            blocking_threads: parse(matches, "blocking_threads", "--blocking-threads")?,
+            parse_threads: match parse(matches, "parse_threads", "--parse-threads")? {
+                Some(parse_threads) => parse_threads,
+                None => thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(1),
+            },
+            cors_allowed_origins: matches
+                .values_of("cors_allowed_origin")
+                .map(|origins| origins.map(|origin| origin.to_owned()).collect())
+                .unwrap_or_default(),
+            interface_labels: matches
+                .values_of("interface_label")
+                .map(|labels| {
+                    labels
+                        .map(|label| {
+                            label.parse().map_err(|reason| OptionsError::InvalidValue {
+                                flag: "--interface-label".to_owned(),
+                                value: label.to_owned(),
+                                reason,
+                            })
+                        })
+                        .collect::<Result<_, _>>()
+                })
+                .transpose()?
+                .unwrap_or_default(),
+            label_budgets: matches
+                .values_of("max_label_values")
+                .map(|budgets| {
+                    budgets
+                        .map(|budget| {
+                            budget.parse().map_err(|reason| OptionsError::InvalidValue {
+                                flag: "--max-label-values".to_owned(),
+                                value: budget.to_owned(),
+                                reason,
+                            })
+                        })
+                        .collect::<Result<_, _>>()
+                })
+                .transpose()?
+                .unwrap_or_default(),
+            output_filters: matches
+                .values_of("output_filter")
+                .map(|filters| {
+                    filters
+                        .map(|filter| {
+                            filter.parse().map_err(|reason| OptionsError::InvalidValue {
+                                flag: "--output-filter".to_owned(),
+                                value: filter.to_owned(),
+                                reason,
+                            })
+                        })
+                        .collect::<Result<_, _>>()
+                })
+                .transpose()?
+                .unwrap_or_default(),
+            sample_interval: parse_secs(matches, "sample_interval", "--sample-interval")?,
+            idle_after: parse_secs(matches, "idle_after", "--idle-after")?,
+            textfile_output: matches.value_of("textfile_output").map(|e| e.to_owned()),
+            textfile_interval: parse_secs(matches, "textfile_interval", "--textfile-interval")?
+                .unwrap_or_default(),
+            textfile_max_age: parse_secs(matches, "textfile_max_age", "--textfile-max-age")?,
+            collector: parse(matches, "collector", "--collector")?.unwrap_or_default(),
+            wg_path: matches.value_of("wg_path").map(|e| e.to_owned()),
+            exec_prefix: matches.value_of("exec_prefix").map(|e| e.to_owned()),
+            fake_backend: matches.is_present("with_fake_backend"),
+            dump_file: matches.value_of("dump_file").map(|e| e.to_owned()),
+            ssh_target: matches.value_of("ssh_target").map(|e| e.to_owned()),
+            ssh_identity: matches.value_of("ssh_identity").map(|e| e.to_owned()),
+            targets: matches
+                .values_of("target")
+                .map(|targets| {
+                    targets
+                        .map(|target| {
+                            target.parse().map_err(|reason| OptionsError::InvalidValue {
+                                flag: "--target".to_owned(),
+                                value: target.to_owned(),
+                                reason,
+                            })
+                        })
+                        .collect::<Result<_, _>>()
+                })
+                .transpose()?
+                .unwrap_or_default(),
+            netns: matches
+                .values_of("netns")
+                .map(|netns| netns.map(|e| e.to_owned()).collect())
+                .unwrap_or_default(),
+            docker_socket: matches.value_of("docker_socket").map(|e| e.to_owned()),
+            probe_modules: matches
+                .values_of("probe_module")
+                .map(|modules| {
+                    modules
+                        .map(|module| {
+                            module.parse().map_err(|reason| OptionsError::InvalidValue {
+                                flag: "--probe-module".to_owned(),
+                                value: module.to_owned(),
+                                reason,
+                            })
+                        })
+                        .collect::<Result<_, _>>()
+                })
+                .transpose()?
+                .unwrap_or_default(),
+            collect_per_interface: matches.is_present("collect_per_interface"),
+            min_collect_interval: parse_secs(
+                matches,
+                "min_collect_interval",
+                "--min-collect-interval",
+            )?,
+            handshake_timeout: parse_secs(matches, "handshake_timeout", "--handshake-timeout")?
+                .unwrap_or_default(),
+            interface_grace_period: parse_secs(
+                matches,
+                "interface_grace_period",
+                "--interface-grace-period",
+            )?
+            .unwrap_or_default(),
+        };
+
+        options.validate()?;
+        Ok(options)
+    }
+
+    /// Renders the options, defaults included, as a TOML document usable
+    /// as `--config-file`. The durations are in seconds, the unset options
+    /// are left out.
+    pub fn to_toml(&self) -> String {
+        let mut s = String::new();
+        push_toml(&mut s, "version", Some(CONFIG_VERSION.to_string()));
+        push_toml(&mut s, "verbose", Some(self.verbose.to_string()));
+        push_toml(&mut s, "port", Some(self.port.to_string()));
+        push_toml(&mut s, "daemonize", Some(self.daemonize.to_string()));
+        push_toml(&mut s, "pid_file", self.pid_file.as_ref().map(toml_string));
+        push_toml(
+            &mut s,
+            "extract_names_config_files",
+            Some(toml_array(&self.extract_names_config_files)),
+        );
+        push_toml(
+            &mut s,
+            "name_comment_key",
+            self.name_comment_key.as_ref().map(toml_string),
+        );
+        push_toml(
+            &mut s,
+            "peer_id_comment_key",
+            self.peer_id_comment_key.as_ref().map(toml_string),
+        );
+        push_toml(
+            &mut s,
+            "site_comment_key",
+            self.site_comment_key.as_ref().map(toml_string),
+        );
+        push_toml(
+            &mut s,
+            "client_comment_key",
+            self.client_comment_key.as_ref().map(toml_string),
+        );
+        push_toml(
+            &mut s,
+            "group_comment_key",
+            self.group_comment_key.as_ref().map(toml_string),
+        );
+        push_toml(&mut s, "group_sla", Some(toml_array(&self.group_slas)));
+        push_toml(
+            &mut s,
+            "only_configured_peers",
+            Some(self.only_configured_peers.to_string()),
+        );
+        push_toml(
+            &mut s,
+            "unnamed_peers_info",
+            Some(self.unnamed_peers_info.to_string()),
+        );
+        push_toml(&mut s, "tc_stats", Some(self.tc_stats.to_string()));
+        push_toml(
+            &mut s,
+            "firewall_counters",
+            self.firewall_counters.map(toml_string),
+        );
+        push_toml(
+            &mut s,
+            "install_firewall_counters",
+            Some(self.install_firewall_counters.to_string()),
+        );
+        push_toml(&mut s, "check_routes", Some(self.check_routes.to_string()));
+        push_toml(
+            &mut s,
+            "dns_check_interval",
+            self.dns_check_interval.map(|e| e.as_secs().to_string()),
+        );
+        push_toml(
+            &mut s,
+            "active_concurrency",
+            Some(self.active_concurrency.to_string()),
+        );
+        push_toml(
+            &mut s,
+            "active_jitter",
+            Some(self.active_jitter.to_string()),
+        );
+        push_toml(
+            &mut s,
+            "min_bytes_threshold",
+            Some(self.min_bytes_threshold.to_string()),
+        );
+        push_toml(&mut s, "export_bits", Some(self.export_bits.to_string()));
+        push_toml(&mut s, "bind_family", Some(toml_string(self.bind_family)));
+        push_toml(&mut s, "seccomp", Some(self.seccomp.to_string()));
+        push_toml(&mut s, "landlock", Some(self.landlock.to_string()));
+        push_toml(&mut s, "sandbox", Some(self.sandbox.to_string()));
+        push_toml(&mut s, "read_only", Some(self.read_only.to_string()));
+        push_toml(&mut s, "compat", Some(toml_string(self.compat)));
+        push_toml(&mut s, "cardinality", Some(toml_string(self.cardinality)));
+        push_toml(
+            &mut s,
+            "max_label_values",
+            Some(toml_array(&self.label_budgets)),
+        );
+        push_toml(
+            &mut s,
+            "handshake_timeout",
+            Some(self.handshake_timeout.as_secs().to_string()),
+        );
+        push_toml(
+            &mut s,
+            "interface_grace_period",
+            Some(self.interface_grace_period.as_secs().to_string()),
+        );
+        push_toml(&mut s, "collector", Some(toml_string(self.collector)));
+        push_toml(&mut s, "wg_path", self.wg_path.as_ref().map(toml_string));
+        push_toml(
+            &mut s,
+            "exec_prefix",
+            self.exec_prefix.as_ref().map(toml_string),
+        );
+        push_toml(&mut s, "target", Some(toml_array(&self.targets)));
+        push_toml(&mut s, "netns", Some(toml_array(&self.netns)));
+        push_toml(
+            &mut s,
+            "with_fake_backend",
+            Some(self.fake_backend.to_string()),
+        );
+        push_toml(
+            &mut s,
+            "dump_file",
+            self.dump_file.as_ref().map(toml_string),
+        );
+        push_toml(
+            &mut s,
+            "ssh_target",
+            self.ssh_target.as_ref().map(toml_string),
+        );
+        push_toml(
+            &mut s,
+            "ssh_identity",
+            self.ssh_identity.as_ref().map(toml_string),
+        );
+        push_toml(
+            &mut s,
+            "docker_socket",
+            self.docker_socket.as_ref().map(toml_string),
+        );
+        push_toml(
+            &mut s,
+            "probe_module",
+            Some(toml_array(&self.probe_modules)),
+        );
+        push_toml(
+            &mut s,
+            "collect_per_interface",
+            Some(self.collect_per_interface.to_string()),
+        );
+        push_toml(
+            &mut s,
+            "min_collect_interval",
+            self.min_collect_interval.map(|e| e.as_secs().to_string()),
+        );
+        push_toml(
+            &mut s,
+            "diagnostics_file",
+            self.diagnostics_file.as_ref().map(toml_string),
+        );
+        push_toml(
+            &mut s,
+            "otlp_endpoint",
+            self.otlp_endpoint.as_ref().map(toml_string),
+        );
+        push_toml(
+            &mut s,
+            "debug_endpoints",
+            Some(self.debug_endpoints.to_string()),
+        );
+        push_toml(&mut s, "log_target", Some(toml_string(self.log_target)));
+        push_toml(&mut s, "log_file", self.log_file.as_ref().map(toml_string));
+        push_toml(
+            &mut s,
+            "audit_log",
+            self.audit_log.as_ref().map(toml_string),
+        );
+        push_toml(&mut s, "rate_limit", self.rate_limit.map(|e| e.to_string()));
+        push_toml(
+            &mut s,
+            "worker_threads",
+            self.worker_threads.map(|e| e.to_string()),
+        );
+        push_toml(
+            &mut s,
+            "blocking_threads",
+            self.blocking_threads.map(|e| e.to_string()),
+        );
+        push_toml(
+            &mut s,
+            "parse_threads",
+            Some(self.parse_threads.to_string()),
+        );
+        push_toml(
+            &mut s,
+            "memory_budget",
+            self.memory_budget.map(|e| e.to_string()),
+        );
+        push_toml(
+            &mut s,
+            "max_response_size",
+            self.max_response_size.map(|e| e.to_string()),
+        );
+        push_toml(&mut s, "history_size", Some(self.history_size.to_string()));
+        push_toml(
+            &mut s,
+            "usage_file",
+            self.usage_file.as_ref().map(toml_string),
+        );
+        push_toml(
+            &mut s,
+            "usage_retention_days",
+            Some((self.usage_retention.as_secs() / 86400).to_string()),
+        );
+        push_toml(
+            &mut s,
+            "state_key_file",
+            self.state_key_file.as_ref().map(toml_string),
+        );
+        push_toml(
+            &mut s,
+            "cors_allowed_origins",
+            Some(toml_array(&self.cors_allowed_origins)),
+        );
+        push_toml(
+            &mut s,
+            "interface_labels",
+            Some(toml_array(&self.interface_labels)),
+        );
+        push_toml(
+            &mut s,
+            "idle_after",
+            self.idle_after.map(|e| e.as_secs().to_string()),
+        );
+        push_toml(
+            &mut s,
+            "output_filter",
+            Some(toml_array(&self.output_filters)),
+        );
+        push_toml(
+            &mut s,
+            "textfile_output",
+            self.textfile_output.as_ref().map(toml_string),
+        );
+        push_toml(
+            &mut s,
+            "textfile_interval",
+            Some(self.textfile_interval.as_secs().to_string()),
+        );
+        push_toml(
+            &mut s,
+            "textfile_max_age",
+            self.textfile_max_age.map(|e| e.as_secs().to_string()),
+        );
+        push_toml(
+            &mut s,
+            "sample_interval",
+            self.sample_interval.map(|e| e.as_secs().to_string()),
+        );
+
+        s.push_str("\n[alert_rules]\n");
+        push_toml(
+            &mut s,
+            "handshake_max_age",
+            self.alert_rules
+                .handshake_max_age
+                .map(|e| e.as_secs().to_string()),
+        );
+        push_toml(
+            &mut s,
+            "min_peers",
+            self.alert_rules.min_peers.map(|e| e.to_string()),
+        );
+        push_toml(
+            &mut s,
+            "max_peers",
+            self.alert_rules.max_peers.map(|e| e.to_string()),
+        );
+
+        push_toml_rotation(&mut s, "log_file_rotation", &self.log_file_rotation);
+        push_toml_rotation(&mut s, "audit_log_rotation", &self.audit_log_rotation);
+        s
+    }
+
+    /// Checks the constraints the argument parser cannot express.
+    pub fn validate(&self) -> Result<(), OptionsError> {
+        if self.handshake_timeout == Duration::from_secs(0) {
+            return Err(invalid_value(
+                "--handshake-timeout",
+                0,
+                "must be greater than 0",
+            ));
+        }
+        if self.min_collect_interval == Some(Duration::from_secs(0)) {
+            return Err(invalid_value(
+                "--min-collect-interval",
+                0,
+                "must be greater than 0",
+            ));
+        }
+        if self.idle_after == Some(Duration::from_secs(0)) {
+            return Err(invalid_value("--idle-after", 0, "must be greater than 0"));
+        }
+        if self.sample_interval == Some(Duration::from_secs(0)) {
+            return Err(invalid_value(
+                "--sample-interval",
+                0,
+                "must be greater than 0",
+            ));
+        }
+        if let Some(textfile_output) = &self.textfile_output {
+            // the collector of node_exporter only reads these
+            if !textfile_output.ends_with(".prom") {
+                return Err(invalid_value(
+                    "--textfile-output",
+                    textfile_output,
+                    "must end with .prom",
+                ));
+            }
+            if self.textfile_interval == Duration::from_secs(0) {
+                return Err(invalid_value(
+                    "--textfile-interval",
+                    0,
+                    "must be greater than 0",
+                ));
+            }
+            if let Some(max_age) = self.textfile_max_age {
+                if max_age < self.textfile_interval {
+                    return Err(invalid_value(
+                        "--textfile-max-age",
+                        max_age.as_secs(),
+                        "must not be shorter than --textfile-interval",
+                    ));
+                }
+            }
+        }
+        for (i, sla) in self.group_slas.iter().enumerate() {
+            if self.group_slas[..i]
+                .iter()
+                .any(|other| other.group == sla.group)
+            {
+                return Err(invalid_value(
+                    "--group-sla",
+                    sla,
+                    "the group already has a service level",
+                ));
+            }
+        }
+        for (i, filter) in self.output_filters.iter().enumerate() {
+            if !outputs::NAMES.contains(&filter.output.as_str()) {
+                return Err(invalid_value(
+                    "--output-filter",
+                    filter,
+                    &format!(
+                        "unknown output, valid outputs are {}",
+                        outputs::NAMES.join(", ")
+                    ),
+                ));
+            }
+            if self.output_filters[..i]
+                .iter()
+                .any(|other| other.output == filter.output)
+            {
+                return Err(invalid_value(
+                    "--output-filter",
+                    filter,
+                    "the output is already filtered",
+                ));
+            }
+        }
+        if self.rate_limit == Some(0) {
+            return Err(invalid_value("--rate-limit", 0, "must be greater than 0"));
+        }
+        if self.worker_threads == Some(0) {
+            return Err(invalid_value(
+                "--worker-threads",
+                0,
+                "must be greater than 0",
+            ));
+        }
+        if let Some(blocking_threads) = self.blocking_threads {
+            if blocking_threads == 0 || blocking_threads > MAX_BLOCKING_THREADS {
+                return Err(invalid_value(
+                    "--blocking-threads",
+                    blocking_threads,
+                    "must be between 1 and 32768",
+                ));
+            }
+        }
+        if self.parse_threads == 0 {
+            return Err(invalid_value(
+                "--parse-threads",
+                0,
+                "must be greater than 0",
+            ));
+        }
+        if self.memory_budget == Some(0) {
+            return Err(invalid_value(
+                "--memory-budget",
+                0,
+                "must be greater than 0",
+            ));
+        }
+        if let Some(max_response_size) = self.max_response_size {
+            if max_response_size < MIN_RESPONSE_SIZE {
+                return Err(invalid_value(
+                    "--max-response-size",
+                    max_response_size,
+                    &format!("must be at least {}", MIN_RESPONSE_SIZE),
+                ));
+            }
+        }
+        if self
+            .name_comment_key
+            .as_ref()
+            .is_some_and(|key| key.trim().is_empty())
+        {
+            return Err(invalid_value("--name-comment-key", "", "must not be empty"));
+        }
+        for names_file in self.extract_names_config_files.iter() {
+            if names_file.path.is_empty() {
+                return Err(invalid_value(
+                    "-n",
+                    names_file.interface.as_ref().map_or("", |s| s as &str),
+                    "the path of the config file is missing",
+                ));
+            }
+        }
+        if self.active_jitter > 100 {
+            return Err(invalid_value(
+                "--active-jitter",
+                self.active_jitter,
+                "must be a percentage, between 0 and 100",
+            ));
+        }
+        for origin in self.cors_allowed_origins.iter() {
+            if origin != "*" && !origin.starts_with("http://") && !origin.starts_with("https://") {
+                return Err(invalid_value(
+                    "--cors-allowed-origin",
+                    origin,
+                    "must be * or an origin such as https://dashboard.example.com",
+                ));
+            }
+        }
+        if let (Some(min_peers), Some(max_peers)) =
+            (self.alert_rules.min_peers, self.alert_rules.max_peers)
+        {
+            if min_peers > max_peers {
+                return Err(OptionsError::Conflict {
+                    flag: "--alert-min-peers".to_owned(),
+                    other_flag: "--alert-max-peers".to_owned(),
+                    reason: format!(
+                        "the min peers ({}) cannot exceed the max peers ({})",
+                        min_peers, max_peers
+                    ),
+                });
+            }
+        }
+        if self.wg_path.as_ref().is_some_and(|path| path.is_empty()) {
+            return Err(invalid_value("--wg-path", "", "must not be empty"));
+        }
+        if let Some(exec_prefix) = &self.exec_prefix {
+            if exec_prefix.trim().is_empty() {
+                return Err(invalid_value(
+                    "--exec-prefix",
+                    exec_prefix,
+                    "must not be empty",
+                ));
+            }
+            // all set no_new_privs, which sudo and doas cannot escalate past
+            let sandboxing = if self.seccomp {
+                Some("--seccomp")
+            } else if self.landlock {
+                Some("--landlock")
+            } else if self.read_only {
+                Some("--read-only")
+            } else {
+                None
+            };
+            if let Some(sandboxing) = sandboxing {
+                return Err(OptionsError::Conflict {
+                    flag: "--exec-prefix".to_owned(),
+                    other_flag: sandboxing.to_owned(),
+                    reason: "the sandbox forbids the privilege escalation of the wrapper"
+                        .to_owned(),
+                });
+            }
+        }
+        if self.fake_backend && self.collector != Collector::Wg {
+            return Err(OptionsError::Conflict {
+                flag: "--with-fake-backend".to_owned(),
+                other_flag: "--collector".to_owned(),
+                reason: "the fake backend replaces the collector".to_owned(),
+            });
+        }
+        if let Some(dump_file) = &self.dump_file {
+            if dump_file.is_empty() {
+                return Err(invalid_value("--dump-file", "", "must not be empty"));
+            }
+            let other_flag = if self.fake_backend {
+                Some("--with-fake-backend")
+            } else if self.collector != Collector::Wg {
+                Some("--collector")
+            } else {
+                None
+            };
+            if let Some(other_flag) = other_flag {
+                return Err(OptionsError::Conflict {
+                    flag: "--dump-file".to_owned(),
+                    other_flag: other_flag.to_owned(),
+                    reason: "the dump file replaces the collector".to_owned(),
+                });
+            }
+        }
+        if let Some(ssh_target) = &self.ssh_target {
+            // it would be taken for an option of ssh
+            if ssh_target.is_empty() || ssh_target.starts_with('-') {
+                return Err(invalid_value(
+                    "--ssh-target",
+                    ssh_target,
+                    "is not a valid SSH destination",
+                ));
             }
+            let conflict = if self.collector != Collector::Wg {
+                Some(("--collector", "the remote host is collected with wg"))
+            } else if self.fake_backend {
+                Some((
+                    "--with-fake-backend",
+                    "the fake backend replaces the collector",
+                ))
+            } else if self.dump_file.is_some() {
+                Some(("--dump-file", "the dump file replaces the collector"))
+            } else if self.landlock {
+                Some((
+                    "--landlock",
+                    "ssh reads its keys and config outside the paths the sandbox allows",
+                ))
+            } else {
+                None
+            };
+            if let Some((other_flag, reason)) = conflict {
+                return Err(OptionsError::Conflict {
+                    flag: "--ssh-target".to_owned(),
+                    other_flag: other_flag.to_owned(),
+                    reason: reason.to_owned(),
+                });
+            }
+        }
+        if let Some(ssh_identity) = &self.ssh_identity {
+            if self.ssh_target.is_none() {
+                return Err(invalid_value(
+                    "--ssh-identity",
+                    ssh_identity,
+                    "requires --ssh-target",
+                ));
+            }
+            if ssh_identity.is_empty() {
+                return Err(invalid_value("--ssh-identity", "", "must not be empty"));
+            }
+        }
+        for (i, target) in self.targets.iter().enumerate() {
+            if self.targets[..i]
+                .iter()
+                .any(|other| other.host == target.host)
+            {
+                return Err(invalid_value(
+                    "--target",
+                    target,
+                    "the host is already a target",
+                ));
+            }
+        }
+        if self.install_firewall_counters {
+            if self.firewall_counters != Some(Firewall::Nftables) {
+                return Err(invalid_value(
+                    "--install-firewall-counters",
+                    "true",
+                    "requires --firewall-counters nftables",
+                ));
+            }
+            if self.ssh_target.is_some() {
+                return Err(OptionsError::Conflict {
+                    flag: "--install-firewall-counters".to_owned(),
+                    other_flag: "--ssh-target".to_owned(),
+                    reason: "the counters are installed on the local host".to_owned(),
+                });
+            }
+            if self.read_only {
+                return Err(OptionsError::Conflict {
+                    flag: "--install-firewall-counters".to_owned(),
+                    other_flag: "--read-only".to_owned(),
+                    reason: "installing the counters changes the nftables ruleset".to_owned(),
+                });
+            }
+        }
+        if !self.targets.is_empty() && self.ssh_target.is_some() {
+            return Err(OptionsError::Conflict {
+                flag: "--target".to_owned(),
+                other_flag: "--ssh-target".to_owned(),
+                reason: "the interfaces of the SSH target would get the local host label; give it as a target"
+                    .to_owned(),
+            });
+        }
+        for (i, netns) in self.netns.iter().enumerate() {
+            if !is_host_name(netns) {
+                return Err(invalid_value(
+                    "--netns",
+                    netns,
+                    "is not a valid namespace name",
+                ));
+            }
+            if self.netns[..i].contains(netns) {
+                return Err(invalid_value(
+                    "--netns",
+                    netns,
+                    "the namespace is already collected",
+                ));
+            }
+        }
+        if self.docker_socket.as_deref() == Some("") {
+            return Err(invalid_value("--docker-socket", "", "is empty"));
+        }
+        let namespaces_flag = if !self.netns.is_empty() {
+            Some("--netns")
+        } else if self.docker_socket.is_some() {
+            Some("--docker-socket")
         } else {
-            Options {
-                verbose: matches.is_present("verbose"),
-                extract_names_config_file: None,
+            None
+        };
+        if let Some(flag) = namespaces_flag {
+            // ip netns exec and nsenter enter the namespace with setns
+            let other_flag = if self.collector == Collector::Netlink {
+                Some("--collector")
+            } else if self.ssh_target.is_some() {
+                Some("--ssh-target")
+            } else if self.seccomp {
+                Some("--seccomp")
+            } else if self.landlock {
+                Some("--landlock")
+            } else {
+                None
+            };
+            if let Some(other_flag) = other_flag {
+                return Err(OptionsError::Conflict {
+                    flag: flag.to_owned(),
+                    other_flag: other_flag.to_owned(),
+                    reason:
+                        "the namespaces are entered by running wg through ip netns exec or nsenter"
+                            .to_owned(),
+                });
+            }
+        }
+        for (i, module) in self.probe_modules.iter().enumerate() {
+            if self.probe_modules[..i]
+                .iter()
+                .any(|other| other.name == module.name)
+            {
+                return Err(invalid_value(
+                    "--probe-module",
+                    module,
+                    "the module is already defined",
+                ));
             }
         }
+        if !self.targets.is_empty() && self.landlock {
+            // ssh and the like read their keys and known hosts in $HOME
+            return Err(OptionsError::Conflict {
+                flag: "--target".to_owned(),
+                other_flag: "--landlock".to_owned(),
+                reason: "the sandbox forbids the commands to read their configuration".to_owned(),
+            });
+        }
+        if !self.probe_modules.is_empty() && self.landlock {
+            return Err(OptionsError::Conflict {
+                flag: "--probe-module".to_owned(),
+                other_flag: if self.sandbox {
+                    "--sandbox"
+                } else {
+                    "--landlock"
+                }
+                .to_owned(),
+                reason: "the sandbox forbids the commands to read their configuration".to_owned(),
+            });
+        }
+        if let Some(otlp_endpoint) = &self.otlp_endpoint {
+            if cfg!(not(feature = "otlp")) {
+                return Err(invalid_value(
+                    "--otlp-endpoint",
+                    otlp_endpoint,
+                    "the exporter is built without the otlp feature",
+                ));
+            }
+            let uri = otlp_endpoint.parse::<http::Uri>().ok();
+            if uri.as_ref().and_then(|uri| uri.scheme_str()) != Some("http")
+                || uri.as_ref().and_then(|uri| uri.host()).is_none()
+            {
+                return Err(invalid_value(
+                    "--otlp-endpoint",
+                    otlp_endpoint,
+                    "is not an http:// URL",
+                ));
+            }
+            if self.sandbox {
+                return Err(OptionsError::Conflict {
+                    flag: "--otlp-endpoint".to_owned(),
+                    other_flag: "--sandbox".to_owned(),
+                    reason: "the sandbox forbids connecting to the collector".to_owned(),
+                });
+            }
+        }
+        if self.log_file.is_some() && self.log_file == self.audit_log {
+            return Err(OptionsError::Conflict {
+                flag: "--log-file".to_owned(),
+                other_flag: "--audit-log".to_owned(),
+                reason: "the log and the audit log must be different files".to_owned(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn valid() -> Options {
+        Options {
+            handshake_timeout: Duration::from_secs(180),
+            parse_threads: 1,
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(valid().validate(), Ok(()));
+
+        let options = Options {
+            sample_interval: Some(Duration::from_secs(0)),
+            ..valid()
+        };
+        assert_eq!(
+            options.validate().unwrap_err().to_string(),
+            "invalid value \"0\" for --sample-interval: must be greater than 0"
+        );
+
+        let options = Options {
+            idle_after: Some(Duration::from_secs(0)),
+            ..valid()
+        };
+        assert_eq!(
+            options.validate().unwrap_err().to_string(),
+            "invalid value \"0\" for --idle-after: must be greater than 0"
+        );
+
+        let options = Options {
+            min_collect_interval: Some(Duration::from_secs(0)),
+            ..valid()
+        };
+        assert_eq!(
+            options.validate().unwrap_err().to_string(),
+            "invalid value \"0\" for --min-collect-interval: must be greater than 0"
+        );
+
+        let options = Options {
+            blocking_threads: Some(40000),
+            ..valid()
+        };
+        assert_eq!(
+            options.validate().unwrap_err().to_string(),
+            "invalid value \"40000\" for --blocking-threads: must be between 1 and 32768"
+        );
+
+        let options = Options {
+            exec_prefix: Some("sudo -n".to_owned()),
+            seccomp: true,
+            landlock: true,
+            ..valid()
+        };
+        assert_eq!(
+            options.validate().unwrap_err().to_string(),
+            "--exec-prefix conflicts with --seccomp: the sandbox forbids the privilege escalation of the wrapper"
+        );
+
+        let options = Options {
+            output_filters: vec!["mqtt:online".parse().unwrap()],
+            ..valid()
+        };
+        assert_eq!(
+            options.validate().unwrap_err().to_string(),
+            "invalid value \"mqtt:online\" for --output-filter: unknown output, valid outputs are websocket, textfile"
+        );
+
+        let options = Options {
+            textfile_output: Some("/var/lib/node_exporter/wireguard.prom".to_owned()),
+            textfile_interval: Duration::from_secs(60),
+            textfile_max_age: Some(Duration::from_secs(30)),
+            ..valid()
+        };
+        assert_eq!(
+            options.validate().unwrap_err().to_string(),
+            "invalid value \"30\" for --textfile-max-age: must not be shorter than --textfile-interval"
+        );
+
+        let options = Options {
+            cors_allowed_origins: vec!["dashboard.example.com".to_owned()],
+            ..valid()
+        };
+        assert!(options.validate().is_err());
+
+        let options = Options {
+            alert_rules: AlertRules {
+                min_peers: Some(5),
+                max_peers: Some(2),
+                ..AlertRules::default()
+            },
+            ..valid()
+        };
+        assert_eq!(
+            options.validate().unwrap_err().to_string(),
+            "--alert-min-peers conflicts with --alert-max-peers: the min peers (5) cannot exceed the max peers (2)"
+        );
+    }
+
+    #[test]
+    fn test_fake_backend() {
+        let options = Options {
+            fake_backend: true,
+            ..valid()
+        };
+        assert_eq!(options.validate(), Ok(()));
+
+        let options = Options {
+            fake_backend: true,
+            collector: Collector::Netlink,
+            ..valid()
+        };
+        assert_eq!(
+            options.validate().unwrap_err().to_string(),
+            "--with-fake-backend conflicts with --collector: the fake backend replaces the collector"
+        );
+    }
+
+    #[test]
+    fn test_dump_file() {
+        let options = Options {
+            dump_file: Some("-".to_owned()),
+            ..valid()
+        };
+        assert_eq!(options.validate(), Ok(()));
+
+        let options = Options {
+            dump_file: Some("/var/lib/wg/dump.txt".to_owned()),
+            fake_backend: true,
+            ..valid()
+        };
+        assert_eq!(
+            options.validate().unwrap_err().to_string(),
+            "--dump-file conflicts with --with-fake-backend: the dump file replaces the collector"
+        );
+    }
+
+    #[test]
+    fn test_ssh_target() {
+        let options = Options {
+            ssh_target: Some("wg-exporter@gw1".to_owned()),
+            ssh_identity: Some("/etc/wg_exporter/id_ed25519".to_owned()),
+            ..valid()
+        };
+        assert_eq!(options.validate(), Ok(()));
+
+        let options = Options {
+            ssh_target: Some("-oProxyCommand=sh".to_owned()),
+            ..valid()
+        };
+        assert!(options.validate().is_err());
+
+        let options = Options {
+            ssh_target: Some("gw1".to_owned()),
+            landlock: true,
+            ..valid()
+        };
+        assert_eq!(
+            options.validate().unwrap_err().to_string(),
+            "--ssh-target conflicts with --landlock: ssh reads its keys and config outside the paths the sandbox allows"
+        );
+
+        let options = Options {
+            ssh_identity: Some("/etc/wg_exporter/id_ed25519".to_owned()),
+            ..valid()
+        };
+        assert!(options.validate().is_err());
+    }
+
+    #[test]
+    fn test_group_sla() {
+        let sla: GroupSla = "acme corp=300".parse().unwrap();
+        assert_eq!(
+            sla,
+            GroupSla {
+                group: "acme corp".to_owned(),
+                max_handshake_age: Duration::from_secs(300),
+            }
+        );
+        assert_eq!(sla.to_string(), "acme corp=300");
+        assert!("acme".parse::<GroupSla>().is_err());
+        assert!("=300".parse::<GroupSla>().is_err());
+        assert!("acme=0".parse::<GroupSla>().is_err());
+        assert!("acme=5m".parse::<GroupSla>().is_err());
+
+        let options = Options {
+            group_slas: vec![sla.clone(), sla],
+            ..valid()
+        };
+        assert_eq!(
+            options.validate().unwrap_err().to_string(),
+            "invalid value \"acme corp=300\" for --group-sla: the group already has a service level"
+        );
+    }
+
+    #[test]
+    fn test_install_firewall_counters() {
+        let options = Options {
+            firewall_counters: Some(Firewall::Nftables),
+            install_firewall_counters: true,
+            ..valid()
+        };
+        assert_eq!(options.validate(), Ok(()));
+
+        let options = Options {
+            firewall_counters: Some(Firewall::Iptables),
+            install_firewall_counters: true,
+            ..valid()
+        };
+        assert_eq!(
+            options.validate().unwrap_err().to_string(),
+            "invalid value \"true\" for --install-firewall-counters: requires --firewall-counters nftables"
+        );
+    }
+
+    #[test]
+    fn test_read_only() {
+        let options = Options {
+            read_only: true,
+            firewall_counters: Some(Firewall::Nftables),
+            ..valid()
+        };
+        assert_eq!(options.validate(), Ok(()));
+
+        let options = Options {
+            install_firewall_counters: true,
+            ..options
+        };
+        assert_eq!(
+            options.validate().unwrap_err().to_string(),
+            "--install-firewall-counters conflicts with --read-only: installing the counters changes the nftables ruleset"
+        );
+
+        let options = Options {
+            read_only: true,
+            exec_prefix: Some("sudo -n".to_owned()),
+            ..valid()
+        };
+        assert_eq!(
+            options.validate().unwrap_err().to_string(),
+            "--exec-prefix conflicts with --read-only: the sandbox forbids the privilege escalation of the wrapper"
+        );
+    }
+
+    #[test]
+    fn test_to_toml() {
+        let options = Options {
+            port: 9586,
+            extract_names_config_files: vec![NamesFile::from("wg0:/etc/wireguard/wg0.conf")],
+            name_comment_key: Some("say \"name\"".to_owned()),
+            sample_interval: Some(Duration::from_secs(5)),
+            ..valid()
+        };
+
+        let toml = options.to_toml();
+        assert!(toml.starts_with("version = 1\nverbose = false\nport = 9586\ndaemonize = false\nextract_names_config_files = [\"wg0:/etc/wireguard/wg0.conf\"]\nname_comment_key = \"say \\\"name\\\"\"\n"));
+        assert!(toml.contains("\nhandshake_timeout = 180\n"));
+        assert!(toml
+            .contains("\nsample_interval = 5\n\n[alert_rules]\n\n[log_file_rotation]\nkeep = 0\n"));
+        assert!(!toml.contains("pid_file"));
+    }
+
+    #[test]
+    fn test_names_file() {
+        assert_eq!(
+            NamesFile::from("wg0:/etc/wireguard/wg0.conf"),
+            NamesFile {
+                interface: Some("wg0".to_owned()),
+                path: "/etc/wireguard/wg0.conf".to_owned(),
+            }
+        );
+        assert_eq!(
+            NamesFile::from("/etc/wireguard/wg0.conf"),
+            NamesFile {
+                interface: None,
+                path: "/etc/wireguard/wg0.conf".to_owned(),
+            }
+        );
+        assert_eq!(
+            NamesFile::from("/etc/wire:guard/wg0.conf"),
+            NamesFile {
+                interface: None,
+                path: "/etc/wire:guard/wg0.conf".to_owned(),
+            }
+        );
+        assert_eq!(
+            NamesFile::from(r"C:\WireGuard\wg0.conf"),
+            NamesFile {
+                interface: None,
+                path: r"C:\WireGuard\wg0.conf".to_owned(),
+            }
+        );
+        assert_eq!(
+            NamesFile::from(r"wg0:C:\WireGuard\wg0.conf"),
+            NamesFile {
+                interface: Some("wg0".to_owned()),
+                path: r"C:\WireGuard\wg0.conf".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_output_filter() {
+        let filter: OutputFilter = "websocket:interface=wg0,interface=wg1,online"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            filter,
+            OutputFilter {
+                output: "websocket".to_owned(),
+                interfaces: vec!["wg0".to_owned(), "wg1".to_owned()],
+                state: Some(PeerState::Online),
+            }
+        );
+        assert_eq!(
+            filter.to_string(),
+            "websocket:interface=wg0,interface=wg1,online"
+        );
+        assert!("websocket".parse::<OutputFilter>().is_err());
+        assert!(":online".parse::<OutputFilter>().is_err());
+        assert!("websocket:online,offline".parse::<OutputFilter>().is_err());
+        assert!("websocket:interface=".parse::<OutputFilter>().is_err());
+        assert!("websocket:idle".parse::<OutputFilter>().is_err());
+    }
+
+    #[test]
+    fn test_interface_label() {
+        let label: InterfaceLabel = "wg0:env=prod=1".parse().unwrap();
+        assert_eq!(
+            label,
+            InterfaceLabel {
+                interface: "wg0".to_owned(),
+                name: "env".to_owned(),
+                value: "prod=1".to_owned(),
+            }
+        );
+        assert_eq!(label.to_string(), "wg0:env=prod=1");
+        assert!("env=prod".parse::<InterfaceLabel>().is_err());
+        assert!("wg0:env".parse::<InterfaceLabel>().is_err());
+        assert!("wg0:1env=prod".parse::<InterfaceLabel>().is_err());
+        assert!("wg0:__env=prod".parse::<InterfaceLabel>().is_err());
+    }
+
+    #[test]
+    fn test_netns() {
+        let options = Options {
+            netns: vec!["vpn".to_owned()],
+            ..valid()
+        };
+        assert_eq!(options.validate(), Ok(()));
+
+        let options = Options {
+            netns: vec!["vpn".to_owned(), "vpn".to_owned()],
+            ..valid()
+        };
+        assert_eq!(
+            options.validate().unwrap_err().to_string(),
+            "invalid value \"vpn\" for --netns: the namespace is already collected"
+        );
+
+        let options = Options {
+            netns: vec!["../vpn".to_owned()],
+            ..valid()
+        };
+        assert!(options.validate().is_err());
+
+        let options = Options {
+            netns: vec!["vpn".to_owned()],
+            seccomp: true,
+            ..valid()
+        };
+        assert_eq!(
+            options.validate().unwrap_err().to_string(),
+            "--netns conflicts with --seccomp: the namespaces are entered by running wg through ip netns exec or nsenter"
+        );
+    }
+
+    #[test]
+    fn test_docker_socket() {
+        let options = Options {
+            docker_socket: Some("/var/run/docker.sock".to_owned()),
+            ..valid()
+        };
+        assert_eq!(options.validate(), Ok(()));
+
+        let options = Options {
+            docker_socket: Some(String::new()),
+            ..valid()
+        };
+        assert!(options.validate().is_err());
+
+        let options = Options {
+            docker_socket: Some("/var/run/docker.sock".to_owned()),
+            collector: Collector::Netlink,
+            ..valid()
+        };
+        assert_eq!(
+            options.validate().unwrap_err().to_string(),
+            "--docker-socket conflicts with --collector: the namespaces are entered by running wg through ip netns exec or nsenter"
+        );
+    }
+
+    #[test]
+    fn test_probe_module() {
+        let module: ProbeModule = "ssh=ssh -o BatchMode=yes exporter@{target}"
+            .parse()
+            .unwrap();
+        assert_eq!(module.name, "ssh");
+        assert_eq!(module.command("gw1"), "ssh -o BatchMode=yes exporter@gw1");
+        assert_eq!(
+            module.to_string(),
+            "ssh=ssh -o BatchMode=yes exporter@{target}"
+        );
+        assert!("ssh=ssh gw1".parse::<ProbeModule>().is_err());
+        assert!("ssh {target}".parse::<ProbeModule>().is_err());
+        assert!("=ssh {target}".parse::<ProbeModule>().is_err());
+
+        let options = Options {
+            probe_modules: vec![module],
+            landlock: true,
+            ..valid()
+        };
+        assert_eq!(
+            options.validate().unwrap_err().to_string(),
+            "--probe-module conflicts with --landlock: the sandbox forbids the commands to read their configuration"
+        );
+        let options = Options {
+            sandbox: true,
+            ..options
+        };
+        assert_eq!(
+            options.validate().unwrap_err().to_string(),
+            "--probe-module conflicts with --sandbox: the sandbox forbids the commands to read their configuration"
+        );
+    }
+
+    #[test]
+    fn test_otlp_endpoint() {
+        let options = Options {
+            otlp_endpoint: Some("http://localhost:4318".to_owned()),
+            ..valid()
+        };
+        if cfg!(feature = "otlp") {
+            assert_eq!(options.validate(), Ok(()));
+        } else {
+            assert_eq!(
+                options.validate().unwrap_err().to_string(),
+                "invalid value \"http://localhost:4318\" for --otlp-endpoint: the exporter is built without the otlp feature"
+            );
+            return;
+        }
+
+        let options = Options {
+            otlp_endpoint: Some("https://collector:4318".to_owned()),
+            ..valid()
+        };
+        assert_eq!(
+            options.validate().unwrap_err().to_string(),
+            "invalid value \"https://collector:4318\" for --otlp-endpoint: is not an http:// URL"
+        );
+
+        let options = Options {
+            otlp_endpoint: Some("localhost:4318".to_owned()),
+            ..valid()
+        };
+        assert!(options.validate().is_err());
+
+        let options = Options {
+            otlp_endpoint: Some("http://localhost:4318".to_owned()),
+            sandbox: true,
+            landlock: true,
+            seccomp: true,
+            ..valid()
+        };
+        assert_eq!(
+            options.validate().unwrap_err().to_string(),
+            "--otlp-endpoint conflicts with --sandbox: the sandbox forbids connecting to the collector"
+        );
+    }
+
+    #[test]
+    fn test_target() {
+        let target: Target = "gw1=ssh -o BatchMode=yes root@gw1".parse().unwrap();
+        assert_eq!(
+            target,
+            Target {
+                host: "gw1".to_owned(),
+                command: "ssh -o BatchMode=yes root@gw1".to_owned(),
+            }
+        );
+        assert_eq!(target.to_string(), "gw1=ssh -o BatchMode=yes root@gw1");
+        assert!("gw1".parse::<Target>().is_err());
+        assert!("gw1= ".parse::<Target>().is_err());
+        assert!("=ssh gw1".parse::<Target>().is_err());
+        assert!("gw/1=ssh gw1".parse::<Target>().is_err());
+        assert!("-oProxyCommand=ssh gw1".parse::<Target>().is_err());
+
+        let options = Options {
+            targets: vec![target.clone(), target],
+            ..valid()
+        };
+        assert_eq!(
+            options.validate().unwrap_err().to_string(),
+            "invalid value \"gw1=ssh -o BatchMode=yes root@gw1\" for --target: the host is already a target"
+        );
+
+        let options = Options {
+            targets: vec!["gw1=ssh gw1".parse().unwrap()],
+            ssh_target: Some("gw2".to_owned()),
+            ..valid()
+        };
+        assert_eq!(
+            options.validate().unwrap_err().to_string(),
+            "--target conflicts with --ssh-target: the interfaces of the SSH target would get the local host label; give it as a target"
+        );
     }
 }
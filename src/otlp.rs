@@ -0,0 +1,250 @@
+// Export of the spans of every scrape over OTLP/HTTP, in its JSON encoding,
+// built with the otlp feature. A scrape is a trace whose root span covers
+// the request, with a child span for every phase (exec, names, parse,
+// render, write). The spans are buffered and posted by a thread of their
+// own, so a slow or missing collector never delays the scrapes.
+use crate::exporter_error::ExporterError;
+use futures::Stream;
+use http::header::CONTENT_TYPE;
+use hyper::{Body, Client, Request, Uri};
+use log::{debug, error, warn};
+use serde_json::{json, Value};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::runtime::current_thread::Runtime;
+
+// how often the spans buffered are posted
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+// the spans buffered while the collector cannot be reached. Older ones are
+// discarded.
+const MAX_PENDING_SPANS: usize = 4096;
+
+// span kinds of OTLP
+const SPAN_KIND_INTERNAL: u8 = 1;
+const SPAN_KIND_SERVER: u8 = 2;
+
+// the spans waiting to be posted, None unless --otlp-endpoint is set
+static PENDING: Mutex<Option<Vec<Value>>> = Mutex::new(None);
+
+/// The spans of a scrape, exported when it is finished.
+pub(crate) struct Trace {
+    trace_id: String,
+    span_id: String,
+    started: SystemTime,
+    spans: Mutex<Vec<Value>>,
+}
+
+impl Trace {
+    pub fn new() -> Trace {
+        Trace {
+            trace_id: format!("{:016x}{:016x}", random(), random()),
+            span_id: format!("{:016x}", random()),
+            started: SystemTime::now(),
+            spans: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Adds the span of a phase which just ended after `duration`.
+    pub fn record(&self, phase: &'static str, duration: Duration) {
+        let ended = SystemTime::now();
+        self.spans.lock().unwrap().push(span(
+            &self.trace_id,
+            &format!("{:016x}", random()),
+            Some(&self.span_id),
+            phase,
+            SPAN_KIND_INTERNAL,
+            ended - duration,
+            ended,
+        ));
+    }
+
+    /// Ends the root span and queues the trace for the export, if enabled.
+    pub fn finish(&self) {
+        let mut pending = PENDING.lock().unwrap();
+        let pending = match &mut *pending {
+            Some(pending) => pending,
+            None => return,
+        };
+        pending.push(span(
+            &self.trace_id,
+            &self.span_id,
+            None,
+            "scrape",
+            SPAN_KIND_SERVER,
+            self.started,
+            SystemTime::now(),
+        ));
+        pending.append(&mut self.spans.lock().unwrap());
+        if pending.len() > MAX_PENDING_SPANS {
+            let excess = pending.len() - MAX_PENDING_SPANS;
+            pending.drain(..excess);
+        }
+    }
+}
+
+// a random id, from the keys the standard library seeds its hash maps with
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn span(
+    trace_id: &str,
+    span_id: &str,
+    parent_span_id: Option<&str>,
+    name: &str,
+    kind: u8,
+    started: SystemTime,
+    ended: SystemTime,
+) -> Value {
+    let mut span = json!({
+        "traceId": trace_id,
+        "spanId": span_id,
+        "name": name,
+        "kind": kind,
+        "startTimeUnixNano": unix_nanos(started),
+        "endTimeUnixNano": unix_nanos(ended),
+    });
+    if let Some(parent_span_id) = parent_span_id {
+        span["parentSpanId"] = json!(parent_span_id);
+    }
+    span
+}
+
+/// The body of an export request of OTLP/HTTP carrying `spans`.
+fn export_request(spans: Vec<Value>) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{
+                    "key": "service.name",
+                    "value": { "stringValue": env!("CARGO_PKG_NAME") },
+                }],
+            },
+            "scopeSpans": [{
+                "scope": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+fn post(
+    runtime: &mut Runtime,
+    client: &Client<hyper::client::HttpConnector>,
+    uri: &Uri,
+    spans: Vec<Value>,
+) -> Result<(), ExporterError> {
+    let request = Request::post(uri.clone())
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(export_request(spans).to_string()))?;
+    let response = runtime.block_on(client.request(request))?;
+    let status = response.status();
+    // the body is read whole for the connection to be reused
+    runtime.block_on(response.into_body().concat2())?;
+    if !status.is_success() {
+        return Err(io::Error::other(format!("the collector answered {}", status)).into());
+    }
+    Ok(())
+}
+
+/// Starts posting the spans of the scrapes to the `/v1/traces` path of
+/// `endpoint` every `EXPORT_INTERVAL`.
+pub(crate) fn enable(endpoint: &str) -> Result<(), ExporterError> {
+    let uri = format!("{}/v1/traces", endpoint.trim_end_matches('/')).parse::<Uri>()?;
+    *PENDING.lock().unwrap() = Some(Vec::new());
+
+    thread::spawn(move || {
+        let mut runtime = match Runtime::new() {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                error!("cannot export the spans: {}", e);
+                *PENDING.lock().unwrap() = None;
+                return;
+            }
+        };
+        let client = Client::new();
+        loop {
+            thread::sleep(EXPORT_INTERVAL);
+            let spans = match PENDING.lock().unwrap().as_mut() {
+                Some(pending) => std::mem::take(pending),
+                None => Vec::new(),
+            };
+            if spans.is_empty() {
+                continue;
+            }
+            let count = spans.len();
+            match post(&mut runtime, &client, &uri, spans) {
+                Ok(()) => debug!("{} spans exported to {}", count, uri),
+                Err(e) => warn!("cannot export {} spans to {}: {}", count, uri, e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace() {
+        // nothing is queued until the export is enabled
+        let trace = Trace::new();
+        trace.record("exec", Duration::from_millis(20));
+        trace.finish();
+        assert!(PENDING.lock().unwrap().is_none());
+
+        *PENDING.lock().unwrap() = Some(Vec::new());
+        let trace = Trace::new();
+        trace.record("exec", Duration::from_millis(20));
+        trace.record("parse", Duration::from_millis(5));
+        trace.finish();
+
+        // the scrapes of the other tests may have been queued meanwhile
+        let mut spans = PENDING.lock().unwrap().take().unwrap();
+        spans.retain(|span| span["traceId"] == trace.trace_id);
+        assert_eq!(spans.len(), 3);
+        let (root, phases) = spans.split_first().unwrap();
+        assert_eq!(root["name"], "scrape");
+        assert_eq!(root["kind"], SPAN_KIND_SERVER);
+        assert_eq!(root["traceId"].as_str().unwrap().len(), 32);
+        assert_eq!(root["spanId"].as_str().unwrap().len(), 16);
+        assert!(root.get("parentSpanId").is_none());
+        for phase in phases {
+            assert_eq!(phase["traceId"], root["traceId"]);
+            assert_eq!(phase["parentSpanId"], root["spanId"]);
+            assert_ne!(phase["spanId"], root["spanId"]);
+        }
+        let nanos =
+            |span: &Value, field: &str| span[field].as_str().unwrap().parse::<u128>().unwrap();
+        assert_eq!(
+            nanos(&phases[0], "endTimeUnixNano") - nanos(&phases[0], "startTimeUnixNano"),
+            20_000_000
+        );
+        assert_eq!(phases[1]["name"], "parse");
+
+        let request = export_request(spans);
+        assert_eq!(
+            request["resourceSpans"][0]["scopeSpans"][0]["spans"]
+                .as_array()
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+}
@@ -0,0 +1,199 @@
+// The outputs fed by the background collections. Every sink runs on its own
+// thread at its own interval: it gets a collection of its own, so a slow or
+// failing sink neither delays nor breaks the others, and its failures are
+// logged and counted under its name. `/metrics` and the JSON API are not
+// sinks, they collect when requested.
+use crate::exporter_error::ExporterError;
+use crate::idle;
+use crate::metrics::{MetricType, Registry};
+use crate::options::{OutputFilter, PeerState};
+use crate::wireguard::{Endpoint, WireGuard};
+use crate::wireguard_config::PeerEntries;
+use log::error;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// The names of the sinks, see `--output-filter`.
+pub(crate) const NAMES: &[&str] = &["websocket", "textfile"];
+
+// the failures of every sink spawned, by name
+static FAILURES: Mutex<BTreeMap<&'static str, u64>> = Mutex::new(BTreeMap::new());
+
+pub(crate) trait Sink: Send + Sync {
+    /// The name of the sink, in the logs and the `output` label.
+    fn name(&self) -> &'static str;
+
+    /// How long the sink waits after an emission before the next one.
+    fn interval(&self) -> Duration;
+
+    /// Emits a collection.
+    fn emit(&self, wg: &WireGuard, pehm: Option<&PeerEntries>) -> Result<(), ExporterError>;
+
+    /// Whether the sink has consumers of its own, which keep it running
+    /// while the exporter is idle.
+    fn busy(&self) -> bool {
+        false
+    }
+}
+
+/// Starts feeding every sink, `collect` performing a collection and
+/// handing it to the sink.
+pub(crate) fn spawn<C>(sinks: Vec<Arc<dyn Sink>>, collect: C)
+where
+    C: Fn(&dyn Sink) -> Result<(), ExporterError> + Send + Sync + 'static,
+{
+    let collect = Arc::new(collect);
+    for sink in sinks {
+        FAILURES.lock().unwrap().entry(sink.name()).or_insert(0);
+        let collect = collect.clone();
+        thread::spawn(move || loop {
+            idle::wait_while_idle(sink.busy());
+            run(&*collect, &*sink);
+            thread::sleep(sink.interval());
+        });
+    }
+}
+
+fn run<C>(collect: &C, sink: &dyn Sink)
+where
+    C: Fn(&dyn Sink) -> Result<(), ExporterError>,
+{
+    if let Err(e) = collect(sink) {
+        error!("the {} output failed: {}", sink.name(), e);
+        *FAILURES.lock().unwrap().entry(sink.name()).or_insert(0) += 1;
+    }
+}
+
+/// Keeps the peers of `wg` selected by `filter`, their interfaces left
+/// as they are. The interfaces not selected are dropped whole.
+pub(crate) fn filter(
+    wg: &WireGuard,
+    filter: &OutputFilter,
+    now: u64,
+    handshake_timeout: Duration,
+) -> WireGuard {
+    let mut wg = wg.clone();
+    if !filter.interfaces.is_empty() {
+        wg.interfaces
+            .retain(|interface, _| filter.interfaces.contains(interface));
+    }
+    if let Some(state) = filter.state {
+        for endpoints in wg.interfaces.values_mut() {
+            endpoints.retain(|endpoint| match endpoint {
+                Endpoint::Local(_) => true,
+                Endpoint::Remote(ep) => {
+                    ep.is_online(now, handshake_timeout) == (state == PeerState::Online)
+                }
+            });
+        }
+    }
+    wg
+}
+
+/// Renders the failures of the sinks spawned.
+pub(crate) fn render(registry: &mut Registry) {
+    let mut failures = registry.family(
+        "wireguard_exporter_output_failures_total",
+        MetricType::Counter,
+        "Collections an output failed to emit",
+    );
+    for (name, count) in FAILURES.lock().unwrap().iter() {
+        failures.push(&[("output", name)], *count as f64);
+    }
+    registry.register(failures);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exporter_error::ExporterError;
+    use std::convert::TryFrom;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct Counting {
+        emitted: AtomicUsize,
+    }
+
+    impl Sink for Counting {
+        fn name(&self) -> &'static str {
+            "test_counting"
+        }
+
+        fn interval(&self) -> Duration {
+            Duration::from_secs(60)
+        }
+
+        fn emit(&self, _: &WireGuard, _: Option<&PeerEntries>) -> Result<(), ExporterError> {
+            self.emitted.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_run() {
+        let sink = Counting::default();
+        let wg = WireGuard::try_from("").unwrap();
+
+        run(&|sink: &dyn Sink| sink.emit(&wg, None), &sink);
+        assert_eq!(sink.emitted.load(Ordering::SeqCst), 1);
+
+        // the failure is counted, not propagated
+        run(&|_: &dyn Sink| Err(ExporterError::Generic {}), &sink);
+        run(&|_: &dyn Sink| Err(ExporterError::Generic {}), &sink);
+        assert_eq!(sink.emitted.load(Ordering::SeqCst), 1);
+
+        let mut registry = Registry::default();
+        render(&mut registry);
+        assert!(registry
+            .render_text()
+            .contains("wireguard_exporter_output_failures_total{output=\"test_counting\"} 2\n"));
+    }
+
+    fn filter_interfaces(wg: &WireGuard, filter: &str) -> Vec<String> {
+        let filtered = super::filter(wg, &filter.parse().unwrap(), 0, Duration::from_secs(180));
+        let mut interfaces: Vec<_> = filtered.interfaces.keys().cloned().collect();
+        interfaces.sort();
+        interfaces
+    }
+
+    #[test]
+    fn test_filter() {
+        const TEXT: &str = "wg0\t000q4qAC0ExW/BuGSmVR1nxH9JAXT6g9Wd3oEGy5lA=\t0000AC0ExW/BuGSmVR1nxH9JAXT6g9Wd3oEGy5lA=\t51820\toff
+wg0\t2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=\t(none)\t37.159.76.245:29159\t10.70.0.2/32\t1555771458\t10288508\t139524160\toff
+wg0\tqnoxQoQI8KKMupLnSSureORV0wMmH7JryZNsmGVISzU=\t(none)\t(none)\t10.70.0.3/32\t0\t0\t0\toff
+wg1\t111q4qAC0ExW/BuGSmVR1nxH9JAXT6g9Wd3oEGy5lA=\t1111AC0ExW/BuGSmVR1nxH9JAXT6g9Wd3oEGy5lA=\t51821\toff
+";
+        let wg = WireGuard::try_from(TEXT).unwrap();
+        let peers = |filter: &str| {
+            let filtered = super::filter(
+                &wg,
+                &filter.parse().unwrap(),
+                1555771458 + 60,
+                Duration::from_secs(180),
+            );
+            let mut peers: Vec<_> = filtered
+                .interfaces
+                .iter()
+                .flat_map(|(interface, endpoints)| {
+                    endpoints.iter().filter_map(move |endpoint| match endpoint {
+                        Endpoint::Remote(ep) => {
+                            Some(format!("{}/{}", interface, &ep.public_key[..4]))
+                        }
+                        Endpoint::Local(_) => None,
+                    })
+                })
+                .collect();
+            peers.sort();
+            peers
+        };
+
+        assert_eq!(peers("websocket:online"), ["wg0/2S7m"]);
+        assert_eq!(peers("websocket:offline"), ["wg0/qnox"]);
+        assert!(peers("websocket:interface=wg1").is_empty());
+        assert_eq!(filter_interfaces(&wg, "websocket:interface=wg1"), ["wg1"]);
+        assert_eq!(peers("websocket:interface=wg0,offline"), ["wg0/qnox"]);
+    }
+}
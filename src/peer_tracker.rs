@@ -0,0 +1,1172 @@
+use crate::clock;
+use crate::implementation::{self, Implementation};
+use crate::memory_budget::{MemoryBudget, Reservation};
+use crate::metrics::{MetricType, Registry};
+use crate::published::Published;
+use crate::rotating_file::RotatingFile;
+use crate::usage::UsageStore;
+use crate::wireguard::{Endpoint, WireGuard};
+use log::{error, warn};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum PeerEvent {
+    Added {
+        interface: String,
+        public_key: String,
+    },
+    Removed {
+        interface: String,
+        public_key: String,
+    },
+    EndpointChanged {
+        interface: String,
+        public_key: String,
+        old_endpoint: Option<String>,
+        new_endpoint: Option<String>,
+    },
+    InterfaceKeyChanged {
+        interface: String,
+        old_public_key: String,
+        new_public_key: String,
+    },
+    InterfaceAdded {
+        interface: String,
+    },
+    InterfaceRemoved {
+        interface: String,
+    },
+}
+
+impl PeerEvent {
+    /// Returns the audit log record of the event, happened at `now`
+    /// (seconds since the epoch).
+    pub fn to_json(&self, now: u64) -> Value {
+        let time = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(now));
+        match self {
+            PeerEvent::Added {
+                interface,
+                public_key,
+            } => json!({
+                "time": time.to_string(),
+                "event": "added",
+                "interface": interface,
+                "public_key": public_key,
+            }),
+            PeerEvent::Removed {
+                interface,
+                public_key,
+            } => json!({
+                "time": time.to_string(),
+                "event": "removed",
+                "interface": interface,
+                "public_key": public_key,
+            }),
+            PeerEvent::EndpointChanged {
+                interface,
+                public_key,
+                old_endpoint,
+                new_endpoint,
+            } => json!({
+                "time": time.to_string(),
+                "event": "endpoint_changed",
+                "interface": interface,
+                "public_key": public_key,
+                "old_endpoint": old_endpoint,
+                "new_endpoint": new_endpoint,
+            }),
+            PeerEvent::InterfaceKeyChanged {
+                interface,
+                old_public_key,
+                new_public_key,
+            } => json!({
+                "time": time.to_string(),
+                "event": "interface_key_changed",
+                "interface": interface,
+                "old_public_key": old_public_key,
+                "new_public_key": new_public_key,
+            }),
+            PeerEvent::InterfaceAdded { interface } => json!({
+                "time": time.to_string(),
+                "event": "interface_added",
+                "interface": interface,
+            }),
+            PeerEvent::InterfaceRemoved { interface } => json!({
+                "time": time.to_string(),
+                "event": "interface_removed",
+                "interface": interface,
+            }),
+        }
+    }
+}
+
+impl fmt::Display for PeerEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerEvent::Added {
+                interface,
+                public_key,
+            } => write!(f, "peer {} added to {}", public_key, interface),
+            PeerEvent::Removed {
+                interface,
+                public_key,
+            } => write!(f, "peer {} removed from {}", public_key, interface),
+            PeerEvent::EndpointChanged {
+                interface,
+                public_key,
+                old_endpoint,
+                new_endpoint,
+            } => write!(
+                f,
+                "peer {} of {} moved from {} to {}",
+                public_key,
+                interface,
+                old_endpoint.as_ref().map(|e| e as &str).unwrap_or("(none)"),
+                new_endpoint.as_ref().map(|e| e as &str).unwrap_or("(none)")
+            ),
+            PeerEvent::InterfaceKeyChanged {
+                interface,
+                old_public_key,
+                new_public_key,
+            } => write!(
+                f,
+                "public key of {} changed from {} to {}",
+                interface, old_public_key, new_public_key
+            ),
+            PeerEvent::InterfaceAdded { interface } => write!(f, "interface {} added", interface),
+            PeerEvent::InterfaceRemoved { interface } => {
+                write!(f, "interface {} removed", interface)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Churn {
+    added: u64,
+    removed: u64,
+}
+
+#[derive(Debug, Default, Clone)]
+struct InterfaceKey {
+    public_key: String,
+    rotations: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct PeerSnapshot {
+    endpoint: Option<String>,
+    sent_bytes: u128,
+    received_bytes: u128,
+}
+
+// (interface, public_key) -> peer
+type Snapshot = BTreeMap<(String, String), PeerSnapshot>;
+
+#[derive(Debug, Clone)]
+struct PeerSession {
+    // None while the peer is offline
+    start: Option<u64>,
+    sent_bytes: u128,
+    received_bytes: u128,
+}
+
+// the snapshots retained for the diffs are at least this far apart
+const HISTORY_INTERVAL: u64 = 60;
+const HISTORY_MAX_LEN: usize = 64;
+// a rough estimate of the memory retained by every peer of a snapshot
+const SNAPSHOT_BYTES_PER_PEER: usize = 256;
+
+#[derive(Debug, Default)]
+struct PeerTrackerState {
+    // None until the first collection, which is the baseline
+    latest: Option<(u64, Arc<Snapshot>)>,
+    // the reservations are None without a memory budget
+    history: VecDeque<(u64, Arc<Snapshot>, Option<Reservation>)>,
+    // the latest `recent_len` collections
+    recent: VecDeque<(u64, Arc<Snapshot>, Option<Reservation>)>,
+    churn: BTreeMap<String, Churn>,
+    interface_keys: BTreeMap<String, InterfaceKey>,
+    // detected when the interface appears
+    implementations: BTreeMap<String, Implementation>,
+    // None while the interface is up, else the time it disappeared at
+    interfaces: BTreeMap<String, Option<u64>>,
+    // the time of the latest session update and the sessions then
+    sessions: Arc<Sessions>,
+}
+
+type Sessions = (u64, BTreeMap<(String, String), PeerSession>);
+
+// what the queries are answered from, published after every collection
+#[derive(Debug, Default)]
+struct PeerTrackerView {
+    latest: Option<(u64, Arc<Snapshot>)>,
+    history: Vec<(u64, Arc<Snapshot>)>,
+    recent: Vec<(u64, Arc<Snapshot>)>,
+    churn: BTreeMap<String, Churn>,
+    interface_keys: BTreeMap<String, InterfaceKey>,
+    implementations: BTreeMap<String, Implementation>,
+    sessions: Arc<Sessions>,
+}
+
+/// Compares every collection with the previous one to find out which
+/// peers appeared, which disappeared and which changed endpoint, and
+/// whether the key of an interface changed or an interface came and went.
+/// Some of the past collections
+/// are retained to answer `diff` queries, and optionally the latest ones
+/// to answer `history` queries. The queries are answered from a view
+/// published by the collections, so they never wait for one.
+#[derive(Debug, Default)]
+pub(crate) struct PeerTracker {
+    state: Mutex<PeerTrackerState>,
+    view: Published<PeerTrackerView>,
+    audit_log: Option<Mutex<RotatingFile>>,
+    memory_budget: Option<Arc<MemoryBudget>>,
+    recent_len: usize,
+    usage: Option<Arc<UsageStore>>,
+    interface_grace_period: Duration,
+}
+
+// appends `snapshot` to `snapshots`, dropping the oldest ones to make room
+// in `memory_budget` if needed. Returns false if it does not fit at all.
+fn retain_snapshot(
+    snapshots: &mut VecDeque<(u64, Arc<Snapshot>, Option<Reservation>)>,
+    now: u64,
+    snapshot: &Arc<Snapshot>,
+    memory_budget: Option<&Arc<MemoryBudget>>,
+) -> bool {
+    let memory_budget = match memory_budget {
+        Some(memory_budget) => memory_budget,
+        None => {
+            snapshots.push_back((now, snapshot.clone(), None));
+            return true;
+        }
+    };
+
+    let bytes = snapshot.len() * SNAPSHOT_BYTES_PER_PEER;
+    loop {
+        if let Some(reservation) = memory_budget.try_reserve(bytes) {
+            snapshots.push_back((now, snapshot.clone(), Some(reservation)));
+            return true;
+        }
+        if snapshots.pop_front().is_none() {
+            return false;
+        }
+    }
+}
+
+fn snapshot(wg: &WireGuard) -> Snapshot {
+    let mut snapshot = Snapshot::new();
+    for (interface, endpoints) in wg.interfaces.iter() {
+        for endpoint in endpoints {
+            if let Endpoint::Remote(ep) = endpoint {
+                snapshot.insert(
+                    (interface.to_owned(), ep.public_key.to_owned()),
+                    PeerSnapshot {
+                        endpoint: ep.endpoint(),
+                        sent_bytes: ep.sent_bytes,
+                        received_bytes: ep.received_bytes,
+                    },
+                );
+            }
+        }
+    }
+    snapshot
+}
+
+impl PeerTracker {
+    /// Creates a tracker writing the events, as JSON lines,
+    /// to `audit_log` if specified and retaining the latest `recent_len`
+    /// collections for `history`. The snapshots retained are accounted
+    /// in `memory_budget`, if specified. The bytes transferred between
+    /// the collections are added to `usage`, if specified. The series of
+    /// an interface that disappeared are rendered for
+    /// `interface_grace_period` more, then its state is dropped.
+    pub fn new(
+        audit_log: Option<RotatingFile>,
+        memory_budget: Option<Arc<MemoryBudget>>,
+        recent_len: usize,
+        usage: Option<Arc<UsageStore>>,
+        interface_grace_period: Duration,
+    ) -> PeerTracker {
+        PeerTracker {
+            state: Mutex::new(PeerTrackerState::default()),
+            view: Published::default(),
+            audit_log: audit_log.map(Mutex::new),
+            memory_budget,
+            recent_len,
+            usage,
+            interface_grace_period,
+        }
+    }
+
+    fn record_usage(&self, usage: &UsageStore, previous: &Snapshot, current: &Snapshot, now: u64) {
+        // a counter going backwards has been reset, along with the interface
+        let delta = |previous: u128, current: u128| {
+            if current >= previous {
+                current - previous
+            } else {
+                current
+            }
+        };
+
+        let transfers = current
+            .iter()
+            .filter_map(|((interface, public_key), peer)| {
+                previous
+                    .get(&(interface.to_owned(), public_key.to_owned()))
+                    .map(|previous_peer| {
+                        (
+                            interface as &str,
+                            public_key as &str,
+                            delta(previous_peer.sent_bytes, peer.sent_bytes) as u64,
+                            delta(previous_peer.received_bytes, peer.received_bytes) as u64,
+                        )
+                    })
+            });
+        if let Err(e) = usage.record(now, transfers) {
+            error!("cannot write the usage file: {}", e);
+        }
+    }
+
+    // publishes the view of `state`, swapping it in for the queries
+    fn publish(&self, state: &PeerTrackerState) {
+        let snapshots = |snapshots: &VecDeque<(u64, Arc<Snapshot>, Option<Reservation>)>| {
+            snapshots
+                .iter()
+                .map(|(taken, snapshot, _)| (*taken, snapshot.clone()))
+                .collect()
+        };
+        self.view.store(PeerTrackerView {
+            latest: state.latest.clone(),
+            history: snapshots(&state.history),
+            recent: snapshots(&state.recent),
+            churn: state.churn.clone(),
+            interface_keys: state.interface_keys.clone(),
+            implementations: state.implementations.clone(),
+            sessions: state.sessions.clone(),
+        });
+    }
+
+    /// The number of peers in the latest collection.
+    pub fn peer_count(&self) -> usize {
+        self.view
+            .load()
+            .latest
+            .as_ref()
+            .map(|(_, snapshot)| snapshot.len())
+            .unwrap_or(0)
+    }
+
+    fn audit(&self, events: &[PeerEvent], now: u64) {
+        if let Some(audit_log) = &self.audit_log {
+            let mut records = String::new();
+            for event in events {
+                records.push_str(&event.to_json(now).to_string());
+                records.push('\n');
+            }
+
+            let mut audit_log = audit_log.lock().unwrap();
+            if let Err(e) = audit_log
+                .write_all(records.as_bytes())
+                .and_then(|_| audit_log.flush())
+            {
+                error!("cannot write to the audit log: {}", e);
+            }
+        }
+    }
+
+    /// Records the peers of `wg`, collected at `now` (seconds since the
+    /// epoch), and returns what changed since the previous call. The
+    /// first call only sets the baseline.
+    pub fn observe(&self, wg: &WireGuard, now: u64) -> Vec<PeerEvent> {
+        let current = Arc::new(snapshot(wg));
+
+        let mut events = Vec::new();
+
+        let mut state = self.state.lock().unwrap();
+        let mut interfaces: Vec<&String> = wg.interfaces.keys().collect();
+        interfaces.sort();
+        for interface in interfaces {
+            if let Some(None) = state.interfaces.insert(interface.to_owned(), None) {
+                continue;
+            }
+            // a restarted interface may be served by another implementation
+            state
+                .implementations
+                .insert(interface.to_owned(), implementation::detect(interface));
+            events.push(PeerEvent::InterfaceAdded {
+                interface: interface.to_owned(),
+            });
+        }
+        for (interface, removed) in state.interfaces.iter_mut() {
+            if removed.is_none() && !wg.interfaces.contains_key(interface) {
+                *removed = Some(now);
+                events.push(PeerEvent::InterfaceRemoved {
+                    interface: interface.to_owned(),
+                });
+            }
+        }
+
+        for (interface, endpoints) in wg.interfaces.iter() {
+            state.churn.entry(interface.to_owned()).or_default();
+
+            for endpoint in endpoints {
+                if let Endpoint::Local(local) = endpoint {
+                    let interface_key = state
+                        .interface_keys
+                        .entry(interface.to_owned())
+                        .or_insert_with(|| InterfaceKey {
+                            public_key: local.public_key.to_owned(),
+                            rotations: 0,
+                        });
+
+                    if interface_key.public_key != local.public_key {
+                        events.push(PeerEvent::InterfaceKeyChanged {
+                            interface: interface.to_owned(),
+                            old_public_key: interface_key.public_key.to_owned(),
+                            new_public_key: local.public_key.to_owned(),
+                        });
+                        interface_key.public_key = local.public_key.to_owned();
+                        interface_key.rotations += 1;
+                    }
+                }
+            }
+        }
+
+        // snapshots from after a step back of the clock would make the
+        // history go back and forth in time
+        if state.latest.as_ref().is_some_and(|(taken, _)| *taken > now) {
+            state.history.retain(|(taken, _, _)| *taken <= now);
+            state.recent.retain(|(taken, _, _)| *taken <= now);
+        }
+
+        let history_due = state
+            .history
+            .back()
+            .map(|(taken, _, _)| now >= taken + HISTORY_INTERVAL)
+            .unwrap_or(true);
+        if history_due {
+            if state.history.len() == HISTORY_MAX_LEN {
+                state.history.pop_front();
+            }
+            if !retain_snapshot(
+                &mut state.history,
+                now,
+                &current,
+                self.memory_budget.as_ref(),
+            ) {
+                warn!("the memory budget cannot fit a snapshot of the peers, /api/v1/diff will have no history");
+            }
+        }
+
+        if self.recent_len > 0 {
+            if state.recent.len() == self.recent_len {
+                state.recent.pop_front();
+            }
+            if !retain_snapshot(
+                &mut state.recent,
+                now,
+                &current,
+                self.memory_budget.as_ref(),
+            ) {
+                warn!("the memory budget cannot fit a snapshot of the peers, /api/v1/history will be empty");
+            }
+        }
+
+        let previous = match state.latest.replace((now, current.clone())) {
+            Some((_, previous)) => previous,
+            None => {
+                self.publish(&state);
+                return Vec::new();
+            }
+        };
+
+        if let Some(usage) = &self.usage {
+            self.record_usage(usage, &previous, &current, now);
+        }
+
+        for ((interface, public_key), peer) in current.iter() {
+            match previous.get(&(interface.to_owned(), public_key.to_owned())) {
+                None => events.push(PeerEvent::Added {
+                    interface: interface.to_owned(),
+                    public_key: public_key.to_owned(),
+                }),
+                Some(previous_peer) if previous_peer.endpoint != peer.endpoint => {
+                    events.push(PeerEvent::EndpointChanged {
+                        interface: interface.to_owned(),
+                        public_key: public_key.to_owned(),
+                        old_endpoint: previous_peer.endpoint.clone(),
+                        new_endpoint: peer.endpoint.clone(),
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+        for (interface, public_key) in previous.keys() {
+            if !current.contains_key(&(interface.to_owned(), public_key.to_owned())) {
+                events.push(PeerEvent::Removed {
+                    interface: interface.to_owned(),
+                    public_key: public_key.to_owned(),
+                });
+            }
+        }
+
+        for event in events.iter() {
+            match event {
+                PeerEvent::Added { interface, .. } => {
+                    state.churn.entry(interface.to_owned()).or_default().added += 1
+                }
+                PeerEvent::Removed { interface, .. } => {
+                    state.churn.entry(interface.to_owned()).or_default().removed += 1
+                }
+                PeerEvent::EndpointChanged { .. }
+                | PeerEvent::InterfaceKeyChanged { .. }
+                | PeerEvent::InterfaceAdded { .. }
+                | PeerEvent::InterfaceRemoved { .. } => {}
+            }
+        }
+        self.drop_removed_interfaces(&mut state, now);
+        self.publish(&state);
+        drop(state);
+
+        if !events.is_empty() {
+            self.audit(&events, now);
+        }
+        events
+    }
+
+    // drops the state of the interfaces that disappeared more than the
+    // grace period ago, for their series to stop being rendered
+    fn drop_removed_interfaces(&self, state: &mut PeerTrackerState, now: u64) {
+        let grace_period = self.interface_grace_period.as_secs();
+        let expired: Vec<String> = state
+            .interfaces
+            .iter()
+            .filter(|(_, removed)| {
+                removed.is_some_and(|removed| clock::age(now, removed) >= grace_period)
+            })
+            .map(|(interface, _)| interface.to_owned())
+            .collect();
+        for interface in expired {
+            state.interfaces.remove(&interface);
+            state.churn.remove(&interface);
+            state.interface_keys.remove(&interface);
+            state.implementations.remove(&interface);
+        }
+    }
+
+    /// Updates the estimated session of every peer of `wg`, collected at
+    /// `now`. A session starts with the handshake of a peer coming online
+    /// and lasts while the peer keeps completing handshakes within
+    /// `handshake_timeout` or exchanging traffic; it ends once neither
+    /// happens. Sessions already running at the first call are assumed to
+    /// have started with the latest handshake.
+    pub fn update_sessions(&self, wg: &WireGuard, now: u64, handshake_timeout: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let previous = std::mem::take(&mut state.sessions);
+        let (_, previous) = &*previous;
+
+        let mut sessions = BTreeMap::new();
+        for (interface, endpoints) in wg.interfaces.iter() {
+            for endpoint in endpoints {
+                if let Endpoint::Remote(ep) = endpoint {
+                    let key = (interface.to_owned(), ep.public_key.to_owned());
+                    let previous = previous.get(&key);
+
+                    let active = ep.is_online(now, handshake_timeout)
+                        || previous.is_some_and(|previous| {
+                            ep.sent_bytes > previous.sent_bytes
+                                || ep.received_bytes > previous.received_bytes
+                        });
+                    let start = if active {
+                        previous.and_then(|previous| previous.start).or_else(|| {
+                            Some(if ep.latest_handshake != 0 {
+                                ep.latest_handshake.min(now)
+                            } else {
+                                now
+                            })
+                        })
+                    } else {
+                        None
+                    };
+
+                    sessions.insert(
+                        key,
+                        PeerSession {
+                            start,
+                            sent_bytes: ep.sent_bytes,
+                            received_bytes: ep.received_bytes,
+                        },
+                    );
+                }
+            }
+        }
+        state.sessions = Arc::new((now, sessions));
+        self.publish(&state);
+    }
+
+    /// Returns the peers added, removed, whose endpoint changed and whose
+    /// counters changed between `since` (seconds since the epoch) and the
+    /// latest collection. The comparison starts from the newest retained
+    /// collection not after `since` (or the oldest one, if none), which is
+    /// reported in the `since` field. Returns `None` before the first
+    /// collection.
+    pub fn diff(&self, since: u64) -> Option<Value> {
+        let view = self.view.load();
+        let (until, latest) = view.latest.as_ref()?;
+
+        let (since, old) = view
+            .history
+            .iter()
+            .rev()
+            .find(|(taken, _)| *taken <= since)
+            .or_else(|| view.history.first())?;
+
+        let mut added = Vec::new();
+        let mut removed = Vec::new();
+        let mut endpoint_changed = Vec::new();
+        let mut counters_changed = Vec::new();
+
+        for ((interface, public_key), peer) in latest.iter() {
+            match old.get(&(interface.to_owned(), public_key.to_owned())) {
+                None => added.push(json!({
+                    "interface": interface,
+                    "public_key": public_key,
+                })),
+                Some(old_peer) => {
+                    if old_peer.endpoint != peer.endpoint {
+                        endpoint_changed.push(json!({
+                            "interface": interface,
+                            "public_key": public_key,
+                            "old_endpoint": old_peer.endpoint,
+                            "new_endpoint": peer.endpoint,
+                        }));
+                    }
+                    if old_peer.sent_bytes != peer.sent_bytes
+                        || old_peer.received_bytes != peer.received_bytes
+                    {
+                        counters_changed.push(json!({
+                            "interface": interface,
+                            "public_key": public_key,
+                            "sent_bytes": peer.sent_bytes as u64,
+                            "received_bytes": peer.received_bytes as u64,
+                            "sent_bytes_delta": peer.sent_bytes as i64 - old_peer.sent_bytes as i64,
+                            "received_bytes_delta": peer.received_bytes as i64 - old_peer.received_bytes as i64,
+                        }));
+                    }
+                }
+            }
+        }
+        for (interface, public_key) in old.keys() {
+            if !latest.contains_key(&(interface.to_owned(), public_key.to_owned())) {
+                removed.push(json!({
+                    "interface": interface,
+                    "public_key": public_key,
+                }));
+            }
+        }
+
+        Some(json!({
+            "since": since,
+            "until": until,
+            "added": added,
+            "removed": removed,
+            "endpoint_changed": endpoint_changed,
+            "counters_changed": counters_changed,
+        }))
+    }
+
+    /// Returns, for every peer, the byte counters of the retained
+    /// collections performed since `since` (seconds since the epoch),
+    /// oldest first.
+    pub fn history(&self, since: u64) -> Value {
+        let view = self.view.load();
+
+        let mut peers: BTreeMap<(&str, &str), Vec<Value>> = BTreeMap::new();
+        for (taken, snapshot) in view.recent.iter().filter(|(taken, _)| *taken >= since) {
+            for ((interface, public_key), peer) in snapshot.iter() {
+                peers
+                    .entry((interface, public_key))
+                    .or_default()
+                    .push(json!({
+                        "time": taken,
+                        "sent_bytes": peer.sent_bytes as u64,
+                        "received_bytes": peer.received_bytes as u64,
+                    }));
+            }
+        }
+
+        let peers: Vec<Value> = peers
+            .into_iter()
+            .map(|((interface, public_key), samples)| {
+                json!({
+                    "interface": interface,
+                    "public_key": public_key,
+                    "samples": samples,
+                })
+            })
+            .collect();
+        json!({ "peers": peers })
+    }
+
+    pub fn render(&self, registry: &mut Registry) {
+        let mut peers_added = registry.family(
+            "wireguard_peers_added_total",
+            MetricType::Counter,
+            "Peers that appeared since the exporter started",
+        );
+        let mut peers_removed = registry.family(
+            "wireguard_peers_removed_total",
+            MetricType::Counter,
+            "Peers that disappeared since the exporter started",
+        );
+
+        let mut key_rotations = registry.family(
+            "wireguard_interface_key_rotations_total",
+            MetricType::Counter,
+            "Changes of the interface public key since the exporter started",
+        );
+        let mut interface_info = registry.family(
+            "wireguard_interface_info",
+            MetricType::Gauge,
+            "Current public key and implementation of the interface",
+        );
+
+        let mut session_duration = registry.family(
+            "wireguard_peer_session_duration_seconds",
+            MetricType::Gauge,
+            "Estimated duration of the current session of the peer, 0 if offline",
+        );
+
+        let view = self.view.load();
+        for (interface, churn) in view.churn.iter() {
+            let attributes = [("interface", interface as &str)];
+            peers_added.push(&attributes, churn.added as f64);
+            peers_removed.push(&attributes, churn.removed as f64);
+        }
+        for (interface, interface_key) in view.interface_keys.iter() {
+            let attributes = [("interface", interface as &str)];
+            key_rotations.push(&attributes, interface_key.rotations as f64);
+
+            let attributes = [
+                ("interface", interface as &str),
+                ("public_key", &interface_key.public_key),
+                (
+                    "implementation",
+                    view.implementations
+                        .get(interface)
+                        .unwrap_or(&Implementation::Unknown)
+                        .as_str(),
+                ),
+            ];
+            interface_info.push(&attributes, 1.0);
+        }
+
+        let (updated, sessions) = &*view.sessions;
+        for ((interface, public_key), session) in sessions.iter() {
+            let attributes = [
+                ("interface", interface as &str),
+                ("public_key", public_key as &str),
+            ];
+            let duration = session
+                .start
+                .map(|start| clock::age(*updated, start))
+                .unwrap_or(0);
+            session_duration.push(&attributes, duration as f64);
+        }
+
+        registry.register(peers_added);
+        registry.register(peers_removed);
+        registry.register(key_rotations);
+        registry.register(interface_info);
+        registry.register(session_duration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usage::Period;
+    use std::convert::TryFrom;
+
+    const LOCAL: &str = "wg0\t000q4qAC0ExW/BuGSmVR1nxH9JAXT6g9Wd3oEGy5lA=\t0000u8LWR682knVm350lnuqlCJzw5SNLW9Nf96P+m8=\t51820\toff\n";
+    const PEER_A: &str = "wg0\tA=\t(none)\t(none)\t10.70.0.2/32\t0\t0\t0\toff\n";
+    const PEER_B: &str = "wg0\tB=\t(none)\t(none)\t10.70.0.3/32\t0\t0\t0\toff\n";
+    const PEER_C: &str = "wg1\tC=\t(none)\t(none)\t10.70.1.2/32\t0\t0\t0\toff\n";
+
+    fn wg(lines: &[&str]) -> WireGuard {
+        WireGuard::try_from(&lines.concat() as &str).unwrap()
+    }
+
+    #[test]
+    fn test_history_memory_budget() {
+        // room for the snapshots of two collections of two peers
+        let memory_budget = Arc::new(MemoryBudget::new(4 * SNAPSHOT_BYTES_PER_PEER));
+        let tracker = PeerTracker::new(
+            None,
+            Some(memory_budget.clone()),
+            0,
+            None,
+            Duration::default(),
+        );
+
+        for now in &[1000, 1060, 1120] {
+            tracker.observe(&wg(&[LOCAL, PEER_A, PEER_B]), *now);
+        }
+        assert_eq!(tracker.state.lock().unwrap().history.len(), 2);
+        assert_eq!(tracker.diff(0).unwrap()["since"], 1060);
+        assert_eq!(memory_budget.used(), 4 * SNAPSHOT_BYTES_PER_PEER);
+        assert_eq!(tracker.peer_count(), 2);
+
+        drop(tracker);
+        assert_eq!(memory_budget.used(), 0);
+    }
+
+    #[test]
+    fn test_clock_stepped_back() {
+        let tracker = PeerTracker::new(None, None, 4, None, Duration::default());
+        for now in &[4000, 4060, 4120] {
+            tracker.observe(&wg(&[LOCAL, PEER_A]), *now);
+        }
+        // the snapshots from the future are dropped, the history goes on
+        tracker.observe(&wg(&[LOCAL, PEER_A]), 4050);
+        let state = tracker.state.lock().unwrap();
+        let taken: Vec<u64> = state.recent.iter().map(|(taken, _, _)| *taken).collect();
+        assert_eq!(taken, vec![4000, 4050]);
+        assert_eq!(state.history.back().unwrap().0, 4000);
+    }
+
+    #[test]
+    fn test_record_usage() {
+        let path = std::env::temp_dir().join(format!(
+            "prometheus_wireguard_exporter_tracker_usage_{}.json",
+            std::process::id()
+        ));
+        let usage = Arc::new(UsageStore::open(&path, Duration::from_secs(86400), None).unwrap());
+        let tracker = PeerTracker::new(None, None, 0, Some(usage.clone()), Duration::default());
+        let peer_a = |bytes: u64| {
+            format!(
+                "wg0\tA=\t(none)\t(none)\t10.70.0.2/32\t0\t{}\t{}\toff\n",
+                bytes, bytes
+            )
+        };
+
+        tracker.observe(&wg(&[LOCAL, &peer_a(1000)]), 1_560_000_000);
+        tracker.observe(&wg(&[LOCAL, &peer_a(1500)]), 1_560_000_060);
+        // the interface has been restarted
+        tracker.observe(&wg(&[LOCAL, &peer_a(200)]), 1_560_000_120);
+        std::fs::remove_file(&path).unwrap();
+
+        let usage = usage.usage(0, u64::MAX, Period::Hour);
+        assert_eq!(usage["usage"][0]["sent_bytes"], 700);
+        assert_eq!(usage["usage"][0]["received_bytes"], 700);
+    }
+
+    #[test]
+    fn test_history() {
+        let tracker = PeerTracker::new(None, None, 2, None, Duration::default());
+        let peer_a = |bytes: u64| {
+            format!(
+                "wg0\tA=\t(none)\t(none)\t10.70.0.2/32\t0\t{}\t{}\toff\n",
+                bytes,
+                bytes * 2
+            )
+        };
+
+        tracker.observe(&wg(&[LOCAL, &peer_a(10), PEER_B]), 1000);
+        tracker.observe(&wg(&[LOCAL, &peer_a(20), PEER_B]), 1010);
+        tracker.observe(&wg(&[LOCAL, &peer_a(30)]), 1020);
+
+        // the first collection is gone
+        assert_eq!(
+            tracker.history(0),
+            json!({
+                "peers": [
+                    {
+                        "interface": "wg0",
+                        "public_key": "A=",
+                        "samples": [
+                            { "time": 1010, "sent_bytes": 20, "received_bytes": 40 },
+                            { "time": 1020, "sent_bytes": 30, "received_bytes": 60 },
+                        ],
+                    },
+                    {
+                        "interface": "wg0",
+                        "public_key": "B=",
+                        "samples": [{ "time": 1010, "sent_bytes": 0, "received_bytes": 0 }],
+                    },
+                ],
+            })
+        );
+        assert_eq!(
+            tracker.history(1015)["peers"][0]["samples"][0]["time"],
+            1020
+        );
+        assert_eq!(PeerTracker::default().history(0), json!({ "peers": [] }));
+    }
+
+    #[test]
+    fn test_diff() {
+        let tracker = PeerTracker::default();
+        assert_eq!(tracker.diff(0), None);
+
+        tracker.observe(&wg(&[LOCAL, PEER_A, PEER_B]), 1000);
+        tracker.observe(
+            &wg(&[
+                LOCAL,
+                "wg0\tA=\t(none)\t1.2.3.4:51820\t10.70.0.2/32\t0\t100\t50\toff\n",
+                PEER_C,
+            ]),
+            1030,
+        );
+        // too close to the previous one to be retained for the diffs
+        assert_eq!(tracker.state.lock().unwrap().history.len(), 1);
+
+        let diff = tracker.diff(1010).unwrap();
+        assert_eq!(
+            diff,
+            json!({
+                "since": 1000,
+                "until": 1030,
+                "added": [{ "interface": "wg1", "public_key": "C=" }],
+                "removed": [{ "interface": "wg0", "public_key": "B=" }],
+                "endpoint_changed": [{
+                    "interface": "wg0",
+                    "public_key": "A=",
+                    "old_endpoint": null,
+                    "new_endpoint": "1.2.3.4:51820",
+                }],
+                "counters_changed": [{
+                    "interface": "wg0",
+                    "public_key": "A=",
+                    "sent_bytes": 100,
+                    "received_bytes": 50,
+                    "sent_bytes_delta": 100,
+                    "received_bytes_delta": 50,
+                }],
+            })
+        );
+
+        // before the oldest retained collection
+        assert_eq!(tracker.diff(0).unwrap()["since"], 1000);
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let path = std::env::temp_dir().join(format!("audit-test-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let tracker = PeerTracker::new(
+            Some(RotatingFile::open(&path, Default::default()).unwrap()),
+            None,
+            0,
+            None,
+            Duration::default(),
+        );
+        tracker.observe(&wg(&[LOCAL, PEER_A, PEER_B]), 1_560_000_000);
+        tracker.observe(
+            &wg(&[
+                LOCAL,
+                "wg0\tA=\t(none)\t1.2.3.4:51820\t10.70.0.2/32\t0\t0\t0\toff\n",
+            ]),
+            1_560_000_060,
+        );
+
+        let records: Vec<Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            records,
+            vec![
+                json!({
+                    "time": "2019-06-08T13:21:00Z",
+                    "event": "endpoint_changed",
+                    "interface": "wg0",
+                    "public_key": "A=",
+                    "old_endpoint": null,
+                    "new_endpoint": "1.2.3.4:51820",
+                }),
+                json!({
+                    "time": "2019-06-08T13:21:00Z",
+                    "event": "removed",
+                    "interface": "wg0",
+                    "public_key": "B=",
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_observe() {
+        // the implementation does not depend on the host running the tests
+        implementation::disable();
+        let tracker = PeerTracker::new(None, None, 0, None, Duration::from_secs(60));
+
+        assert_eq!(tracker.observe(&wg(&[LOCAL, PEER_A]), 0), vec![]);
+        assert_eq!(tracker.observe(&wg(&[LOCAL, PEER_A]), 10), vec![]);
+        assert_eq!(
+            tracker.observe(&wg(&[LOCAL, PEER_B, PEER_C]), 20),
+            vec![
+                PeerEvent::InterfaceAdded {
+                    interface: "wg1".to_owned()
+                },
+                PeerEvent::Added {
+                    interface: "wg0".to_owned(),
+                    public_key: "B=".to_owned()
+                },
+                PeerEvent::Added {
+                    interface: "wg1".to_owned(),
+                    public_key: "C=".to_owned()
+                },
+                PeerEvent::Removed {
+                    interface: "wg0".to_owned(),
+                    public_key: "A=".to_owned()
+                },
+            ]
+        );
+        tracker.observe(&wg(&[LOCAL, PEER_B]), 30);
+
+        let mut registry = Registry::default();
+        tracker.render(&mut registry);
+        assert_eq!(
+            registry.render_text(),
+            "# HELP wireguard_peers_added_total Peers that appeared since the exporter started\n# TYPE wireguard_peers_added_total counter\nwireguard_peers_added_total{interface=\"wg0\"} 1\nwireguard_peers_added_total{interface=\"wg1\"} 1\n# HELP wireguard_peers_removed_total Peers that disappeared since the exporter started\n# TYPE wireguard_peers_removed_total counter\nwireguard_peers_removed_total{interface=\"wg0\"} 1\nwireguard_peers_removed_total{interface=\"wg1\"} 1\n# HELP wireguard_interface_key_rotations_total Changes of the interface public key since the exporter started\n# TYPE wireguard_interface_key_rotations_total counter\nwireguard_interface_key_rotations_total{interface=\"wg0\"} 0\n# HELP wireguard_interface_info Current public key and implementation of the interface\n# TYPE wireguard_interface_info gauge\nwireguard_interface_info{interface=\"wg0\",public_key=\"000q4qAC0ExW/BuGSmVR1nxH9JAXT6g9Wd3oEGy5lA=\",implementation=\"unknown\"} 1\n# HELP wireguard_peer_session_duration_seconds Estimated duration of the current session of the peer, 0 if offline\n# TYPE wireguard_peer_session_duration_seconds gauge\n"
+        );
+    }
+
+    #[test]
+    fn test_update_sessions() {
+        let tracker = PeerTracker::default();
+        let handshake_timeout = Duration::from_secs(180);
+        let peer_a = |latest_handshake: u64, bytes: u64| {
+            format!(
+                "wg0\tA=\t(none)\t1.2.3.4:51820\t10.70.0.2/32\t{}\t{}\t{}\toff\n",
+                latest_handshake, bytes, bytes
+            )
+        };
+        let session_durations = |tracker: &PeerTracker| {
+            let mut registry = Registry::default();
+            tracker.render(&mut registry);
+            registry
+                .render_text()
+                .lines()
+                .filter(|line| line.starts_with("wireguard_peer_session_duration_seconds{"))
+                .map(|line| line.rsplit(' ').next().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        // A is online, B never completed a handshake
+        tracker.update_sessions(
+            &wg(&[LOCAL, &peer_a(900, 0), PEER_B]),
+            1000,
+            handshake_timeout,
+        );
+        assert_eq!(session_durations(&tracker), vec!["100", "0"]);
+
+        tracker.update_sessions(&wg(&[LOCAL, &peer_a(1050, 10)]), 1100, handshake_timeout);
+        assert_eq!(session_durations(&tracker), vec!["200"]);
+
+        // the handshake is too old but the traffic goes on
+        tracker.update_sessions(&wg(&[LOCAL, &peer_a(1050, 20)]), 1300, handshake_timeout);
+        assert_eq!(session_durations(&tracker), vec!["400"]);
+
+        // offline
+        tracker.update_sessions(&wg(&[LOCAL, &peer_a(1050, 20)]), 1500, handshake_timeout);
+        assert_eq!(session_durations(&tracker), vec!["0"]);
+
+        // a new session
+        tracker.update_sessions(&wg(&[LOCAL, &peer_a(1590, 30)]), 1600, handshake_timeout);
+        assert_eq!(session_durations(&tracker), vec!["10"]);
+    }
+
+    #[test]
+    fn test_interface_key_rotation() {
+        implementation::disable();
+        let tracker = PeerTracker::default();
+        let rotated = "wg0\tNEWq4qAC0ExW/BuGSmVR1nxH9JAXT6g9Wd3oEGy5lA=\t0000u8LWR682knVm350lnuqlCJzw5SNLW9Nf96P+m8=\t51820\toff\n";
+
+        tracker.observe(&wg(&[LOCAL, PEER_A]), 0);
+        assert_eq!(
+            tracker.observe(&wg(&[rotated, PEER_A]), 10),
+            vec![PeerEvent::InterfaceKeyChanged {
+                interface: "wg0".to_owned(),
+                old_public_key: "000q4qAC0ExW/BuGSmVR1nxH9JAXT6g9Wd3oEGy5lA=".to_owned(),
+                new_public_key: "NEWq4qAC0ExW/BuGSmVR1nxH9JAXT6g9Wd3oEGy5lA=".to_owned(),
+            }]
+        );
+        assert_eq!(tracker.observe(&wg(&[rotated, PEER_A]), 20), vec![]);
+
+        let mut registry = Registry::default();
+        tracker.render(&mut registry);
+        let s = registry.render_text();
+        assert!(s.contains("wireguard_interface_key_rotations_total{interface=\"wg0\"} 1\n"));
+        assert!(s.contains("wireguard_interface_info{interface=\"wg0\",public_key=\"NEWq4qAC0ExW/BuGSmVR1nxH9JAXT6g9Wd3oEGy5lA=\",implementation=\"unknown\"} 1\n"));
+    }
+
+    #[test]
+    fn test_interfaces_coming_and_going() {
+        let tracker = PeerTracker::new(None, None, 0, None, Duration::from_secs(60));
+        let removed = |interface: &str| PeerEvent::Removed {
+            interface: interface.to_owned(),
+            public_key: "C=".to_owned(),
+        };
+        let rendered = |tracker: &PeerTracker| {
+            let mut registry = Registry::default();
+            tracker.render(&mut registry);
+            registry.render_text()
+        };
+
+        tracker.observe(&wg(&[LOCAL, PEER_A]), 1000);
+        assert_eq!(
+            tracker.observe(&wg(&[LOCAL, PEER_A, PEER_C]), 1010),
+            vec![
+                PeerEvent::InterfaceAdded {
+                    interface: "wg1".to_owned()
+                },
+                PeerEvent::Added {
+                    interface: "wg1".to_owned(),
+                    public_key: "C=".to_owned(),
+                },
+            ]
+        );
+        assert_eq!(
+            tracker.observe(&wg(&[LOCAL, PEER_A]), 1020),
+            vec![
+                PeerEvent::InterfaceRemoved {
+                    interface: "wg1".to_owned()
+                },
+                removed("wg1"),
+            ]
+        );
+        assert_eq!(
+            PeerEvent::InterfaceRemoved {
+                interface: "wg1".to_owned()
+            }
+            .to_string(),
+            "interface wg1 removed"
+        );
+
+        // still rendered during the grace period
+        tracker.observe(&wg(&[LOCAL, PEER_A]), 1070);
+        assert!(rendered(&tracker).contains("wireguard_peers_removed_total{interface=\"wg1\"} 1\n"));
+
+        tracker.observe(&wg(&[LOCAL, PEER_A]), 1080);
+        let s = rendered(&tracker);
+        assert!(!s.contains("interface=\"wg1\""));
+        assert!(s.contains("wireguard_peers_added_total{interface=\"wg0\"} 0\n"));
+
+        // starting over
+        tracker.observe(&wg(&[LOCAL, PEER_A, PEER_C]), 1090);
+        assert!(rendered(&tracker).contains("wireguard_peers_added_total{interface=\"wg1\"} 1\n"));
+    }
+}
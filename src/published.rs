@@ -0,0 +1,42 @@
+use std::sync::{Arc, RwLock};
+
+/// A value built by a writer and read by many. The readers take the
+/// current version, an immutable `Arc`, and work on it without holding
+/// anything: they never wait for the writer building the next version,
+/// which is swapped in whole once ready. The lock only guards the swap of
+/// the pointer.
+#[derive(Debug, Default)]
+pub(crate) struct Published<T> {
+    current: RwLock<Arc<T>>,
+}
+
+impl<T> Published<T> {
+    /// The current version.
+    pub fn load(&self) -> Arc<T> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Replaces the current version, the readers holding the previous one
+    /// keep it until they are done.
+    pub fn store(&self, value: T) {
+        let value = Arc::new(value);
+        // the previous version, if no longer read, is freed out of the lock
+        let previous = std::mem::replace(&mut *self.current.write().unwrap(), value);
+        drop(previous);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_published() {
+        let published = Published::default();
+        published.store(vec![1]);
+        let old = published.load();
+        published.store(vec![1, 2]);
+        assert_eq!(*old, vec![1]);
+        assert_eq!(*published.load(), vec![1, 2]);
+    }
+}
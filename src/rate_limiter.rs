@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+// above this many tracked clients the expired windows are purged, so
+// a port scan from many addresses cannot make the map grow forever.
+const PURGE_THRESHOLD: usize = 1024;
+
+/// Fixed window rate limiter: every client IP can perform up to
+/// `requests_per_minute` requests in each one minute window.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    requests_per_minute: u32,
+    clients: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_minute: u32) -> RateLimiter {
+        RateLimiter {
+            requests_per_minute,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Accounts a request from `ip`. Returns `Err` with the time left in the
+    /// current window if the client exceeded its quota.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut clients = self.clients.lock().unwrap();
+
+        if clients.len() > PURGE_THRESHOLD {
+            clients.retain(|_, (window_start, _)| now.duration_since(*window_start) < WINDOW);
+        }
+
+        let (window_start, count) = clients.entry(ip).or_insert((now, 0));
+        if now.duration_since(*window_start) >= WINDOW {
+            *window_start = now;
+            *count = 0;
+        }
+
+        if *count >= self.requests_per_minute {
+            Err(WINDOW - now.duration_since(*window_start))
+        } else {
+            *count += 1;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit() {
+        let rl = RateLimiter::new(2);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "::1".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(rl.check_at(a, now).is_ok());
+        assert!(rl.check_at(a, now).is_ok());
+        assert!(rl.check_at(a, now + Duration::from_secs(10)).is_err());
+        // other clients have their own quota
+        assert!(rl.check_at(b, now).is_ok());
+        // a new window resets the count
+        assert!(rl.check_at(a, now + WINDOW).is_ok());
+    }
+}
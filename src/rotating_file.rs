@@ -0,0 +1,191 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct RotationPolicy {
+    /// rotate when the file would grow past this size (in bytes)
+    pub max_size: Option<u64>,
+    /// rotate when the file has been written to for longer than this
+    pub max_age: Option<Duration>,
+    /// how many rotated files to retain (file.1 being the most recent)
+    pub keep: usize,
+}
+
+/// An append-only file that is rotated according to a `RotationPolicy`.
+/// Rotated files get a numeric suffix: `file.1`, `file.2` and so on; the
+/// ones beyond `keep` are deleted.
+#[derive(Debug)]
+pub(crate) struct RotatingFile {
+    path: PathBuf,
+    policy: RotationPolicy,
+    file: File,
+    size: u64,
+    opened: Instant,
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(format!(".{}", index));
+    PathBuf::from(s)
+}
+
+impl RotatingFile {
+    pub fn open<P: AsRef<Path>>(path: P, policy: RotationPolicy) -> io::Result<RotatingFile> {
+        let path = path.as_ref().to_owned();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(RotatingFile {
+            path,
+            policy,
+            file,
+            size,
+            opened: Instant::now(),
+        })
+    }
+
+    fn must_rotate(&self, incoming: usize) -> bool {
+        if self.size == 0 {
+            // never rotate an empty file, even if the record alone is too big
+            return false;
+        }
+
+        let too_big = self
+            .policy
+            .max_size
+            .map(|max_size| self.size + incoming as u64 > max_size)
+            .unwrap_or(false);
+        let too_old = self
+            .policy
+            .max_age
+            .map(|max_age| self.opened.elapsed() >= max_age)
+            .unwrap_or(false);
+
+        too_big || too_old
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.policy.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            // the oldest one goes away, the others are shifted by one
+            let oldest = rotated_path(&self.path, self.policy.keep);
+            if oldest.exists() {
+                fs::remove_file(&oldest)?;
+            }
+            for index in (1..self.policy.keep).rev() {
+                let from = rotated_path(&self.path, index);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(&self.path, index + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.must_rotate(buf.len()) {
+            self.rotate()?;
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "prometheus_wireguard_exporter_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_rotate_by_size() {
+        let dir = test_dir("rotate_by_size");
+        let path = dir.join("test.log");
+
+        let mut file = RotatingFile::open(
+            &path,
+            RotationPolicy {
+                max_size: Some(10),
+                max_age: None,
+                keep: 2,
+            },
+        )
+        .unwrap();
+
+        for line in &["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 1)).unwrap(),
+            "third\n"
+        );
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 2)).unwrap(),
+            "second\n"
+        );
+        assert!(!rotated_path(&path, 3).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_by_age() {
+        let dir = test_dir("rotate_by_age");
+        let path = dir.join("test.log");
+
+        let mut file = RotatingFile::open(
+            &path,
+            RotationPolicy {
+                max_size: None,
+                max_age: Some(Duration::from_secs(0)),
+                keep: 1,
+            },
+        )
+        .unwrap();
+
+        file.write_all(b"first\n").unwrap();
+        file.write_all(b"second\n").unwrap();
+        file.flush().unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second\n");
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 1)).unwrap(),
+            "first\n"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
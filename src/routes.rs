@@ -0,0 +1,214 @@
+// Checks that the allowed IPs of the peers are routed through their
+// interface, as wg-quick sets up when the interface comes up.
+use crate::metrics::{MetricType, Registry};
+use crate::wireguard::{Endpoint, WireGuard};
+use log::debug;
+use serde_json::Value;
+use std::io;
+use std::net::IpAddr;
+use std::process::Command;
+
+/// An address range, as the address bits (left aligned in the width of
+/// the family) and the prefix length.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Range {
+    v6: bool,
+    bits: u128,
+    len: u8,
+}
+
+impl Range {
+    /// Parses `10.70.0.0/24` or a bare address, a host range.
+    pub fn parse(s: &str) -> Option<Range> {
+        let mut split = s.splitn(2, '/');
+        let (v6, bits, max_len) = match split.next()?.parse::<IpAddr>().ok()? {
+            IpAddr::V4(ip) => (false, u128::from(u32::from(ip)) << 96, 32),
+            IpAddr::V6(ip) => (true, u128::from(ip), 128),
+        };
+        let len = match split.next() {
+            Some(len) => len.parse().ok().filter(|len| *len <= max_len)?,
+            None => max_len,
+        };
+        Some(Range { v6, bits, len })
+    }
+
+    fn default_route(v6: bool) -> Range {
+        Range {
+            v6,
+            bits: 0,
+            len: 0,
+        }
+    }
+
+    /// Whether `other` lies entirely within this range.
+    pub fn contains(&self, other: &Range) -> bool {
+        if self.v6 != other.v6 || self.len > other.len {
+            return false;
+        }
+        let mask = u128::MAX
+            .checked_shl(128 - u32::from(self.len))
+            .unwrap_or(0);
+        self.bits & mask == other.bits & mask
+    }
+}
+
+/// A route of the kernel, of any table.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Route {
+    pub dev: String,
+    pub dst: Range,
+}
+
+/// Parses the output of `ip -j route show table all`. Only the unicast
+/// routes forward traffic to a device, the others are ignored.
+pub(crate) fn parse_routes(text: &str, v6: bool) -> serde_json::Result<Vec<Route>> {
+    let value: Value = serde_json::from_str(text)?;
+    let routes = value
+        .as_array()
+        .map(|routes| routes.iter())
+        .into_iter()
+        .flatten()
+        .filter(|route| route["type"].as_str().unwrap_or("unicast") == "unicast")
+        .filter_map(|route| {
+            let dst = match route["dst"].as_str()? {
+                "default" => Range::default_route(v6),
+                dst => Range::parse(dst)?,
+            };
+            Some(Route {
+                dev: route["dev"].as_str()?.to_owned(),
+                dst,
+            })
+        })
+        .collect();
+    Ok(routes)
+}
+
+fn ip(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("ip").args(args).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Reads the IPv4 and IPv6 routes of all the tables, policy routing
+/// being how wg-quick routes the peers allowing `0.0.0.0/0`.
+pub(crate) fn read() -> io::Result<Vec<Route>> {
+    let mut routes = Vec::new();
+    for (family, v6) in &[("-4", false), ("-6", true)] {
+        let text = ip(&["-j", family, "route", "show", "table", "all"])?;
+        routes.extend(
+            parse_routes(&text, *v6).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        );
+    }
+    Ok(routes)
+}
+
+/// Renders, for every peer with allowed IPs, whether all of them are
+/// covered by a route through the interface of the peer.
+pub(crate) fn render(registry: &mut Registry, wg: &WireGuard, routes: &[Route]) {
+    let mut present = registry.family(
+        "wireguard_peer_route_present",
+        MetricType::Gauge,
+        "Whether all the allowed IPs of the peer are routed through its interface",
+    );
+
+    let mut interfaces: Vec<_> = wg.interfaces.iter().collect();
+    interfaces.sort_by_key(|(interface, _)| *interface);
+
+    for (interface, endpoints) in interfaces {
+        for endpoint in endpoints {
+            if let Endpoint::Remote(ep) = endpoint {
+                let ranges: Vec<(&str, Range)> = ep
+                    .allowed_ips
+                    .iter()
+                    .filter_map(|allowed_ip| Some((allowed_ip as &str, Range::parse(allowed_ip)?)))
+                    .collect();
+                if ranges.is_empty() {
+                    continue;
+                }
+
+                let missing: Vec<&str> = ranges
+                    .iter()
+                    .filter(|(_, range)| {
+                        !routes
+                            .iter()
+                            .any(|route| route.dev == **interface && route.dst.contains(range))
+                    })
+                    .map(|(allowed_ip, _)| *allowed_ip)
+                    .collect();
+                if !missing.is_empty() {
+                    debug!(
+                        "no route through {} for {} of peer {}",
+                        interface,
+                        missing.join(", "),
+                        ep.public_key
+                    );
+                }
+
+                present.push(
+                    &[
+                        ("interface", interface as &str),
+                        ("public_key", &ep.public_key),
+                    ],
+                    if missing.is_empty() { 1.0 } else { 0.0 },
+                );
+            }
+        }
+    }
+
+    registry.register(present);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn test_range() {
+        let subnet = Range::parse("10.70.0.0/24").unwrap();
+        assert!(subnet.contains(&Range::parse("10.70.0.2/32").unwrap()));
+        assert!(subnet.contains(&Range::parse("10.70.0.2").unwrap()));
+        assert!(!subnet.contains(&Range::parse("10.70.1.2/32").unwrap()));
+        assert!(!subnet.contains(&Range::parse("10.70.0.0/16").unwrap()));
+        assert!(!subnet.contains(&Range::parse("::/0").unwrap()));
+        assert!(Range::default_route(true).contains(&Range::parse("fd00::2/128").unwrap()));
+        assert!(Range::parse("fd00::/64")
+            .unwrap()
+            .contains(&Range::parse("fd00::2/128").unwrap()));
+        assert_eq!(Range::parse("10.70.0.0/33"), None);
+    }
+
+    #[test]
+    fn test_render() {
+        let routes = parse_routes(
+            r#"[{"dst":"default","gateway":"192.168.1.1","dev":"eth0","protocol":"dhcp","flags":[]},
+                {"dst":"10.70.0.0/24","dev":"wg0","scope":"link","flags":[]},
+                {"type":"local","dst":"10.70.0.1","table":"local","dev":"wg0","flags":[]},
+                {"type":"blackhole","dst":"10.99.0.0/16","flags":[]}]"#,
+            false,
+        )
+        .unwrap();
+        assert_eq!(routes.len(), 2);
+
+        let wg = WireGuard::try_from(
+            "wg0\tA=\t(none)\t(none)\t10.70.0.2/32\t0\t0\t0\toff
+wg0\tB=\t(none)\t(none)\t10.70.0.3/32,10.80.0.0/24\t0\t0\t0\toff
+",
+        )
+        .unwrap();
+
+        let mut registry = Registry::default();
+        render(&mut registry, &wg, &routes);
+        let text = registry.render_text();
+        assert!(
+            text.contains("wireguard_peer_route_present{interface=\"wg0\",public_key=\"A=\"} 1\n")
+        );
+        assert!(
+            text.contains("wireguard_peer_route_present{interface=\"wg0\",public_key=\"B=\"} 0\n")
+        );
+    }
+}
@@ -0,0 +1,237 @@
+use crate::exporter_error::ExporterError;
+use crate::outputs::Sink;
+use crate::wireguard::WireGuard;
+use crate::wireguard_config::PeerEntries;
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use log::debug;
+use serde_json::{json, Map, Value};
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Debug, Default)]
+struct SamplerState {
+    latest: Option<Value>,
+    subscribers: Vec<UnboundedSender<String>>,
+}
+
+/// Fed with the peers every `interval` as an output, streams the changes
+/// to its subscribers as JSON patches (RFC 6902).
+#[derive(Debug, Default)]
+pub(crate) struct Sampler {
+    interval: Duration,
+    state: Mutex<SamplerState>,
+}
+
+// escapes a key so it can be used as a JSON pointer (RFC 6901)
+// token. Public keys contain slashes so this is needed.
+fn escape_pointer(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+/// Returns the JSON patch operations that transform `old` into `new`.
+/// Objects are compared key by key, everything else is replaced as a whole.
+pub(crate) fn diff(path: &str, old: &Value, new: &Value, ops: &mut Vec<Value>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            for (key, old_value) in old.iter() {
+                let key_path = format!("{}/{}", path, escape_pointer(key));
+                match new.get(key) {
+                    Some(new_value) => diff(&key_path, old_value, new_value, ops),
+                    None => ops.push(json!({ "op": "remove", "path": key_path })),
+                }
+            }
+            for (key, new_value) in new.iter() {
+                if !old.contains_key(key) {
+                    let key_path = format!("{}/{}", path, escape_pointer(key));
+                    ops.push(json!({ "op": "add", "path": key_path, "value": new_value }));
+                }
+            }
+        }
+        _ => {
+            if old != new {
+                ops.push(json!({ "op": "replace", "path": path, "value": new }));
+            }
+        }
+    }
+}
+
+/// Turns the `/api/v1/peers` document into an object keyed by
+/// interface and public key, so the peers can be diffed by identity.
+pub(crate) fn index_peers(peers: &Value) -> Value {
+    let mut interfaces = Map::new();
+
+    if let Some(peers) = peers["peers"].as_array() {
+        for peer in peers {
+            let interface = peer["interface"].as_str().unwrap_or_default();
+            let public_key = peer["public_key"].as_str().unwrap_or_default();
+
+            if let Value::Object(peers) = interfaces
+                .entry(interface.to_owned())
+                .or_insert_with(|| Value::Object(Map::new()))
+            {
+                peers.insert(public_key.to_owned(), peer.clone());
+            }
+        }
+    }
+
+    Value::Object(interfaces)
+}
+
+impl Sampler {
+    pub fn new(interval: Duration) -> Sampler {
+        Sampler {
+            interval,
+            ..Sampler::default()
+        }
+    }
+
+    fn publish(&self, sample: Value) {
+        let mut state = self.state.lock().unwrap();
+
+        let mut ops = Vec::new();
+        match &state.latest {
+            Some(latest) => diff("", latest, &sample, &mut ops),
+            None => ops.push(json!({ "op": "replace", "path": "", "value": sample })),
+        }
+        state.latest = Some(sample);
+
+        if ops.is_empty() {
+            return;
+        }
+
+        let message = Value::Array(ops).to_string();
+        debug!(
+            "publishing {} to {} subscribers",
+            message,
+            state.subscribers.len()
+        );
+
+        // the subscribers that went away are dropped here
+        state
+            .subscribers
+            .retain(|subscriber| subscriber.unbounded_send(message.clone()).is_ok());
+    }
+
+    /// Returns a stream of JSON patches. The first one replaces the whole
+    /// document with the latest sample (if any), the following ones apply
+    /// the changes observed by the sampler.
+    pub fn subscribe(&self) -> UnboundedReceiver<String> {
+        let (sender, receiver) = unbounded();
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(latest) = &state.latest {
+            let message = json!([{ "op": "replace", "path": "", "value": latest }]).to_string();
+            // cannot fail, we hold the receiver
+            let _ = sender.unbounded_send(message);
+        }
+
+        state.subscribers.push(sender);
+        receiver
+    }
+}
+
+impl Sink for Sampler {
+    fn name(&self) -> &'static str {
+        "websocket"
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn emit(&self, wg: &WireGuard, pehm: Option<&PeerEntries>) -> Result<(), ExporterError> {
+        self.publish(index_peers(&wg.render_json_with_names(pehm)));
+        Ok(())
+    }
+
+    fn busy(&self) -> bool {
+        !self.state.lock().unwrap().subscribers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{Async, Future, Stream};
+
+    #[test]
+    fn test_diff() {
+        let old = json!({
+            "wg0": {
+                "a/b=": { "sent_bytes": 10, "remote_ip": "1.1.1.1" },
+                "gone=": { "sent_bytes": 0 },
+            }
+        });
+        let new = json!({
+            "wg0": {
+                "a/b=": { "sent_bytes": 20, "remote_ip": "1.1.1.1" },
+            },
+            "wg1": {
+                "new=": { "sent_bytes": 0 },
+            }
+        });
+
+        let mut ops = Vec::new();
+        diff("", &old, &new, &mut ops);
+
+        assert_eq!(
+            Value::Array(ops),
+            json!([
+                { "op": "replace", "path": "/wg0/a~1b=/sent_bytes", "value": 20 },
+                { "op": "remove", "path": "/wg0/gone=" },
+                { "op": "add", "path": "/wg1", "value": { "new=": { "sent_bytes": 0 } } },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_index_peers() {
+        let peers = json!({ "peers": [
+            { "interface": "wg0", "public_key": "a", "sent_bytes": 1 },
+            { "interface": "wg0", "public_key": "b", "sent_bytes": 2 },
+            { "interface": "wg1", "public_key": "a", "sent_bytes": 3 },
+        ]});
+
+        assert_eq!(
+            index_peers(&peers),
+            json!({
+                "wg0": {
+                    "a": { "interface": "wg0", "public_key": "a", "sent_bytes": 1 },
+                    "b": { "interface": "wg0", "public_key": "b", "sent_bytes": 2 },
+                },
+                "wg1": {
+                    "a": { "interface": "wg1", "public_key": "a", "sent_bytes": 3 },
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let sampler = Sampler::default();
+        sampler.publish(json!({ "wg0": {} }));
+
+        let mut receiver = sampler.subscribe();
+        sampler.publish(json!({ "wg0": {} }));
+        sampler.publish(json!({ "wg0": { "a": 1 } }));
+        drop(sampler);
+
+        let messages: Vec<String> = futures::future::lazy(move || {
+            let mut messages = Vec::new();
+            while let Ok(Async::Ready(Some(message))) = receiver.poll() {
+                messages.push(message);
+            }
+            Ok::<_, ()>(messages)
+        })
+        .wait()
+        .unwrap();
+
+        assert_eq!(
+            messages,
+            vec![
+                r#"[{"op":"replace","path":"","value":{"wg0":{}}}]"#.to_owned(),
+                r#"[{"op":"add","path":"/wg0/a","value":1}]"#.to_owned(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,682 @@
+// Restrictions the exporter applies to itself once initialized: a seccomp
+// filter denying the system calls neither the exporter nor the tools it
+// runs (wg, tc, ip...) need, Landlock rules limiting the filesystem to
+// the paths it uses, and in read-only mode the capabilities dropped to
+// those reading the interfaces needs. All are inherited by the threads
+// spawned later and by the child processes.
+use crate::firewall::Firewall;
+use crate::options::{Collector, Options};
+use crate::service;
+use log::{debug, info, warn};
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+fn cvt(ret: libc::c_long) -> io::Result<libc::c_long> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn set_no_new_privs() -> io::Result<()> {
+    cvt(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) }.into())?;
+    Ok(())
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: libc::c_ushort,
+    filter: *const SockFilter,
+}
+
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_JMP_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_SET_MODE_FILTER: libc::c_long = 1;
+const SECCOMP_FILTER_FLAG_TSYNC: libc::c_long = 1;
+// the offsets in struct seccomp_data
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+// the low half of the first argument, on the little endian architectures
+const SECCOMP_DATA_ARGS: u32 = 16;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+// system administration, tracing, kernel modules and namespaces: the
+// calls an attacker taking over the process would need to go further
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_acct,
+    libc::SYS_add_key,
+    libc::SYS_adjtimex,
+    libc::SYS_chroot,
+    libc::SYS_clock_adjtime,
+    libc::SYS_clock_settime,
+    libc::SYS_delete_module,
+    libc::SYS_finit_module,
+    libc::SYS_init_module,
+    libc::SYS_kcmp,
+    libc::SYS_kexec_load,
+    libc::SYS_keyctl,
+    libc::SYS_lookup_dcookie,
+    libc::SYS_mount,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_perf_event_open,
+    libc::SYS_pivot_root,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_ptrace,
+    libc::SYS_quotactl,
+    libc::SYS_reboot,
+    libc::SYS_request_key,
+    libc::SYS_setdomainname,
+    libc::SYS_sethostname,
+    libc::SYS_setns,
+    libc::SYS_settimeofday,
+    libc::SYS_swapoff,
+    libc::SYS_swapon,
+    libc::SYS_syslog,
+    libc::SYS_umount2,
+    libc::SYS_unshare,
+    libc::SYS_userfaultfd,
+    libc::SYS_vhangup,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_kexec_file_load,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_iopl,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_ioperm,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_uselib,
+    // kexec_file_load, missing from libc
+    #[cfg(target_arch = "aarch64")]
+    294,
+];
+
+// the families of the sockets the exporter and the tools it runs open:
+// syslog and the userspace WireGuard sockets, name resolution and the
+// listener, the netlink queries of wg, ip and tc
+const SANDBOX_SOCKET_FAMILIES: &[libc::c_int] = &[
+    libc::AF_UNIX,
+    libc::AF_INET,
+    libc::AF_INET6,
+    libc::AF_NETLINK,
+];
+
+/// Builds the filter denying `denied` with EPERM, as well as every call
+/// made with another ABI (such as the 32 bit ones), which could be used
+/// to get around the list. Unless `socket_families` is empty, the
+/// sockets of the other families are denied too.
+fn seccomp_program(
+    arch: u32,
+    denied: &[libc::c_long],
+    socket: libc::c_long,
+    socket_families: &[libc::c_int],
+) -> Vec<SockFilter> {
+    let stmt = |code, k| SockFilter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let jump = |code, k, jt: usize, jf: usize| SockFilter {
+        code,
+        jt: jt as u8,
+        jf: jf as u8,
+        k,
+    };
+    let deny = stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32);
+    let mut program = vec![
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+        jump(BPF_JMP_JEQ_K, arch, 1, 0),
+        deny,
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+    ];
+
+    // the x32 calls are flagged with this bit
+    let mut checks = vec![(BPF_JMP_JGE_K, 0x4000_0000)];
+    checks.extend(denied.iter().map(|nr| (BPF_JMP_JEQ_K, *nr as u32)));
+    // socket, the load of its family, the checks and their deny
+    let socket_len = if socket_families.is_empty() {
+        0
+    } else {
+        socket_families.len() + 3
+    };
+    // each check jumps over the remaining ones, the socket checks and
+    // the allow to the deny
+    for (i, (code, k)) in checks.iter().enumerate() {
+        program.push(jump(*code, *k, checks.len() - i + socket_len, 0));
+    }
+    if !socket_families.is_empty() {
+        let n = socket_families.len();
+        program.push(jump(BPF_JMP_JEQ_K, socket as u32, 0, n + 2));
+        program.push(stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARGS));
+        for (i, family) in socket_families.iter().enumerate() {
+            program.push(jump(BPF_JMP_JEQ_K, *family as u32, n - i, 0));
+        }
+        program.push(deny);
+    }
+    program.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+    program.push(deny);
+    program
+}
+
+/// Installs the seccomp filter on all the threads of the process, with
+/// the socket families restricted in `--sandbox` mode.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub(crate) fn apply_seccomp(options: &Options) -> io::Result<()> {
+    let socket_families = if options.sandbox {
+        SANDBOX_SOCKET_FAMILIES
+    } else {
+        &[]
+    };
+    let program = seccomp_program(
+        AUDIT_ARCH,
+        DENIED_SYSCALLS,
+        libc::SYS_socket,
+        socket_families,
+    );
+    let fprog = SockFprog {
+        len: program.len() as libc::c_ushort,
+        filter: program.as_ptr(),
+    };
+    set_no_new_privs()?;
+    cvt(unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            SECCOMP_SET_MODE_FILTER,
+            SECCOMP_FILTER_FLAG_TSYNC,
+            &fprog as *const SockFprog,
+        )
+    })?;
+    info!(
+        "seccomp filter installed, {} system calls denied{}",
+        DENIED_SYSCALLS.len(),
+        if options.sandbox {
+            " and sockets restricted"
+        } else {
+            ""
+        }
+    );
+    Ok(())
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub(crate) fn apply_seccomp(_options: &Options) -> io::Result<()> {
+    Err(io::Error::other(
+        "the seccomp filter is only available on x86_64 and aarch64",
+    ))
+}
+
+// the Landlock system calls have the same numbers on every architecture
+const SYS_LANDLOCK_CREATE_RULESET: libc::c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: libc::c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: libc::c_long = 446;
+const LANDLOCK_CREATE_RULESET_VERSION: libc::c_long = 1;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_long = 1;
+const LANDLOCK_RULE_NET_PORT: libc::c_long = 2;
+// the first version of the ABI restricting TCP
+const LANDLOCK_NET_ABI: libc::c_long = 4;
+
+const ACCESS_EXECUTE: u64 = 1;
+const ACCESS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_READ_FILE: u64 = 1 << 2;
+const ACCESS_READ_DIR: u64 = 1 << 3;
+const ACCESS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_MAKE_REG: u64 = 1 << 8;
+// all the rights of the first version of the ABI
+const ACCESS_ALL: u64 = (1 << 13) - 1;
+// the rights that apply to files, as opposed to directories
+const ACCESS_FILE: u64 = ACCESS_EXECUTE | ACCESS_WRITE_FILE | ACCESS_READ_FILE;
+
+const ACCESS_NET_BIND_TCP: u64 = 1;
+const ACCESS_NET_CONNECT_TCP: u64 = 1 << 1;
+
+const READ: u64 = ACCESS_READ_FILE | ACCESS_READ_DIR;
+const READ_EXECUTE: u64 = READ | ACCESS_EXECUTE;
+const READ_WRITE: u64 = READ | ACCESS_WRITE_FILE | ACCESS_REMOVE_FILE | ACCESS_MAKE_REG;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+    // only known from the version 4 of the ABI
+    handled_access_net: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+#[repr(C, packed)]
+struct NetPortAttr {
+    allowed_access: u64,
+    port: u64,
+}
+
+/// The paths the exporter and the tools it runs need, with the rights
+/// they need on them.
+pub(crate) fn landlock_rules(options: &Options) -> Vec<(String, u64)> {
+    let mut rules: Vec<(String, u64)> = [
+        // the tools and their libraries
+        ("/usr", READ_EXECUTE),
+        ("/bin", READ_EXECUTE),
+        ("/sbin", READ_EXECUTE),
+        ("/lib", READ_EXECUTE),
+        ("/lib64", READ_EXECUTE),
+        ("/etc/ld.so.cache", READ),
+        // name resolution, for --dns-check-interval
+        ("/etc/hosts", READ),
+        ("/etc/resolv.conf", READ),
+        ("/etc/nsswitch.conf", READ),
+        ("/etc/gai.conf", READ),
+        ("/etc/host.conf", READ),
+        ("/etc/localtime", READ),
+        // ip and tc
+        ("/etc/iproute2", READ),
+        // the userspace implementations of WireGuard
+        ("/run/wireguard", READ),
+        ("/var/run/wireguard", READ),
+        ("/proc", READ),
+        ("/sys", READ),
+        ("/dev/null", READ | ACCESS_WRITE_FILE),
+        ("/dev/urandom", READ),
+    ]
+    .iter()
+    .map(|(path, access)| ((*path).to_owned(), *access))
+    .collect();
+
+    // wg, tc and the others may be installed elsewhere, /opt or a nix
+    // profile, and are looked up in the PATH
+    if let Some(path) = std::env::var_os("PATH") {
+        for dir in std::env::split_paths(&path) {
+            if let Some(dir) = dir.to_str().filter(|dir| dir.starts_with('/')) {
+                rules.push((dir.to_owned(), READ_EXECUTE));
+            }
+        }
+    }
+
+    // as well as where --wg-path points
+    if let Some(dir) = options
+        .wg_path
+        .as_deref()
+        .and_then(|wg_path| Path::new(wg_path).parent())
+        .and_then(|dir| dir.to_str())
+        .filter(|dir| dir.starts_with('/'))
+    {
+        rules.push((dir.to_owned(), READ_EXECUTE));
+    }
+
+    for names_file in &options.extract_names_config_files {
+        rules.push((names_file.path.clone(), READ));
+        rules.push((format!("{}.d", names_file.path), READ));
+    }
+    if let Some(state_key_file) = &options.state_key_file {
+        rules.push((state_key_file.clone(), READ));
+    }
+    // the directory of the --dump-file, which is replaced by a rename
+    // rather than rewritten in place
+    if let Some(dump_file) = options.dump_file.as_deref().filter(|path| *path != "-") {
+        let dir = Path::new(dump_file)
+            .parent()
+            .and_then(|dir| dir.to_str())
+            .filter(|dir| !dir.is_empty())
+            .unwrap_or(".");
+        rules.push((dir.to_owned(), READ));
+    }
+    for dir in service::writable_dirs(options) {
+        rules.push((dir, READ_WRITE));
+    }
+    rules
+}
+
+/// The TCP ports the exporter may bind and connect to in `--sandbox`
+/// mode: its listener and, to resolve the endpoints, the DNS servers
+/// falling back to TCP.
+pub(crate) fn landlock_net_rules(options: &Options) -> Vec<(u16, u64)> {
+    let mut rules = vec![(options.port, ACCESS_NET_BIND_TCP)];
+    if options.dns_check_interval.is_some() {
+        rules.push((53, ACCESS_NET_CONNECT_TCP));
+    }
+    rules
+}
+
+fn add_net_rule(ruleset: libc::c_int, port: u16, access: u64) -> io::Result<()> {
+    let attr = NetPortAttr {
+        allowed_access: access,
+        port: u64::from(port),
+    };
+    cvt(unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_ADD_RULE,
+            ruleset,
+            LANDLOCK_RULE_NET_PORT,
+            &attr as *const NetPortAttr,
+            0,
+        )
+    })
+    .map(|_| ())
+}
+
+fn add_rule(ruleset: libc::c_int, path: &str, access: u64) -> io::Result<()> {
+    let c_path = CString::new(Path::new(path).as_os_str().as_bytes())?;
+    let fd = cvt(unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) }.into())?
+        as libc::c_int;
+    let is_dir = Path::new(path).is_dir();
+    let attr = PathBeneathAttr {
+        allowed_access: if is_dir { access } else { access & ACCESS_FILE },
+        parent_fd: fd,
+    };
+    let added = cvt(unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_ADD_RULE,
+            ruleset,
+            LANDLOCK_RULE_PATH_BENEATH,
+            &attr as *const PathBeneathAttr,
+            0,
+        )
+    });
+    unsafe { libc::close(fd) };
+    added.map(|_| ())
+}
+
+/// Restricts the filesystem access of the process, and of the threads
+/// and processes it starts afterwards, to `landlock_rules`, and in
+/// `--sandbox` mode its TCP access to `landlock_net_rules`. Kernels
+/// without Landlock are reported and left alone.
+pub(crate) fn apply_landlock(options: &Options) -> io::Result<()> {
+    let abi = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 1 {
+        warn!("Landlock is not available: {}", io::Error::last_os_error());
+        return Ok(());
+    }
+
+    let restrict_net = options.sandbox && abi >= LANDLOCK_NET_ABI;
+    if options.sandbox && !restrict_net {
+        warn!(
+            "Landlock ABI version {} cannot restrict TCP, Linux 6.7 or later is needed",
+            abi
+        );
+    }
+    let attr = RulesetAttr {
+        handled_access_fs: ACCESS_ALL,
+        handled_access_net: ACCESS_NET_BIND_TCP | ACCESS_NET_CONNECT_TCP,
+    };
+    // the older kernels reject the fields they do not know
+    let size = if restrict_net {
+        mem::size_of::<RulesetAttr>()
+    } else {
+        mem::size_of::<u64>()
+    };
+    let ruleset = cvt(unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            &attr as *const RulesetAttr,
+            size,
+            0,
+        )
+    })? as libc::c_int;
+
+    let result = landlock_rules(options)
+        .iter()
+        .filter(|(path, _)| Path::new(path).exists())
+        .try_for_each(|(path, access)| {
+            debug!("allowing {:#x} on {}", access, path);
+            add_rule(ruleset, path, *access)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+        })
+        .and_then(|_| {
+            landlock_net_rules(options)
+                .iter()
+                .filter(|_| restrict_net)
+                .try_for_each(|(port, access)| {
+                    debug!("allowing {:#x} on TCP port {}", access, port);
+                    add_net_rule(ruleset, *port, *access)
+                })
+        })
+        .and_then(|_| set_no_new_privs())
+        .and_then(|_| {
+            cvt(unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset, 0) }).map(|_| ())
+        });
+    unsafe { libc::close(ruleset) };
+    result?;
+    info!("Landlock rules applied (ABI version {})", abi);
+    Ok(())
+}
+
+// the capabilities, as numbered by linux/capability.h
+const CAP_NET_BIND_SERVICE: u32 = 10;
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_NET_RAW: u32 = 13;
+const CAP_SYS_PTRACE: u32 = 19;
+const CAP_SYS_ADMIN: u32 = 21;
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// The capabilities kept in `--read-only` mode, those the collection
+/// cannot do without. `CAP_NET_ADMIN` lets wg query the kernel
+/// interfaces as well as configure them: that wg is only asked to show
+/// them is up to the exporter.
+pub(crate) fn read_only_capabilities(options: &Options) -> Vec<u32> {
+    let mut kept = Vec::new();
+    // the UAPI sockets are files
+    if options.collector != Collector::Uapi || options.firewall_counters.is_some() {
+        kept.push(CAP_NET_ADMIN);
+    }
+    // iptables-save opens a raw socket
+    if options.firewall_counters == Some(Firewall::Iptables) {
+        kept.push(CAP_NET_RAW);
+    }
+    // the listener is bound later
+    if options.port < 1024 {
+        kept.push(CAP_NET_BIND_SERVICE);
+    }
+    // ip netns exec and nsenter enter the namespaces of other processes
+    if !options.netns.is_empty() || options.docker_socket.is_some() {
+        kept.push(CAP_SYS_ADMIN);
+        kept.push(CAP_SYS_PTRACE);
+    }
+    kept
+}
+
+/// Drops the capabilities other than `kept` from the effective,
+/// permitted and inheritable sets, the ambient ones going with them, and
+/// sets no_new_privs, so that neither the process nor the programs it runs
+/// (not even as root, nor setuid ones) can get them back.
+pub(crate) fn drop_capabilities(kept: &[u32]) -> io::Result<()> {
+    let mask = kept.iter().fold(0u64, |mask, cap| mask | 1 << cap);
+    let mut header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapUserData::default(); 2];
+    cvt(unsafe {
+        libc::syscall(
+            libc::SYS_capget,
+            &mut header as *mut CapUserHeader,
+            data.as_mut_ptr(),
+        )
+    })?;
+    for (i, data) in data.iter_mut().enumerate() {
+        let mask = (mask >> (32 * i)) as u32;
+        data.effective &= mask;
+        data.permitted &= mask;
+        data.inheritable &= mask;
+    }
+    cvt(unsafe {
+        libc::syscall(
+            libc::SYS_capset,
+            &mut header as *mut CapUserHeader,
+            data.as_ptr(),
+        )
+    })?;
+    set_no_new_privs()?;
+    info!(
+        "capabilities dropped, keeping {:#x}",
+        u64::from(data[1].permitted) << 32 | u64::from(data[0].permitted)
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::NamesFile;
+
+    #[test]
+    fn test_seccomp_program() {
+        let program = seccomp_program(0xc000_003e, &[101, 165], 41, &[]);
+        assert_eq!(program.len(), 9);
+        // the x32 check and every denied call jump to the final deny
+        for (i, filter) in program.iter().enumerate().skip(4).take(3) {
+            assert_eq!(i + 1 + filter.jt as usize, program.len() - 1);
+        }
+        assert_eq!(program[6].k, 165);
+        assert_eq!(program[7].k, SECCOMP_RET_ALLOW);
+        assert_eq!(program[8].k, SECCOMP_RET_ERRNO | 1);
+
+        let program = seccomp_program(0xc000_003e, &[101, 165], 41, &[1, 2]);
+        assert_eq!(program.len(), 14);
+        for (i, filter) in program.iter().enumerate().skip(4).take(3) {
+            assert_eq!(i + 1 + filter.jt as usize, program.len() - 1);
+        }
+        // the other calls skip the socket checks
+        assert_eq!(program[7].k, 41);
+        assert_eq!(8 + program[7].jf as usize, 12);
+        assert_eq!(program[8].k, SECCOMP_DATA_ARGS);
+        // the allowed families jump to the allow, the others fall to a deny
+        assert_eq!(10 + program[9].jt as usize, 12);
+        assert_eq!(11 + program[10].jt as usize, 12);
+        assert_eq!(program[11].k, SECCOMP_RET_ERRNO | 1);
+        assert_eq!(program[12].k, SECCOMP_RET_ALLOW);
+    }
+
+    #[test]
+    fn test_landlock_rules() {
+        let options = Options {
+            extract_names_config_files: vec![NamesFile::from("wg0:/etc/wireguard/wg0.conf")],
+            usage_file: Some("/var/lib/wg_exporter/usage.json".to_owned()),
+            wg_path: Some("/opt/wireguard-tools/bin/wg".to_owned()),
+            dump_file: Some("/var/lib/wg_exporter/dump.txt".to_owned()),
+            ..Options::default()
+        };
+        let rules = landlock_rules(&options);
+        assert!(rules.contains(&("/var/lib/wg_exporter".to_owned(), READ)));
+
+        for (dump_file, dir) in &[("dump.txt", Some(".")), ("-", None)] {
+            let options = Options {
+                dump_file: Some((*dump_file).to_owned()),
+                ..Options::default()
+            };
+            let rules = landlock_rules(&options);
+            assert_eq!(
+                rules.contains(&((*dir).unwrap_or(".").to_owned(), READ)),
+                dir.is_some()
+            );
+        }
+        assert!(rules.contains(&("/opt/wireguard-tools/bin".to_owned(), READ_EXECUTE)));
+        assert!(rules.contains(&("/etc/wireguard/wg0.conf".to_owned(), READ)));
+        assert!(rules.contains(&("/etc/wireguard/wg0.conf.d".to_owned(), READ)));
+        assert!(rules.contains(&("/var/lib/wg_exporter".to_owned(), READ_WRITE)));
+        // nothing else is writable
+        assert_eq!(
+            rules
+                .iter()
+                .filter(|(_, access)| access & ACCESS_MAKE_REG != 0)
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_landlock_net_rules() {
+        let options = Options {
+            port: 9586,
+            ..Options::default()
+        };
+        assert_eq!(
+            landlock_net_rules(&options),
+            vec![(9586, ACCESS_NET_BIND_TCP)]
+        );
+        let options = Options {
+            dns_check_interval: Some(std::time::Duration::from_secs(300)),
+            ..options
+        };
+        assert!(landlock_net_rules(&options).contains(&(53, ACCESS_NET_CONNECT_TCP)));
+    }
+
+    #[test]
+    fn test_read_only_capabilities() {
+        let options = Options {
+            port: 9586,
+            ..Options::default()
+        };
+        assert_eq!(read_only_capabilities(&options), vec![CAP_NET_ADMIN]);
+
+        let options = Options {
+            port: 9586,
+            collector: Collector::Uapi,
+            ..Options::default()
+        };
+        assert!(read_only_capabilities(&options).is_empty());
+
+        let options = Options {
+            port: 443,
+            firewall_counters: Some(Firewall::Iptables),
+            netns: vec!["blue".to_owned()],
+            ..Options::default()
+        };
+        assert_eq!(
+            read_only_capabilities(&options),
+            vec![
+                CAP_NET_ADMIN,
+                CAP_NET_RAW,
+                CAP_NET_BIND_SERVICE,
+                CAP_SYS_ADMIN,
+                CAP_SYS_PTRACE
+            ]
+        );
+    }
+}
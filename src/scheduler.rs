@@ -0,0 +1,189 @@
+// Scheduling of the active subsystems, the ones generating traffic of
+// their own such as the lookups of --dns-check-interval. They share a
+// limit of tasks in flight, and their due times are spread so that the
+// tasks of thousands of peers do not all fall due together.
+use crate::metrics::{MetricType, Registry};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Debug, Default)]
+struct Stats {
+    started: u64,
+    deferred: u64,
+    in_flight: usize,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    in_flight: usize,
+    // subsystem -> stats
+    stats: BTreeMap<&'static str, Stats>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Scheduler {
+    max_in_flight: usize,
+    // the fraction of the interval the due times are spread over
+    jitter: f64,
+    state: Mutex<State>,
+}
+
+/// A slot for a task, given back when dropped.
+#[derive(Debug)]
+pub(crate) struct Permit<'a> {
+    scheduler: &'a Scheduler,
+    subsystem: &'static str,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        let mut state = self.scheduler.state.lock().unwrap();
+        state.in_flight -= 1;
+        if let Some(stats) = state.stats.get_mut(self.subsystem) {
+            stats.in_flight -= 1;
+        }
+    }
+}
+
+impl Scheduler {
+    /// `jitter` is the percentage of the interval of a task its due time
+    /// is delayed by at most.
+    pub fn new(max_in_flight: usize, jitter: u8) -> Scheduler {
+        Scheduler {
+            max_in_flight,
+            jitter: f64::from(jitter) / 100.0,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Declares a subsystem, for its stats to be rendered from the start.
+    pub fn register(&self, subsystem: &'static str) {
+        self.state
+            .lock()
+            .unwrap()
+            .stats
+            .entry(subsystem)
+            .or_default();
+    }
+
+    /// The time (in seconds since the epoch) the task `key` done at
+    /// `done_at` is due again. The delay added to the interval depends
+    /// on the key only, so that the tasks drift apart while each keeps
+    /// a steady period.
+    pub fn next_due(&self, key: &str, done_at: u64, interval: Duration) -> u64 {
+        let spread = (interval.as_secs() as f64 * self.jitter) as u64;
+        let delay = if spread == 0 {
+            0
+        } else {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            hasher.finish() % (spread + 1)
+        };
+        done_at + interval.as_secs() + delay
+    }
+
+    /// Takes a slot for a task of `subsystem`, unless the tasks in flight
+    /// are at the limit: the task is then deferred and should be retried
+    /// later.
+    pub fn try_start(&self, subsystem: &'static str) -> Option<Permit<'_>> {
+        let mut state = self.state.lock().unwrap();
+        let available = state.in_flight < self.max_in_flight;
+        if available {
+            state.in_flight += 1;
+        }
+        let stats = state.stats.entry(subsystem).or_default();
+        if !available {
+            stats.deferred += 1;
+            return None;
+        }
+        stats.started += 1;
+        stats.in_flight += 1;
+        Some(Permit {
+            scheduler: self,
+            subsystem,
+        })
+    }
+
+    /// Renders the tasks started and deferred, and those in flight, of
+    /// every subsystem. Nothing is rendered without active subsystems.
+    pub fn render(&self, registry: &mut Registry) {
+        let state = self.state.lock().unwrap();
+        if state.stats.is_empty() {
+            return;
+        }
+
+        let mut tasks = registry.family(
+            "wireguard_exporter_active_tasks_total",
+            MetricType::Counter,
+            "Tasks of the active subsystems, started or deferred for lack of a slot",
+        );
+        let mut in_flight = registry.family(
+            "wireguard_exporter_active_tasks_in_flight",
+            MetricType::Gauge,
+            "Tasks of the active subsystems in flight",
+        );
+        for (subsystem, stats) in state.stats.iter() {
+            tasks.push(
+                &[("subsystem", subsystem), ("result", "started")],
+                stats.started as f64,
+            );
+            tasks.push(
+                &[("subsystem", subsystem), ("result", "deferred")],
+                stats.deferred as f64,
+            );
+            in_flight.push(&[("subsystem", subsystem)], stats.in_flight as f64);
+        }
+        registry.register(tasks);
+        registry.register(in_flight);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_due() {
+        let interval = Duration::from_secs(300);
+        let scheduler = Scheduler::new(4, 0);
+        assert_eq!(scheduler.next_due("a", 1000, interval), 1300);
+
+        let scheduler = Scheduler::new(4, 10);
+        let due: Vec<u64> = (0..100)
+            .map(|i| scheduler.next_due(&format!("host{}", i), 1000, interval))
+            .collect();
+        assert!(due.iter().all(|due| (1300..=1330).contains(due)));
+        // spread, and steady for a given key
+        assert!(due.iter().min() < due.iter().max());
+        assert_eq!(scheduler.next_due("host7", 1000, interval), due[7]);
+    }
+
+    #[test]
+    fn test_try_start() {
+        let scheduler = Scheduler::new(1, 0);
+        scheduler.register("dns");
+        let permit = scheduler.try_start("dns");
+        assert!(permit.is_some());
+        assert!(scheduler.try_start("dns").is_none());
+        drop(permit);
+        assert!(scheduler.try_start("dns").is_some());
+
+        let mut registry = Registry::default();
+        scheduler.render(&mut registry);
+        let text = registry.render_text();
+        assert!(text.contains(
+            "wireguard_exporter_active_tasks_total{subsystem=\"dns\",result=\"started\"} 2\n"
+        ));
+        assert!(text.contains(
+            "wireguard_exporter_active_tasks_total{subsystem=\"dns\",result=\"deferred\"} 1\n"
+        ));
+        assert!(text.contains("wireguard_exporter_active_tasks_in_flight{subsystem=\"dns\"} 0\n"));
+
+        let mut registry = Registry::default();
+        Scheduler::new(1, 0).render(&mut registry);
+        assert_eq!(registry.render_text(), "");
+    }
+}
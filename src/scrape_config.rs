@@ -0,0 +1,111 @@
+// The `scrape-config` subcommand: a Prometheus scrape config, and the
+// Alertmanager route to go with it, matching the options the exporter is
+// run with, to be pasted rather than written by hand.
+use crate::options::Options;
+
+/// The name of the job, which the route and the rules match on.
+const JOB: &str = "wireguard";
+
+// the target of `host` on `port`, bracketing an IPv6 address
+fn target(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Renders the config scraping the exporter on `host`.
+pub(crate) fn render(options: &Options, host: &str) -> String {
+    let mut s = format!(
+        "# prometheus.yml
+# the exporter serves plain HTTP without authentication: put it behind a
+# reverse proxy to scrape it over TLS, and adjust the scheme and the target
+scrape_configs:
+  - job_name: {job}
+    scheme: http
+    metrics_path: /metrics
+    static_configs:
+      - targets: ['{target}']
+",
+        job = JOB,
+        target = target(host, options.port),
+    );
+    if let Some(rate_limit) = options.rate_limit {
+        // a faster scrape gets 429 Too Many Requests
+        s.push_str(&format!(
+            "    # --rate-limit allows {} requests per minute per client\n    scrape_interval: {}s\n",
+            rate_limit,
+            60_u32.div_ceil(rate_limit)
+        ));
+    }
+
+    if !options.alert_rules.is_empty() {
+        s.push_str(&format!(
+            "
+# rules.yml, listed in the rule_files of prometheus.yml, turning the
+# alert rules evaluated by the exporter into Prometheus alerts
+groups:
+  - name: {job}
+    rules:
+      - alert: WireGuardExporterAlert
+        expr: wireguard_exporter_alert{{job=\"{job}\"}} == 1
+        for: 5m
+        annotations:
+          summary: 'rule {{{{ $labels.rule }}}} violated on {{{{ $labels.interface }}}}'
+",
+            job = JOB,
+        ));
+    }
+
+    s.push_str(&format!(
+        "
+# alertmanager.yml, routing the alerts on the exporter to a receiver of
+# their own
+route:
+  routes:
+    - matchers:
+        - job=\"{job}\"
+      receiver: {job}
+receivers:
+  - name: {job}
+",
+        job = JOB,
+    ));
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alerts::AlertRules;
+
+    #[test]
+    fn test_render() {
+        let options = Options {
+            port: 9586,
+            ..Options::default()
+        };
+        let config = render(&options, "gw1.example.com");
+        assert!(config.contains("      - targets: ['gw1.example.com:9586']\n"));
+        assert!(!config.contains("scrape_interval"));
+        assert!(!config.contains("rules:"));
+        assert!(config.contains("\nroute:\n"));
+
+        let options = Options {
+            port: 9000,
+            rate_limit: Some(4),
+            alert_rules: AlertRules {
+                min_peers: Some(1),
+                ..AlertRules::default()
+            },
+            ..Options::default()
+        };
+        let config = render(&options, "2001:db8::1");
+        assert!(config.contains("      - targets: ['[2001:db8::1]:9000']\n"));
+        assert!(config.contains("    scrape_interval: 15s\n"));
+        assert!(config.contains(
+            "          summary: 'rule {{ $labels.rule }} violated on {{ $labels.interface }}'\n"
+        ));
+    }
+}
@@ -0,0 +1,437 @@
+use futures::future::{ok, Future};
+use futures::{Poll, Stream};
+use http::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE,
+    CONTENT_LENGTH, VARY,
+};
+use http::{StatusCode, Uri};
+use hyper::body::Payload;
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Chunk, Request, Response, Server};
+use log::error;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::runtime::{self, Runtime};
+
+pub(crate) type ResponseFuture =
+    Box<dyn Future<Item = Response<Body>, Error = failure::Error> + Send + 'static>;
+
+pub(crate) fn empty_response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+pub(crate) fn json_response(value: &serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(value.to_string()))
+        .unwrap()
+}
+
+// the body of a response, passing the time it took to `written` once
+// hyper is done with it
+struct TimedBody<F: FnOnce(Duration)> {
+    body: Body,
+    started: Instant,
+    written: Option<F>,
+}
+
+impl<F: FnOnce(Duration)> Stream for TimedBody<F> {
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        self.body.poll()
+    }
+}
+
+impl<F: FnOnce(Duration)> Drop for TimedBody<F> {
+    fn drop(&mut self) {
+        if let Some(written) = self.written.take() {
+            written(self.started.elapsed());
+        }
+    }
+}
+
+/// Times the write of the body of `response`, from now until it is
+/// written whole or the client is gone, and passes the duration to
+/// `written`. The length of the body, if known, is kept in
+/// `Content-Length` rather than the body being sent in chunks.
+pub(crate) fn time_body<F>(response: Response<Body>, written: F) -> Response<Body>
+where
+    F: FnOnce(Duration) + Send + 'static,
+{
+    let (mut parts, body) = response.into_parts();
+    if let Some(length) = body.content_length() {
+        if !parts.headers.contains_key(CONTENT_LENGTH) {
+            parts
+                .headers
+                .insert(CONTENT_LENGTH, HeaderValue::from(length));
+        }
+    }
+    Response::from_parts(
+        parts,
+        Body::wrap_stream(TimedBody {
+            body,
+            started: Instant::now(),
+            written: Some(written),
+        }),
+    )
+}
+
+/// Returns the percent-decoded value of the `name` query string parameter
+/// of `uri`.
+pub(crate) fn query_param(uri: &Uri, name: &str) -> Option<String> {
+    uri.query()?.split('&').find_map(|pair| {
+        let mut pair = pair.splitn(2, '=');
+        if pair.next().map(percent_decode).as_deref() == Some(name) {
+            Some(percent_decode(pair.next().unwrap_or_default()))
+        } else {
+            None
+        }
+    })
+}
+
+/// Decodes the `%XX` escapes and the `+` standing for a space of a query
+/// string component. A malformed escape is kept as is.
+fn percent_decode(s: &str) -> String {
+    let s = s.as_bytes();
+    let mut decoded = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        let escaped = if s[i] == b'%' {
+            s.get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        } else {
+            None
+        };
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            None if s[i] == b'+' => decoded.push(b' '),
+            None => decoded.push(s[i]),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Returns the value of the `Access-Control-Allow-Origin` header for a
+/// request coming from `origin`, if that origin is allowed.
+pub(crate) fn cors_allowed_origin(origin: Option<&str>, allowed: &[String]) -> Option<String> {
+    let origin = origin?;
+
+    if allowed.iter().any(|allowed| allowed == "*") {
+        Some("*".to_owned())
+    } else if allowed.iter().any(|allowed| allowed == origin) {
+        Some(origin.to_owned())
+    } else {
+        None
+    }
+}
+
+pub(crate) fn add_cors_headers(response: &mut Response<Body>, allowed_origin: Option<String>) {
+    let headers = response.headers_mut();
+    headers.insert(VARY, HeaderValue::from_static("Origin"));
+
+    if let Some(allowed_origin) = allowed_origin {
+        if let Ok(allowed_origin) = HeaderValue::from_str(&allowed_origin) {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allowed_origin);
+        }
+    }
+}
+
+pub(crate) fn preflight_response(allowed_origin: Option<String>) -> Response<Body> {
+    let mut response = empty_response(StatusCode::NO_CONTENT);
+    if allowed_origin.is_some() {
+        let headers = response.headers_mut();
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::from_static("GET, OPTIONS"),
+        );
+        headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static("86400"));
+    }
+    add_cors_headers(&mut response, allowed_origin);
+    response
+}
+
+/// Builds the runtime serving the requests with `worker_threads` threads
+/// and up to `blocking_threads` collections at once. The tokio defaults
+/// (the number of CPUs and 100) apply to the unset values.
+pub(crate) fn runtime(
+    worker_threads: Option<usize>,
+    blocking_threads: Option<usize>,
+) -> io::Result<Runtime> {
+    let mut builder = runtime::Builder::new();
+    if let Some(worker_threads) = worker_threads {
+        builder.core_threads(worker_threads);
+    }
+    if let Some(blocking_threads) = blocking_threads {
+        builder.blocking_threads(blocking_threads);
+    }
+    builder.build()
+}
+
+/// The address families the exporter listens on.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum BindFamily {
+    /// `0.0.0.0`
+    #[default]
+    Ipv4,
+    /// `[::]`, with `IPV6_V6ONLY` set
+    Ipv6,
+    /// `[::]`, with `IPV6_V6ONLY` cleared so IPv4 clients are accepted
+    /// as IPv4-mapped addresses
+    Dual,
+}
+
+impl fmt::Display for BindFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BindFamily::Ipv4 => "ipv4",
+            BindFamily::Ipv6 => "ipv6",
+            BindFamily::Dual => "dual",
+        })
+    }
+}
+
+impl FromStr for BindFamily {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ipv4" => Ok(BindFamily::Ipv4),
+            "ipv6" => Ok(BindFamily::Ipv6),
+            "dual" => Ok(BindFamily::Dual),
+            _ => Err(format!(
+                "invalid bind family {}, valid values are ipv4, ipv6 and dual",
+                s
+            )),
+        }
+    }
+}
+
+fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+// binds [::]:port with IPV6_V6ONLY set to `only_v6`, which std cannot do
+// as the option must be set before binding
+fn bind_v6(port: u16, only_v6: bool) -> io::Result<TcpListener> {
+    let fd =
+        cvt(unsafe { libc::socket(libc::AF_INET6, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) })?;
+    // closes the socket on the errors below
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+    let on: libc::c_int = 1;
+    let only_v6 = only_v6 as libc::c_int;
+    for (level, name, value) in &[
+        (libc::SOL_SOCKET, libc::SO_REUSEADDR, &on),
+        (libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, &only_v6),
+    ] {
+        cvt(unsafe {
+            libc::setsockopt(
+                fd,
+                *level,
+                *name,
+                *value as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        })?;
+    }
+
+    let mut addr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+    addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+    addr.sin6_port = port.to_be();
+    cvt(unsafe {
+        libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_in6 as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+        )
+    })?;
+    cvt(unsafe { libc::listen(fd, 1024) })?;
+    Ok(listener)
+}
+
+/// Binds the wildcard address of `family` on `port`. `IPV6_V6ONLY` is
+/// always set explicitly, as its default depends on the system (see
+/// `net.ipv6.bindv6only` on Linux).
+pub(crate) fn bind(family: BindFamily, port: u16) -> io::Result<TcpListener> {
+    match family {
+        BindFamily::Ipv4 => TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)),
+        BindFamily::Ipv6 => bind_v6(port, true),
+        BindFamily::Dual => bind_v6(port, false),
+    }
+}
+
+fn only_v6(listener: &TcpListener) -> io::Result<bool> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    cvt(unsafe {
+        libc::getsockopt(
+            listener.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_V6ONLY,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    })?;
+    Ok(value != 0)
+}
+
+/// Describes what `listener` accepts, as read back from the socket.
+pub(crate) fn describe_listener(listener: &TcpListener) -> io::Result<String> {
+    let addr = listener.local_addr()?;
+    let families = if addr.is_ipv4() {
+        "IPv4 only"
+    } else if only_v6(listener)? {
+        "IPv6 only"
+    } else {
+        "IPv4 and IPv6"
+    };
+    Ok(format!("{} ({})", addr, families))
+}
+
+/// Starts the HTTP server on `runtime` and blocks until it terminates.
+/// Every request is passed to `handler` along with the address of the
+/// client; if the returned future fails the error is logged and the client
+/// gets an empty 500 response.
+pub(crate) fn serve<H>(
+    mut runtime: Runtime,
+    listener: TcpListener,
+    handler: H,
+) -> Result<(), hyper::Error>
+where
+    H: Fn(Request<Body>, SocketAddr) -> ResponseFuture + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+
+    let new_svc = make_service_fn(move |socket: &AddrStream| {
+        let handler = handler.clone();
+        let remote_addr = socket.remote_addr();
+        service_fn(move |req| {
+            handler(req, remote_addr).then(|res| match res {
+                Ok(response) => ok::<_, hyper::Error>(response),
+                Err(err) => {
+                    error!("internal server error: {:?}", err);
+                    ok(empty_response(StatusCode::INTERNAL_SERVER_ERROR))
+                }
+            })
+        })
+    });
+
+    let server = Server::from_tcp(listener)?
+        .serve(new_svc)
+        .map_err(|e| eprintln!("server error: {}", e));
+    runtime.spawn(server);
+    runtime.shutdown_on_idle().wait().unwrap();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv6Addr, TcpStream};
+
+    #[test]
+    fn test_query_param() {
+        let uri: Uri = "/api/v1/diff?foo=bar&since=1560000000".parse().unwrap();
+        assert_eq!(query_param(&uri, "since").as_deref(), Some("1560000000"));
+        assert_eq!(query_param(&uri, "foo").as_deref(), Some("bar"));
+        assert_eq!(query_param(&uri, "bar"), None);
+
+        let uri: Uri = "/api/v1/diff".parse().unwrap();
+        assert_eq!(query_param(&uri, "since"), None);
+
+        let uri: Uri = "/metrics?labels=allowed_ips%2Cfriendly_name,+a%2b&%6Eame=%E2%9C%93%"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            query_param(&uri, "labels").as_deref(),
+            Some("allowed_ips,friendly_name, a+")
+        );
+        assert_eq!(query_param(&uri, "name").as_deref(), Some("\u{2713}%"));
+    }
+
+    #[test]
+    fn test_time_body() {
+        let written = Arc::new(std::sync::Mutex::new(None));
+        let response = time_body(Response::new(Body::from("metrics")), {
+            let written = written.clone();
+            move |duration| *written.lock().unwrap() = Some(duration)
+        });
+        assert_eq!(response.headers()[CONTENT_LENGTH], "7");
+
+        let body = response.into_body().concat2().wait().unwrap();
+        assert_eq!(&body[..], b"metrics");
+        assert!(written.lock().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_bind() {
+        // which loopback addresses every family is reachable from
+        for (family, description, ipv4, ipv6) in &[
+            (BindFamily::Ipv4, "(IPv4 only)", true, false),
+            (BindFamily::Ipv6, "(IPv6 only)", false, true),
+            (BindFamily::Dual, "(IPv4 and IPv6)", true, true),
+        ] {
+            let listener = bind(*family, 0).unwrap();
+            let port = listener.local_addr().unwrap().port();
+            assert!(listener.local_addr().unwrap().ip().is_unspecified());
+            assert!(describe_listener(&listener).unwrap().ends_with(description));
+
+            assert_eq!(
+                TcpStream::connect((Ipv4Addr::LOCALHOST, port)).is_ok(),
+                *ipv4,
+                "{} over IPv4",
+                family
+            );
+            assert_eq!(
+                TcpStream::connect((Ipv6Addr::LOCALHOST, port)).is_ok(),
+                *ipv6,
+                "{} over IPv6",
+                family
+            );
+        }
+
+        assert_eq!("dual".parse(), Ok(BindFamily::Dual));
+        assert!("inet6".parse::<BindFamily>().is_err());
+    }
+
+    #[test]
+    fn test_cors_allowed_origin() {
+        let allowed = vec!["https://dashboard.example.com".to_owned()];
+
+        assert_eq!(
+            cors_allowed_origin(Some("https://dashboard.example.com"), &allowed),
+            Some("https://dashboard.example.com".to_owned())
+        );
+        assert_eq!(
+            cors_allowed_origin(Some("https://evil.example.com"), &allowed),
+            None
+        );
+        assert_eq!(cors_allowed_origin(None, &allowed), None);
+        assert_eq!(
+            cors_allowed_origin(Some("https://evil.example.com"), &["*".to_owned()]),
+            Some("*".to_owned())
+        );
+    }
+}
@@ -0,0 +1,142 @@
+// The `install-service` and `uninstall-service` subcommands: a hardened
+// systemd unit running the exporter with the flags it was invoked with.
+use crate::options::Options;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub(crate) const DEFAULT_UNIT_PATH: &str =
+    "/etc/systemd/system/prometheus_wireguard_exporter.service";
+
+// quotes an ExecStart argument as per systemd.service(5)
+fn quote(arg: &str) -> String {
+    let escaped = arg.replace('%', "%%").replace('$', "$$");
+    if !escaped.is_empty()
+        && !escaped
+            .chars()
+            .any(|c| c.is_whitespace() || c == '"' || c == '\'' || c == '\\' || c == ';')
+    {
+        return escaped;
+    }
+
+    let mut quoted = String::from("\"");
+    for c in escaped.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\n"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// the directories the exporter writes to, which must be writable
+// despite ProtectSystem=strict
+pub(crate) fn writable_dirs(options: &Options) -> Vec<String> {
+    let mut dirs: Vec<String> = [
+        &options.log_file,
+        &options.audit_log,
+        &options.diagnostics_file,
+        &options.pid_file,
+        &options.usage_file,
+        &options.textfile_output,
+    ]
+    .iter()
+    .filter_map(|file| file.as_ref())
+    .filter_map(|file| Path::new(file).parent())
+    .filter_map(|dir| dir.to_str())
+    .filter(|dir| !dir.is_empty())
+    .map(|dir| dir.to_owned())
+    .collect();
+    dirs.sort();
+    dirs.dedup();
+    dirs
+}
+
+/// Renders a unit starting `exe` with `args`. The exporter runs as a
+/// dynamic user with just the capability needed to query WireGuard.
+pub(crate) fn render_unit(exe: &str, args: &[String], options: &Options) -> String {
+    let exec_start: Vec<String> = std::iter::once(exe)
+        .chain(args.iter().map(|arg| arg as &str))
+        .map(quote)
+        .collect();
+
+    let mut unit = format!(
+        "[Unit]
+Description=Prometheus WireGuard Exporter
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=simple
+ExecStart={}
+Restart=on-failure
+# invalid arguments, see the exit codes in the README
+RestartPreventExitStatus=2
+DynamicUser=yes
+AmbientCapabilities=CAP_NET_ADMIN
+CapabilityBoundingSet=CAP_NET_ADMIN
+NoNewPrivileges=yes
+ProtectSystem=strict
+ProtectHome=yes
+PrivateTmp=yes
+PrivateDevices=yes
+ProtectKernelTunables=yes
+ProtectKernelModules=yes
+ProtectControlGroups=yes
+RestrictAddressFamilies=AF_UNIX AF_INET AF_INET6 AF_NETLINK
+",
+        exec_start.join(" ")
+    );
+    for dir in writable_dirs(options) {
+        unit.push_str(&format!("ReadWritePaths={}\n", quote(&dir)));
+    }
+    unit.push_str("\n[Install]\nWantedBy=multi-user.target\n");
+    unit
+}
+
+pub(crate) fn install(path: &str, unit: &str) -> io::Result<()> {
+    fs::write(path, unit)
+}
+
+pub(crate) fn uninstall(path: &str) -> io::Result<()> {
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_unit() {
+        let options = Options {
+            log_file: Some("/var/log/wg_exporter/exporter.log".to_owned()),
+            audit_log: Some("/var/log/wg_exporter/audit.log".to_owned()),
+            ..Options::default()
+        };
+        let args = vec![
+            "-n".to_owned(),
+            "/etc/wireguard/my wg0.conf".to_owned(),
+            "--log-file".to_owned(),
+            "/var/log/wg_exporter/exporter.log".to_owned(),
+        ];
+
+        let unit = render_unit(
+            "/usr/local/bin/prometheus_wireguard_exporter",
+            &args,
+            &options,
+        );
+        assert!(unit.contains("\nExecStart=/usr/local/bin/prometheus_wireguard_exporter -n \"/etc/wireguard/my wg0.conf\" --log-file /var/log/wg_exporter/exporter.log\n"));
+        assert!(unit.contains("\nAmbientCapabilities=CAP_NET_ADMIN\n"));
+        assert_eq!(unit.matches("ReadWritePaths=").count(), 1);
+        assert!(unit.contains("\nReadWritePaths=/var/log/wg_exporter\n"));
+
+        assert_eq!(quote("100%"), "100%%");
+        assert_eq!(quote("a\"b"), "\"a\\\"b\"");
+        assert_eq!(quote(""), "\"\"");
+    }
+}
@@ -0,0 +1,276 @@
+// Traffic control statistics of the WireGuard interfaces, to tell the
+// peers held back by a shaper from the ones limited by the tunnel.
+use crate::metrics::{MetricType, Registry};
+use crate::wireguard::{canonical_allowed_ip, Endpoint, WireGuard};
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::process::Command;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) struct ClassStats {
+    pub dropped: u64,
+    pub overlimits: u64,
+}
+
+/// The classes of an interface and the addresses classified into them.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct InterfaceShaping {
+    /// class id -> statistics
+    pub classes: BTreeMap<String, ClassStats>,
+    /// (address range, class id)
+    pub filters: Vec<(String, String)>,
+}
+
+// writes a range in the canonical form of the allowed IPs, appending the
+// prefix length to a bare address
+fn normalize_range(range: &str) -> Option<String> {
+    let address = range.split('/').next()?;
+    address.parse::<IpAddr>().ok()?;
+    Some(canonical_allowed_ip(range))
+}
+
+// parses a u32 match on the source or destination IPv4 address,
+// such as 0a460002/ffffffff at 16
+fn parse_u32_match(value_mask: &str, offset: &str) -> Option<String> {
+    if offset != "12" && offset != "16" {
+        return None;
+    }
+    let mut split = value_mask.split('/');
+    let value = u32::from_str_radix(split.next()?, 16).ok()?;
+    let mask = u32::from_str_radix(split.next()?, 16).ok()?;
+    Some(format!("{}/{}", Ipv4Addr::from(value), mask.count_ones()))
+}
+
+/// Parses the output of `tc -s class show dev <interface>`.
+pub(crate) fn parse_classes(text: &str) -> BTreeMap<String, ClassStats> {
+    let mut classes = BTreeMap::new();
+    let mut class = None;
+    for line in text.lines() {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        match tokens.first() {
+            Some(&"class") if tokens.len() > 2 => class = Some(tokens[2].to_owned()),
+            Some(&"Sent") => {
+                if let Some(class) = class.take() {
+                    let field = |name: &str| {
+                        tokens
+                            .iter()
+                            .position(|token| token.trim_start_matches('(') == name)
+                            .and_then(|i| tokens.get(i + 1))
+                            .and_then(|value| value.trim_end_matches(',').parse().ok())
+                            .unwrap_or(0)
+                    };
+                    classes.insert(
+                        class,
+                        ClassStats {
+                            dropped: field("dropped"),
+                            overlimits: field("overlimits"),
+                        },
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+    classes
+}
+
+/// Parses the output of `tc filter show dev <interface>`, returning the
+/// address ranges matched by the u32 and flower filters along with the
+/// class they lead to.
+pub(crate) fn parse_filters(text: &str) -> Vec<(String, String)> {
+    let mut filters = Vec::new();
+    let mut class: Option<String> = None;
+    for line in text.lines() {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.first() == Some(&"filter") {
+            // u32 filters name the class flowid, flower filters classid
+            class = tokens
+                .iter()
+                .position(|token| *token == "flowid" || *token == "classid")
+                .and_then(|i| tokens.get(i + 1))
+                .map(|class| (*class).to_owned());
+            continue;
+        }
+
+        let class = match &class {
+            Some(class) => class,
+            None => continue,
+        };
+        let range = match tokens.as_slice() {
+            ["match", value_mask, "at", offset, ..] => parse_u32_match(value_mask, offset),
+            ["dst_ip", range, ..] | ["src_ip", range, ..] => normalize_range(range),
+            _ => None,
+        };
+        if let Some(range) = range {
+            filters.push((range, class.to_owned()));
+        }
+    }
+    filters
+}
+
+fn tc(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("tc").args(args).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Reads the classes and the filters of `interface` with `tc`.
+pub(crate) fn read(interface: &str) -> io::Result<InterfaceShaping> {
+    Ok(InterfaceShaping {
+        classes: parse_classes(&tc(&["-s", "class", "show", "dev", interface])?),
+        filters: parse_filters(&tc(&["filter", "show", "dev", interface])?),
+    })
+}
+
+/// Renders, for every peer and every class its allowed IPs are
+/// classified into, the packets dropped and the overlimits of the class.
+/// A class shared by several peers is reported for each of them.
+pub(crate) fn render(
+    registry: &mut Registry,
+    wg: &WireGuard,
+    shaping: &HashMap<String, InterfaceShaping>,
+) {
+    let mut dropped = registry.family(
+        "wireguard_peer_shaping_dropped_packets_total",
+        MetricType::Counter,
+        "Packets dropped by the traffic control class of the peer",
+    );
+    let mut overlimits = registry.family(
+        "wireguard_peer_shaping_overlimits_total",
+        MetricType::Counter,
+        "Overlimits of the traffic control class of the peer",
+    );
+
+    let mut interfaces: Vec<_> = wg.interfaces.iter().collect();
+    interfaces.sort_by_key(|(interface, _)| *interface);
+
+    for (interface, endpoints) in interfaces {
+        let shaping = match shaping.get(interface) {
+            Some(shaping) => shaping,
+            None => continue,
+        };
+
+        for endpoint in endpoints {
+            if let Endpoint::Remote(ep) = endpoint {
+                let mut classes: Vec<&str> = shaping
+                    .filters
+                    .iter()
+                    .filter(|(range, _)| ep.allowed_ips.contains(range))
+                    .map(|(_, class)| class as &str)
+                    .collect();
+                classes.sort_unstable();
+                classes.dedup();
+
+                for class in classes {
+                    if let Some(stats) = shaping.classes.get(class) {
+                        let attributes = [
+                            ("interface", interface as &str),
+                            ("public_key", &ep.public_key),
+                            ("class", class),
+                        ];
+                        dropped.push(&attributes, stats.dropped as f64);
+                        overlimits.push(&attributes, stats.overlimits as f64);
+                    }
+                }
+            }
+        }
+    }
+
+    registry.register(dropped);
+    registry.register(overlimits);
+}
+
+/// Reads the shaping of every interface of `wg`, skipping (and logging)
+/// the interfaces `tc` fails on.
+pub(crate) fn read_all(wg: &WireGuard) -> HashMap<String, InterfaceShaping> {
+    let mut shaping = HashMap::new();
+    for interface in wg.interfaces.keys() {
+        match read(interface) {
+            Ok(interface_shaping) => {
+                shaping.insert(interface.to_owned(), interface_shaping);
+            }
+            Err(e) => warn!("cannot read the traffic control of {}: {}", interface, e),
+        }
+    }
+    shaping
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    const CLASSES: &str = "class htb 1:1 root rate 100Mbit ceil 100Mbit burst 1600b cburst 1600b
+ Sent 2000 bytes 20 pkt (dropped 0, overlimits 0 requeues 0)
+ backlog 0b 0p requeues 0
+class htb 1:10 parent 1:1 prio 0 rate 1Mbit ceil 1Mbit burst 1600b cburst 1600b
+ Sent 1000 bytes 10 pkt (dropped 5, overlimits 42 requeues 0)
+ backlog 0b 0p requeues 0
+ lended: 10 borrowed: 0 giants: 0
+class htb 1:20 parent 1:1 prio 0 rate 2Mbit ceil 2Mbit burst 1600b cburst 1600b
+ Sent 0 bytes 0 pkt (dropped 0, overlimits 3 requeues 0)
+";
+
+    const FILTERS: &str = "filter parent 1: protocol ip pref 1 u32 chain 0
+filter parent 1: protocol ip pref 1 u32 chain 0 fh 800: ht divisor 1
+filter parent 1: protocol ip pref 1 u32 chain 0 fh 800::800 order 2048 key ht 800 bkt 0 flowid 1:10 not_in_hw
+  match 0a460002/ffffffff at 16
+filter parent 1: protocol ip pref 2 flower chain 0 handle 0x1 classid 1:20
+  eth_type ipv4
+  dst_ip 10.70.1.0/24
+  not_in_hw
+";
+
+    #[test]
+    fn test_parse() {
+        let classes = parse_classes(CLASSES);
+        assert_eq!(classes.len(), 3);
+        assert_eq!(
+            classes["1:10"],
+            ClassStats {
+                dropped: 5,
+                overlimits: 42
+            }
+        );
+
+        assert_eq!(
+            parse_filters(FILTERS),
+            vec![
+                ("10.70.0.2/32".to_owned(), "1:10".to_owned()),
+                ("10.70.1.0/24".to_owned(), "1:20".to_owned()),
+            ]
+        );
+        assert_eq!(normalize_range("fd00::2"), Some("fd00::2/128".to_owned()));
+    }
+
+    #[test]
+    fn test_render() {
+        let wg = WireGuard::try_from(
+            "wg0\tA=\t(none)\t(none)\t10.70.0.2/32\t0\t0\t0\toff
+wg0\tB=\t(none)\t(none)\t10.70.0.3/32\t0\t0\t0\toff
+",
+        )
+        .unwrap();
+        let mut shaping = HashMap::new();
+        shaping.insert(
+            "wg0".to_owned(),
+            InterfaceShaping {
+                classes: parse_classes(CLASSES),
+                filters: parse_filters(FILTERS),
+            },
+        );
+
+        let mut registry = Registry::default();
+        render(&mut registry, &wg, &shaping);
+        let text = registry.render_text();
+        assert!(text.contains("wireguard_peer_shaping_dropped_packets_total{interface=\"wg0\",public_key=\"A=\",class=\"1:10\"} 5\n"));
+        assert!(text.contains("wireguard_peer_shaping_overlimits_total{interface=\"wg0\",public_key=\"A=\",class=\"1:10\"} 42\n"));
+        assert!(!text.contains("B="));
+    }
+}
@@ -0,0 +1,184 @@
+// The textfile output: the peer series written every interval to a `.prom`
+// file for the textfile collector of node_exporter, on the hosts where only
+// node_exporter is scraped. The file is replaced atomically, so that the
+// collector never reads a half-written one, and carries the time it was
+// written at, so that the one left behind by a stopped exporter shows up as
+// stale rather than as a WireGuard which stopped moving.
+use crate::exporter_error::ExporterError;
+use crate::metrics::{MetricType, Registry};
+use crate::outputs::Sink;
+use crate::wireguard::WireGuard;
+use crate::wireguard_config::PeerEntries;
+use log::{info, warn};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Renders the series written to the file.
+pub(crate) type Render = dyn Fn(&mut Registry, &WireGuard, Option<&PeerEntries>) + Send + Sync;
+
+pub(crate) struct Textfile {
+    path: PathBuf,
+    interval: Duration,
+    max_age: Duration,
+    render: Box<Render>,
+}
+
+impl Textfile {
+    /// The output writing the series of `render` to `path` every
+    /// `interval`, the file being stale once older than `max_age`. The
+    /// temporary files left behind by a previous run are removed.
+    pub(crate) fn new(
+        path: &str,
+        interval: Duration,
+        max_age: Duration,
+        render: Box<Render>,
+    ) -> Self {
+        let textfile = Textfile {
+            path: PathBuf::from(path),
+            interval,
+            max_age,
+            render,
+        };
+        if let Err(e) = textfile.remove_orphans() {
+            warn!("cannot remove the temporary files of {}: {}", path, e);
+        }
+        textfile
+    }
+
+    fn dir(&self) -> &Path {
+        match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        }
+    }
+
+    // the prefix of the temporary files, hidden and without the .prom
+    // extension, so the collector never reads them
+    fn tmp_prefix(&self) -> String {
+        let name = self.path.file_name().unwrap_or_default();
+        format!(".{}.", name.to_string_lossy())
+    }
+
+    fn tmp_path(&self) -> PathBuf {
+        self.dir()
+            .join(format!("{}{}.tmp", self.tmp_prefix(), process::id()))
+    }
+
+    // removes the temporary files of the exporters killed while writing
+    fn remove_orphans(&self) -> io::Result<()> {
+        let prefix = self.tmp_prefix();
+        for entry in fs::read_dir(self.dir())? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(&prefix) && name.ends_with(".tmp") {
+                fs::remove_file(entry.path())?;
+                info!("removed the orphaned temporary file {:?}", entry.path());
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the file with `text`, written to a temporary file of the
+    /// same directory first and renamed over it.
+    fn write(&self, text: &str) -> io::Result<()> {
+        let tmp = self.tmp_path();
+        let written = File::create(&tmp)
+            .and_then(|mut file| {
+                file.write_all(text.as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp, &self.path));
+        if written.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        written
+    }
+}
+
+/// Renders when the file was written and how old it may get, for
+/// `time() - wireguard_exporter_textfile_timestamp_seconds >
+/// wireguard_exporter_textfile_max_age_seconds` to catch a stale file.
+fn render_marker(registry: &mut Registry, written: SystemTime, max_age: Duration) {
+    let mut timestamp = registry.family(
+        "wireguard_exporter_textfile_timestamp_seconds",
+        MetricType::Gauge,
+        "When the exporter wrote the file, in seconds since the epoch",
+    );
+    let seconds = written.duration_since(UNIX_EPOCH).unwrap_or_default();
+    timestamp.push(&[], seconds.as_secs() as f64);
+    registry.register(timestamp);
+
+    let mut age = registry.family(
+        "wireguard_exporter_textfile_max_age_seconds",
+        MetricType::Gauge,
+        "How old the file may get before it is stale, see --textfile-max-age",
+    );
+    age.push(&[], max_age.as_secs() as f64);
+    registry.register(age);
+}
+
+impl Sink for Textfile {
+    fn name(&self) -> &'static str {
+        "textfile"
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn emit(&self, wg: &WireGuard, pehm: Option<&PeerEntries>) -> Result<(), ExporterError> {
+        let mut registry = Registry::default();
+        (self.render)(&mut registry, wg, pehm);
+        render_marker(&mut registry, SystemTime::now(), self.max_age);
+        self.write(&registry.render_text())?;
+        Ok(())
+    }
+
+    // node_exporter reads the file without a request to the exporter
+    fn busy(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn test_emit() {
+        let dir = std::env::temp_dir().join(format!("wgx_textfile_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wireguard.prom");
+        let orphan = dir.join(".wireguard.prom.1.tmp");
+        fs::write(&orphan, "# HELP half").unwrap();
+        let other = dir.join(".node.prom.1.tmp");
+        fs::write(&other, "").unwrap();
+
+        let textfile = Textfile::new(
+            path.to_str().unwrap(),
+            Duration::from_secs(60),
+            Duration::from_secs(180),
+            Box::new(|registry, wg, _| {
+                let mut peers = registry.family("wireguard_test_peers", MetricType::Gauge, "Peers");
+                peers.push(&[], wg.interfaces["wg0"].len() as f64);
+                registry.register(peers);
+            }),
+        );
+        assert!(!orphan.exists());
+        assert!(other.exists());
+
+        let wg =
+            WireGuard::try_from("wg0\tA=\t(none)\t(none)\t10.70.0.2/32\t0\t0\t0\toff\n").unwrap();
+        textfile.emit(&wg, None).unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with("# HELP wireguard_test_peers Peers\n# TYPE wireguard_test_peers gauge\nwireguard_test_peers 1\n"));
+        assert!(text.contains("\nwireguard_exporter_textfile_max_age_seconds 180\n"));
+        assert!(!textfile.tmp_path().exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
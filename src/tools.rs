@@ -0,0 +1,278 @@
+// What the WireGuard support of the host is made of: the version of the
+// wireguard-tools `wg` the exporter runs, read once at startup, and
+// whether the kernel module is there. Without the module the interfaces
+// are served by a userspace implementation (wireguard-go, boringtun).
+// The same probe looks for the usual misconfigurations, which would
+// otherwise only show as a failing first scrape.
+use crate::implementation::{KERNEL_MODULE_PATH, UAPI_DIRS};
+use crate::metrics::{MetricType, Registry};
+use crate::options::{Collector, Options};
+use log::{debug, warn};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+// the capability wg needs to query the interfaces
+const CAP_NET_ADMIN: u32 = 12;
+
+// the version of wg, if it could be read
+static VERSION: Mutex<Option<String>> = Mutex::new(None);
+// whether the probe found no problem
+static ENVIRONMENT_OK: AtomicBool = AtomicBool::new(true);
+
+/// A misconfiguration of the host, with how to fix it.
+#[derive(Debug, PartialEq)]
+struct Problem {
+    what: &'static str,
+    remediation: &'static str,
+}
+
+/// Reads the version of `wg`, once for the life of the exporter, and
+/// warns about the misconfigurations of the host for `options`.
+pub(crate) fn probe(options: &Options) {
+    let output = crate::wg_command(options, &["--version"]).output();
+    let wg_found = match &output {
+        Err(e) => e.kind() != io::ErrorKind::NotFound,
+        Ok(_) => true,
+    };
+    let version = output.map_err(|e| e.to_string()).and_then(|output| {
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        parse_version(&stdout)
+            .map(|version| version.to_owned())
+            .ok_or_else(|| format!("unexpected output {:?}", stdout.trim()))
+    });
+    match version {
+        Ok(version) => {
+            debug!("wg is of version {}", version);
+            *VERSION.lock().unwrap() = Some(version);
+        }
+        Err(e) => warn!("cannot read the version of wg: {}", e),
+    }
+
+    // the remote host of --ssh-target is not the one to check
+    if options.ssh_target.is_some() {
+        return;
+    }
+    let problems = check(
+        options.collector,
+        wg_found,
+        Path::new(KERNEL_MODULE_PATH).exists(),
+        has_uapi_sockets(),
+        net_admin(),
+    );
+    for problem in problems.iter() {
+        warn!("{}: {}", problem.what, problem.remediation);
+    }
+    ENVIRONMENT_OK.store(problems.is_empty(), Ordering::Relaxed);
+}
+
+/// The problems of a host collected by `collector`, where `wg` is (or
+/// not) found, the kernel module loaded, userspace implementations
+/// listening and the exporter granted `CAP_NET_ADMIN` (if known).
+fn check(
+    collector: Collector,
+    wg_found: bool,
+    kernel_module: bool,
+    uapi_sockets: bool,
+    net_admin: Option<bool>,
+) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    if !wg_found && collector == Collector::Wg {
+        problems.push(Problem {
+            what: "wg cannot be found",
+            remediation: "install wireguard-tools, or give its path with --wg-path",
+        });
+    }
+    if collector == Collector::Uapi {
+        if !uapi_sockets {
+            problems.push(Problem {
+                what: "no userspace implementation is running",
+                remediation: "start wireguard-go or boringtun (their sockets are looked for in /var/run/wireguard), or use --collector wg for the kernel interfaces",
+            });
+        }
+    } else if !kernel_module && collector == Collector::Netlink {
+        problems.push(Problem {
+            what: "the WireGuard kernel module is not loaded",
+            remediation: "run modprobe wireguard, or use --collector wg for the interfaces of a userspace implementation",
+        });
+    } else if !kernel_module && !uapi_sockets {
+        problems.push(Problem {
+            what: "the WireGuard kernel module is not loaded and no userspace implementation is running",
+            remediation: "run modprobe wireguard, or start wireguard-go or boringtun (their sockets are looked for in /var/run/wireguard)",
+        });
+    }
+    // the UAPI sockets are guarded by their permissions instead
+    if net_admin == Some(false) && collector != Collector::Uapi {
+        problems.push(Problem {
+            what: "the exporter lacks the CAP_NET_ADMIN capability wg needs",
+            remediation: "run it as root, add AmbientCapabilities=CAP_NET_ADMIN to its systemd unit, or start its container with --cap-add NET_ADMIN",
+        });
+    }
+    problems
+}
+
+// whether a userspace implementation created its UAPI socket
+fn has_uapi_sockets() -> bool {
+    UAPI_DIRS.iter().any(|dir| {
+        fs::read_dir(dir).is_ok_and(|entries| {
+            entries.filter_map(Result::ok).any(|entry| {
+                entry
+                    .file_name()
+                    .to_str()
+                    .is_some_and(|name| name.ends_with(".sock"))
+            })
+        })
+    })
+}
+
+// whether the effective capabilities of the exporter include
+// CAP_NET_ADMIN, None if they cannot be read
+fn net_admin() -> Option<bool> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    parse_net_admin(&status)
+}
+
+/// Whether the `CapEff` line of `/proc/<pid>/status` includes
+/// `CAP_NET_ADMIN`.
+fn parse_net_admin(status: &str) -> Option<bool> {
+    let capabilities = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))?;
+    let capabilities = u64::from_str_radix(capabilities.trim(), 16).ok()?;
+    Some(capabilities & (1 << CAP_NET_ADMIN) != 0)
+}
+
+/// The version in the output of `wg --version`, as in `wireguard-tools
+/// v1.0.20210914 - https://git.zx2c4.com/wireguard-tools/`.
+fn parse_version(output: &str) -> Option<&str> {
+    let mut words = output.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some("wireguard-tools"), Some(version)) => Some(version.trim_start_matches('v')),
+        _ => None,
+    }
+}
+
+/// Renders the version of `wg` and whether the kernel module is loaded.
+pub(crate) fn render(registry: &mut Registry) {
+    let version = VERSION.lock().unwrap().clone();
+    render_with(
+        registry,
+        version.as_deref(),
+        Path::new(KERNEL_MODULE_PATH).exists(),
+        ENVIRONMENT_OK.load(Ordering::Relaxed),
+    );
+}
+
+fn render_with(
+    registry: &mut Registry,
+    version: Option<&str>,
+    kernel_module: bool,
+    environment_ok: bool,
+) {
+    let mut tools_info = registry.family(
+        "wireguard_tools_info",
+        MetricType::Gauge,
+        "Version of the wireguard-tools wg run by the exporter",
+    );
+    if let Some(version) = version {
+        tools_info.push(&[("version", version)], 1.0);
+    }
+    registry.register(tools_info);
+
+    let mut module = registry.family(
+        "wireguard_kernel_module_loaded",
+        MetricType::Gauge,
+        "Whether the WireGuard kernel module is loaded, 0 if the interfaces are served by a userspace implementation",
+    );
+    module.push(&[], if kernel_module { 1.0 } else { 0.0 });
+    registry.register(module);
+
+    let mut ok = registry.family(
+        "wireguard_exporter_environment_ok",
+        MetricType::Gauge,
+        "Whether the startup probe found no misconfiguration of the host, see the warnings logged otherwise",
+    );
+    ok.push(&[], if environment_ok { 1.0 } else { 0.0 });
+    registry.register(ok);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(
+            parse_version(
+                "wireguard-tools v1.0.20210914 - https://git.zx2c4.com/wireguard-tools/\n"
+            ),
+            Some("1.0.20210914")
+        );
+        assert_eq!(parse_version("wg: command not found"), None);
+        assert_eq!(parse_version(""), None);
+    }
+
+    #[test]
+    fn test_render() {
+        let mut registry = Registry::default();
+        render_with(&mut registry, Some("1.0.20210914"), false, true);
+        let s = registry.render_text();
+        assert!(s.contains("wireguard_tools_info{version=\"1.0.20210914\"} 1\n"));
+        assert!(s.contains("wireguard_kernel_module_loaded 0\n"));
+        assert!(s.contains("wireguard_exporter_environment_ok 1\n"));
+
+        let mut registry = Registry::default();
+        render_with(&mut registry, None, true, false);
+        let s = registry.render_text();
+        assert!(!s.contains("wireguard_tools_info{"));
+        assert!(s.contains("wireguard_kernel_module_loaded 1\n"));
+        assert!(s.contains("wireguard_exporter_environment_ok 0\n"));
+    }
+
+    #[test]
+    fn test_check() {
+        assert_eq!(check(Collector::Wg, true, true, false, Some(true)), vec![]);
+        // served by a userspace implementation, the capabilities unknown
+        assert_eq!(check(Collector::Wg, true, false, true, None), vec![]);
+        // wg is not needed over netlink, but the kernel module is
+        assert_eq!(check(Collector::Netlink, false, true, false, None), vec![]);
+        assert_eq!(
+            check(Collector::Netlink, true, false, true, None)[0].what,
+            "the WireGuard kernel module is not loaded"
+        );
+        // neither wg nor the capability are needed over the UAPI
+        assert_eq!(
+            check(Collector::Uapi, false, false, true, Some(false)),
+            vec![]
+        );
+        assert_eq!(
+            check(Collector::Uapi, true, true, false, None)[0].what,
+            "no userspace implementation is running"
+        );
+
+        let whats: Vec<_> = check(Collector::Wg, false, false, false, Some(false))
+            .iter()
+            .map(|problem| problem.what)
+            .collect();
+        assert_eq!(
+            whats,
+            vec![
+                "wg cannot be found",
+                "the WireGuard kernel module is not loaded and no userspace implementation is running",
+                "the exporter lacks the CAP_NET_ADMIN capability wg needs",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_net_admin() {
+        let status =
+            "Name:\tprometheus_wire\nCapPrm:\t0000000000001000\nCapEff:\t0000000000001000\n";
+        assert_eq!(parse_net_admin(status), Some(true));
+        let status = "CapEff:\t00000000a80425fb\n";
+        assert_eq!(parse_net_admin(status), Some(false));
+        assert_eq!(parse_net_admin("Name:\tx\n"), None);
+    }
+}
@@ -0,0 +1,260 @@
+// Collection over the cross-platform UAPI of the userspace implementations,
+// wireguard-go and boringtun, whose interfaces are not in the kernel: every
+// socket of the UAPI directories is asked for its device, which is rendered
+// in the `wg show all dump` format like the netlink devices, without `wg`.
+use crate::crypto;
+use crate::implementation::UAPI_DIRS;
+use crate::websocket::base64;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// the implementation answers at once, this only guards against a hung one
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// as wg prints the missing keys, endpoints and allowed IPs
+const EMPTY: &str = "(none)";
+
+/// The UAPI sockets, by interface name. An interface with a socket in
+/// several directories is only listed once, as wg does.
+pub(crate) fn sockets() -> io::Result<Vec<(String, PathBuf)>> {
+    sockets_in(UAPI_DIRS)
+}
+
+fn sockets_in(dirs: &[&str]) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut sockets: Vec<(String, PathBuf)> = Vec::new();
+    for dir in dirs {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if let Some(interface) = name.strip_suffix(".sock") {
+                if !sockets.iter().any(|(other, _)| other == interface) {
+                    sockets.push((interface.to_owned(), entry.path()));
+                }
+            }
+        }
+    }
+    sockets.sort();
+    Ok(sockets)
+}
+
+/// Asks the implementation listening on `socket` for its device, as the
+/// `key=value` lines of the UAPI.
+pub(crate) fn get(socket: &Path) -> io::Result<Vec<String>> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.write_all(b"get=1\n\n")?;
+
+    // the response ends with an empty line
+    let mut lines = Vec::new();
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.is_empty() {
+            return Ok(lines);
+        }
+        lines.push(line);
+    }
+    Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "truncated response",
+    ))
+}
+
+fn invalid(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+// a key of the UAPI, in hexadecimal, as 32 bytes
+fn key_of(value: &str) -> io::Result<[u8; 32]> {
+    // not quoted, it can be a private key
+    let error = || invalid("invalid key".to_owned());
+    if value.len() != 64 || !value.is_ascii() {
+        return Err(error());
+    }
+    let mut bytes = [0; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[2 * i..2 * i + 2], 16).map_err(|_| error())?;
+    }
+    Ok(bytes)
+}
+
+fn key(bytes: Option<[u8; 32]>) -> String {
+    // an all-zero preshared key is the lack of one
+    match bytes {
+        Some(bytes) if bytes != [0; 32] => base64(&bytes),
+        _ => EMPTY.to_owned(),
+    }
+}
+
+#[derive(Debug, Default)]
+struct Peer {
+    public_key: [u8; 32],
+    preshared_key: Option<[u8; 32]>,
+    endpoint: Option<String>,
+    allowed_ips: Vec<String>,
+    latest_handshake: u64,
+    rx_bytes: u64,
+    tx_bytes: u64,
+    persistent_keepalive: u16,
+}
+
+/// Renders the response of `get` for `interface` as `wg show all dump`
+/// does.
+pub(crate) fn to_dump(interface: &str, lines: &[String]) -> io::Result<String> {
+    let mut private_key = None;
+    let mut listen_port = 0;
+    let mut fwmark = 0u32;
+    let mut peers: Vec<Peer> = Vec::new();
+
+    let number = |key: &str, value: &str| {
+        value
+            .parse::<u64>()
+            .map_err(|_| invalid(format!("invalid {} {:?}", key, value)))
+    };
+    for line in lines {
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| invalid(format!("invalid line {:?}", line)))?;
+        match (key, peers.last_mut()) {
+            ("errno", _) if value != "0" => {
+                return Err(invalid(format!(
+                    "the implementation answered errno {}",
+                    value
+                )))
+            }
+            ("public_key", _) => peers.push(Peer {
+                public_key: key_of(value)?,
+                ..Peer::default()
+            }),
+            ("private_key", None) => private_key = Some(key_of(value)?),
+            ("listen_port", None) => listen_port = number(key, value)?,
+            ("fwmark", None) => fwmark = number(key, value)? as u32,
+            ("preshared_key", Some(peer)) => peer.preshared_key = Some(key_of(value)?),
+            ("endpoint", Some(peer)) => peer.endpoint = Some(value.to_owned()),
+            ("allowed_ip", Some(peer)) => peer.allowed_ips.push(value.to_owned()),
+            ("last_handshake_time_sec", Some(peer)) => peer.latest_handshake = number(key, value)?,
+            ("rx_bytes", Some(peer)) => peer.rx_bytes = number(key, value)?,
+            ("tx_bytes", Some(peer)) => peer.tx_bytes = number(key, value)?,
+            ("persistent_keepalive_interval", Some(peer)) => {
+                peer.persistent_keepalive = number(key, value)? as u16
+            }
+            // protocol_version, last_handshake_time_nsec and the keys of
+            // later versions
+            _ => (),
+        }
+    }
+
+    let off = |value: u64, formatted: String| {
+        if value == 0 {
+            "off".to_owned()
+        } else {
+            formatted
+        }
+    };
+    let mut dump = format!(
+        "{}\t{}\t{}\t{}\t{}\n",
+        interface,
+        key(private_key),
+        key(private_key.map(|private_key| crypto::public_key(&private_key))),
+        listen_port,
+        off(fwmark as u64, format!("0x{:x}", fwmark)),
+    );
+    for peer in peers {
+        dump.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            interface,
+            base64(&peer.public_key),
+            key(peer.preshared_key),
+            peer.endpoint.as_deref().unwrap_or(EMPTY),
+            if peer.allowed_ips.is_empty() {
+                EMPTY.to_owned()
+            } else {
+                peer.allowed_ips.join(",")
+            },
+            peer.latest_handshake,
+            peer.rx_bytes,
+            peer.tx_bytes,
+            off(
+                peer.persistent_keepalive as u64,
+                peer.persistent_keepalive.to_string()
+            ),
+        ));
+    }
+    Ok(dump)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::io::Read;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    const RESPONSE: &str =
+        "private_key=77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a
+listen_port=51820
+public_key=d9b3a403802d0e9a47e54a9c1c3b7a8ee2a9b6b3b43ac4b0b8a5f86e4d4cb3f5
+preshared_key=0000000000000000000000000000000000000000000000000000000000000000
+protocol_version=1
+endpoint=[2001:db8::1]:29159
+last_handshake_time_sec=1555771458
+last_handshake_time_nsec=0
+tx_bytes=139524160
+rx_bytes=10288508
+persistent_keepalive_interval=25
+allowed_ip=10.70.0.2/32
+allowed_ip=fd00::2/128
+public_key=aa7ae842840bc2285ca8a4a52b7121e63c3d5a1ac8e81ed0cc0a5a2f69c6ee4c
+errno=0
+";
+
+    #[test]
+    fn test_to_dump() {
+        let lines: Vec<String> = RESPONSE.lines().map(|line| line.to_owned()).collect();
+        let dump = to_dump("wg0", &lines).unwrap();
+        assert_eq!(
+            dump,
+            "wg0\tdwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo=\thSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo=\t51820\toff
+wg0\t2bOkA4AtDppH5UqcHDt6juKptrO0OsSwuKX4bk1Ms/U=\t(none)\t[2001:db8::1]:29159\t10.70.0.2/32,fd00::2/128\t1555771458\t10288508\t139524160\t25
+wg0\tqnroQoQLwihcqKSlK3Eh5jw9WhrI6B7QzApaL2nG7kw=\t(none)\t(none)\t(none)\t0\t0\t0\toff
+"
+        );
+        // parsed as a wg dump
+        assert!(crate::wireguard::WireGuard::try_from(&dump as &str).is_ok());
+
+        let e = to_dump("wg0", &["errno=2".to_owned()]).unwrap_err();
+        assert_eq!(e.to_string(), "the implementation answered errno 2");
+        assert!(to_dump("wg0", &["public_key=zz".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn test_get() {
+        let dir = std::env::temp_dir().join(format!("wgx_uapi_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("wg0.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 7];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(b"listen_port=51820\nerrno=0\n\n").unwrap();
+            request
+        });
+
+        assert_eq!(
+            sockets_in(&[dir.to_str().unwrap(), "/nonexistent"]).unwrap(),
+            vec![("wg0".to_owned(), socket.clone())]
+        );
+        assert_eq!(get(&socket).unwrap(), ["listen_port=51820", "errno=0"]);
+        assert_eq!(&server.join().unwrap(), b"get=1\n\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
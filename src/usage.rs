@@ -0,0 +1,630 @@
+use crate::crypto::{self, Key};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, UNIX_EPOCH};
+
+const HOUR: u64 = 3600;
+
+/// The periods the usage can be summed over.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Period {
+    Hour,
+    Day,
+    Month,
+}
+
+impl Period {
+    // the length of the RFC 3339 prefix identifying the period
+    // (2019-06-08T13, 2019-06-08 or 2019-06)
+    fn label_len(self) -> usize {
+        match self {
+            Period::Hour => 13,
+            Period::Day => 10,
+            Period::Month => 7,
+        }
+    }
+}
+
+impl FromStr for Period {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hour" => Ok(Period::Hour),
+            "day" => Ok(Period::Day),
+            "month" => Ok(Period::Month),
+            _ => Err(format!(
+                "invalid period {}, valid values are hour, day and month",
+                s
+            )),
+        }
+    }
+}
+
+// (hour, interface, public_key) -> (sent_bytes, received_bytes)
+type Hours = BTreeMap<(u64, String, String), (u64, u64)>;
+
+#[derive(Debug, Default)]
+struct UsageState {
+    hours: Hours,
+    // the hour the hours past the retention were last dropped in
+    pruned_hour: Option<u64>,
+    // the database of a .sqlite or .db file
+    #[cfg(feature = "sqlite")]
+    db: Option<rusqlite::Connection>,
+}
+
+/// The bytes transferred by every peer, summed by hour. The totals are
+/// written whenever they change, and the hours older than the retention
+/// are dropped. They are kept in a JSON file, encrypted with a key, or in
+/// an SQLite database if the file ends with `.sqlite` or `.db`.
+#[derive(Debug)]
+pub(crate) struct UsageStore {
+    path: PathBuf,
+    retention: Duration,
+    key: Option<Key>,
+    state: Mutex<UsageState>,
+}
+
+/// Whether `path` is kept in an SQLite database rather than a JSON file.
+fn is_sqlite(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|extension| extension.to_str()),
+        Some("sqlite") | Some("db")
+    )
+}
+
+fn invalid_data<E: fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn parse_hours(value: &Value) -> Option<Hours> {
+    let mut hours = Hours::new();
+    for row in value["hours"].as_array()? {
+        hours.insert(
+            (
+                row["hour"].as_u64()?,
+                row["interface"].as_str()?.to_owned(),
+                row["public_key"].as_str()?.to_owned(),
+            ),
+            (row["sent_bytes"].as_u64()?, row["received_bytes"].as_u64()?),
+        );
+    }
+    Some(hours)
+}
+
+impl UsageStore {
+    /// Loads the totals from `path`, which is created on the first
+    /// write if it does not exist. A plain file is read even if `key` is
+    /// set, and encrypted when next written. A database cannot be
+    /// encrypted.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        retention: Duration,
+        key: Option<Key>,
+    ) -> io::Result<UsageStore> {
+        let path = path.as_ref().to_owned();
+        if is_sqlite(&path) {
+            if key.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "an SQLite usage file cannot be encrypted",
+                ));
+            }
+            return UsageStore::open_sqlite(path, retention);
+        }
+
+        let hours = match fs::read(&path) {
+            Ok(content) => {
+                let content = match (&key, crypto::is_encrypted(&content)) {
+                    (Some(key), true) => crypto::decrypt(key, &content)?,
+                    (None, true) => {
+                        return Err(invalid_data("the file is encrypted but no key was given"))
+                    }
+                    (_, false) => content,
+                };
+                let value: Value = serde_json::from_slice(&content).map_err(invalid_data)?;
+                parse_hours(&value).ok_or_else(|| invalid_data("malformed usage file"))?
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Hours::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(UsageStore {
+            path,
+            retention,
+            key,
+            state: Mutex::new(UsageState {
+                hours,
+                ..UsageState::default()
+            }),
+        })
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn open_sqlite(path: PathBuf, retention: Duration) -> io::Result<UsageStore> {
+        let db = rusqlite::Connection::open(&path).map_err(invalid_data)?;
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS usage (
+                hour INTEGER NOT NULL,
+                interface TEXT NOT NULL,
+                public_key TEXT NOT NULL,
+                sent_bytes INTEGER NOT NULL,
+                received_bytes INTEGER NOT NULL,
+                PRIMARY KEY (hour, interface, public_key)
+            )",
+        )
+        .map_err(invalid_data)?;
+
+        let mut hours = Hours::new();
+        {
+            let mut statement = db
+                .prepare(
+                    "SELECT hour, interface, public_key, sent_bytes, received_bytes FROM usage",
+                )
+                .map_err(invalid_data)?;
+            let rows = statement
+                .query_map([], |row| {
+                    Ok((
+                        (row.get(0)?, row.get(1)?, row.get(2)?),
+                        (row.get(3)?, row.get(4)?),
+                    ))
+                })
+                .map_err(invalid_data)?;
+            for row in rows {
+                let (key, totals) = row.map_err(invalid_data)?;
+                hours.insert(key, totals);
+            }
+        }
+
+        Ok(UsageStore {
+            path,
+            retention,
+            key: None,
+            state: Mutex::new(UsageState {
+                hours,
+                pruned_hour: None,
+                db: Some(db),
+            }),
+        })
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    fn open_sqlite(_path: PathBuf, _retention: Duration) -> io::Result<UsageStore> {
+        Err(io::Error::other(
+            "the exporter is built without the sqlite feature",
+        ))
+    }
+
+    /// Adds the bytes transferred by the peers, as `(interface,
+    /// public_key, sent_bytes, received_bytes)`, to the hour of `now`
+    /// (seconds since the epoch).
+    pub fn record<'a, I>(&self, now: u64, transfers: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = (&'a str, &'a str, u64, u64)>,
+    {
+        let hour = now - now % HOUR;
+
+        let mut state = self.state.lock().unwrap();
+        let mut changed = Vec::new();
+        for (interface, public_key, sent_bytes, received_bytes) in transfers {
+            if sent_bytes == 0 && received_bytes == 0 {
+                continue;
+            }
+            let key = (hour, interface.to_owned(), public_key.to_owned());
+            let totals = state.hours.entry(key.clone()).or_default();
+            totals.0 += sent_bytes;
+            totals.1 += received_bytes;
+            changed.push(key);
+        }
+
+        // the first record of every hour drops the hours past the retention
+        let oldest = now.saturating_sub(self.retention.as_secs());
+        let pruned = state.pruned_hour != Some(hour);
+        if pruned {
+            state.hours.retain(|(hour, _, _), _| *hour + HOUR > oldest);
+            state.pruned_hour = Some(hour);
+        }
+        if changed.is_empty() && !pruned {
+            return Ok(());
+        }
+
+        #[cfg(feature = "sqlite")]
+        if let UsageState {
+            hours,
+            db: Some(db),
+            ..
+        } = &mut *state
+        {
+            return UsageStore::save_sqlite(db, hours, &changed, oldest).map_err(invalid_data);
+        }
+        self.save(&state.hours)
+    }
+
+    // writes the totals of the `changed` hours and deletes those older
+    // than `oldest`, in a single transaction
+    #[cfg(feature = "sqlite")]
+    fn save_sqlite(
+        db: &mut rusqlite::Connection,
+        hours: &Hours,
+        changed: &[(u64, String, String)],
+        oldest: u64,
+    ) -> rusqlite::Result<()> {
+        let transaction = db.transaction()?;
+        for key in changed {
+            let (sent_bytes, received_bytes) = hours[key];
+            transaction.execute(
+                "INSERT INTO usage (hour, interface, public_key, sent_bytes, received_bytes)
+                VALUES (?1, ?2, ?3, ?4, ?5)
+                ON CONFLICT (hour, interface, public_key)
+                DO UPDATE SET sent_bytes = excluded.sent_bytes, received_bytes = excluded.received_bytes",
+                rusqlite::params![key.0, key.1, key.2, sent_bytes, received_bytes],
+            )?;
+        }
+        transaction.execute(
+            "DELETE FROM usage WHERE hour + ?1 <= ?2",
+            rusqlite::params![HOUR, oldest],
+        )?;
+        transaction.commit()
+    }
+
+    // replaces the file at once, so a crash cannot leave it truncated
+    fn save(&self, hours: &Hours) -> io::Result<()> {
+        let rows: Vec<Value> = hours
+            .iter()
+            .map(
+                |((hour, interface, public_key), (sent_bytes, received_bytes))| {
+                    json!({
+                        "hour": hour,
+                        "interface": interface,
+                        "public_key": public_key,
+                        "sent_bytes": sent_bytes,
+                        "received_bytes": received_bytes,
+                    })
+                },
+            )
+            .collect();
+
+        let mut content = json!({ "hours": rows }).to_string().into_bytes();
+        if let Some(key) = &self.key {
+            content = crypto::encrypt(key, &content)?;
+        }
+
+        let mut tmp_path = self.path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, content)?;
+        fs::rename(&tmp_path, &self.path)
+    }
+
+    /// Returns the bytes transferred by every peer between `since` and
+    /// `until` (seconds since the epoch), summed by `period` (in UTC),
+    /// sorted by period, interface and public key.
+    pub fn rows(&self, since: u64, until: u64, period: Period) -> Vec<UsageRow> {
+        let state = self.state.lock().unwrap();
+
+        // (period, interface, public_key) -> (sent_bytes, received_bytes)
+        let mut totals: BTreeMap<(String, &str, &str), (u64, u64)> = BTreeMap::new();
+        for ((hour, interface, public_key), (sent_bytes, received_bytes)) in state.hours.iter() {
+            if *hour + HOUR <= since || *hour >= until {
+                continue;
+            }
+            let timestamp =
+                humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(*hour))
+                    .to_string();
+            let entry = totals
+                .entry((
+                    timestamp[..period.label_len()].to_owned(),
+                    interface,
+                    public_key,
+                ))
+                .or_default();
+            entry.0 += sent_bytes;
+            entry.1 += received_bytes;
+        }
+
+        totals
+            .into_iter()
+            .map(
+                |((period, interface, public_key), (sent_bytes, received_bytes))| UsageRow {
+                    period,
+                    interface: interface.to_owned(),
+                    public_key: public_key.to_owned(),
+                    sent_bytes,
+                    received_bytes,
+                },
+            )
+            .collect()
+    }
+
+    /// The same as `rows`, as the `/api/v1/usage` document.
+    pub fn usage(&self, since: u64, until: u64, period: Period) -> Value {
+        let usage: Vec<Value> = self
+            .rows(since, until, period)
+            .into_iter()
+            .map(|row| {
+                json!({
+                    "period": row.period,
+                    "interface": row.interface,
+                    "public_key": row.public_key,
+                    "sent_bytes": row.sent_bytes,
+                    "received_bytes": row.received_bytes,
+                })
+            })
+            .collect();
+        json!({ "usage": usage })
+    }
+}
+
+/// The bytes transferred by a peer in a period.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct UsageRow {
+    pub period: String,
+    pub interface: String,
+    pub public_key: String,
+    pub sent_bytes: u64,
+    pub received_bytes: u64,
+}
+
+// quotes a CSV field if needed, as per RFC 4180
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Renders `rows` as CSV, with a header line, naming the peers with
+/// `friendly_name`.
+pub(crate) fn render_csv<'a, F>(rows: &[UsageRow], friendly_name: F) -> String
+where
+    F: Fn(&str, &str) -> Option<&'a str>,
+{
+    let mut csv =
+        String::from("period,interface,public_key,friendly_name,sent_bytes,received_bytes\r\n");
+    for row in rows {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{}\r\n",
+            row.period,
+            csv_field(&row.interface),
+            csv_field(&row.public_key),
+            csv_field(friendly_name(&row.interface, &row.public_key).unwrap_or("")),
+            row.sent_bytes,
+            row.received_bytes
+        ));
+    }
+    csv
+}
+
+/// Parses a point in time given as seconds since the epoch, as a date
+/// (`2019-06-01`, midnight UTC) or as an RFC 3339 timestamp.
+pub(crate) fn parse_time(s: &str) -> Result<u64, String> {
+    if let Ok(seconds) = s.parse::<u64>() {
+        return Ok(seconds);
+    }
+    let timestamp = if s.len() == 10 {
+        format!("{}T00:00:00Z", s)
+    } else {
+        s.to_owned()
+    };
+    humantime::parse_rfc3339(&timestamp)
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|since_epoch| since_epoch.as_secs())
+        .ok_or_else(|| format!("invalid time {}, expected a date such as 2019-06-01", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage() {
+        let path = std::env::temp_dir().join(format!(
+            "prometheus_wireguard_exporter_usage_{}.json",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        let retention = Duration::from_secs(40 * 24 * HOUR);
+
+        // 2019-06-08T13:21:00Z
+        let now = 1_560_000_060;
+        let store = UsageStore::open(&path, retention, None).unwrap();
+        store
+            .record(now, vec![("wg0", "A=", 100, 200), ("wg0", "B=", 0, 0)])
+            .unwrap();
+        store.record(now + 60, vec![("wg0", "A=", 10, 20)]).unwrap();
+        // the next day
+        store
+            .record(now + 24 * HOUR, vec![("wg0", "A=", 1, 2)])
+            .unwrap();
+
+        // reloaded from the file
+        let store = UsageStore::open(&path, retention, None).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            store.usage(0, u64::MAX, Period::Day),
+            json!({
+                "usage": [
+                    {
+                        "period": "2019-06-08",
+                        "interface": "wg0",
+                        "public_key": "A=",
+                        "sent_bytes": 110,
+                        "received_bytes": 220,
+                    },
+                    {
+                        "period": "2019-06-09",
+                        "interface": "wg0",
+                        "public_key": "A=",
+                        "sent_bytes": 1,
+                        "received_bytes": 2,
+                    },
+                ],
+            })
+        );
+        assert_eq!(
+            store.usage(0, u64::MAX, Period::Month)["usage"][0]["sent_bytes"],
+            111
+        );
+        assert_eq!(
+            store.usage(now + HOUR, u64::MAX, Period::Hour)["usage"][0]["period"],
+            "2019-06-09T13"
+        );
+
+        let csv = render_csv(&store.rows(0, u64::MAX, Period::Month), |_, public_key| {
+            if public_key == "A=" {
+                Some("Laptop, \"work\"")
+            } else {
+                None
+            }
+        });
+        assert_eq!(
+            csv,
+            "period,interface,public_key,friendly_name,sent_bytes,received_bytes\r\n2019-06,wg0,A=,\"Laptop, \"\"work\"\"\",111,222\r\n"
+        );
+    }
+
+    #[test]
+    fn test_saved_every_record() {
+        let path = std::env::temp_dir().join(format!(
+            "prometheus_wireguard_exporter_usage_saved_{}.json",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        let retention = Duration::from_secs(40 * 24 * HOUR);
+
+        // two records in the same hour, both kept by a restart
+        let store = UsageStore::open(&path, retention, None).unwrap();
+        store
+            .record(1_560_000_060, vec![("wg0", "A=", 100, 200)])
+            .unwrap();
+        store
+            .record(1_560_000_120, vec![("wg0", "A=", 10, 20)])
+            .unwrap();
+        let store = UsageStore::open(&path, retention, None).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let usage = store.usage(0, u64::MAX, Period::Hour);
+        assert_eq!(usage["usage"][0]["sent_bytes"], 110);
+        assert_eq!(usage["usage"][0]["received_bytes"], 220);
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn test_sqlite() {
+        let path = std::env::temp_dir().join(format!(
+            "prometheus_wireguard_exporter_usage_{}.sqlite",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        let retention = Duration::from_secs(2 * 24 * HOUR);
+
+        let store = UsageStore::open(&path, retention, None).unwrap();
+        store
+            .record(1_560_000_060, vec![("wg0", "A=", 100, 200)])
+            .unwrap();
+        store
+            .record(
+                1_560_000_120,
+                vec![("wg0", "A=", 10, 20), ("wg0", "B=", 1, 2)],
+            )
+            .unwrap();
+        let store = UsageStore::open(&path, retention, None).unwrap();
+        assert_eq!(
+            store.usage(0, u64::MAX, Period::Day),
+            json!({
+                "usage": [
+                    {
+                        "period": "2019-06-08",
+                        "interface": "wg0",
+                        "public_key": "A=",
+                        "sent_bytes": 110,
+                        "received_bytes": 220,
+                    },
+                    {
+                        "period": "2019-06-08",
+                        "interface": "wg0",
+                        "public_key": "B=",
+                        "sent_bytes": 1,
+                        "received_bytes": 2,
+                    },
+                ],
+            })
+        );
+
+        // the hours past the retention are deleted from the database
+        store
+            .record(1_560_000_060 + 3 * 24 * HOUR, vec![("wg0", "A=", 1, 1)])
+            .unwrap();
+        let store = UsageStore::open(&path, retention, None).unwrap();
+        assert_eq!(store.rows(0, u64::MAX, Period::Hour).len(), 1);
+
+        let key = Key::from_hex(&"2a".repeat(32)).unwrap();
+        assert!(UsageStore::open(&path, retention, Some(key)).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(not(feature = "sqlite"))]
+    fn test_sqlite_unavailable() {
+        let path = std::env::temp_dir().join("prometheus_wireguard_exporter_usage.sqlite");
+        let e = UsageStore::open(&path, Duration::from_secs(HOUR), None).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "the exporter is built without the sqlite feature"
+        );
+        assert!(!path.exists());
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn test_encrypted() {
+        let path = std::env::temp_dir().join(format!(
+            "prometheus_wireguard_exporter_usage_encrypted_{}.json",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        let retention = Duration::from_secs(40 * 24 * HOUR);
+        let key = Key::from_hex(&"2a".repeat(32)).unwrap();
+
+        // a plain file is encrypted when rewritten
+        let store = UsageStore::open(&path, retention, None).unwrap();
+        store
+            .record(1_560_000_060, vec![("wg0", "A=", 100, 200)])
+            .unwrap();
+        let store = UsageStore::open(&path, retention, Some(key.clone())).unwrap();
+        store
+            .record(1_560_000_060 + HOUR, vec![("wg0", "A=", 10, 20)])
+            .unwrap();
+
+        let content = fs::read(&path).unwrap();
+        assert!(crypto::is_encrypted(&content));
+        assert!(!String::from_utf8_lossy(&content).contains("A="));
+
+        let store = UsageStore::open(&path, retention, Some(key)).unwrap();
+        assert_eq!(
+            store.usage(0, u64::MAX, Period::Day)["usage"][0]["sent_bytes"],
+            110
+        );
+        assert!(UsageStore::open(&path, retention, None).is_err());
+        let other = Key::from_hex(&"2b".repeat(32)).unwrap();
+        assert!(UsageStore::open(&path, retention, Some(other)).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("1560000060"), Ok(1_560_000_060));
+        assert_eq!(parse_time("2019-06-08"), Ok(1_559_952_000));
+        assert_eq!(parse_time("2019-06-08T13:21:00Z"), Ok(1_560_000_060));
+        assert!(parse_time("June").is_err());
+    }
+}
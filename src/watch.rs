@@ -0,0 +1,247 @@
+// The `watch` subcommand: polls the JSON API of a running exporter and
+// redraws a table of its peers, with the transfer rates computed
+// between two consecutive samples.
+use crate::clock;
+use crate::exporter_error::ExporterError;
+use futures::{Future, Stream};
+use hyper::{Client, Uri};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::runtime::current_thread::Runtime;
+
+// clears the screen and moves the cursor to the top left corner
+const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    taken: Instant,
+    received_bytes: u64,
+    sent_bytes: u64,
+}
+
+/// Formats a byte count using binary prefixes, eg. `1.5 MiB`.
+pub(crate) fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{:.0} {}", value, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Formats the time elapsed since `latest_handshake` (seconds since the
+/// epoch, 0 meaning no handshake ever happened).
+pub(crate) fn format_handshake_age(latest_handshake: u64, now: u64) -> String {
+    if latest_handshake == 0 {
+        return "never".to_owned();
+    }
+
+    let age = clock::age(now, latest_handshake);
+    if age < 60 {
+        format!("{}s ago", age)
+    } else if age < 3600 {
+        format!("{}m {}s ago", age / 60, age % 60)
+    } else if age < 86400 {
+        format!("{}h {}m ago", age / 3600, age % 3600 / 60)
+    } else {
+        format!("{}d {}h ago", age / 86400, age % 86400 / 3600)
+    }
+}
+
+fn rate(previous: u64, current: u64, elapsed: Duration) -> Option<f64> {
+    let elapsed = elapsed.as_secs_f64();
+    if elapsed > 0.0 && current >= previous {
+        Some((current - previous) as f64 / elapsed)
+    } else {
+        // the very first sample or a counter reset
+        None
+    }
+}
+
+fn format_rate(rate: Option<f64>) -> String {
+    match rate {
+        Some(rate) => format!("{}/s", format_bytes(rate)),
+        None => "-".to_owned(),
+    }
+}
+
+/// Renders the `/api/v1/peers` document as a table. `previous` holds the
+/// samples of the last refresh and is updated with the current ones.
+fn render_table(
+    peers: &Value,
+    previous: &mut HashMap<String, Sample>,
+    taken: Instant,
+    now: u64,
+) -> String {
+    let mut rows = vec![[
+        "INTERFACE".to_owned(),
+        "PEER".to_owned(),
+        "ENDPOINT".to_owned(),
+        "HANDSHAKE".to_owned(),
+        "RX RATE".to_owned(),
+        "TX RATE".to_owned(),
+        "RX".to_owned(),
+        "TX".to_owned(),
+    ]];
+
+    let mut samples = HashMap::new();
+    for peer in peers["peers"].as_array().into_iter().flatten() {
+        let interface = peer["interface"].as_str().unwrap_or_default();
+        let public_key = peer["public_key"].as_str().unwrap_or_default();
+        let sample = Sample {
+            taken,
+            received_bytes: peer["received_bytes"].as_u64().unwrap_or_default(),
+            sent_bytes: peer["sent_bytes"].as_u64().unwrap_or_default(),
+        };
+
+        let key = format!("{}/{}", interface, public_key);
+        let (rx_rate, tx_rate) = match previous.get(&key) {
+            Some(p) => (
+                rate(p.received_bytes, sample.received_bytes, taken - p.taken),
+                rate(p.sent_bytes, sample.sent_bytes, taken - p.taken),
+            ),
+            None => (None, None),
+        };
+
+        let endpoint = match (peer["remote_ip"].as_str(), peer["remote_port"].as_u64()) {
+            (Some(ip), Some(port)) if ip.contains(':') => format!("[{}]:{}", ip, port),
+            (Some(ip), Some(port)) => format!("{}:{}", ip, port),
+            _ => "-".to_owned(),
+        };
+
+        rows.push([
+            interface.to_owned(),
+            peer["friendly_name"]
+                .as_str()
+                .unwrap_or(public_key)
+                .to_owned(),
+            endpoint,
+            format_handshake_age(peer["latest_handshake"].as_u64().unwrap_or_default(), now),
+            format_rate(rx_rate),
+            format_rate(tx_rate),
+            format_bytes(sample.received_bytes as f64),
+            format_bytes(sample.sent_bytes as f64),
+        ]);
+        samples.insert(key, sample);
+    }
+    *previous = samples;
+
+    let mut widths = [0; 8];
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut table = String::new();
+    for row in rows.iter() {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        table.push_str(cells.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+fn fetch(
+    runtime: &mut Runtime,
+    client: &Client<hyper::client::HttpConnector>,
+    uri: Uri,
+) -> Result<Value, ExporterError> {
+    let body = runtime.block_on(
+        client
+            .get(uri)
+            .and_then(|response| response.into_body().concat2()),
+    )?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// Polls `{url}/api/v1/peers` every `interval` and redraws the table
+/// of peers until interrupted. Failed polls are shown instead of the table.
+pub(crate) fn run(url: &str, interval: Duration) -> Result<(), ExporterError> {
+    let uri = format!("{}/api/v1/peers", url.trim_end_matches('/')).parse::<Uri>()?;
+
+    let mut runtime = Runtime::new()?;
+    let client = Client::new();
+    let mut previous = HashMap::new();
+
+    loop {
+        let screen = match fetch(&mut runtime, &client, uri.clone()) {
+            Ok(peers) => render_table(&peers, &mut previous, Instant::now(), clock::now()),
+            Err(e) => format!("cannot fetch {}: {}\n", uri, e),
+        };
+
+        print!(
+            "{}{}\nrefreshing every {:?}, press Ctrl-C to exit\n",
+            CLEAR_SCREEN, screen, interval
+        );
+        io::stdout().flush()?;
+        thread::sleep(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_format() {
+        assert_eq!(format_bytes(512.0), "512 B");
+        assert_eq!(format_bytes(1536.0), "1.5 KiB");
+        assert_eq!(format_bytes(10_288_508.0), "9.8 MiB");
+
+        assert_eq!(format_handshake_age(0, 1000), "never");
+        assert_eq!(format_handshake_age(990, 1000), "10s ago");
+        assert_eq!(format_handshake_age(1000, 1125), "2m 5s ago");
+        assert_eq!(format_handshake_age(1000, 1000 + 90_000), "1d 1h ago");
+    }
+
+    #[test]
+    fn test_render_table() {
+        let peers = |sent_bytes: u64| {
+            json!({ "peers": [{
+                "interface": "wg0",
+                "public_key": "2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=",
+                "friendly_name": "OnePlus 6T",
+                "remote_ip": "37.159.76.245",
+                "remote_port": 29159,
+                "latest_handshake": 1000,
+                "sent_bytes": sent_bytes,
+                "received_bytes": 0,
+            }]})
+        };
+
+        let mut previous = HashMap::new();
+        let start = Instant::now();
+
+        let table = render_table(&peers(0), &mut previous, start, 1010);
+        let row = table.lines().nth(1).unwrap();
+        assert!(row.starts_with("wg0        OnePlus 6T  37.159.76.245:29159  10s ago"));
+        assert!(row.contains("  -  "));
+
+        let table = render_table(
+            &peers(20480),
+            &mut previous,
+            start + Duration::from_secs(2),
+            1012,
+        );
+        let row = table.lines().nth(1).unwrap();
+        assert!(row.contains("10.0 KiB/s"));
+        assert!(row.ends_with("20.0 KiB"));
+    }
+}
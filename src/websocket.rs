@@ -0,0 +1,215 @@
+// Just enough of RFC 6455 to push text messages to a browser or CLI
+// client: the opening handshake and unmasked server-to-client frames.
+// Messages sent by the client are never read.
+use futures::{Future, Stream};
+use http::header::{CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE};
+use http::StatusCode;
+use hyper::{Body, Request, Response};
+use log::{debug, trace};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub(crate) fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [
+        0x6745_2301,
+        0xEFCD_AB89,
+        0x98BA_DCFE,
+        0x1032_5476,
+        0xC3D2_E1F0,
+    ];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for (i, wi) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ED9_EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*wi);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (h, v) in h.iter_mut().zip(&[a, b, c, d, e]) {
+            *h = h.wrapping_add(*v);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (chunk, h) in digest.chunks_mut(4).zip(h.iter()) {
+        chunk.copy_from_slice(&h.to_be_bytes());
+    }
+    digest
+}
+
+pub(crate) fn base64(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for chunk in data.chunks(3) {
+        let n = (u32::from(chunk[0]) << 16)
+            | (u32::from(*chunk.get(1).unwrap_or(&0)) << 8)
+            | u32::from(*chunk.get(2).unwrap_or(&0));
+
+        s.push(BASE64_ALPHABET[(n >> 18) as usize & 63] as char);
+        s.push(BASE64_ALPHABET[(n >> 12) as usize & 63] as char);
+        if chunk.len() > 1 {
+            s.push(BASE64_ALPHABET[(n >> 6) as usize & 63] as char);
+        } else {
+            s.push('=');
+        }
+        if chunk.len() > 2 {
+            s.push(BASE64_ALPHABET[n as usize & 63] as char);
+        } else {
+            s.push('=');
+        }
+    }
+
+    s
+}
+
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+/// Encodes `text` as a single, final, unmasked text frame.
+fn text_frame(text: &str) -> Vec<u8> {
+    let payload = text.as_bytes();
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x81);
+
+    if payload.len() < 126 {
+        frame.push(payload.len() as u8);
+    } else if payload.len() <= 0xFFFF {
+        frame.push(126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        frame.push(127);
+        frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Completes the WebSocket handshake for `req` and, once the connection
+/// has been upgraded, sends every message of `messages` as a text frame.
+/// Returns 400 if `req` is not a WebSocket handshake.
+pub(crate) fn upgrade<S>(req: Request<Body>, messages: S) -> Response<Body>
+where
+    S: Stream<Item = String, Error = ()> + Send + 'static,
+{
+    let is_websocket = req
+        .headers()
+        .get(UPGRADE)
+        .and_then(|upgrade| upgrade.to_str().ok())
+        .map(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false);
+
+    let key = match req.headers().get(SEC_WEBSOCKET_KEY) {
+        Some(key) if is_websocket => key.to_str().unwrap_or_default().to_owned(),
+        _ => {
+            trace!("not a websocket handshake");
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::empty())
+                .unwrap();
+        }
+    };
+
+    let task = req
+        .into_body()
+        .on_upgrade()
+        .map_err(|e| debug!("websocket upgrade failed: {}", e))
+        .and_then(move |upgraded| {
+            messages
+                .fold(upgraded, |upgraded, message| {
+                    tokio_io::io::write_all(upgraded, text_frame(&message))
+                        .map(|(upgraded, _)| upgraded)
+                        .map_err(|e| debug!("websocket client went away: {}", e))
+                })
+                .map(|_| ())
+        });
+    hyper::rt::spawn(task);
+
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(UPGRADE, "websocket")
+        .header(CONNECTION, "Upgrade")
+        .header(SEC_WEBSOCKET_ACCEPT, accept_key(&key))
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha1() {
+        let hex: String = sha1(b"abc").iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(hex, "a9993e364706816aba3e25717850c26c9cd0d89d");
+
+        // two blocks
+        let hex: String = sha1(&[b'a'; 100])
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(hex, "7f9000257a4918d7072655ea468540cdcbd42e0c");
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // from RFC 6455, section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_text_frame() {
+        assert_eq!(text_frame("hi"), vec![0x81, 2, b'h', b'i']);
+
+        let long = "a".repeat(300);
+        let frame = text_frame(&long);
+        assert_eq!(&frame[..4], &[0x81, 126, 1, 44]);
+        assert_eq!(frame.len(), 304);
+    }
+}
@@ -1,13 +1,65 @@
+use crate::clock;
+use crate::compat::Compat;
 use crate::exporter_error::ExporterError;
-use crate::wireguard_config::PeerEntryHashMap;
-use log::{debug, trace};
-use prometheus_exporter_base::PrometheusCounter;
-use std::collections::HashMap;
+use crate::metrics::{MetricType, Registry};
+use crate::options::GroupSla;
+use crate::watch::{format_bytes, format_handshake_age};
+use crate::websocket::sha1;
+use crate::wireguard_config::{NameSource, PeerEntries};
+use log::{debug, trace, warn};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
-use std::net::SocketAddr;
+use std::fmt::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
 
 const EMPTY: &str = "(none)";
 
+/// What `/api/v1/export` renders the peers of an interface as.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ExportFormat {
+    /// the clients of a wg-easy backup
+    WgEasy,
+    /// the `[Peer]` sections of a wg-quick config
+    WgQuick,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wg-easy" => Ok(ExportFormat::WgEasy),
+            "wg-quick" => Ok(ExportFormat::WgQuick),
+            _ => Err(format!(
+                "invalid format {}, valid values are wg-easy and wg-quick",
+                s
+            )),
+        }
+    }
+}
+
+/// A stable UUID for a peer, as wg-easy keys its clients by UUID: a
+/// version 5 like UUID of the public key.
+fn peer_uuid(public_key: &str) -> String {
+    let mut bytes = sha1(public_key.as_bytes());
+    bytes[6] = (bytes[6] & 0x0f) | 0x50;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes[..16].iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+#[allow(dead_code)]
 #[derive(Default, Debug, Clone)]
 pub(crate) struct LocalEndpoint {
     pub public_key: String,
@@ -23,221 +75,1887 @@ pub(crate) struct RemoteEndpoint {
     pub remote_port: Option<u16>,
     pub local_ip: String,
     pub local_subnet: String,
+    pub allowed_ips: Vec<String>,
     pub latest_handshake: u64,
     pub sent_bytes: u128,
     pub received_bytes: u128,
     pub persistent_keepalive: bool,
+    pub has_preshared_key: bool,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub(crate) enum Endpoint {
+    Local(LocalEndpoint),
+    Remote(RemoteEndpoint),
+}
+
+impl RemoteEndpoint {
+    /// A peer is online if it completed a handshake within `handshake_timeout`.
+    pub(crate) fn is_online(&self, now: u64, handshake_timeout: Duration) -> bool {
+        self.latest_handshake != 0
+            && clock::age(now, self.latest_handshake) <= handshake_timeout.as_secs()
+    }
+
+    /// Returns the endpoint in the `ip:port` form, if known.
+    pub(crate) fn endpoint(&self) -> Option<String> {
+        match (&self.remote_ip, self.remote_port) {
+            (Some(ip), Some(port)) if ip.contains(':') => Some(format!("[{}]:{}", ip, port)),
+            (Some(ip), Some(port)) => Some(format!("{}:{}", ip, port)),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the allowed IPs include IPv4 and IPv6 ranges. The
+    /// entries which are not ranges, such as `(none)`, are of neither.
+    pub(crate) fn ip_families(&self) -> (bool, bool) {
+        let addresses: Vec<IpAddr> = self
+            .allowed_ips
+            .iter()
+            .filter_map(|ip| parse_allowed_ip(ip))
+            .map(|(address, _)| address)
+            .collect();
+        (
+            addresses.iter().any(IpAddr::is_ipv4),
+            addresses.iter().any(IpAddr::is_ipv6),
+        )
+    }
+}
+
+fn to_option_string(s: &str) -> Option<String> {
+    if s == EMPTY {
+        None
+    } else {
+        Some(s.to_owned())
+    }
+}
+
+fn to_bool(s: &str) -> bool {
+    s != "off"
+}
+
+// parses an allowed IP leniently: the octets of IPv4 addresses may have
+// leading zeros and a bare address is a host range
+fn parse_allowed_ip(ip: &str) -> Option<(IpAddr, u8)> {
+    let mut split = ip.splitn(2, '/');
+    let address = split.next()?;
+    let address = match address.parse::<Ipv6Addr>() {
+        Ok(address) => IpAddr::V6(address),
+        Err(_) => {
+            let octets = address
+                .split('.')
+                .map(|octet| octet.parse::<u8>().ok())
+                .collect::<Option<Vec<u8>>>()
+                .filter(|octets| octets.len() == 4)?;
+            IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+        }
+    };
+    let max_len = if address.is_ipv4() { 32 } else { 128 };
+    let len = match split.next() {
+        Some(len) => len.parse::<u8>().ok().filter(|len| *len <= max_len)?,
+        None => max_len,
+    };
+    Some((address, len))
+}
+
+/// Returns the canonical form of an allowed IP: IPv6 addresses lowercase
+/// and compressed, no leading zeros and always a prefix length, so the
+/// labels of a peer are the same whatever wrote them. What cannot be
+/// parsed is returned as is.
+pub(crate) fn canonical_allowed_ip(ip: &str) -> String {
+    match parse_allowed_ip(ip) {
+        Some((address, len)) => format!("{}/{}", address, len),
+        None => ip.to_owned(),
+    }
+}
+
+/// Splits the comma separated allowed IPs of `wg show dump` into their
+/// canonical forms, sorted by family, address and prefix length.
+pub(crate) fn canonical_allowed_ips(ips: &str) -> Vec<String> {
+    let mut ips: Vec<String> = ips.split(',').map(canonical_allowed_ip).collect();
+    ips.sort_by_key(|ip| parse_allowed_ip(ip));
+    ips.dedup();
+    ips
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct WireGuard {
+    pub interfaces: HashMap<String, Vec<Endpoint>>,
+    /// the peers dropped by `retain_configured_peers`, by interface
+    pub unconfigured_peers: Vec<(String, RemoteEndpoint)>,
+    /// how long every interface took to collect, if they were collected
+    /// one at a time
+    pub collect_durations: Vec<(String, Duration)>,
+    /// how old the collection is, if it was served the latest dump because
+    /// of `--min-collect-interval`
+    pub cached_age: Option<Duration>,
+}
+
+// parsing in parallel is not worth spawning a thread for fewer lines
+const MIN_LINES_PER_PARSE_THREAD: usize = 4096;
+
+// parses the field `name` of line `number`, which does not quote it: the
+// keys are never part of the errors
+fn parse_field<T: FromStr>(number: usize, name: &str, value: &str) -> Result<T, ExporterError> {
+    value.parse::<T>().map_err(|_| ExporterError::Dump {
+        line: number,
+        reason: format!("invalid {} {:?}", name, value),
+    })
+}
+
+/// Parses line `number` (from 1) of the dump, None if it is empty. The
+/// dump can come from `--dump-file` as well as from wg, so nothing is
+/// taken for granted.
+fn parse_line(number: usize, line: &str) -> Result<Option<(&str, Endpoint)>, ExporterError> {
+    if line.trim().is_empty() {
+        return Ok(None);
+    }
+    let v: Vec<&str> = line.split('\t').filter(|s| !s.is_empty()).collect();
+    debug!("v == {:?}", v);
+
+    let endpoint = match v.len() {
+        5 => {
+            // this is the local interface
+            Endpoint::Local(LocalEndpoint {
+                public_key: v[1].to_owned(),
+                private_key: v[2].to_owned(),
+                local_port: parse_field(number, "listen port", v[3])?,
+                persistent_keepalive: to_bool(v[4]),
+            })
+        }
+        9 => {
+            // remote endpoint
+            let public_key = v[1].to_owned();
+
+            let (remote_ip, remote_port) = match to_option_string(v[3]) {
+                Some(ip_and_port) => {
+                    let addr: SocketAddr = parse_field(number, "endpoint", &ip_and_port)?;
+                    (Some(addr.ip().to_string()), Some(addr.port()))
+                }
+                None => (None, None),
+            };
+
+            // the first allowed IP in the order of wg, as the labels always
+            // were, empty for a peer without any
+            let (local_ip, local_subnet) = match v[4].split(',').next().and_then(parse_allowed_ip) {
+                Some((address, len)) => (address.to_string(), len.to_string()),
+                None => (String::new(), String::new()),
+            };
+            let allowed_ips = canonical_allowed_ips(v[4]);
+
+            Endpoint::Remote(RemoteEndpoint {
+                public_key,
+                remote_ip,
+                remote_port,
+                local_ip,
+                local_subnet,
+                allowed_ips,
+                latest_handshake: parse_field(number, "latest handshake", v[5])?,
+                sent_bytes: parse_field(number, "transfer rx", v[6])?,
+                received_bytes: parse_field(number, "transfer tx", v[7])?,
+                persistent_keepalive: to_bool(v[8]),
+                // the key itself is a secret, it is not kept
+                has_preshared_key: v[2] != EMPTY,
+            })
+        }
+        fields => {
+            return Err(ExporterError::Dump {
+                line: number,
+                reason: format!("expected 5 or 9 fields, found {}", fields),
+            })
+        }
+    };
+
+    trace!("{:?}", endpoint);
+    Ok(Some((v[0], endpoint)))
+}
+
+// `first` is the number of the first line
+fn parse_lines<'a>(
+    first: usize,
+    lines: &[&'a str],
+) -> Result<Vec<(&'a str, Endpoint)>, ExporterError> {
+    let mut endpoints = Vec::with_capacity(lines.len());
+    for (i, line) in lines.iter().enumerate() {
+        endpoints.extend(parse_line(first + i, line)?);
+    }
+    Ok(endpoints)
+}
+
+/// Turns the output of `wg show <interface> dump` into the lines of
+/// `wg show all dump`, which start with the interface.
+pub(crate) fn prefix_interface_dump(interface: &str, dump: &str) -> String {
+    dump.lines()
+        .map(|line| format!("{}\t{}\n", interface, line))
+        .collect()
+}
+
+/// Appends `suffix` to the interfaces of a `wg show all dump`, as in
+/// `wg0@gw1` for a remote host, so that they tell apart from the local ones.
+pub(crate) fn qualify_interface_dump(suffix: &str, dump: &str) -> String {
+    dump.lines()
+        .map(|line| match line.split_once('\t') {
+            Some((interface, rest)) => format!("{}{}\t{}\n", interface, suffix, rest),
+            None => format!("{}\n", line),
+        })
+        .collect()
+}
+
+impl WireGuard {
+    /// Parses the output of `wg show all dump`. Very large dumps are
+    /// split among up to `threads` threads, the endpoints end up in the
+    /// same order anyway.
+    pub(crate) fn parse(input: &str, threads: usize) -> Result<WireGuard, ExporterError> {
+        debug!("wireguard::parse({}) called", input);
+
+        let lines: Vec<&str> = input.lines().collect();
+        let threads = threads.min(lines.len() / MIN_LINES_PER_PARSE_THREAD).max(1);
+
+        let chunks = if threads == 1 {
+            vec![parse_lines(1, &lines)?]
+        } else {
+            let chunk_len = lines.len().div_ceil(threads);
+            thread::scope(|scope| {
+                let handles: Vec<_> = lines
+                    .chunks(chunk_len)
+                    .enumerate()
+                    .map(|(i, chunk)| scope.spawn(move || parse_lines(1 + i * chunk_len, chunk)))
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().unwrap())
+                    .collect::<Result<Vec<_>, _>>()
+            })?
+        };
+
+        let mut wg = WireGuard {
+            interfaces: HashMap::new(),
+            unconfigured_peers: Vec::new(),
+            collect_durations: Vec::new(),
+            cached_age: None,
+        };
+        for (interface, endpoint) in chunks.into_iter().flatten() {
+            if let Some(endpoints) = wg.interfaces.get_mut(interface) {
+                endpoints.push(endpoint);
+            } else {
+                wg.interfaces.insert(interface.to_owned(), vec![endpoint]);
+            }
+        }
+
+        trace!("{:?}", wg);
+        Ok(wg)
+    }
+}
+
+impl TryFrom<&str> for WireGuard {
+    type Error = ExporterError;
+
+    fn try_from(input: &str) -> Result<Self, Self::Error> {
+        WireGuard::parse(input, 1)
+    }
+}
+
+fn friendly_name<'a>(
+    pehm: Option<&PeerEntries<'a>>,
+    interface: &str,
+    public_key: &str,
+) -> Option<&'a str> {
+    pehm.and_then(|pehm| pehm.get(interface, public_key))
+        .and_then(|peer_entry| peer_entry.name)
 }
 
-#[derive(Debug, Clone)]
-pub(crate) enum Endpoint {
-    Local(LocalEndpoint),
-    Remote(RemoteEndpoint),
-}
+fn render_below_threshold(
+    registry: &mut Registry,
+    below_threshold: &BTreeMap<&str, (u32, u128, u128)>,
+) {
+    let mut peers_family = registry.family(
+        "wireguard_below_threshold_peers",
+        MetricType::Gauge,
+        "Peers not exported individually because of their low traffic",
+    );
+    let mut sent_bytes_family = registry.family(
+        "wireguard_below_threshold_sent_bytes",
+        MetricType::Gauge,
+        "Bytes sent to the peers not exported individually",
+    );
+    let mut received_bytes_family = registry.family(
+        "wireguard_below_threshold_received_bytes",
+        MetricType::Gauge,
+        "Bytes received from the peers not exported individually",
+    );
+
+    for (interface, (peers, sent_bytes, received_bytes)) in below_threshold.iter() {
+        let attributes = [("interface", *interface)];
+        peers_family.push(&attributes, f64::from(*peers));
+        sent_bytes_family.push(&attributes, *sent_bytes as f64);
+        received_bytes_family.push(&attributes, *received_bytes as f64);
+    }
+
+    registry.register(peers_family);
+    registry.register(sent_bytes_family);
+    registry.register(received_bytes_family);
+}
+
+impl WireGuard {
+    /// A short plain text summary, for humans: the peers online on every
+    /// interface and its top peer by traffic, collected at `now`.
+    pub(crate) fn render_summary(
+        &self,
+        pehm: Option<&PeerEntries>,
+        now: u64,
+        handshake_timeout: Duration,
+    ) -> String {
+        let mut interfaces: Vec<_> = self.interfaces.iter().collect();
+        interfaces.sort_by_key(|(interface, _)| *interface);
+
+        let (mut total_online, mut total) = (0, 0);
+        let mut lines = Vec::new();
+        for (interface, endpoints) in interfaces {
+            let peers: Vec<&RemoteEndpoint> = endpoints
+                .iter()
+                .filter_map(|endpoint| match endpoint {
+                    Endpoint::Remote(ep) => Some(ep),
+                    Endpoint::Local(_) => None,
+                })
+                .collect();
+            let online = peers
+                .iter()
+                .filter(|ep| ep.is_online(now, handshake_timeout))
+                .count();
+            total_online += online;
+            total += peers.len();
+            lines.push(format!(
+                "{}: {}/{} peers online",
+                interface,
+                online,
+                peers.len()
+            ));
+
+            let top = peers
+                .iter()
+                .filter(|ep| ep.sent_bytes + ep.received_bytes > 0)
+                .max_by_key(|ep| ep.sent_bytes + ep.received_bytes);
+            if let Some(ep) = top {
+                lines.push(format!(
+                    "  top peer: {}, {} (handshake {})",
+                    friendly_name(pehm, interface, &ep.public_key).unwrap_or(&ep.public_key),
+                    format_bytes((ep.sent_bytes + ep.received_bytes) as f64),
+                    format_handshake_age(ep.latest_handshake, now)
+                ));
+            }
+        }
+
+        let mut summary = format!(
+            "{} interface{}, {}/{} peers online\n",
+            self.interfaces.len(),
+            if self.interfaces.len() == 1 { "" } else { "s" },
+            total_online,
+            total
+        );
+        for line in lines {
+            summary.push_str(&line);
+            summary.push('\n');
+        }
+        summary.push_str(&format!(
+            "collected at {}\n",
+            humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(now))
+        ));
+        summary
+    }
+
+    /// Renders how long the last collection of every interface took,
+    /// when they are collected one at a time.
+    pub(crate) fn render_collect_durations(&self, registry: &mut Registry) {
+        if self.collect_durations.is_empty() {
+            return;
+        }
+        let mut durations = registry.family(
+            "wireguard_exporter_interface_collect_duration_seconds",
+            MetricType::Gauge,
+            "Seconds the last collection of the interface took",
+        );
+        for (interface, duration) in self.collect_durations.iter() {
+            durations.push(&[("interface", interface)], duration.as_secs_f64());
+        }
+        registry.register(durations);
+    }
+
+    /// Renders how old the dump collected is, 0 unless it was served from
+    /// the cache.
+    pub(crate) fn render_data_age(&self, registry: &mut Registry) {
+        let mut age = registry.family(
+            "wireguard_exporter_data_age_seconds",
+            MetricType::Gauge,
+            "Seconds since the dump served was collected",
+        );
+        age.push(&[], self.cached_age.unwrap_or_default().as_secs_f64());
+        registry.register(age);
+    }
+
+    /// Drops the remote endpoints whose public key is not in `pehm`,
+    /// keeping them aside in `unconfigured_peers`.
+    pub(crate) fn retain_configured_peers(&mut self, pehm: &PeerEntries) {
+        for (interface, endpoints) in self.interfaces.iter_mut() {
+            let (retained, dropped) = endpoints.drain(..).partition(|endpoint| match endpoint {
+                Endpoint::Remote(ep) => pehm.get(interface, &ep.public_key).is_some(),
+                Endpoint::Local(_) => true,
+            });
+            *endpoints = retained;
+            for endpoint in dropped {
+                if let Endpoint::Remote(ep) = endpoint {
+                    self.unconfigured_peers.push((interface.clone(), ep));
+                }
+            }
+        }
+    }
+
+    /// The stable IDs given to the peers in `pehm`, by interface and public
+    /// key. An ID given to more than one peer of an interface is ignored,
+    /// as their series would be merged.
+    pub(crate) fn peer_ids<'w, 'c>(
+        &'w self,
+        pehm: &PeerEntries<'c>,
+    ) -> BTreeMap<(&'w str, &'w str), &'c str> {
+        let mut ids = BTreeMap::new();
+        for (interface, endpoints) in self.interfaces.iter() {
+            let mut peers: HashMap<&str, Vec<&str>> = HashMap::new();
+            for endpoint in endpoints {
+                if let Endpoint::Remote(ep) = endpoint {
+                    if let Some(id) = pehm.get(interface, &ep.public_key).and_then(|pe| pe.id) {
+                        peers.entry(id).or_default().push(&ep.public_key);
+                    }
+                }
+            }
+
+            for (id, public_keys) in peers {
+                if public_keys.len() > 1 {
+                    warn!(
+                        "the ID {} is given to {} peers of {}, using their public keys",
+                        id,
+                        public_keys.len(),
+                        interface
+                    );
+                    continue;
+                }
+                ids.insert((interface as &str, public_keys[0]), id);
+            }
+        }
+        ids
+    }
+
+    /// Renders the public key of every peer with a stable ID.
+    pub(crate) fn render_peer_info(registry: &mut Registry, ids: &BTreeMap<(&str, &str), &str>) {
+        let mut peer_info = registry.family(
+            "wireguard_peer_info",
+            MetricType::Gauge,
+            "Public key of the peer with a stable ID",
+        );
+        for ((interface, public_key), id) in ids.iter() {
+            let attributes = [
+                ("interface", *interface),
+                ("peer_id", *id),
+                ("public_key", *public_key),
+            ];
+            peer_info.push(&attributes, 1.0);
+        }
+        registry.register(peer_info);
+    }
+
+    /// Renders, for every interface, how many of its peers are in none of
+    /// the config files of `pehm`, and an info series with the key and
+    /// the endpoint of each of them: peers added out of band are worth
+    /// investigating. The peers dropped by `retain_configured_peers` are
+    /// included.
+    pub(crate) fn render_unknown_peers(&self, registry: &mut Registry, pehm: &PeerEntries) {
+        let mut unknown_peers = registry.family(
+            "wireguard_unknown_peers",
+            MetricType::Gauge,
+            "Peers in none of the config files",
+        );
+        let mut unknown_peer_info = registry.family(
+            "wireguard_unknown_peer_info",
+            MetricType::Gauge,
+            "Peer in none of the config files",
+        );
+
+        // interface -> unknown peers
+        let mut unknown: BTreeMap<&str, Vec<&RemoteEndpoint>> = self
+            .interfaces
+            .keys()
+            .map(|interface| (interface as &str, Vec::new()))
+            .collect();
+        for (interface, endpoints) in self.interfaces.iter() {
+            for endpoint in endpoints {
+                if let Endpoint::Remote(ep) = endpoint {
+                    if pehm.get(interface, &ep.public_key).is_none() {
+                        unknown.entry(interface).or_default().push(ep);
+                    }
+                }
+            }
+        }
+        for (interface, ep) in self.unconfigured_peers.iter() {
+            unknown.entry(interface).or_default().push(ep);
+        }
+
+        for (interface, mut peers) in unknown {
+            peers.sort_by(|a, b| a.public_key.cmp(&b.public_key));
+            for ep in peers.iter() {
+                let endpoint = ep.endpoint().unwrap_or_default();
+                unknown_peer_info.push(
+                    &[
+                        ("interface", interface),
+                        ("public_key", &ep.public_key),
+                        ("endpoint", &endpoint),
+                    ],
+                    1.0,
+                );
+            }
+            unknown_peers.push(&[("interface", interface)], peers.len() as f64);
+        }
+
+        registry.register(unknown_peers);
+        registry.register(unknown_peer_info);
+    }
+
+    /// Renders the remote endpoints as a JSON document, sorted by
+    /// interface, for the `/api/v1/peers` endpoint.
+    pub(crate) fn render_json_with_names(&self, pehm: Option<&PeerEntries>) -> serde_json::Value {
+        let mut interfaces: Vec<_> = self.interfaces.iter().collect();
+        interfaces.sort_by_key(|(interface, _)| *interface);
+
+        let mut peers = Vec::new();
+        for (interface, endpoints) in interfaces {
+            for endpoint in endpoints {
+                if let Endpoint::Remote(ep) = endpoint {
+                    peers.push(json!({
+                        "interface": interface,
+                        "public_key": ep.public_key,
+                        "friendly_name": friendly_name(pehm, interface, &ep.public_key),
+                        "remote_ip": ep.remote_ip,
+                        "remote_port": ep.remote_port,
+                        "local_ip": ep.local_ip,
+                        "local_subnet": ep.local_subnet,
+                        "latest_handshake": ep.latest_handshake,
+                        "sent_bytes": ep.sent_bytes as u64,
+                        "received_bytes": ep.received_bytes as u64,
+                        "persistent_keepalive": ep.persistent_keepalive,
+                    }));
+                }
+            }
+        }
+
+        json!({ "peers": peers })
+    }
+
+    /// Renders, for every site of `pehm`, its number of peers and the
+    /// bytes they exchanged, over all the interfaces. The peers without a
+    /// site are left out.
+    pub(crate) fn render_sites(&self, registry: &mut Registry, pehm: &PeerEntries) {
+        let mut site_peers = registry.family(
+            "wireguard_site_peers",
+            MetricType::Gauge,
+            "Peers of the site",
+        );
+        let mut site_bytes = registry.family(
+            "wireguard_site_bytes_total",
+            MetricType::Counter,
+            "Bytes exchanged with the peers of the site, sent to them or received from them",
+        );
+
+        // site -> (peers, sent bytes, received bytes)
+        let mut sites: BTreeMap<&str, (u32, u128, u128)> = BTreeMap::new();
+        for (interface, endpoints) in self.interfaces.iter() {
+            for endpoint in endpoints {
+                if let Endpoint::Remote(ep) = endpoint {
+                    if let Some(site) = pehm.get(interface, &ep.public_key).and_then(|pe| pe.site) {
+                        let (peers, sent_bytes, received_bytes) = sites.entry(site).or_default();
+                        *peers += 1;
+                        *sent_bytes += ep.sent_bytes;
+                        *received_bytes += ep.received_bytes;
+                    }
+                }
+            }
+        }
+
+        for (site, (peers, sent_bytes, received_bytes)) in sites {
+            site_peers.push(&[("site", site)], peers as f64);
+            site_bytes.push(&[("site", site), ("direction", "sent")], sent_bytes as f64);
+            site_bytes.push(
+                &[("site", site), ("direction", "received")],
+                received_bytes as f64,
+            );
+        }
+        registry.register(site_peers);
+        registry.register(site_bytes);
+    }
+
+    /// Renders, for every client of `pehm`, its number of peers over all
+    /// the interfaces. The peers without a client are left out.
+    pub(crate) fn render_clients(&self, registry: &mut Registry, pehm: &PeerEntries) {
+        let mut peers_by_client = registry.family(
+            "wireguard_peers_by_client",
+            MetricType::Gauge,
+            "Peers running the client",
+        );
+
+        let mut clients: BTreeMap<&str, u32> = BTreeMap::new();
+        for (interface, endpoints) in self.interfaces.iter() {
+            for endpoint in endpoints {
+                if let Endpoint::Remote(ep) = endpoint {
+                    if let Some(client) =
+                        pehm.get(interface, &ep.public_key).and_then(|pe| pe.client)
+                    {
+                        *clients.entry(client).or_default() += 1;
+                    }
+                }
+            }
+        }
+
+        for (client, peers) in clients {
+            peers_by_client.push(&[("client", client)], f64::from(peers));
+        }
+        registry.register(peers_by_client);
+    }
+
+    /// Renders, for every group of `slas`, how many of its peers, over all
+    /// the interfaces, had their latest handshake longer ago than the
+    /// group allows. As for the `handshake_max_age` alert rule, the peers
+    /// never connected do not count.
+    pub(crate) fn render_group_slas(
+        &self,
+        registry: &mut Registry,
+        pehm: &PeerEntries,
+        slas: &[GroupSla],
+        now: u64,
+    ) {
+        let mut violations = registry.family(
+            "wireguard_group_sla_violations",
+            MetricType::Gauge,
+            "Peers of the group whose latest handshake is older than its --group-sla",
+        );
+
+        let mut counts: BTreeMap<&str, u32> =
+            slas.iter().map(|sla| (&sla.group as &str, 0)).collect();
+        for (interface, endpoints) in self.interfaces.iter() {
+            for endpoint in endpoints {
+                if let Endpoint::Remote(ep) = endpoint {
+                    let sla = pehm
+                        .get(interface, &ep.public_key)
+                        .and_then(|pe| pe.group)
+                        .and_then(|group| slas.iter().find(|sla| sla.group == group));
+                    if let Some(sla) = sla {
+                        if ep.latest_handshake != 0
+                            && clock::age(now, ep.latest_handshake)
+                                > sla.max_handshake_age.as_secs()
+                        {
+                            *counts.get_mut(&sla.group as &str).unwrap() += 1;
+                        }
+                    }
+                }
+            }
+        }
+
+        for (group, count) in counts {
+            violations.push(&[("group", group)], f64::from(count));
+        }
+        registry.register(violations);
+    }
+
+    /// Describes the hub and spoke topology: every interface is a hub,
+    /// whose peers are the spokes, grouped by site. The peers without a
+    /// site are listed under a `null` site.
+    pub(crate) fn render_topology_json(
+        &self,
+        pehm: Option<&PeerEntries>,
+        now: u64,
+        handshake_timeout: Duration,
+    ) -> serde_json::Value {
+        let mut interfaces: Vec<_> = self.interfaces.iter().collect();
+        interfaces.sort_by_key(|(interface, _)| *interface);
+
+        let mut hubs = Vec::new();
+        for (interface, endpoints) in interfaces {
+            let mut public_key = None;
+            let mut listen_port = None;
+            let mut sites: BTreeMap<Option<&str>, Vec<serde_json::Value>> = BTreeMap::new();
+            for endpoint in endpoints {
+                match endpoint {
+                    Endpoint::Local(local) => {
+                        public_key = Some(&local.public_key);
+                        listen_port = Some(local.local_port);
+                    }
+                    Endpoint::Remote(ep) => {
+                        let entry = pehm.and_then(|pehm| pehm.get(interface, &ep.public_key));
+                        sites
+                            .entry(entry.and_then(|pe| pe.site))
+                            .or_default()
+                            .push(json!({
+                                "public_key": ep.public_key,
+                                "friendly_name": entry.and_then(|pe| pe.name),
+                                "allowed_ips": ep.allowed_ips,
+                                "online": ep.is_online(now, handshake_timeout),
+                            }));
+                    }
+                }
+            }
+
+            let sites: Vec<_> = sites
+                .into_iter()
+                .map(|(site, spokes)| json!({ "site": site, "spokes": spokes }))
+                .collect();
+            hubs.push(json!({
+                "interface": interface,
+                "public_key": public_key,
+                "listen_port": listen_port,
+                "sites": sites,
+            }));
+        }
+
+        json!({ "hubs": hubs })
+    }
+
+    /// The peers of `interface`, none if it is unknown.
+    fn remote_endpoints<'a>(&'a self, interface: &str) -> impl Iterator<Item = &'a RemoteEndpoint> {
+        self.interfaces
+            .get(interface)
+            .map_or(&[][..], |endpoints| endpoints)
+            .iter()
+            .filter_map(|endpoint| match endpoint {
+                Endpoint::Remote(ep) => Some(ep),
+                Endpoint::Local(_) => None,
+            })
+    }
+
+    /// Renders the peers of `interface` as the clients of a wg-easy
+    /// backup, created at `now`. Only the inventory is known: the private
+    /// and preshared keys of the peers never leave them, so the clients
+    /// have no keys but their public one.
+    pub(crate) fn render_wg_easy_json(
+        &self,
+        interface: &str,
+        pehm: Option<&PeerEntries>,
+        now: u64,
+    ) -> serde_json::Value {
+        let now =
+            humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(now)).to_string();
+        let mut clients = serde_json::Map::new();
+        for ep in self.remote_endpoints(interface) {
+            let id = peer_uuid(&ep.public_key);
+            let name = friendly_name(pehm, interface, &ep.public_key).unwrap_or(&ep.public_key);
+            clients.insert(
+                id.clone(),
+                json!({
+                    "id": id,
+                    "name": name,
+                    "address": ep.local_ip,
+                    "publicKey": ep.public_key,
+                    "enabled": true,
+                    "createdAt": now,
+                    "updatedAt": now,
+                }),
+            );
+        }
+        json!({ "clients": clients })
+    }
+
+    /// Renders the peers of `interface` as the `[Peer]` sections of a
+    /// wg-quick config, named by a comment as `-n` reads them.
+    pub(crate) fn render_wg_quick(&self, interface: &str, pehm: Option<&PeerEntries>) -> String {
+        let mut s = String::new();
+        for ep in self.remote_endpoints(interface) {
+            if !s.is_empty() {
+                s.push('\n');
+            }
+            s.push_str("[Peer]\n");
+            if let Some(name) = friendly_name(pehm, interface, &ep.public_key) {
+                let _ = writeln!(s, "# {}", name);
+            }
+            let _ = writeln!(s, "PublicKey = {}", ep.public_key);
+            let _ = writeln!(s, "AllowedIPs = {}", ep.allowed_ips.join(", "));
+        }
+        s
+    }
+
+    /// Renders, for every interface, how many peers are online and how
+    /// many are not (see `RemoteEndpoint::is_online`), how many peers
+    /// have IPv4 and IPv6 allowed IPs (dual stack peers count in both)
+    /// and how many use a preshared key.
+    pub(crate) fn render_peer_counts(
+        &self,
+        registry: &mut Registry,
+        now: u64,
+        handshake_timeout: Duration,
+    ) {
+        let mut peers_online = registry.family(
+            "wireguard_peers_online",
+            MetricType::Gauge,
+            "Peers with a recent handshake",
+        );
+        let mut peers_offline = registry.family(
+            "wireguard_peers_offline",
+            MetricType::Gauge,
+            "Peers without a recent handshake",
+        );
+        let mut peers_by_family = registry.family(
+            "wireguard_peers_by_family",
+            MetricType::Gauge,
+            "Peers with allowed IPs of the IP family",
+        );
+        let mut peers_with_psk = registry.family(
+            "wireguard_peers_with_preshared_key",
+            MetricType::Gauge,
+            "Peers using a preshared key",
+        );
+        let mut peers_without_psk = registry.family(
+            "wireguard_peers_without_preshared_key",
+            MetricType::Gauge,
+            "Peers not using a preshared key",
+        );
+
+        let mut interfaces: Vec<_> = self.interfaces.iter().collect();
+        interfaces.sort_by_key(|(interface, _)| *interface);
+
+        for (interface, endpoints) in interfaces {
+            let (mut online, mut offline) = (0, 0);
+            let (mut ipv4, mut ipv6) = (0, 0);
+            let (mut with_psk, mut without_psk) = (0, 0);
+            for endpoint in endpoints {
+                if let Endpoint::Remote(ep) = endpoint {
+                    if ep.is_online(now, handshake_timeout) {
+                        online += 1;
+                    } else {
+                        offline += 1;
+                    }
+
+                    let (has_ipv4, has_ipv6) = ep.ip_families();
+                    ipv4 += has_ipv4 as u32;
+                    ipv6 += has_ipv6 as u32;
+
+                    if ep.has_preshared_key {
+                        with_psk += 1;
+                    } else {
+                        without_psk += 1;
+                    }
+                }
+            }
+
+            let attributes = [("interface", interface as &str)];
+            peers_online.push(&attributes, f64::from(online));
+            peers_offline.push(&attributes, f64::from(offline));
+            peers_with_psk.push(&attributes, f64::from(with_psk));
+            peers_without_psk.push(&attributes, f64::from(without_psk));
+
+            for (family, count) in &[("ipv4", ipv4), ("ipv6", ipv6)] {
+                let attributes = [("interface", interface as &str), ("family", family)];
+                peers_by_family.push(&attributes, f64::from(*count));
+            }
+        }
+
+        registry.register(peers_online);
+        registry.register(peers_offline);
+        registry.register(peers_by_family);
+        registry.register(peers_with_psk);
+        registry.register(peers_without_psk);
+    }
+
+    /// Renders, for every interface, how many peers have no friendly name
+    /// in `pehm`. If `with_info` is set, an info series is added for each
+    /// of those peers so they can be listed.
+    pub(crate) fn render_unnamed_peers(
+        &self,
+        registry: &mut Registry,
+        pehm: &PeerEntries,
+        with_info: bool,
+    ) {
+        let mut peers_without_friendly_name = registry.family(
+            "wireguard_peers_without_friendly_name",
+            MetricType::Gauge,
+            "Peers without a friendly name",
+        );
+        let mut peer_without_friendly_name_info = registry.family(
+            "wireguard_peer_without_friendly_name_info",
+            MetricType::Gauge,
+            "Peer without a friendly name",
+        );
+
+        let mut interfaces: Vec<_> = self.interfaces.iter().collect();
+        interfaces.sort_by_key(|(interface, _)| *interface);
+
+        for (interface, endpoints) in interfaces {
+            let mut unnamed = 0;
+            for endpoint in endpoints {
+                if let Endpoint::Remote(ep) = endpoint {
+                    if friendly_name(Some(pehm), interface, &ep.public_key).is_none() {
+                        unnamed += 1;
+
+                        let attributes = [
+                            ("interface", interface as &str),
+                            ("public_key", &ep.public_key),
+                        ];
+                        peer_without_friendly_name_info.push(&attributes, 1.0);
+                    }
+                }
+            }
+
+            let attributes = [("interface", interface as &str)];
+            peers_without_friendly_name.push(&attributes, f64::from(unnamed));
+        }
+
+        registry.register(peers_without_friendly_name);
+        if with_info {
+            registry.register(peer_without_friendly_name_info);
+        }
+    }
+
+    /// Renders, for every interface, how many peers got their friendly
+    /// name from each source (see `NameSource`).
+    pub(crate) fn render_name_sources(&self, registry: &mut Registry, pehm: &PeerEntries) {
+        let mut friendly_names = registry.family(
+            "wireguard_friendly_names",
+            MetricType::Gauge,
+            "Peers by source of their friendly name",
+        );
+
+        let mut interfaces: Vec<_> = self.interfaces.iter().collect();
+        interfaces.sort_by_key(|(interface, _)| *interface);
+
+        for (interface, endpoints) in interfaces {
+            let mut counts = [0u32; NameSource::ALL.len()];
+            for endpoint in endpoints {
+                if let Endpoint::Remote(ep) = endpoint {
+                    let source = pehm.name_source(interface, &ep.public_key);
+                    counts[NameSource::ALL.iter().position(|s| *s == source).unwrap()] += 1;
+                }
+            }
+
+            for (source, count) in NameSource::ALL.iter().zip(counts.iter()) {
+                let attributes = [
+                    ("interface", interface as &str),
+                    ("source", source.as_str()),
+                ];
+                friendly_names.push(&attributes, f64::from(*count));
+            }
+        }
+
+        registry.register(friendly_names);
+    }
+
+    /// Renders the per peer series. Peers that transferred (sent plus
+    /// received) less than `min_bytes_threshold` bytes are not rendered
+    /// individually but summed up in per interface series instead.
+    /// With `export_bits` the transferred bytes are also rendered as
+    /// bits, for the dashboards specified in bits per second.
+    pub(crate) fn render_with_names(
+        &self,
+        registry: &mut Registry,
+        pehm: Option<&PeerEntries>,
+        min_bytes_threshold: u128,
+        compat: Compat,
+        export_bits: bool,
+    ) {
+        let names = compat.metric_names();
+
+        // these are the exported counters
+        let mut sent_bytes_total = registry.family(
+            names.sent_bytes,
+            MetricType::Counter,
+            "Bytes sent to the peer",
+        );
+        let mut received_bytes_total = registry.family(
+            names.received_bytes,
+            MetricType::Counter,
+            "Bytes received from the peer",
+        );
+        let mut latest_handshake = registry.family(
+            names.latest_handshake,
+            MetricType::Gauge,
+            "Seconds from the last handshake",
+        );
+        let mut sent_bits_total = registry.family(
+            "wireguard_sent_bits_total",
+            MetricType::Counter,
+            "Bits sent to the peer",
+        );
+        let mut received_bits_total = registry.family(
+            "wireguard_received_bits_total",
+            MetricType::Counter,
+            "Bits received from the peer",
+        );
+
+        // interface -> (peers, sent bytes, received bytes)
+        let mut below_threshold: BTreeMap<&str, (u32, u128, u128)> = BTreeMap::new();
+
+        // sorted, for the same input to always render the same
+        let mut interfaces: Vec<_> = self.interfaces.iter().collect();
+        interfaces.sort_by_key(|(interface, _)| *interface);
+
+        for (interface, endpoints) in interfaces {
+            for endpoint in endpoints {
+                // only show remote endpoints
+                if let Endpoint::Remote(ep) = endpoint {
+                    debug!("{:?}", ep);
+
+                    if ep.sent_bytes + ep.received_bytes < min_bytes_threshold {
+                        let (peers, sent_bytes, received_bytes) =
+                            below_threshold.entry(interface).or_default();
+                        *peers += 1;
+                        *sent_bytes += ep.sent_bytes;
+                        *received_bytes += ep.received_bytes;
+                        continue;
+                    }
+
+                    let allowed_ips = ep.allowed_ips.join(",");
+                    let mut attributes: Vec<(&str, &str)> = vec![
+                        (names.interface, interface),
+                        (names.public_key, &ep.public_key),
+                    ];
+                    if let Some((local_ip, local_subnet)) = names.local_ip {
+                        attributes.push((local_ip, &ep.local_ip));
+                        attributes.push((local_subnet, &ep.local_subnet));
+                    }
+                    if let Some(label) = names.allowed_ips {
+                        attributes.push((label, &allowed_ips));
+                    }
+
+                    // let's add the friendly_name attribute if present
+                    // and has meaniningful value
+                    if let Some(ep_friendly_name) = friendly_name(pehm, interface, &ep.public_key) {
+                        attributes.push((names.friendly_name, ep_friendly_name));
+                    }
+                    if let Some(client) = pehm
+                        .and_then(|pehm| pehm.get(interface, &ep.public_key))
+                        .and_then(|pe| pe.client)
+                    {
+                        attributes.push(("client", client));
+                    }
+
+                    sent_bytes_total.push(&attributes, ep.sent_bytes as f64);
+                    received_bytes_total.push(&attributes, ep.received_bytes as f64);
+                    latest_handshake.push(&attributes, ep.latest_handshake as f64);
+                    sent_bits_total.push(&attributes, (ep.sent_bytes * 8) as f64);
+                    received_bits_total.push(&attributes, (ep.received_bytes * 8) as f64);
+                }
+            }
+        }
+
+        registry.register(sent_bytes_total);
+        registry.register(received_bytes_total);
+        registry.register(latest_handshake);
+        if export_bits {
+            registry.register(sent_bits_total);
+            registry.register(received_bits_total);
+        }
+
+        if min_bytes_threshold > 0 {
+            render_below_threshold(registry, &below_threshold);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // renders in the text format what `render` adds to an empty registry
+    fn text<F: FnOnce(&mut Registry)>(render: F) -> String {
+        let mut registry = Registry::default();
+        render(&mut registry);
+        registry.render_text()
+    }
+
+    const TEXT : &str = "wg0\t000q4qAC0ExW/BuGSmVR1nxH9JAXT6g9Wd3oEGy5lA=\t0000u8LWR682knVm350lnuqlCJzw5SNLW9Nf96P+m8=\t51820\toff
+wg0\t2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=\t(none)\t37.159.76.245:29159\t10.70.0.2/32\t1555771458\t10288508\t139524160\toff
+wg0\tqnoxQoQI8KKMupLnSSureORV0wMmH7JryZNsmGVISzU=\t(none)\t(none)\t10.70.0.3/32\t0\t0\t0\toff
+wg0\tL2UoJZN7RmEKsMmqaJgKG0m1S2Zs2wd2ptAf+kb3008=\t(none)\t(none)\t10.70.0.4/32\t0\t0\t0\toff
+wg0\tMdVOIPKt9K2MPj/sO2NlWQbOnFJ6L/qX80mmhQwsUlA=\t(none)\t(none)\t10.70.0.50/32\t0\t0\t0\toff
+wg2\tMdVOIPKt9K2MPj/sO2NlWQbOnFJcL/qX80mmhQwsUlA=\t(none)\t(none)\t10.70.5.50/32\t0\t0\t0\toff
+pollo\tYdVOIPKt9K2MPsO2NlWQbOnFJcL/qX80mmhQwsUlA=\t(none)\t(none)\t10.70.70.50/32\t0\t0\t0\toff
+wg0\t928vO9Lf4+Mo84cWu4k1oRyzf0AR7FTGoPKHGoTMSHk=\t(none)\t5.90.62.106:21741\t10.70.0.80/32\t1555344925\t283012\t6604620\toff
+";
+
+    #[test]
+    fn test_parse() {
+        let a = WireGuard::try_from(TEXT).unwrap();
+        println!("{:?}", a);
+        assert!(a.interfaces.len() == 3);
+        assert!(a.interfaces["wg0"].len() == 6);
+
+        let e1 = match &a.interfaces["wg0"][1] {
+            Endpoint::Local(_) => panic!(),
+            Endpoint::Remote(re) => re,
+        };
+
+        assert_eq!(
+            e1.public_key,
+            "2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk="
+        );
+    }
+
+    #[test]
+    fn test_parse_crlf() {
+        // as wg.exe prints it on Windows
+        let a = WireGuard::try_from(TEXT).unwrap();
+        let b = WireGuard::try_from(&TEXT.replace('\n', "\r\n") as &str).unwrap();
+        let render = |wg: &WireGuard| {
+            text(|registry| wg.render_with_names(registry, None, 0, Compat::None, false))
+        };
+        assert_eq!(render(&a), render(&b));
+    }
+
+    #[test]
+    fn test_canonical_allowed_ips() {
+        assert_eq!(canonical_allowed_ip("FD00:0:0::02/128"), "fd00::2/128");
+        assert_eq!(canonical_allowed_ip("010.070.000.002/032"), "10.70.0.2/32");
+        assert_eq!(canonical_allowed_ip("10.70.0.2"), "10.70.0.2/32");
+        assert_eq!(canonical_allowed_ip("(none)"), "(none)");
+        assert_eq!(
+            canonical_allowed_ips("fd00::2/128,10.70.0.3/32,10.70.0.2/32,10.70.0.0/24"),
+            vec![
+                "10.70.0.0/24",
+                "10.70.0.2/32",
+                "10.70.0.3/32",
+                "fd00::2/128"
+            ]
+        );
+
+        // the same peer as written by another version of wg
+        let a = WireGuard::try_from(
+            "wg0\tA=\t(none)\t(none)\tFD00::2/128,10.70.0.2/32\t0\t0\t0\toff\n",
+        )
+        .unwrap();
+        let b = WireGuard::try_from(
+            "wg0\tA=\t(none)\t(none)\t10.70.0.2/32,fd00:0::2/128\t0\t0\t0\toff\n",
+        )
+        .unwrap();
+        let render = |wg: &WireGuard| {
+            text(|registry| wg.render_with_names(registry, None, 0, Compat::Wgexporter, false))
+        };
+        assert_eq!(render(&a), render(&b));
+        assert!(render(&a).contains("allowed_ips=\"10.70.0.2/32,fd00::2/128\""));
+    }
+
+    #[test]
+    fn test_local_ip() {
+        let local_ip = |allowed_ips: &str| {
+            let wg = WireGuard::try_from(&format!(
+                "wg0\tA=\t(none)\t(none)\t{}\t0\t0\t0\toff\n",
+                allowed_ips
+            ) as &str)
+            .unwrap();
+            match &wg.interfaces["wg0"][0] {
+                Endpoint::Remote(ep) => (ep.local_ip.clone(), ep.local_subnet.clone()),
+                Endpoint::Local(_) => unreachable!(),
+            }
+        };
+        // the first allowed IP in the order of wg, not the sorted one
+        assert_eq!(
+            local_ip("192.168.1.0/24,10.70.0.2/32"),
+            ("192.168.1.0".to_owned(), "24".to_owned())
+        );
+        assert_eq!(
+            local_ip("FD00:0::2/128,10.70.0.2/32"),
+            ("fd00::2".to_owned(), "128".to_owned())
+        );
+        assert_eq!(local_ip("(none)"), (String::new(), String::new()));
+    }
+
+    #[test]
+    fn test_qualify_interface_dump() {
+        let dump = qualify_interface_dump("@gw1", TEXT);
+        let wg = WireGuard::try_from(&dump as &str).unwrap();
+        let mut interfaces: Vec<_> = wg.interfaces.keys().collect();
+        interfaces.sort();
+        assert_eq!(interfaces, ["pollo@gw1", "wg0@gw1", "wg2@gw1"]);
+        assert_eq!(dump.lines().count(), TEXT.lines().count());
+    }
+
+    #[test]
+    fn test_prefix_interface_dump() {
+        let dump = prefix_interface_dump(
+            "wg0",
+            "000q4qAC0ExW/BuGSmVR1nxH9JAXT6g9Wd3oEGy5lA=\t0000u8LWR682knVm350lnuqlCJzw5SNLW9Nf96P+m8=\t51820\toff
+2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=\t(none)\t37.159.76.245:29159\t10.70.0.2/32\t1555771458\t10288508\t139524160\toff
+",
+        );
+        assert_eq!(
+            dump,
+            TEXT.lines()
+                .take(2)
+                .map(|line| format!("{}\n", line))
+                .collect::<String>()
+        );
+
+        let mut wg = WireGuard::try_from(&dump as &str).unwrap();
+        assert_eq!(wg.interfaces["wg0"].len(), 2);
+        wg.collect_durations = vec![("wg0".to_owned(), Duration::from_millis(250))];
+        assert_eq!(
+            text(|registry| wg.render_collect_durations(registry)),
+            "# HELP wireguard_exporter_interface_collect_duration_seconds Seconds the last collection of the interface took\n# TYPE wireguard_exporter_interface_collect_duration_seconds gauge\nwireguard_exporter_interface_collect_duration_seconds{interface=\"wg0\"} 0.25\n"
+        );
+    }
+
+    #[test]
+    fn test_render_data_age() {
+        let mut wg = WireGuard::try_from(TEXT).unwrap();
+        assert_eq!(
+            text(|registry| wg.render_data_age(registry)),
+            "# HELP wireguard_exporter_data_age_seconds Seconds since the dump served was collected\n# TYPE wireguard_exporter_data_age_seconds gauge\nwireguard_exporter_data_age_seconds 0\n"
+        );
+        wg.cached_age = Some(Duration::from_millis(2500));
+        assert!(text(|registry| wg.render_data_age(registry))
+            .ends_with("wireguard_exporter_data_age_seconds 2.5\n"));
+    }
+
+    #[test]
+    fn test_parse_parallel() {
+        let mut dump = String::new();
+        for interface in 0..3 {
+            for peer in 0..5000 {
+                dump.push_str(&format!(
+                    "wg{}\tpeer{}=\t(none)\t10.0.{}.{}:51820\t10.70.0.2/32\t{}\t{}\t0\toff\n",
+                    interface,
+                    peer,
+                    peer / 256,
+                    peer % 256,
+                    peer,
+                    interface
+                ));
+            }
+        }
+
+        let sequential = WireGuard::parse(&dump, 1).unwrap();
+        let parallel = WireGuard::parse(&dump, 4).unwrap();
+        assert_eq!(parallel.interfaces["wg1"].len(), 5000);
+        assert_eq!(
+            parallel.render_json_with_names(None),
+            sequential.render_json_with_names(None)
+        );
 
-fn to_option_string(s: &str) -> Option<String> {
-    if s == EMPTY {
-        None
-    } else {
-        Some(s.to_owned())
+        // the errors are reported as well, with the line
+        dump.push_str("wg2\tpeer=\t(none)\t(none)\t10.70.0.2/32\tnot-a-number\t0\t0\toff\n");
+        assert_eq!(
+            WireGuard::parse(&dump, 4).unwrap_err().to_string(),
+            "line 15001 of the dump: invalid latest handshake \"not-a-number\""
+        );
     }
-}
 
-fn to_bool(s: &str) -> bool {
-    s != "off"
-}
+    #[test]
+    fn test_parse_malformed() {
+        // the blank lines are skipped
+        let a = WireGuard::try_from(&format!("\n{}\n  \n", TEXT) as &str).unwrap();
+        assert_eq!(a.interfaces["wg0"].len(), 6);
 
-#[derive(Debug, Clone)]
-pub(crate) struct WireGuard {
-    pub interfaces: HashMap<String, Vec<Endpoint>>,
-}
+        let error = |dump: &str| WireGuard::try_from(dump).unwrap_err().to_string();
+        assert_eq!(
+            error("wg0\tA=\t(none)\n"),
+            "line 1 of the dump: expected 5 or 9 fields, found 3"
+        );
+        assert_eq!(
+            error("wg0\tA=\tB=\t51820\toff\nwg0\tC=\t(none)\t(none)\t10.70.0.2/32\t0\t0\t0\toff\textra\n"),
+            "line 2 of the dump: expected 5 or 9 fields, found 10"
+        );
+        assert_eq!(
+            error("wg0\tA=\tB=\tport\toff\n"),
+            "line 1 of the dump: invalid listen port \"port\""
+        );
+        assert_eq!(
+            error("wg0\tC=\t(none)\tbad\t10.70.0.2/32\t0\t0\t0\toff\n"),
+            "line 1 of the dump: invalid endpoint \"bad\""
+        );
+        assert_eq!(
+            error("wg0\tC=\t(none)\t(none)\t10.70.0.2/32\t0\t-1\t0\toff\n"),
+            "line 1 of the dump: invalid transfer rx \"-1\""
+        );
+        assert_eq!(
+            error("wg0\tC=\t(none)\t(none)\t10.70.0.2/32\t0\t0\tx\toff\n"),
+            "line 1 of the dump: invalid transfer tx \"x\""
+        );
+    }
 
-impl TryFrom<&str> for WireGuard {
-    type Error = ExporterError;
+    #[test]
+    fn test_parse_and_serialize() {
+        let a = WireGuard::try_from(TEXT).unwrap();
+        let s = text(|registry| a.render_with_names(registry, None, 0, Compat::None, false));
+        println!("{}", s);
+    }
 
-    fn try_from(input: &str) -> Result<Self, Self::Error> {
-        debug!("wireguard::try_from({}) called", input);
-        let mut wg = WireGuard {
-            interfaces: HashMap::new(),
+    #[test]
+    fn test_render_json() {
+        use crate::wireguard_config::{PeerEntry, PeerEntryHashMap};
+
+        let a = WireGuard::try_from(TEXT).unwrap();
+
+        let mut pehm = PeerEntryHashMap::new();
+        let pe = PeerEntry {
+            public_key: "2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=",
+            allowed_ips: "10.70.0.2/32",
+            endpoint: None,
+            name: Some("OnePlus 6T"),
+            id: None,
+            site: None,
+            client: None,
+            group: None,
+            unknown_keys: Vec::new(),
         };
+        pehm.insert(pe.public_key, pe);
 
-        for line in input.lines() {
-            let v: Vec<&str> = line.split('\t').filter(|s| !s.is_empty()).collect();
-            debug!("v == {:?}", v);
-
-            let endpoint = if v.len() == 5 {
-                // this is the local interface
-                Endpoint::Local(LocalEndpoint {
-                    public_key: v[1].to_owned(),
-                    private_key: v[2].to_owned(),
-                    local_port: v[3].parse::<u16>().unwrap(),
-                    persistent_keepalive: to_bool(v[4]),
-                })
-            } else {
-                // remote endpoint
-                let public_key = v[1].to_owned();
+        let json = a.render_json_with_names(Some(&pehm.into()));
+        let peers = json["peers"].as_array().unwrap();
+        assert_eq!(peers.len(), 7);
 
-                let (remote_ip, remote_port) = if let Some(ip_and_port) = to_option_string(v[3]) {
-                    let addr: SocketAddr = ip_and_port.parse::<SocketAddr>().unwrap();
+        // interfaces are sorted
+        assert_eq!(peers[0]["interface"], "pollo");
+        assert_eq!(peers[1]["interface"], "wg0");
+        assert_eq!(peers[6]["interface"], "wg2");
 
-                    (Some(addr.ip().to_string()), Some(addr.port()))
-                } else {
-                    (None, None)
-                };
+        assert_eq!(
+            peers[1],
+            json!({
+                "interface": "wg0",
+                "public_key": "2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=",
+                "friendly_name": "OnePlus 6T",
+                "remote_ip": "37.159.76.245",
+                "remote_port": 29159,
+                "local_ip": "10.70.0.2",
+                "local_subnet": "32",
+                "latest_handshake": 1555771458u64,
+                "sent_bytes": 10288508,
+                "received_bytes": 139524160,
+                "persistent_keepalive": false,
+            })
+        );
+        assert_eq!(peers[2]["friendly_name"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_retain_configured_peers() {
+        use crate::wireguard_config::{PeerEntry, PeerEntryHashMap};
 
-                let tok: Vec<&str> = v[4].split('/').collect();
-                let (local_ip, local_subnet) = (tok[0].to_owned(), tok[1].to_owned());
+        let mut a = WireGuard::try_from(TEXT).unwrap();
 
-                Endpoint::Remote(RemoteEndpoint {
+        let mut pehm = PeerEntryHashMap::new();
+        let pe = PeerEntry {
+            public_key: "2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=",
+            allowed_ips: "10.70.0.2/32",
+            endpoint: None,
+            name: None,
+            id: None,
+            site: None,
+            client: None,
+            group: None,
+            unknown_keys: Vec::new(),
+        };
+        pehm.insert(pe.public_key, pe);
+
+        a.retain_configured_peers(&pehm.into());
+        // the local endpoint and the configured peer
+        assert_eq!(a.interfaces["wg0"].len(), 2);
+        assert!(a.interfaces["wg2"].is_empty());
+        assert!(a.interfaces["pollo"].is_empty());
+    }
+
+    #[test]
+    fn test_render_peer_counts() {
+        let a = WireGuard::try_from(TEXT).unwrap();
+
+        // only the first wg0 peer had a handshake in the last 3 minutes
+        let s = text(|registry| {
+            a.render_peer_counts(registry, 1555771458 + 60, Duration::from_secs(180))
+        });
+        assert_eq!(
+            s,
+            "# HELP wireguard_peers_online Peers with a recent handshake\n# TYPE wireguard_peers_online gauge\nwireguard_peers_online{interface=\"pollo\"} 0\nwireguard_peers_online{interface=\"wg0\"} 1\nwireguard_peers_online{interface=\"wg2\"} 0\n# HELP wireguard_peers_offline Peers without a recent handshake\n# TYPE wireguard_peers_offline gauge\nwireguard_peers_offline{interface=\"pollo\"} 1\nwireguard_peers_offline{interface=\"wg0\"} 4\nwireguard_peers_offline{interface=\"wg2\"} 1\n# HELP wireguard_peers_by_family Peers with allowed IPs of the IP family\n# TYPE wireguard_peers_by_family gauge\nwireguard_peers_by_family{interface=\"pollo\",family=\"ipv4\"} 1\nwireguard_peers_by_family{interface=\"pollo\",family=\"ipv6\"} 0\nwireguard_peers_by_family{interface=\"wg0\",family=\"ipv4\"} 5\nwireguard_peers_by_family{interface=\"wg0\",family=\"ipv6\"} 0\nwireguard_peers_by_family{interface=\"wg2\",family=\"ipv4\"} 1\nwireguard_peers_by_family{interface=\"wg2\",family=\"ipv6\"} 0\n# HELP wireguard_peers_with_preshared_key Peers using a preshared key\n# TYPE wireguard_peers_with_preshared_key gauge\nwireguard_peers_with_preshared_key{interface=\"pollo\"} 0\nwireguard_peers_with_preshared_key{interface=\"wg0\"} 0\nwireguard_peers_with_preshared_key{interface=\"wg2\"} 0\n# HELP wireguard_peers_without_preshared_key Peers not using a preshared key\n# TYPE wireguard_peers_without_preshared_key gauge\nwireguard_peers_without_preshared_key{interface=\"pollo\"} 1\nwireguard_peers_without_preshared_key{interface=\"wg0\"} 5\nwireguard_peers_without_preshared_key{interface=\"wg2\"} 1\n"
+        );
+
+        let dual_stack = WireGuard::try_from(
+            "wg0\tqnoxQoQI8KKMupLnSSureORV0wMmH7JryZNsmGVISzU=\tpskpskAC0ExW/BuGSmVR1nxH9JAXT6g9Wd3oEGy5lA=\t(none)\t10.70.0.3/32,fd00::3/128\t0\t0\t0\toff\n",
+        )
+        .unwrap();
+        let s =
+            text(|registry| dual_stack.render_peer_counts(registry, 0, Duration::from_secs(180)));
+        assert!(s.contains("wireguard_peers_by_family{interface=\"wg0\",family=\"ipv4\"} 1\n"));
+        assert!(s.contains("wireguard_peers_by_family{interface=\"wg0\",family=\"ipv6\"} 1\n"));
+        assert!(s.contains("wireguard_peers_with_preshared_key{interface=\"wg0\"} 1\n"));
+
+        // a peer without allowed IPs is of neither family
+        let without_allowed_ips = WireGuard::try_from(
+            "wg0\tqnoxQoQI8KKMupLnSSureORV0wMmH7JryZNsmGVISzU=\t(none)\t(none)\t10.70.0.3/32\t0\t0\t0\toff
+wg0\t2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=\t(none)\t(none)\t(none)\t0\t0\t0\toff
+",
+        )
+        .unwrap();
+        let s = text(|registry| {
+            without_allowed_ips.render_peer_counts(registry, 0, Duration::from_secs(180))
+        });
+        assert!(s.contains("wireguard_peers_by_family{interface=\"wg0\",family=\"ipv4\"} 1\n"));
+        assert!(s.contains("wireguard_peers_by_family{interface=\"wg0\",family=\"ipv6\"} 0\n"));
+    }
+
+    #[test]
+    fn test_render_summary() {
+        let a = WireGuard::try_from(TEXT).unwrap();
+        assert_eq!(
+            a.render_summary(None, 1555771458 + 60, Duration::from_secs(180)),
+            "3 interfaces, 1/7 peers online\npollo: 0/1 peers online\nwg0: 1/5 peers online\n  top peer: 2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=, 142.9 MiB (handshake 1m 0s ago)\nwg2: 0/1 peers online\ncollected at 2019-04-20T14:45:18Z\n"
+        );
+    }
+
+    #[test]
+    fn test_render_sites() {
+        use crate::wireguard_config::{PeerEntry, PeerEntryHashMap};
+
+        let a = WireGuard::try_from(TEXT).unwrap();
+        let mut pehm = PeerEntryHashMap::new();
+        for (public_key, site) in &[
+            ("2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=", "paris"),
+            ("928vO9Lf4+Mo84cWu4k1oRyzf0AR7FTGoPKHGoTMSHk=", "paris"),
+            ("qnoxQoQI8KKMupLnSSureORV0wMmH7JryZNsmGVISzU=", "lyon"),
+        ] {
+            pehm.insert(
+                *public_key,
+                PeerEntry {
                     public_key,
-                    remote_ip,
-                    remote_port,
-                    local_ip,
-                    local_subnet,
-                    latest_handshake: v[5].parse::<u64>()?,
-                    sent_bytes: v[6].parse::<u128>().unwrap(),
-                    received_bytes: v[7].parse::<u128>().unwrap(),
-                    persistent_keepalive: to_bool(v[8]),
-                })
-            };
+                    site: Some(site),
+                    unknown_keys: Vec::new(),
+                    ..PeerEntry::default()
+                },
+            );
+        }
+
+        let s = text(|registry| a.render_sites(registry, &pehm.into()));
+        assert_eq!(
+            s,
+            "# HELP wireguard_site_peers Peers of the site\n# TYPE wireguard_site_peers gauge\nwireguard_site_peers{site=\"lyon\"} 1\nwireguard_site_peers{site=\"paris\"} 2\n# HELP wireguard_site_bytes_total Bytes exchanged with the peers of the site, sent to them or received from them\n# TYPE wireguard_site_bytes_total counter\nwireguard_site_bytes_total{site=\"lyon\",direction=\"sent\"} 0\nwireguard_site_bytes_total{site=\"lyon\",direction=\"received\"} 0\nwireguard_site_bytes_total{site=\"paris\",direction=\"sent\"} 10571520\nwireguard_site_bytes_total{site=\"paris\",direction=\"received\"} 146128780\n"
+        );
+    }
 
-            trace!("{:?}", endpoint);
+    #[test]
+    fn test_render_group_slas() {
+        use crate::wireguard_config::{PeerEntry, PeerEntryHashMap};
 
-            if let Some(endpoints) = wg.interfaces.get_mut(v[0]) {
-                endpoints.push(endpoint);
-            } else {
-                let mut new_vec = Vec::new();
-                new_vec.push(endpoint);
-                wg.interfaces.insert(v[0].to_owned(), new_vec);
-            }
+        let a = WireGuard::try_from(TEXT).unwrap();
+        let mut pehm = PeerEntryHashMap::new();
+        for public_key in &[
+            "2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=",
+            "928vO9Lf4+Mo84cWu4k1oRyzf0AR7FTGoPKHGoTMSHk=",
+            // never connected
+            "qnoxQoQI8KKMupLnSSureORV0wMmH7JryZNsmGVISzU=",
+        ] {
+            pehm.insert(
+                *public_key,
+                PeerEntry {
+                    public_key,
+                    group: Some("acme"),
+                    ..PeerEntry::default()
+                },
+            );
         }
+        let slas = ["acme=300".parse().unwrap(), "globex=60".parse().unwrap()];
 
-        trace!("{:?}", wg);
-        Ok(wg)
+        let s = text(|registry| a.render_group_slas(registry, &pehm.into(), &slas, 1555771558));
+        assert_eq!(
+            s,
+            "# HELP wireguard_group_sla_violations Peers of the group whose latest handshake is older than its --group-sla\n# TYPE wireguard_group_sla_violations gauge\nwireguard_group_sla_violations{group=\"acme\"} 1\nwireguard_group_sla_violations{group=\"globex\"} 0\n"
+        );
     }
-}
 
-impl WireGuard {
-    pub(crate) fn render_with_names(&self, pehm: Option<&PeerEntryHashMap>) -> String {
-        // these are the exported counters
-        let pc_sent_bytes_total = PrometheusCounter::new(
-            "wireguard_sent_bytes_total",
-            "counter",
-            "Bytes sent to the peer",
+    #[test]
+    fn test_render_clients() {
+        use crate::wireguard_config::{PeerEntry, PeerEntryHashMap};
+
+        let a = WireGuard::try_from(TEXT).unwrap();
+        let mut pehm = PeerEntryHashMap::new();
+        for (public_key, client) in &[
+            ("2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=", "ios"),
+            ("928vO9Lf4+Mo84cWu4k1oRyzf0AR7FTGoPKHGoTMSHk=", "ios"),
+            ("qnoxQoQI8KKMupLnSSureORV0wMmH7JryZNsmGVISzU=", "linux"),
+        ] {
+            pehm.insert(
+                *public_key,
+                PeerEntry {
+                    public_key,
+                    client: Some(client),
+                    ..PeerEntry::default()
+                },
+            );
+        }
+        let pehm = pehm.into();
+
+        let s = text(|registry| a.render_clients(registry, &pehm));
+        assert_eq!(
+            s,
+            "# HELP wireguard_peers_by_client Peers running the client\n# TYPE wireguard_peers_by_client gauge\nwireguard_peers_by_client{client=\"ios\"} 2\nwireguard_peers_by_client{client=\"linux\"} 1\n"
         );
-        let pc_received_bytes_total = PrometheusCounter::new(
-            "wireguard_received_bytes_total",
-            "counter",
-            "Bytes received from the peer",
+
+        let s = text(|registry| a.render_with_names(registry, Some(&pehm), 0, Compat::None, false));
+        assert!(s.contains("public_key=\"qnoxQoQI8KKMupLnSSureORV0wMmH7JryZNsmGVISzU=\",local_ip=\"10.70.0.3\",local_subnet=\"32\",client=\"linux\"}"));
+    }
+
+    #[test]
+    fn test_render_topology_json() {
+        use crate::wireguard_config::{PeerEntry, PeerEntryHashMap};
+
+        let a = WireGuard::try_from(TEXT).unwrap();
+        let mut pehm = PeerEntryHashMap::new();
+        let pe = PeerEntry {
+            public_key: "2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=",
+            name: Some("phone"),
+            site: Some("paris"),
+            unknown_keys: Vec::new(),
+            ..PeerEntry::default()
+        };
+        pehm.insert(pe.public_key, pe);
+
+        let json = a.render_topology_json(
+            Some(&pehm.into()),
+            1555771458 + 60,
+            Duration::from_secs(180),
         );
-        let pc_latest_handshake = PrometheusCounter::new(
-            "wireguard_latest_handshake_seconds",
-            "gauge",
-            "Seconds from the last handshake",
+        let hubs = json["hubs"].as_array().unwrap();
+        assert_eq!(hubs.len(), 3);
+        assert_eq!(hubs[1]["interface"], "wg0");
+        assert_eq!(
+            hubs[1]["public_key"],
+            "000q4qAC0ExW/BuGSmVR1nxH9JAXT6g9Wd3oEGy5lA="
         );
+        assert_eq!(hubs[1]["listen_port"], 51820);
 
-        // these 3 vectors will hold the intermediate
-        // values. We use the vector in order to traverse
-        // the interfaces slice only once: since we need to output
-        // the values grouped by counter we populate the vectors here
-        // and then reorder during the final string creation phase.
-        let mut s_sent_bytes_total = Vec::new();
-        s_sent_bytes_total.push(pc_sent_bytes_total.render_header());
+        // the peers without a site come first
+        let sites = hubs[1]["sites"].as_array().unwrap();
+        assert_eq!(sites.len(), 2);
+        assert_eq!(sites[0]["site"], serde_json::Value::Null);
+        assert_eq!(sites[0]["spokes"].as_array().unwrap().len(), 4);
+        assert_eq!(
+            sites[1],
+            json!({
+                "site": "paris",
+                "spokes": [{
+                    "public_key": "2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=",
+                    "friendly_name": "phone",
+                    "allowed_ips": ["10.70.0.2/32"],
+                    "online": true,
+                }],
+            })
+        );
+    }
 
-        let mut s_received_bytes_total = Vec::new();
-        s_received_bytes_total.push(pc_received_bytes_total.render_header());
+    #[test]
+    fn test_peer_uuid() {
+        let uuid = peer_uuid("2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=");
+        assert_eq!(
+            uuid,
+            peer_uuid("2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=")
+        );
+        assert_ne!(
+            uuid,
+            peer_uuid("qnoxQoQI8KKMupLnSSureORV0wMmH7JryZNsmGVISzU=")
+        );
+        let groups: Vec<_> = uuid.split('-').map(|group| group.len()).collect();
+        assert_eq!(groups, vec![8, 4, 4, 4, 12]);
+        assert_eq!(&uuid[14..15], "5");
+    }
 
-        let mut s_latest_handshake = Vec::new();
-        s_latest_handshake.push(pc_latest_handshake.render_header());
+    #[test]
+    fn test_render_wg_easy_json() {
+        use crate::wireguard_config::{PeerEntry, PeerEntryHashMap};
 
-        for (interface, endpoints) in self.interfaces.iter() {
-            for endpoint in endpoints {
-                // only show remote endpoints
-                if let Endpoint::Remote(ep) = endpoint {
-                    debug!("{:?}", ep);
+        let a = WireGuard::try_from(TEXT).unwrap();
+        let json = a.render_wg_easy_json("pollo", None, 1555771458);
+        let clients = json["clients"].as_object().unwrap();
+        assert_eq!(clients.len(), 1);
+        let (id, client) = clients.iter().next().unwrap();
+        assert_eq!(
+            *client,
+            json!({
+                "id": id,
+                "name": "YdVOIPKt9K2MPsO2NlWQbOnFJcL/qX80mmhQwsUlA=",
+                "address": "10.70.70.50",
+                "publicKey": "YdVOIPKt9K2MPsO2NlWQbOnFJcL/qX80mmhQwsUlA=",
+                "enabled": true,
+                "createdAt": "2019-04-20T14:44:18Z",
+                "updatedAt": "2019-04-20T14:44:18Z",
+            })
+        );
 
-                    let mut attributes: Vec<(&str, &str)> = Vec::new();
-                    attributes.push(("inteface", interface));
-                    attributes.push(("public_key", &ep.public_key));
-                    attributes.push(("local_ip", &ep.local_ip));
-                    attributes.push(("local_subnet", &ep.local_subnet));
+        let mut pehm = PeerEntryHashMap::new();
+        let pe = PeerEntry {
+            public_key: "2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=",
+            name: Some("phone"),
+            ..PeerEntry::default()
+        };
+        pehm.insert(pe.public_key, pe);
+        let json = a.render_wg_easy_json("wg0", Some(&pehm.into()), 1555771458);
+        assert_eq!(json["clients"].as_object().unwrap().len(), 5);
+        let id = peer_uuid("2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=");
+        assert_eq!(json["clients"][&id]["name"], "phone");
+    }
 
-                    // let's add the friendly_name attribute if present
-                    // and has meaniningful value
-                    if let Some(pehm) = pehm {
-                        if let Some(ep_friendly_name) = pehm.get(&ep.public_key as &str) {
-                            if let Some(ep_friendly_name) = ep_friendly_name.name {
-                                attributes.push(("friendly_name", &ep_friendly_name));
-                            }
-                        }
-                    }
+    #[test]
+    fn test_render_wg_quick() {
+        use crate::wireguard_config::{PeerEntry, PeerEntryHashMap};
 
-                    s_sent_bytes_total
-                        .push(pc_sent_bytes_total.render_counter(Some(&attributes), ep.sent_bytes));
-                    s_received_bytes_total.push(
-                        pc_received_bytes_total
-                            .render_counter(Some(&attributes), ep.received_bytes),
-                    );
-                    s_latest_handshake.push(
-                        pc_latest_handshake.render_counter(Some(&attributes), ep.latest_handshake),
-                    );
-                }
-            }
-        }
+        let a = WireGuard::try_from(TEXT).unwrap();
+        assert_eq!(
+            a.render_wg_quick("pollo", None),
+            "[Peer]\nPublicKey = YdVOIPKt9K2MPsO2NlWQbOnFJcL/qX80mmhQwsUlA=\nAllowedIPs = 10.70.70.50/32\n"
+        );
+        assert_eq!(a.render_wg_quick("wg1", None), "");
 
-        // now let's join the results and return it to the caller
-        let mut s = String::with_capacity(s_latest_handshake.len() * 64 * 3);
-        for item in s_sent_bytes_total {
-            s.push_str(&item);
-        }
-        for item in s_received_bytes_total {
-            s.push_str(&item);
-        }
-        for item in s_latest_handshake {
-            s.push_str(&item);
-        }
+        let mut pehm = PeerEntryHashMap::new();
+        let pe = PeerEntry {
+            public_key: "2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=",
+            name: Some("phone"),
+            ..PeerEntry::default()
+        };
+        pehm.insert(pe.public_key, pe);
+        let s = a.render_wg_quick("wg0", Some(&pehm.into()));
+        assert!(s.starts_with("[Peer]\n# phone\nPublicKey = 2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=\nAllowedIPs = 10.70.0.2/32\n\n[Peer]\nPublicKey = qnox"));
 
-        s
+        // the export reads back as a names file
+        let names = crate::wireguard_config::peer_entry_hashmap_try_from(
+            &s,
+            &crate::wireguard_config::CommentKeys::default(),
+        )
+        .unwrap();
+        assert_eq!(names.len(), 5);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_render_unnamed_peers() {
+        use crate::wireguard_config::{PeerEntry, PeerEntryHashMap};
 
-    const TEXT : &'static str = "wg0\t000q4qAC0ExW/BuGSmVR1nxH9JAXT6g9Wd3oEGy5lA=\t0000u8LWR682knVm350lnuqlCJzw5SNLW9Nf96P+m8=\t51820\toff
-wg0\t2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=\t(none)\t37.159.76.245:29159\t10.70.0.2/32\t1555771458\t10288508\t139524160\toff
-wg0\tqnoxQoQI8KKMupLnSSureORV0wMmH7JryZNsmGVISzU=\t(none)\t(none)\t10.70.0.3/32\t0\t0\t0\toff
-wg0\tL2UoJZN7RmEKsMmqaJgKG0m1S2Zs2wd2ptAf+kb3008=\t(none)\t(none)\t10.70.0.4/32\t0\t0\t0\toff
-wg0\tMdVOIPKt9K2MPj/sO2NlWQbOnFJ6L/qX80mmhQwsUlA=\t(none)\t(none)\t10.70.0.50/32\t0\t0\t0\toff
-wg2\tMdVOIPKt9K2MPj/sO2NlWQbOnFJcL/qX80mmhQwsUlA=\t(none)\t(none)\t10.70.5.50/32\t0\t0\t0\toff
-pollo\tYdVOIPKt9K2MPsO2NlWQbOnFJcL/qX80mmhQwsUlA=\t(none)\t(none)\t10.70.70.50/32\t0\t0\t0\toff
-wg0\t928vO9Lf4+Mo84cWu4k1oRyzf0AR7FTGoPKHGoTMSHk=\t(none)\t5.90.62.106:21741\t10.70.0.80/32\t1555344925\t283012\t6604620\toff
-";
+        let a = WireGuard::try_from(TEXT).unwrap();
+
+        let mut pehm = PeerEntryHashMap::new();
+        for (public_key, name) in &[
+            (
+                "2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=",
+                Some("OnePlus 6T"),
+            ),
+            ("qnoxQoQI8KKMupLnSSureORV0wMmH7JryZNsmGVISzU=", None),
+            (
+                "MdVOIPKt9K2MPj/sO2NlWQbOnFJcL/qX80mmhQwsUlA=",
+                Some("frcognoarch"),
+            ),
+        ] {
+            pehm.insert(
+                public_key,
+                PeerEntry {
+                    public_key,
+                    allowed_ips: "",
+                    endpoint: None,
+                    name: *name,
+                    id: None,
+                    site: None,
+                    client: None,
+                    group: None,
+                    unknown_keys: Vec::new(),
+                },
+            );
+        }
+
+        let pehm = PeerEntries::from(pehm);
+        let s = text(|registry| a.render_unnamed_peers(registry, &pehm, false));
+        assert_eq!(
+            s,
+            "# HELP wireguard_peers_without_friendly_name Peers without a friendly name\n# TYPE wireguard_peers_without_friendly_name gauge\nwireguard_peers_without_friendly_name{interface=\"pollo\"} 1\nwireguard_peers_without_friendly_name{interface=\"wg0\"} 4\nwireguard_peers_without_friendly_name{interface=\"wg2\"} 0\n"
+        );
+
+        let s = text(|registry| a.render_unnamed_peers(registry, &pehm, true));
+        assert!(s.contains("wireguard_peer_without_friendly_name_info{interface=\"wg0\",public_key=\"qnoxQoQI8KKMupLnSSureORV0wMmH7JryZNsmGVISzU=\"} 1\n"));
+        assert!(!s.contains("public_key=\"2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=\""));
+    }
 
     #[test]
-    fn test_parse() {
-        let a = WireGuard::try_from(TEXT).unwrap();
-        println!("{:?}", a);
-        assert!(a.interfaces.len() == 3);
-        assert!(a.interfaces["wg0"].len() == 6);
+    fn test_render_name_sources() {
+        use crate::wireguard_config::{PeerEntry, PeerEntryHashMap};
 
-        let e1 = match &a.interfaces["wg0"][1] {
-            Endpoint::Local(_) => panic!(),
-            Endpoint::Remote(re) => re,
+        let a = WireGuard::try_from(TEXT).unwrap();
+        let pehm_of = |peers: &[(&'static str, Option<&'static str>)]| {
+            let mut pehm = PeerEntryHashMap::new();
+            for (public_key, name) in peers {
+                pehm.insert(
+                    *public_key,
+                    PeerEntry {
+                        public_key,
+                        allowed_ips: "",
+                        endpoint: None,
+                        name: *name,
+                        id: None,
+                        site: None,
+                        client: None,
+                        group: None,
+                        unknown_keys: Vec::new(),
+                    },
+                );
+            }
+            pehm
         };
 
+        let mut pehm = PeerEntries::default();
+        pehm.insert(
+            None,
+            pehm_of(&[
+                (
+                    "2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=",
+                    Some("phone"),
+                ),
+                (
+                    "qnoxQoQI8KKMupLnSSureORV0wMmH7JryZNsmGVISzU=",
+                    Some("laptop"),
+                ),
+            ]),
+        );
+        // the entry of the interface wins, even without a name
+        pehm.insert(
+            Some("wg0"),
+            pehm_of(&[("qnoxQoQI8KKMupLnSSureORV0wMmH7JryZNsmGVISzU=", None)]),
+        );
+        pehm.insert(
+            Some("wg2"),
+            pehm_of(&[("MdVOIPKt9K2MPj/sO2NlWQbOnFJcL/qX80mmhQwsUlA=", Some("site"))]),
+        );
+
+        let s = text(|registry| a.render_name_sources(registry, &pehm));
+        for (interface, source, count) in &[
+            ("pollo", "none", 1),
+            ("wg0", "interface_config", 0),
+            ("wg0", "config", 1),
+            ("wg0", "none", 4),
+            ("wg2", "interface_config", 1),
+            ("wg2", "none", 0),
+        ] {
+            assert!(s.contains(&format!(
+                "wireguard_friendly_names{{interface=\"{}\",source=\"{}\"}} {}\n",
+                interface, source, count
+            )));
+        }
+    }
+
+    #[test]
+    fn test_peer_ids() {
+        use crate::wireguard_config::{PeerEntry, PeerEntryHashMap};
+
+        let a = WireGuard::try_from(TEXT).unwrap();
+        let mut pehm = PeerEntryHashMap::new();
+        for (public_key, id) in &[
+            ("2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=", "phone"),
+            // the same ID twice on an interface, neither peer gets it
+            ("qnoxQoQI8KKMupLnSSureORV0wMmH7JryZNsmGVISzU=", "laptop"),
+            ("L2UoJZN7RmEKsMmqaJgKG0m1S2Zs2wd2ptAf+kb3008=", "laptop"),
+        ] {
+            pehm.insert(
+                *public_key,
+                PeerEntry {
+                    public_key,
+                    allowed_ips: "",
+                    endpoint: None,
+                    name: None,
+                    id: Some(id),
+                    site: None,
+                    client: None,
+                    group: None,
+                    unknown_keys: Vec::new(),
+                },
+            );
+        }
+        let mut entries = PeerEntries::default();
+        entries.insert(None, pehm);
+
+        let ids = a.peer_ids(&entries);
         assert_eq!(
-            e1.public_key,
-            "2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk="
+            ids.into_iter().collect::<Vec<_>>(),
+            vec![(
+                ("wg0", "2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk="),
+                "phone"
+            )]
         );
+
+        let s = text(|registry| WireGuard::render_peer_info(registry, &a.peer_ids(&entries)));
+        assert!(s.contains("wireguard_peer_info{interface=\"wg0\",peer_id=\"phone\",public_key=\"2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=\"} 1\n"));
     }
 
     #[test]
-    fn test_parse_and_serialize() {
+    fn test_render_unknown_peers() {
+        use crate::wireguard_config::{PeerEntry, PeerEntryHashMap};
+
+        let mut a = WireGuard::try_from(TEXT).unwrap();
+
+        let mut pehm = PeerEntryHashMap::new();
+        for public_key in &[
+            "2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=",
+            "qnoxQoQI8KKMupLnSSureORV0wMmH7JryZNsmGVISzU=",
+            "L2UoJZN7RmEKsMmqaJgKG0m1S2Zs2wd2ptAf+kb3008=",
+            "MdVOIPKt9K2MPj/sO2NlWQbOnFJ6L/qX80mmhQwsUlA=",
+            "MdVOIPKt9K2MPj/sO2NlWQbOnFJcL/qX80mmhQwsUlA=",
+        ] {
+            pehm.insert(
+                public_key,
+                PeerEntry {
+                    public_key,
+                    ..PeerEntry::default()
+                },
+            );
+        }
+        let pehm = PeerEntries::from(pehm);
+
+        let s = text(|registry| a.render_unknown_peers(registry, &pehm));
+        assert!(s.contains("wireguard_unknown_peers{interface=\"pollo\"} 1\n"));
+        assert!(s.contains("wireguard_unknown_peers{interface=\"wg0\"} 1\n"));
+        assert!(s.contains("wireguard_unknown_peers{interface=\"wg2\"} 0\n"));
+        assert!(s.contains("wireguard_unknown_peer_info{interface=\"wg0\",public_key=\"928vO9Lf4+Mo84cWu4k1oRyzf0AR7FTGoPKHGoTMSHk=\",endpoint=\"5.90.62.106:21741\"} 1\n"));
+
+        // the peers dropped by --only-configured-peers are still reported
+        a.retain_configured_peers(&pehm);
+        assert_eq!(a.unconfigured_peers.len(), 2);
+        assert_eq!(text(|registry| a.render_unknown_peers(registry, &pehm)), s);
+    }
+
+    #[test]
+    fn test_render_below_threshold() {
         let a = WireGuard::try_from(TEXT).unwrap();
-        let s = a.render_with_names(None);
-        println!("{}", s);
+        let s =
+            text(|registry| a.render_with_names(registry, None, 10_000_000, Compat::None, false));
+
+        // only the first wg0 peer transferred more than 10MB
+        assert_eq!(s.matches("wireguard_sent_bytes_total{").count(), 1);
+        assert!(s.contains("public_key=\"2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=\""));
+        assert!(s.ends_with("# HELP wireguard_below_threshold_peers Peers not exported individually because of their low traffic\n# TYPE wireguard_below_threshold_peers gauge\nwireguard_below_threshold_peers{interface=\"pollo\"} 1\nwireguard_below_threshold_peers{interface=\"wg0\"} 4\nwireguard_below_threshold_peers{interface=\"wg2\"} 1\n# HELP wireguard_below_threshold_sent_bytes Bytes sent to the peers not exported individually\n# TYPE wireguard_below_threshold_sent_bytes gauge\nwireguard_below_threshold_sent_bytes{interface=\"pollo\"} 0\nwireguard_below_threshold_sent_bytes{interface=\"wg0\"} 283012\nwireguard_below_threshold_sent_bytes{interface=\"wg2\"} 0\n# HELP wireguard_below_threshold_received_bytes Bytes received from the peers not exported individually\n# TYPE wireguard_below_threshold_received_bytes gauge\nwireguard_below_threshold_received_bytes{interface=\"pollo\"} 0\nwireguard_below_threshold_received_bytes{interface=\"wg0\"} 6604620\nwireguard_below_threshold_received_bytes{interface=\"wg2\"} 0\n"));
+    }
+
+    #[test]
+    fn test_render_compat() {
+        let wg = WireGuard::try_from(TEXT).unwrap();
+
+        let s = text(|registry| wg.render_with_names(registry, None, 0, Compat::Mindflavor, false));
+        assert!(s.contains("wireguard_sent_bytes_total{interface=\"wg0\",public_key=\"2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=\",allowed_ip_0=\"10.70.0.2\",allowed_subnet_0=\"32\"} 10288508\n"));
+
+        let s = text(|registry| {
+            wg.render_with_names(
+                registry,
+                None,
+                0,
+                Compat::PrometheusWireguardExporterGo,
+                false,
+            )
+        });
+        assert!(s.contains("wireguard_peer_transmit_bytes_total{device=\"wg0\",public_key=\"2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=\"} 10288508\n"));
+        assert!(!s.contains("wireguard_sent_bytes_total"));
+
+        let s = text(|registry| wg.render_with_names(registry, None, 0, Compat::Wgexporter, false));
+        assert!(s.contains("wireguard_peer_latest_handshake_seconds{interface=\"wg0\",public_key=\"2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=\",allowed_ips=\"10.70.0.2/32\"} 1555771458\n"));
+    }
+
+    #[test]
+    fn test_render_bits() {
+        let wg = WireGuard::try_from(TEXT).unwrap();
+
+        let s = text(|registry| wg.render_with_names(registry, None, 0, Compat::None, false));
+        assert!(!s.contains("bits_total"));
+
+        let s = text(|registry| wg.render_with_names(registry, None, 0, Compat::None, true));
+        assert!(s.contains("wireguard_sent_bits_total{inteface=\"wg0\",public_key=\"2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=\",local_ip=\"10.70.0.2\",local_subnet=\"32\"} 82308064\n"));
+        assert!(s.contains("wireguard_received_bits_total{inteface=\"wg0\",public_key=\"2S7mA0vEMethCNQrJpJKE81/JmhgtB+tHHLYQhgM6kk=\",local_ip=\"10.70.0.2\",local_subnet=\"32\"} 1116193280\n"));
     }
 
     #[test]
@@ -250,29 +1968,34 @@ wg0\t928vO9Lf4+Mo84cWu4k1oRyzf0AR7FTGoPKHGoTMSHk=\t(none)\t5.90.62.106:21741\t10
             remote_port: Some(100),
             local_ip: "local_ip".to_owned(),
             local_subnet: "local_subnet".to_owned(),
+            allowed_ips: vec!["local_ip/local_subnet".to_owned()],
             latest_handshake: 500,
             sent_bytes: 1000,
             received_bytes: 5000,
             persistent_keepalive: false,
+            has_preshared_key: false,
         });
         let mut wg = WireGuard {
             interfaces: HashMap::new(),
+            unconfigured_peers: Vec::new(),
+            collect_durations: Vec::new(),
+            cached_age: None,
         };
 
-        let mut v = Vec::new();
-        v.push(re);
+        let v = vec![re];
         wg.interfaces.insert("Pippo".to_owned(), v);
 
-        let prometheus = wg.render_with_names(None);
+        let prometheus =
+            text(|registry| wg.render_with_names(registry, None, 0, Compat::None, false));
 
         assert_eq!(prometheus, REF);
     }
 
     #[test]
     fn test_render_to_prometheus_complex() {
-        use crate::wireguard_config::PeerEntry;
+        use crate::wireguard_config::{PeerEntry, PeerEntryHashMap};
 
-        const REF :&'static str = "# HELP wireguard_sent_bytes_total Bytes sent to the peer\n# TYPE wireguard_sent_bytes_total counter\nwireguard_sent_bytes_total{inteface=\"Pippo\",public_key=\"test\",local_ip=\"local_ip\",local_subnet=\"local_subnet\"} 1000\nwireguard_sent_bytes_total{inteface=\"Pippo\",public_key=\"second_test\",local_ip=\"local_ip\",local_subnet=\"local_subnet\",friendly_name=\"this is my friendly name\"} 14\n# HELP wireguard_received_bytes_total Bytes received from the peer\n# TYPE wireguard_received_bytes_total counter\nwireguard_received_bytes_total{inteface=\"Pippo\",public_key=\"test\",local_ip=\"local_ip\",local_subnet=\"local_subnet\"} 5000\nwireguard_received_bytes_total{inteface=\"Pippo\",public_key=\"second_test\",local_ip=\"local_ip\",local_subnet=\"local_subnet\",friendly_name=\"this is my friendly name\"} 1000000000\n# HELP wireguard_latest_handshake_seconds Seconds from the last handshake\n# TYPE wireguard_latest_handshake_seconds gauge\nwireguard_latest_handshake_seconds{inteface=\"Pippo\",public_key=\"test\",local_ip=\"local_ip\",local_subnet=\"local_subnet\"} 500\nwireguard_latest_handshake_seconds{inteface=\"Pippo\",public_key=\"second_test\",local_ip=\"local_ip\",local_subnet=\"local_subnet\",friendly_name=\"this is my friendly name\"} 50\n";
+        const REF :&str = "# HELP wireguard_sent_bytes_total Bytes sent to the peer\n# TYPE wireguard_sent_bytes_total counter\nwireguard_sent_bytes_total{inteface=\"Pippo\",public_key=\"test\",local_ip=\"local_ip\",local_subnet=\"local_subnet\"} 1000\nwireguard_sent_bytes_total{inteface=\"Pippo\",public_key=\"second_test\",local_ip=\"local_ip\",local_subnet=\"local_subnet\",friendly_name=\"this is my friendly name\"} 14\n# HELP wireguard_received_bytes_total Bytes received from the peer\n# TYPE wireguard_received_bytes_total counter\nwireguard_received_bytes_total{inteface=\"Pippo\",public_key=\"test\",local_ip=\"local_ip\",local_subnet=\"local_subnet\"} 5000\nwireguard_received_bytes_total{inteface=\"Pippo\",public_key=\"second_test\",local_ip=\"local_ip\",local_subnet=\"local_subnet\",friendly_name=\"this is my friendly name\"} 1000000000\n# HELP wireguard_latest_handshake_seconds Seconds from the last handshake\n# TYPE wireguard_latest_handshake_seconds gauge\nwireguard_latest_handshake_seconds{inteface=\"Pippo\",public_key=\"test\",local_ip=\"local_ip\",local_subnet=\"local_subnet\"} 500\nwireguard_latest_handshake_seconds{inteface=\"Pippo\",public_key=\"second_test\",local_ip=\"local_ip\",local_subnet=\"local_subnet\",friendly_name=\"this is my friendly name\"} 50\n";
 
         let re1 = Endpoint::Remote(RemoteEndpoint {
             public_key: "test".to_owned(),
@@ -280,10 +2003,12 @@ wg0\t928vO9Lf4+Mo84cWu4k1oRyzf0AR7FTGoPKHGoTMSHk=\t(none)\t5.90.62.106:21741\t10
             remote_port: Some(100),
             local_ip: "local_ip".to_owned(),
             local_subnet: "local_subnet".to_owned(),
+            allowed_ips: vec!["local_ip/local_subnet".to_owned()],
             latest_handshake: 500,
             sent_bytes: 1000,
             received_bytes: 5000,
             persistent_keepalive: false,
+            has_preshared_key: false,
         });
         let re2 = Endpoint::Remote(RemoteEndpoint {
             public_key: "second_test".to_owned(),
@@ -291,32 +2016,42 @@ wg0\t928vO9Lf4+Mo84cWu4k1oRyzf0AR7FTGoPKHGoTMSHk=\t(none)\t5.90.62.106:21741\t10
             remote_port: Some(100),
             local_ip: "local_ip".to_owned(),
             local_subnet: "local_subnet".to_owned(),
+            allowed_ips: vec!["local_ip/local_subnet".to_owned()],
             latest_handshake: 50,
             sent_bytes: 14,
             received_bytes: 1_000_000_000,
             persistent_keepalive: false,
+            has_preshared_key: false,
         });
 
         let mut wg = WireGuard {
             interfaces: HashMap::new(),
+            unconfigured_peers: Vec::new(),
+            collect_durations: Vec::new(),
+            cached_age: None,
         };
 
-        let mut v = Vec::new();
-        v.push(re1);
-        v.push(re2);
+        let v = vec![re1, re2];
         wg.interfaces.insert("Pippo".to_owned(), v);
 
         let mut pehm = PeerEntryHashMap::new();
         let pe = PeerEntry {
             public_key: "second_test",
             allowed_ips: "ignored",
+            endpoint: None,
             name: Some("this is my friendly name"),
+            id: None,
+            site: None,
+            client: None,
+            group: None,
+            unknown_keys: Vec::new(),
         };
         pehm.insert(pe.public_key, pe);
 
-        let prometheus = wg.render_with_names(Some(&pehm));
+        let prometheus = text(|registry| {
+            wg.render_with_names(registry, Some(&pehm.into()), 0, Compat::None, false)
+        });
 
         assert_eq!(prometheus, REF);
     }
-
 }
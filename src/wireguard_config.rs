@@ -1,19 +1,166 @@
 use crate::exporter_error::PeerEntryParseError;
-use log::debug;
-use std::collections::HashMap;
+use crate::metrics::{MetricType, Registry};
+use log::{debug, warn};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+// the collections that failed because a names file could not be read,
+// or could not be parsed
+static READ_FAILURES: AtomicU64 = AtomicU64::new(0);
+static PARSE_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+struct UnknownKeys {
+    // the keys of the latest names files parsed, with the peers having them
+    latest: BTreeMap<String, BTreeSet<String>>,
+    // every key ever found
+    found: BTreeSet<String>,
+}
+
+static UNKNOWN_KEYS: Mutex<UnknownKeys> = Mutex::new(UnknownKeys {
+    latest: BTreeMap::new(),
+    found: BTreeSet::new(),
+});
+
+/// Where the friendly name of a peer comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NameSource {
+    /// a names file bound to the interface of the peer
+    InterfaceConfig,
+    /// a names file applying to all the interfaces
+    Config,
+    /// the peer has no friendly name
+    None,
+}
+
+impl NameSource {
+    pub const ALL: [NameSource; 3] = [
+        NameSource::InterfaceConfig,
+        NameSource::Config,
+        NameSource::None,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NameSource::InterfaceConfig => "interface_config",
+            NameSource::Config => "config",
+            NameSource::None => "none",
+        }
+    }
+}
+
+/// Counts a collection failed because of the names files, `parse` telling
+/// whether they were read but could not be parsed.
+pub(crate) fn record_failure(parse: bool) {
+    let failures = if parse {
+        &PARSE_FAILURES
+    } else {
+        &READ_FAILURES
+    };
+    failures.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records the unknown comment keys of the peers, see `comment_key`, as
+/// those of the latest names files parsed. The keys never found before
+/// are logged.
+pub(crate) fn record_unknown_keys(pehm: &PeerEntries) {
+    let mut latest: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for entry in pehm.entries() {
+        for key in entry.unknown_keys.iter() {
+            latest
+                .entry((*key).to_owned())
+                .or_default()
+                .insert(entry.public_key.to_owned());
+        }
+    }
+
+    let mut unknown_keys = UNKNOWN_KEYS.lock().unwrap();
+    for (key, peers) in latest.iter() {
+        if unknown_keys.found.insert(key.clone()) {
+            warn!(
+                "the names files assign the unknown key {} to {} peer(s), such as {}: is it a typo?",
+                key,
+                peers.len(),
+                peers.iter().next().unwrap()
+            );
+        }
+    }
+    unknown_keys.latest = latest;
+}
+
+/// Renders how many unknown comment keys were found.
+pub(crate) fn render_unknown_keys(registry: &mut Registry) {
+    let mut found = registry.family(
+        "wireguard_exporter_unknown_comment_keys_total",
+        MetricType::Counter,
+        "Distinct unknown keys found in the comments of the names files",
+    );
+    found.push(&[], UNKNOWN_KEYS.lock().unwrap().found.len() as f64);
+    registry.register(found);
+}
+
+/// The unknown comment keys of the latest names files parsed, with the
+/// public keys of the peers having them.
+pub(crate) fn render_unknown_keys_json() -> Value {
+    json!({ "keys": UNKNOWN_KEYS.lock().unwrap().latest })
+}
 
+/// Renders the failures counted by `record_failure`.
+pub(crate) fn render_failures(registry: &mut Registry) {
+    let mut failures = registry.family(
+        "wireguard_exporter_name_resolution_failures_total",
+        MetricType::Counter,
+        "Collections failed because a names file could not be read or parsed",
+    );
+    for (reason, count) in &[("read", &READ_FAILURES), ("parse", &PARSE_FAILURES)] {
+        failures.push(&[("reason", reason)], count.load(Ordering::Relaxed) as f64);
+    }
+    registry.register(failures);
+}
+
+#[allow(dead_code)]
 #[derive(Debug, Default, Clone)]
 pub(crate) struct PeerEntry<'a> {
     pub public_key: &'a str,
     pub allowed_ips: &'a str,
+    /// the `Endpoint` of the peer, as written (`host:port`)
+    pub endpoint: Option<&'a str>,
     pub name: Option<&'a str>,
+    /// the stable ID of the peer, see `--peer-id-comment-key`
+    pub id: Option<&'a str>,
+    /// the site of the peer, see `--site-comment-key`
+    pub site: Option<&'a str>,
+    /// the client software of the peer, see `--client-comment-key`
+    pub client: Option<&'a str>,
+    /// the group of the peer, such as its customer, see
+    /// `--group-comment-key`
+    pub group: Option<&'a str>,
+    /// the keys assigned by the comments which are not one of the keys
+    /// given, see `comment_key`
+    pub unknown_keys: Vec<&'a str>,
+}
+
+/// The keys of the comments giving the metadata of a peer, see
+/// `PeerEntry::parse`.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct CommentKeys<'k> {
+    pub name: Option<&'k str>,
+    pub id: Option<&'k str>,
+    pub site: Option<&'k str>,
+    pub client: Option<&'k str>,
+    pub group: Option<&'k str>,
 }
 
 #[inline]
 fn after_char(s: &str, c_split: char) -> &str {
     let mut p: usize = 0;
-    for c in s.chars().into_iter() {
+    for c in s.chars() {
         if c == c_split {
             return &s[p + 1..];
         } else {
@@ -23,57 +170,261 @@ fn after_char(s: &str, c_split: char) -> &str {
     s
 }
 
-impl<'a> TryFrom<&[&'a str]> for PeerEntry<'a> {
-    type Error = PeerEntryParseError;
+/// Extracts the friendly name from `comment` (the text following the `#`).
+/// Without `key` the whole comment is the name. With a key only the
+/// comments assigning it are considered: `# Name = laptop`, `# Name: laptop`
+/// and `#!Name=laptop` all name the peer `laptop` if `key` is `name` (the
+/// key is case insensitive).
+fn name_from_comment<'a>(comment: &'a str, key: Option<&str>) -> Option<&'a str> {
+    let key = match key {
+        Some(key) => key,
+        None => return Some(comment.trim()),
+    };
+
+    let comment = comment.trim_start_matches('!').trim_start();
+    if comment.len() < key.len()
+        || !comment.is_char_boundary(key.len())
+        || !comment[..key.len()].eq_ignore_ascii_case(key)
+    {
+        return None;
+    }
 
-    fn try_from(lines: &[&'a str]) -> Result<PeerEntry<'a>, Self::Error> {
+    let value = comment[key.len()..].trim_start();
+    if value.starts_with('=') || value.starts_with(':') {
+        Some(value[1..].trim())
+    } else {
+        None
+    }
+}
+
+/// The key `comment` assigns, as in `# Name = laptop` or `#!Name: laptop`:
+/// a word followed by `=`, or by `:` and a space so that the URLs are not
+/// taken for keys.
+fn comment_key(comment: &str) -> Option<&str> {
+    let comment = comment.trim_start_matches('!').trim_start();
+    let end = comment
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+        .unwrap_or(comment.len());
+    let key = &comment[..end];
+    if !key.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+
+    let value = comment[end..].trim_start();
+    let assigned = value.starts_with('=')
+        || value
+            .strip_prefix(':')
+            .is_some_and(|value| value.is_empty() || value.starts_with(char::is_whitespace));
+    if assigned {
+        Some(key)
+    } else {
+        None
+    }
+}
+
+impl<'a> PeerEntry<'a> {
+    /// Parses the lines of a `[Peer]` block. The friendly name is taken
+    /// from the comments of the block, including the one trailing the
+    /// `AllowedIPs` line, as per `name_from_comment`. If more than one
+    /// comment names the peer the last one wins. The comments assigning
+    /// the `id`, `site`, `client` or `group` keys, if any, give the ID, the
+    /// site, the client or the group of the peer instead, while the `name`
+    /// key restricts the naming comments. With the `name` key, the other
+    /// keys assigned are `unknown_keys`.
+    pub fn parse(
+        lines: &[&'a str],
+        keys: &CommentKeys,
+    ) -> Result<PeerEntry<'a>, PeerEntryParseError> {
         let mut public_key = "";
         let mut allowed_ips = "";
+        let mut endpoint = None;
         let mut name = None;
+        let mut id = None;
+        let mut site = None;
+        let mut client = None;
+        let mut group = None;
+        let mut unknown_keys = Vec::new();
+
+        let mut comment = |comment: &'a str| {
+            let assigned =
+                |key: Option<&str>| key.and_then(|key| name_from_comment(comment, Some(key)));
+            if let Some(i) = assigned(keys.id) {
+                id = Some(i);
+            } else if let Some(s) = assigned(keys.site) {
+                site = Some(s);
+            } else if let Some(c) = assigned(keys.client) {
+                client = Some(c);
+            } else if let Some(g) = assigned(keys.group) {
+                group = Some(g);
+            } else if let Some(n) = name_from_comment(comment, keys.name) {
+                name = Some(n);
+            } else if let Some(key) = comment_key(comment) {
+                // without the name key every comment is a name
+                unknown_keys.push(key);
+            }
+        };
 
         for line in lines {
             if line.starts_with("PublicKey") {
                 public_key = after_char(line, '=').trim();
             } else if line.starts_with("AllowedIPs") {
-                allowed_ips = after_char(line, '=').trim();
-            } else if line.starts_with('#') {
-                // since the pound sign is 1 byte the below slice will work
-                name = Some(line[1..].trim());
+                let value = after_char(line, '=');
+                match value.find('#') {
+                    Some(hash) => {
+                        allowed_ips = value[..hash].trim();
+                        comment(&value[hash + 1..]);
+                    }
+                    None => allowed_ips = value.trim(),
+                }
+            } else if line.starts_with("Endpoint") {
+                let value = after_char(line, '=');
+                let value = value.split('#').next().unwrap_or(value).trim();
+                if !value.is_empty() {
+                    endpoint = Some(value);
+                }
+            } else if let Some(c) = line.strip_prefix('#') {
+                comment(c);
             }
         }
 
         // Sanity checks
         // If there are more than one PublicKey or AllowedIPs we won't catch it. But
         // WireGuard won't be working either so we can live with this simplification.
-        if public_key == "" {
+        if public_key.is_empty() {
             // we return a owned String for ergonomics. This will allocate but it's ok since it's not supposed
             // to happen :)
             let lines_owned: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
             Err(PeerEntryParseError::PublicKeyNotFound { lines: lines_owned })
-        } else if allowed_ips == "" {
+        } else if allowed_ips.is_empty() {
             let lines_owned: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
             Err(PeerEntryParseError::AllowedIPsEntryNotFound { lines: lines_owned })
         } else {
             Ok(PeerEntry {
                 public_key,
                 allowed_ips,
+                endpoint,
                 name, // name can be None
+                id,
+                site,
+                client,
+                group,
+                unknown_keys,
             })
         }
     }
 }
 
-pub(crate) type PeerEntryHashMap<'a> = (HashMap<&'a str, PeerEntry<'a>>);
+impl<'a> TryFrom<&[&'a str]> for PeerEntry<'a> {
+    type Error = PeerEntryParseError;
+
+    fn try_from(lines: &[&'a str]) -> Result<PeerEntry<'a>, Self::Error> {
+        PeerEntry::parse(lines, &CommentKeys::default())
+    }
+}
+
+pub(crate) type PeerEntryHashMap<'a> = HashMap<&'a str, PeerEntry<'a>>;
+
+/// The peers read from the config files. The ones read from a file bound
+/// to an interface (`-n wg0:/etc/wireguard/wg0.conf`) only apply to that
+/// interface, the others apply to all of them.
+#[derive(Debug, Default)]
+pub(crate) struct PeerEntries<'a> {
+    any_interface: PeerEntryHashMap<'a>,
+    by_interface: HashMap<&'a str, PeerEntryHashMap<'a>>,
+}
+
+impl<'a> PeerEntries<'a> {
+    pub fn insert(&mut self, interface: Option<&'a str>, pehm: PeerEntryHashMap<'a>) {
+        match interface {
+            Some(interface) => self.by_interface.entry(interface).or_default().extend(pehm),
+            None => self.any_interface.extend(pehm),
+        }
+    }
 
-pub(crate) fn peer_entry_hashmap_try_from(
-    txt: &str,
-) -> Result<PeerEntryHashMap, PeerEntryParseError> {
+    pub fn get(&self, interface: &str, public_key: &str) -> Option<&PeerEntry<'a>> {
+        self.by_interface
+            .get(interface)
+            .and_then(|pehm| pehm.get(public_key))
+            .or_else(|| self.any_interface.get(public_key))
+    }
+
+    /// Where the name of the peer, as returned by `get`, comes from.
+    pub fn name_source(&self, interface: &str, public_key: &str) -> NameSource {
+        let (entry, source) = match self
+            .by_interface
+            .get(interface)
+            .and_then(|pehm| pehm.get(public_key))
+        {
+            Some(entry) => (Some(entry), NameSource::InterfaceConfig),
+            None => (self.any_interface.get(public_key), NameSource::Config),
+        };
+        match entry.and_then(|entry| entry.name) {
+            Some(_) => source,
+            None => NameSource::None,
+        }
+    }
+
+    /// All the peers, whichever interface they apply to.
+    pub fn entries(&self) -> impl Iterator<Item = &PeerEntry<'a>> {
+        self.any_interface
+            .values()
+            .chain(self.by_interface.values().flat_map(|pehm| pehm.values()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.any_interface.len()
+            + self
+                .by_interface
+                .values()
+                .map(|pehm| pehm.len())
+                .sum::<usize>()
+    }
+}
+
+impl<'a> From<PeerEntryHashMap<'a>> for PeerEntries<'a> {
+    fn from(pehm: PeerEntryHashMap<'a>) -> Self {
+        let mut peer_entries = PeerEntries::default();
+        peer_entries.insert(None, pehm);
+        peer_entries
+    }
+}
+
+/// Reads the config file at `path` followed by its fragments, the
+/// `*.conf` files in the `<path>.d` drop-in directory (if it exists) in
+/// lexicographic order. Some provisioning tools keep one peer per fragment.
+pub(crate) fn read_config_fragments(path: &str) -> io::Result<Vec<String>> {
+    let mut fragments = vec![fs::read_to_string(path)?];
+
+    let drop_in = PathBuf::from(format!("{}.d", path));
+    if drop_in.is_dir() {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(&drop_in)? {
+            let path = entry?.path();
+            if path.is_file() && path.extension() == Some("conf".as_ref()) {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        for path in paths {
+            debug!("reading config fragment {:?}", path);
+            fragments.push(fs::read_to_string(path)?);
+        }
+    }
+
+    Ok(fragments)
+}
+
+pub(crate) fn peer_entry_hashmap_try_from<'a>(
+    txt: &'a str,
+    keys: &CommentKeys,
+) -> Result<PeerEntryHashMap<'a>, PeerEntryParseError> {
     let mut hm = HashMap::new();
 
     let mut v_blocks = Vec::new();
     let mut cur_block: Option<Vec<&str>> = None;
 
-    for line in txt.lines().into_iter() {
+    for line in txt.lines() {
         if line.starts_with('[') {
             if let Some(inner_cur_block) = cur_block {
                 // close the block
@@ -88,7 +439,7 @@ pub(crate) fn peer_entry_hashmap_try_from(
         } else {
             // push the line if we are in a block (only if not empty)
             if let Some(inner_cur_block) = &mut cur_block {
-                if line != "" {
+                if !line.is_empty() {
                     inner_cur_block.push(line);
                 }
             }
@@ -103,7 +454,7 @@ pub(crate) fn peer_entry_hashmap_try_from(
     debug!("v_blocks == {:?}", v_blocks);
 
     for block in &v_blocks {
-        let p: PeerEntry = PeerEntry::try_from(&block as &[&str])?;
+        let p: PeerEntry = PeerEntry::parse(block, keys)?;
         hm.insert(p.public_key, p);
     }
 
@@ -116,7 +467,7 @@ pub(crate) fn peer_entry_hashmap_try_from(
 mod tests {
     use super::*;
 
-    const TEXT: &'static str = "
+    const TEXT: &str = "
 ListenPort = 51820
 PrivateKey = my_super_secret_private_key
 # PreUp = iptables -t nat -A POSTROUTING -s 10.70.0.0/24  -o enp7s0 -j MASQUERADE
@@ -153,7 +504,7 @@ PublicKey = 928vO9Lf4+Mo84cWu4k1oRyzf0AR7FTGoPKHGoTMSHk=
 AllowedIPs = 10.70.0.80/32
 ";
 
-    const TEXT_NOPK: &'static str = "
+    const TEXT_NOPK: &str = "
 ListenPort = 51820
 PrivateKey = my_super_secret_private_key
 # PreUp = iptables -t nat -A POSTROUTING -s 10.70.0.0/24  -o enp7s0 -j MASQUERADE
@@ -174,7 +525,7 @@ PublicKey = L2UoJZN7RmEKsMmqaJgKG0m1S2Zs2wd2ptAf+kb3008=
 AllowedIPs = 10.70.0.4/32
 ";
 
-    const TEXT_AIP: &'static str = "
+    const TEXT_AIP: &str = "
 ListenPort = 51820
 PrivateKey = my_super_secret_private_key
 # PreUp = iptables -t nat -A POSTROUTING -s 10.70.0.0/24  -o enp7s0 -j MASQUERADE
@@ -197,7 +548,8 @@ PublicKey = L2UoJZN7RmEKsMmqaJgKG0m1S2Zs2wd2ptAf+kb3008=
 
     #[test]
     fn test_parse_ok() {
-        let a: PeerEntryHashMap = peer_entry_hashmap_try_from(TEXT).unwrap();
+        let a: PeerEntryHashMap =
+            peer_entry_hashmap_try_from(TEXT, &CommentKeys::default()).unwrap();
         println!("{:?}", a);
     }
 
@@ -206,7 +558,8 @@ PublicKey = L2UoJZN7RmEKsMmqaJgKG0m1S2Zs2wd2ptAf+kb3008=
         expected = "PublicKeyNotFound { lines: [\"# varch.local (laptop)\", \"AllowedIPs = 10.70.0.3/32\"] }"
     )]
     fn test_parse_no_public_key() {
-        let _: PeerEntryHashMap = peer_entry_hashmap_try_from(TEXT_NOPK).unwrap();
+        let _: PeerEntryHashMap =
+            peer_entry_hashmap_try_from(TEXT_NOPK, &CommentKeys::default()).unwrap();
     }
 
     #[test]
@@ -214,6 +567,180 @@ PublicKey = L2UoJZN7RmEKsMmqaJgKG0m1S2Zs2wd2ptAf+kb3008=
         expected = "AllowedIPsEntryNotFound { lines: [\"# cantarch\", \"PublicKey = L2UoJZN7RmEKsMmqaJgKG0m1S2Zs2wd2ptAf+kb3008=\"] }"
     )]
     fn test_parse_no_allowed_ips() {
-        let _: PeerEntryHashMap = peer_entry_hashmap_try_from(TEXT_AIP).unwrap();
+        let _: PeerEntryHashMap =
+            peer_entry_hashmap_try_from(TEXT_AIP, &CommentKeys::default()).unwrap();
+    }
+
+    #[test]
+    fn test_peer_entries_by_interface() {
+        let wg0 = "[Peer]\n# wg0 name\nPublicKey = key\nAllowedIPs = 10.0.0.2/32\n";
+        let wg1 = "[Peer]\n# wg1 name\nPublicKey = key\nAllowedIPs = 10.1.0.2/32\n";
+        let any = "[Peer]\n# shared\nPublicKey = other\nAllowedIPs = 10.2.0.2/32\n";
+
+        let mut peer_entries = PeerEntries::default();
+        peer_entries.insert(
+            Some("wg0"),
+            peer_entry_hashmap_try_from(wg0, &CommentKeys::default()).unwrap(),
+        );
+        peer_entries.insert(
+            Some("wg1"),
+            peer_entry_hashmap_try_from(wg1, &CommentKeys::default()).unwrap(),
+        );
+        peer_entries.insert(
+            None,
+            peer_entry_hashmap_try_from(any, &CommentKeys::default()).unwrap(),
+        );
+
+        assert_eq!(peer_entries.len(), 3);
+        assert_eq!(
+            peer_entries.get("wg0", "key").unwrap().name,
+            Some("wg0 name")
+        );
+        assert_eq!(
+            peer_entries.get("wg1", "key").unwrap().name,
+            Some("wg1 name")
+        );
+        assert!(peer_entries.get("wg2", "key").is_none());
+        assert_eq!(
+            peer_entries.get("wg1", "other").unwrap().name,
+            Some("shared")
+        );
+    }
+
+    #[test]
+    fn test_crlf() {
+        // as WireGuard for Windows writes its config files
+        let text = "[Interface]\r\nPrivateKey = x\r\n\r\n[Peer]\r\n# Alice's laptop\r\nPublicKey = a\r\nAllowedIPs = 10.0.0.2/32\r\n";
+        let pehm = peer_entry_hashmap_try_from(text, &CommentKeys::default()).unwrap();
+        assert_eq!(pehm["a"].name, Some("Alice's laptop"));
+        assert_eq!(pehm["a"].allowed_ips, "10.0.0.2/32");
+    }
+
+    #[test]
+    fn test_name_comment_key() {
+        let text = "[Peer]
+#!name=wg-easy style
+PublicKey = a
+AllowedIPs = 10.0.0.2/32
+
+[Peer]
+# PreUp = not a name
+# Name: subspace style
+PublicKey = b
+AllowedIPs = 10.0.0.3/32
+
+[Peer]
+PublicKey = c
+AllowedIPs = 10.0.0.4/32 # name = inline
+
+[Peer]
+# no name here
+PublicKey = d
+AllowedIPs = 10.0.0.5/32
+Endpoint = home.example.org:51820 # dynamic DNS
+";
+
+        let pehm = peer_entry_hashmap_try_from(
+            text,
+            &CommentKeys {
+                name: Some("name"),
+                ..CommentKeys::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(pehm["a"].name, Some("wg-easy style"));
+        assert_eq!(pehm["b"].name, Some("subspace style"));
+        assert_eq!(pehm["c"].name, Some("inline"));
+        assert_eq!(pehm["c"].allowed_ips, "10.0.0.4/32");
+        assert_eq!(pehm["d"].name, None);
+        assert_eq!(pehm["d"].endpoint, Some("home.example.org:51820"));
+        assert_eq!(pehm["a"].endpoint, None);
+        assert_eq!(pehm["b"].unknown_keys, ["PreUp"]);
+        assert!(pehm["d"].unknown_keys.is_empty());
+
+        let pehm = peer_entry_hashmap_try_from(text, &CommentKeys::default()).unwrap();
+        assert_eq!(pehm["b"].name, Some("Name: subspace style"));
+        assert_eq!(pehm["c"].name, Some("name = inline"));
+    }
+
+    #[test]
+    fn test_comment_key() {
+        assert_eq!(comment_key(" frendly_name = laptop"), Some("frendly_name"));
+        assert_eq!(comment_key("!Name=laptop"), Some("Name"));
+        assert_eq!(comment_key(" Site: paris"), Some("Site"));
+        assert_eq!(comment_key(" see https://example.com"), None);
+        assert_eq!(comment_key(" https://example.com"), None);
+        assert_eq!(comment_key(" Alice's laptop"), None);
+        assert_eq!(comment_key(" 10.0.0.2 = gateway"), None);
+    }
+
+    #[test]
+    fn test_peer_id_site_comment_keys() {
+        let text = "[Peer]
+# PeerID = laptop-01
+# Site: paris
+# Client = linux
+# Customer = acme
+# Alice's laptop
+PublicKey = a
+AllowedIPs = 10.0.0.2/32
+
+[Peer]
+# Bob's phone
+PublicKey = b
+AllowedIPs = 10.0.0.3/32
+";
+
+        let pehm = peer_entry_hashmap_try_from(
+            text,
+            &CommentKeys {
+                id: Some("PeerID"),
+                site: Some("Site"),
+                client: Some("client"),
+                group: Some("Customer"),
+                ..CommentKeys::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(pehm["a"].id, Some("laptop-01"));
+        assert_eq!(pehm["a"].site, Some("paris"));
+        assert_eq!(pehm["a"].client, Some("linux"));
+        assert_eq!(pehm["a"].group, Some("acme"));
+        assert_eq!(pehm["a"].name, Some("Alice's laptop"));
+        assert_eq!(pehm["b"].id, None);
+        assert_eq!(pehm["b"].site, None);
+        assert_eq!(pehm["b"].client, None);
+        assert_eq!(pehm["b"].name, Some("Bob's phone"));
+    }
+
+    #[test]
+    fn test_read_config_fragments() {
+        let dir = std::env::temp_dir().join(format!(
+            "prometheus_wireguard_exporter_fragments_{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("wg0.conf.d")).unwrap();
+
+        let path = dir.join("wg0.conf");
+        fs::write(&path, "[Interface]\nListenPort = 51820\n").unwrap();
+        assert_eq!(
+            read_config_fragments(path.to_str().unwrap()).unwrap().len(),
+            1
+        );
+
+        fs::write(dir.join("wg0.conf.d/20-b.conf"), "[Peer]\n# b\n").unwrap();
+        fs::write(dir.join("wg0.conf.d/10-a.conf"), "[Peer]\n# a\n").unwrap();
+        fs::write(dir.join("wg0.conf.d/README"), "ignored").unwrap();
+        assert_eq!(
+            read_config_fragments(path.to_str().unwrap()).unwrap(),
+            vec![
+                "[Interface]\nListenPort = 51820\n".to_owned(),
+                "[Peer]\n# a\n".to_owned(),
+                "[Peer]\n# b\n".to_owned(),
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 }